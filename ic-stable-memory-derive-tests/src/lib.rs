@@ -89,6 +89,15 @@ mod derive_tests {
         let c_copy = C::from_dyn_size_bytes(&c_buf);
 
         assert_eq!(c, c_copy);
+
+        let c_copy = C::try_from_dyn_size_bytes(&c_buf).unwrap();
+
+        assert_eq!(c, c_copy);
+
+        assert!(matches!(
+            C::try_from_dyn_size_bytes(&c_buf[0..5]),
+            Err(ic_stable_memory::DecodeError::Candid(_))
+        ));
     }
 }
 
@@ -99,6 +108,7 @@ mod tests {
         SBTreeMap, SBTreeSet, SCertifiedBTreeMap, SHashMap, SHashSet, SLog, SVec,
     };
     use ic_stable_memory::derive::{AsFixedSizeBytes, CandidAsDynSizeBytes, StableType};
+    use ic_stable_memory::mem::leaks::LeakChecker;
     use ic_stable_memory::utils::certification::{
         leaf, leaf_hash, AsHashTree, AsHashableBytes, Hash, HashTree,
     };
@@ -241,6 +251,10 @@ mod tests {
                     state = retrieve_custom_data::<State>(1).unwrap().into_inner();
                 }
             }
+
+            let mut checker = LeakChecker::new();
+            checker.visit(&state);
+            assert!(checker.find_leaks().is_empty());
         }
 
         assert_eq!(get_allocated_size(), 0);
@@ -18,6 +18,12 @@ pub fn derive_candid_as_dyn_size_bytes_impl(ident: &Ident, generics: &Generics)
             fn from_dyn_size_bytes(arr: &[u8]) -> Self {
                 ic_stable_memory::encoding::dyn_size::candid_decode_one_allow_trailing(arr).unwrap()
             }
+
+            #[inline]
+            fn try_from_dyn_size_bytes(arr: &[u8]) -> std::result::Result<Self, ic_stable_memory::DecodeError> {
+                ic_stable_memory::encoding::dyn_size::candid_decode_one_allow_trailing(arr)
+                    .map_err(|e| ic_stable_memory::DecodeError::Candid(e.to_string()))
+            }
         }
     }
 }
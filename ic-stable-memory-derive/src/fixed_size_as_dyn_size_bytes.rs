@@ -18,6 +18,16 @@ pub fn derive_fixed_size_as_dyn_size_bytes_impl(ident: &Ident, generics: &Generi
             fn from_dyn_size_bytes(arr: &[u8]) -> Self {
                 ic_stable_memory::AsFixedSizeBytes::from_fixed_size_bytes(arr)
             }
+
+            #[inline]
+            fn try_from_dyn_size_bytes(arr: &[u8]) -> std::result::Result<Self, ic_stable_memory::DecodeError> {
+                ic_stable_memory::DecodeError::check_len(
+                    arr,
+                    <Self as ic_stable_memory::AsFixedSizeBytes>::SIZE,
+                )?;
+
+                Ok(ic_stable_memory::AsFixedSizeBytes::from_fixed_size_bytes(arr))
+            }
         }
     }
 }
@@ -7,10 +7,11 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
         panic!("Generics not supported");
     }
 
-    let (flag_off_body, flag_on_body) = match data {
+    let (flag_off_body, flag_on_body, visit_body) = match data {
         Data::Struct(d) => {
             let mut flag_off_body = quote! {};
             let mut flag_on_body = quote! {};
+            let mut visit_body = quote! {};
 
             for (idx, f) in d.fields.iter().enumerate() {
                 let t = &f.ty;
@@ -18,25 +19,29 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
                 if let Some(i) = f.ident.clone() {
                     flag_off_body = quote! { #flag_off_body <#t as ic_stable_memory::StableType>::stable_drop_flag_off(&mut self.#i); };
                     flag_on_body = quote! { #flag_on_body <#t as ic_stable_memory::StableType>::stable_drop_flag_on(&mut self.#i); };
+                    visit_body = quote! { #visit_body <#t as ic_stable_memory::StableType>::visit_allocations(&self.#i, visitor); };
                 } else {
                     let idx = Index::from(idx);
 
                     flag_off_body = quote! { #flag_off_body <#t as ic_stable_memory::StableType>::stable_drop_flag_off(&mut self.#idx); };
                     flag_on_body = quote! { #flag_on_body <#t as ic_stable_memory::StableType>::stable_drop_flag_on(&mut self.#idx); };
+                    visit_body = quote! { #visit_body <#t as ic_stable_memory::StableType>::visit_allocations(&self.#idx, visitor); };
                 };
             }
 
-            (flag_off_body, flag_on_body)
+            (flag_off_body, flag_on_body, visit_body)
         }
         Data::Enum(d) => {
             let mut flag_off_body_total = quote! {};
             let mut flag_on_body_total = quote! {};
+            let mut visit_body_total = quote! {};
 
             for v in d.variants.iter() {
                 let v_name = &v.ident;
 
                 let mut flag_off_body = quote! {};
                 let mut flag_on_body = quote! {};
+                let mut visit_body = quote! {};
 
                 let mut enum_header = quote! {};
 
@@ -48,6 +53,7 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
 
                         flag_off_body = quote! { #flag_off_body <#t as ic_stable_memory::StableType>::stable_drop_flag_off(#i); };
                         flag_on_body = quote! { #flag_on_body <#t as ic_stable_memory::StableType>::stable_drop_flag_on(#i); };
+                        visit_body = quote! { #visit_body <#t as ic_stable_memory::StableType>::visit_allocations(#i, visitor); };
                     } else {
                         let val_i = format_ident!("val_{}", idx);
 
@@ -55,10 +61,11 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
 
                         flag_off_body = quote! { #flag_off_body <#t as ic_stable_memory::StableType>::stable_drop_flag_off(#val_i); };
                         flag_on_body = quote! { #flag_on_body <#t as ic_stable_memory::StableType>::stable_drop_flag_on(#val_i); };
+                        visit_body = quote! { #visit_body <#t as ic_stable_memory::StableType>::visit_allocations(#val_i, visitor); };
                     };
                 }
 
-                (flag_off_body_total, flag_on_body_total) = match &v.fields {
+                (flag_off_body_total, flag_on_body_total, visit_body_total) = match &v.fields {
                     Fields::Unit => {
                         let owned = quote! {
                             #flag_off_body_total
@@ -70,7 +77,12 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
                             Self::#v_name => {}
                         };
 
-                        (owned, not_owned)
+                        let visit = quote! {
+                            #visit_body_total
+                            Self::#v_name => {}
+                        };
+
+                        (owned, not_owned, visit)
                     }
                     Fields::Named(_) => {
                         let owned = quote! {
@@ -87,7 +99,14 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
                             }
                         };
 
-                        (owned, not_owned)
+                        let visit = quote! {
+                            #visit_body_total
+                            Self::#v_name { #enum_header } => {
+                                #visit_body
+                            }
+                        };
+
+                        (owned, not_owned, visit)
                     }
                     Fields::Unnamed(_) => {
                         let owned = quote! {
@@ -104,7 +123,14 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
                             }
                         };
 
-                        (owned, not_owned)
+                        let visit = quote! {
+                            #visit_body_total
+                            Self::#v_name(#enum_header) => {
+                                #visit_body
+                            }
+                        };
+
+                        (owned, not_owned, visit)
                     }
                 };
             }
@@ -125,7 +151,13 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
                 }
             };
 
-            (flag_off_body_total, flag_on_body_total)
+            visit_body_total = quote! {
+                match self {
+                    #visit_body_total
+                }
+            };
+
+            (flag_off_body_total, flag_on_body_total, visit_body_total)
         }
         _ => panic!("Unions not supported!"),
     };
@@ -141,6 +173,12 @@ pub fn derive_stable_type_impl(ident: &Ident, data: &Data, generics: &Generics)
             unsafe fn stable_drop_flag_on(&mut self) {
                 #flag_on_body
             }
+
+            #[inline]
+            #[allow(unused_variables)]
+            fn visit_allocations(&self, visitor: &mut dyn FnMut(ic_stable_memory::mem::s_slice::SSlice)) {
+                #visit_body
+            }
         }
     }
 }
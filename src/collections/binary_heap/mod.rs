@@ -0,0 +1,577 @@
+use crate::collections::binary_heap::iter::SBinaryHeapIntoSortedIter;
+use crate::collections::vec::iter::SVecIter;
+use crate::collections::vec::SVec;
+use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use crate::{OutOfMemory, Rejected};
+use std::fmt::{Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+
+#[doc(hidden)]
+pub mod iter;
+
+/// Stable analog of [BinaryHeap](std::collections::BinaryHeap) - a priority queue
+///
+/// This is a max-heap, stored as an implicit binary tree on top of [SVec]`<T>`. It uses the same
+/// growable buffer, so it may reallocate on pushes and is limited to [SVec::max_capacity] elements.
+///
+/// `T` has to implement [Ord], [StableType] and [AsFixedSizeBytes]. [SBinaryHeap] itself implements
+/// these traits and can be nested inside other stable data structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBinaryHeap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// use std::cmp::Reverse;
+///
+/// // a min-heap of (timestamp, task id)
+/// let mut timers = SBinaryHeap::<Reverse<(u64, u64)>>::new();
+///
+/// timers.push(Reverse((300, 1))).expect("Out of memory");
+/// timers.push(Reverse((100, 2))).expect("Out of memory");
+/// timers.push(Reverse((200, 3))).expect("Out of memory");
+///
+/// assert_eq!(timers.pop(), Some(Reverse((100, 2))));
+/// assert_eq!(*timers.peek().unwrap(), Reverse((200, 3)));
+/// ```
+pub struct SBinaryHeap<T: StableType + AsFixedSizeBytes + Ord> {
+    inner: SVec<T>,
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord> SBinaryHeap<T> {
+    /// Creates an empty [SBinaryHeap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self { inner: SVec::new() }
+    }
+
+    /// Creates an empty [SBinaryHeap] of requested capacity
+    ///
+    /// See [SVec::new_with_capacity]
+    #[inline]
+    pub fn new_with_capacity(capacity: usize) -> Result<Self, OutOfMemory> {
+        Ok(Self {
+            inner: SVec::new_with_capacity(capacity)?,
+        })
+    }
+
+    /// Returns the number of elements in this [SBinaryHeap]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns [true] if there are no elements in this [SBinaryHeap]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns the capacity of the underlying [SVec]
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// Pushes a new element into this [SBinaryHeap]
+    ///
+    /// Will try to reallocate if `capacity == length`. If the canister is out of stable memory,
+    /// will return [Rejected] with the element that was about to get inserted.
+    #[inline]
+    pub fn push(&mut self, element: T) -> Result<(), Rejected<T>> {
+        self.inner.push(element)?;
+        self.sift_up(self.len() - 1);
+
+        Ok(())
+    }
+
+    /// Removes the greatest element from this [SBinaryHeap] and returns it
+    ///
+    /// If the [SBinaryHeap] is empty, returns [None].
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len();
+
+        if len > 1 {
+            self.inner.swap(0, len - 1);
+        }
+
+        let elem = self.inner.pop()?;
+
+        if self.len() > 1 {
+            self.sift_down(0);
+        }
+
+        Some(elem)
+    }
+
+    /// Returns a [SRef] pointing to the greatest element of this [SBinaryHeap]
+    ///
+    /// If the [SBinaryHeap] is empty, returns [None].
+    #[inline]
+    pub fn peek(&self) -> Option<SRef<'_, T>> {
+        self.inner.get(0)
+    }
+
+    /// Returns a mutable reference to the greatest element of this [SBinaryHeap]
+    ///
+    /// The heap order is restored when the returned [SBinaryHeapPeekMut] is dropped. If the
+    /// [SBinaryHeap] is empty, returns [None].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBinaryHeap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut heap = SBinaryHeap::<u64>::new();
+    /// heap.push(10).expect("Out of memory");
+    /// heap.push(20).expect("Out of memory");
+    ///
+    /// {
+    ///     let mut top = heap.peek_mut().unwrap();
+    ///     *top = 5;
+    /// }
+    ///
+    /// assert_eq!(*heap.peek().unwrap(), 10);
+    /// ```
+    #[inline]
+    pub fn peek_mut(&mut self) -> Option<SBinaryHeapPeekMut<'_, T>> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(SBinaryHeapPeekMut::new(self))
+        }
+    }
+
+    /// Returns an iterator over elements of this [SBinaryHeap] in arbitrary order
+    #[inline]
+    pub fn iter(&self) -> SVecIter<'_, T> {
+        self.inner.iter()
+    }
+
+    /// Consumes this [SBinaryHeap], returning an iterator which yields elements from the greatest
+    /// to the smallest
+    ///
+    /// Each call to [Iterator::next] is a [SBinaryHeap::pop], so elements which were not consumed
+    /// are released when the iterator gets dropped.
+    #[inline]
+    pub fn into_sorted_iter(self) -> SBinaryHeapIntoSortedIter<T> {
+        SBinaryHeapIntoSortedIter::new(self)
+    }
+
+    /// Removes all elements from this [SBinaryHeap]
+    ///
+    /// See [SVec::clear]
+    #[inline]
+    pub fn clear(&mut self) {
+        self.inner.clear()
+    }
+
+    fn sift_up(&mut self, mut idx: usize) {
+        let mut hole_buf = T::Buf::new(T::SIZE);
+        let hole = self.read_elem(idx, &mut hole_buf);
+
+        let mut parent_buf = T::Buf::new(T::SIZE);
+
+        while idx > 0 {
+            let parent_idx = (idx - 1) / 2;
+            let parent = self.read_elem(parent_idx, &mut parent_buf);
+
+            if hole <= parent {
+                break;
+            }
+
+            self.write_elem_bytes(idx, &parent_buf);
+            idx = parent_idx;
+        }
+
+        self.write_elem_bytes(idx, &hole_buf);
+    }
+
+    fn sift_down(&mut self, mut idx: usize) {
+        let len = self.len();
+
+        let mut hole_buf = T::Buf::new(T::SIZE);
+        let hole = self.read_elem(idx, &mut hole_buf);
+
+        let mut left_buf = T::Buf::new(T::SIZE);
+        let mut right_buf = T::Buf::new(T::SIZE);
+
+        loop {
+            let left_idx = idx * 2 + 1;
+            if left_idx >= len {
+                break;
+            }
+
+            let left = self.read_elem(left_idx, &mut left_buf);
+
+            let (child_idx, child, child_buf) = if left_idx + 1 < len {
+                let right = self.read_elem(left_idx + 1, &mut right_buf);
+
+                if right > left {
+                    (left_idx + 1, right, &right_buf)
+                } else {
+                    (left_idx, left, &left_buf)
+                }
+            } else {
+                (left_idx, left, &left_buf)
+            };
+
+            if hole >= child {
+                break;
+            }
+
+            self.write_elem_bytes(idx, child_buf);
+            idx = child_idx;
+        }
+
+        self.write_elem_bytes(idx, &hole_buf);
+    }
+
+    fn read_elem(&self, idx: usize, buf: &mut T::Buf) -> T {
+        let ptr = self.inner.get_element_ptr(idx).unwrap();
+        unsafe { crate::mem::read_bytes(ptr, buf._deref_mut()) };
+
+        let mut it = T::from_fixed_size_bytes(buf._deref());
+        unsafe { it.stable_drop_flag_off() };
+
+        it
+    }
+
+    fn write_elem_bytes(&mut self, idx: usize, buf: &T::Buf) {
+        let ptr = self.inner.get_element_ptr(idx).unwrap();
+        unsafe { crate::mem::write_bytes(ptr, buf._deref()) };
+    }
+}
+
+/// Mutable reference to the greatest element of a [SBinaryHeap]
+///
+/// Returned by [SBinaryHeap::peek_mut]. If the element was mutably accessed, it is written back
+/// and sifted down to its new place when this reference is dropped.
+pub struct SBinaryHeapPeekMut<'a, T: StableType + AsFixedSizeBytes + Ord> {
+    heap: &'a mut SBinaryHeap<T>,
+    inner: Option<T>,
+    sift: bool,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> SBinaryHeapPeekMut<'a, T> {
+    #[inline]
+    fn new(heap: &'a mut SBinaryHeap<T>) -> Self {
+        let ptr = heap.inner.get_element_ptr(0).unwrap();
+        let inner = unsafe { crate::mem::read_fixed_for_move(ptr) };
+
+        Self {
+            heap,
+            inner: Some(inner),
+            sift: false,
+        }
+    }
+
+    /// Removes the peeked element from the [SBinaryHeap] and returns it
+    #[inline]
+    pub fn pop(mut this: Self) -> T {
+        let ptr = this.heap.inner.get_element_ptr(0).unwrap();
+        unsafe { crate::mem::write_fixed(ptr, this.inner.as_mut().unwrap()) };
+
+        this.inner = None;
+        this.sift = false;
+
+        this.heap.pop().unwrap()
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> Deref for SBinaryHeapPeekMut<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> DerefMut for SBinaryHeapPeekMut<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.sift = true;
+
+        self.inner.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes + Ord> Drop for SBinaryHeapPeekMut<'a, T> {
+    fn drop(&mut self) {
+        if let Some(it) = self.inner.as_mut() {
+            let ptr = self.heap.inner.get_element_ptr(0).unwrap();
+            unsafe { crate::mem::write_fixed(ptr, it) };
+        }
+
+        if self.sift {
+            self.heap.sift_down(0);
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord> Default for SBinaryHeap<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord> AsFixedSizeBytes for SBinaryHeap<T> {
+    const SIZE: usize = SVec::<T>::SIZE;
+    type Buf = <SVec<T> as AsFixedSizeBytes>::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.inner.as_fixed_size_bytes(buf);
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let inner = SVec::<T>::from_fixed_size_bytes(arr);
+        Self { inner }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord> StableType for SBinaryHeap<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.inner.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.inner.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.inner.visit_allocations(visitor);
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord> IncrementalDrop for SBinaryHeap<T> {
+    // removing the last element of a heap never breaks the heap property
+    #[inline]
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        self.inner.clear_incremental(instruction_budget)
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.inner.pending_elements()
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord + Debug> Debug for SBinaryHeap<T> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::binary_heap::{SBinaryHeap, SBinaryHeapPeekMut};
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::{thread_rng, Rng};
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    #[test]
+    fn basic_flow_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut heap = SBinaryHeap::default();
+            assert!(heap.is_empty());
+            assert!(heap.peek().is_none());
+            assert!(heap.peek_mut().is_none());
+            assert!(heap.pop().is_none());
+
+            for i in [5u64, 1, 8, 3, 9, 2, 7] {
+                heap.push(i).unwrap();
+            }
+
+            assert_eq!(heap.len(), 7);
+            assert_eq!(*heap.peek().unwrap(), 9);
+
+            assert_eq!(heap.pop(), Some(9));
+            assert_eq!(heap.pop(), Some(8));
+            assert_eq!(heap.pop(), Some(7));
+            assert_eq!(heap.len(), 4);
+
+            heap.clear();
+            assert!(heap.is_empty());
+
+            let mut min_heap = SBinaryHeap::new_with_capacity(10).unwrap();
+            for i in [5u64, 1, 8] {
+                min_heap.push(Reverse(i)).unwrap();
+            }
+
+            assert_eq!(min_heap.pop(), Some(Reverse(1)));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn peek_mut_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut heap = SBinaryHeap::new();
+            for i in 0..10u64 {
+                heap.push(i).unwrap();
+            }
+
+            {
+                let top = heap.peek_mut().unwrap();
+                assert_eq!(*top, 9);
+            }
+            assert_eq!(*heap.peek().unwrap(), 9);
+
+            {
+                let mut top = heap.peek_mut().unwrap();
+                *top = 0;
+            }
+            assert_eq!(*heap.peek().unwrap(), 8);
+
+            let top = heap.peek_mut().unwrap();
+            assert_eq!(SBinaryHeapPeekMut::pop(top), 8);
+            assert_eq!(heap.len(), 9);
+
+            let sorted = heap.into_sorted_iter().collect::<Vec<_>>();
+            assert_eq!(sorted, vec![7, 6, 5, 4, 3, 2, 1, 0, 0]);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn sboxes_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut heap = SBinaryHeap::new();
+            let mut example = BinaryHeap::new();
+            let mut rng = thread_rng();
+
+            for _ in 0..100 {
+                let str = generate_random_string(&mut rng);
+
+                heap.push(SBox::new(str.clone()).unwrap()).unwrap();
+                example.push(str);
+            }
+
+            {
+                let mut top = heap.peek_mut().unwrap();
+                *top = SBox::new(String::new()).unwrap();
+            }
+            example.pop();
+            example.push(String::new());
+
+            let mut iter = heap.into_sorted_iter();
+            for _ in 0..50 {
+                assert_eq!(*iter.next().unwrap(), example.pop().unwrap());
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn random_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut heap = SBinaryHeap::new();
+            let mut example = BinaryHeap::new();
+            let mut rng = thread_rng();
+
+            for _ in 0..10_000 {
+                if rng.gen_bool(0.6) {
+                    let it = rng.gen::<u32>();
+
+                    heap.push(it).unwrap();
+                    example.push(it);
+                } else {
+                    assert_eq!(heap.pop(), example.pop());
+                }
+
+                assert_eq!(heap.len(), example.len());
+                assert_eq!(heap.peek().map(|it| *it), example.peek().copied());
+            }
+
+            let buf = heap.as_new_fixed_size_bytes();
+            store_custom_data(1, SBox::new(heap).unwrap());
+
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let heap = retrieve_custom_data::<SBinaryHeap<u32>>(1)
+                .unwrap()
+                .into_inner();
+            assert_eq!(heap.as_new_fixed_size_bytes()._deref(), buf._deref());
+
+            assert_eq!(
+                heap.into_sorted_iter().collect::<Vec<_>>(),
+                example
+                    .into_sorted_vec()
+                    .into_iter()
+                    .rev()
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn out_of_memory_works_fine() {
+        stable::clear();
+        init_allocator(1);
+
+        {
+            let mut heap = SBinaryHeap::new();
+            let mut i = 0u64;
+
+            loop {
+                if heap.push(i).is_err() {
+                    break;
+                }
+
+                i += 1;
+            }
+
+            assert_eq!(heap.len() as u64, i);
+            assert_eq!(heap.pop(), Some(i - 1));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
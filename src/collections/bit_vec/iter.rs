@@ -0,0 +1,52 @@
+use crate::collections::bit_vec::{SBitVec, CHUNK_WORDS};
+
+pub struct SBitVecOnesIter<'a> {
+    bits: &'a SBitVec,
+    chunk: Vec<u64>,
+    chunk_from: usize,
+    word_idx: usize,
+    word: u64,
+}
+
+impl<'a> SBitVecOnesIter<'a> {
+    #[inline]
+    pub(crate) fn new(bits: &'a SBitVec) -> Self {
+        Self {
+            bits,
+            chunk: Vec::new(),
+            chunk_from: 0,
+            word_idx: 0,
+            word: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for SBitVecOnesIter<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.word == 0 {
+            if self.word_idx >= self.chunk_from + self.chunk.len() {
+                let from = self.chunk_from + self.chunk.len();
+                if from >= self.bits.words_len() {
+                    return None;
+                }
+
+                let to = (from + CHUNK_WORDS).min(self.bits.words_len());
+
+                self.chunk = self.bits.read_words(from, to);
+                self.chunk_from = from;
+            }
+
+            self.word = self.chunk[self.word_idx - self.chunk_from];
+            self.word_idx += 1;
+        }
+
+        let bit = self.word.trailing_zeros() as u64;
+
+        // unsets the lowest set bit
+        self.word &= self.word - 1;
+
+        Some((self.word_idx - 1) as u64 * u64::BITS as u64 + bit)
+    }
+}
@@ -0,0 +1,569 @@
+use crate::collections::bit_vec::iter::SBitVecOnesIter;
+use crate::collections::vec::SVec;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::primitive::StableType;
+use crate::{OutOfMemory, SSlice, PAGE_SIZE_BYTES};
+use std::fmt::{Debug, Formatter};
+
+#[doc(hidden)]
+pub mod iter;
+
+const WORD_BITS: u64 = u64::BITS as u64;
+
+// the number of words read at once by scanning operations
+pub(crate) const CHUNK_WORDS: usize = PAGE_SIZE_BYTES as usize / u64::SIZE;
+
+/// Compact vector of bits
+///
+/// Bits are packed into [u64] words, which are stored in a [SVec], so a bit vector of length `n`
+/// takes `n / 8` bytes of stable memory (plus the spare capacity of the underlying [SVec]). Can be
+/// used both as a fixed size bitset (see [SBitVec::new_with_len]) and as a growable one (see
+/// [SBitVec::push] and [SBitVec::resize]).
+///
+/// The number of set bits is maintained on every update, so [SBitVec::count_ones] is `O(1)`.
+/// [SBitVec::rank], [SBitVec::select] and [SBitVec::iter_ones] scan the words, reading them
+/// from stable memory in chunks of 64KB.
+///
+/// [SBitVec] implements [StableType] and [AsFixedSizeBytes], so you can nest it in other stable
+/// structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBitVec;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// // which of 100 tokens are minted
+/// let mut minted = SBitVec::new_with_len(100).expect("Out of memory");
+///
+/// minted.set(3, true);
+/// minted.set(42, true);
+/// minted.set(77, true);
+///
+/// assert!(minted.get(42));
+/// assert_eq!(minted.count_ones(), 3);
+/// assert_eq!(minted.iter_ones().collect::<Vec<_>>(), vec![3, 42, 77]);
+///
+/// // two tokens with ids less than 50 are minted
+/// assert_eq!(minted.rank(50), 2);
+/// // the third minted token
+/// assert_eq!(minted.select(2), Some(77));
+/// ```
+pub struct SBitVec {
+    words: SVec<u64>,
+    len: u64,
+    ones: u64,
+}
+
+impl SBitVec {
+    /// Creates a new empty [SBitVec]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            words: SVec::new(),
+            len: 0,
+            ones: 0,
+        }
+    }
+
+    /// Creates a new [SBitVec] of the provided length with all bits unset
+    ///
+    /// If your canister is out of stable memory, returns [OutOfMemory].
+    #[inline]
+    pub fn new_with_len(len: u64) -> Result<Self, OutOfMemory> {
+        let mut it = Self::new();
+        it.resize(len)?;
+
+        Ok(it)
+    }
+
+    /// Returns the number of bits in this [SBitVec]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns [true] if the length of this [SBitVec] is `0`
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of set bits
+    #[inline]
+    pub fn count_ones(&self) -> u64 {
+        self.ones
+    }
+
+    /// Returns the number of unset bits
+    #[inline]
+    pub fn count_zeros(&self) -> u64 {
+        self.len - self.ones
+    }
+
+    /// Returns the bit by its index
+    ///
+    /// # Panics
+    /// Panics if the index is out of bounds.
+    #[inline]
+    pub fn get(&self, idx: u64) -> bool {
+        assert!(idx < self.len, "out of bounds");
+
+        let word = *self.words.get(word_idx(idx)).unwrap();
+
+        word & bit_mask(idx) != 0
+    }
+
+    /// Sets the bit by its index, returning its previous value
+    ///
+    /// # Panics
+    /// Panics if the index is out of bounds.
+    pub fn set(&mut self, idx: u64, bit: bool) -> bool {
+        assert!(idx < self.len, "out of bounds");
+
+        let w_idx = word_idx(idx);
+        let word = *self.words.get(w_idx).unwrap();
+        let prev = word & bit_mask(idx) != 0;
+
+        if prev != bit {
+            if bit {
+                self.words.replace(w_idx, word | bit_mask(idx));
+                self.ones += 1;
+            } else {
+                self.words.replace(w_idx, word & !bit_mask(idx));
+                self.ones -= 1;
+            }
+        }
+
+        prev
+    }
+
+    /// Appends a bit to the end of this [SBitVec]
+    ///
+    /// Allocates a new word every 64 bits. If your canister is out of stable memory, returns
+    /// [OutOfMemory], leaving the [SBitVec] untouched.
+    pub fn push(&mut self, bit: bool) -> Result<(), OutOfMemory> {
+        if self.len.is_multiple_of(WORD_BITS) {
+            self.words.push(0).map_err(|e| e.reason)?;
+        }
+
+        self.len += 1;
+
+        if bit {
+            self.set(self.len - 1, true);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the length of this [SBitVec]
+    ///
+    /// New bits are unset. Reallocates at most once, when growing. If your canister is out of stable
+    /// memory, returns [OutOfMemory], leaving the [SBitVec] untouched. Shrinking never fails and does
+    /// not release any stable memory.
+    pub fn resize(&mut self, new_len: u64) -> Result<(), OutOfMemory> {
+        let new_words_len = words_len(new_len);
+
+        if new_len >= self.len {
+            // bits after the length are always unset, so only new words have to be written
+            let mut additional = new_words_len - self.words.len();
+            self.words.reserve(additional)?;
+
+            while additional > 0 {
+                let chunk = additional.min(CHUNK_WORDS);
+
+                // can't fail, since the capacity is already reserved
+                self.words
+                    .extend_from_iter(std::iter::repeat_n(0, chunk))
+                    .unwrap();
+
+                additional -= chunk;
+            }
+
+            self.len = new_len;
+
+            return Ok(());
+        }
+
+        self.ones -= self.count_ones_in(new_len, self.len);
+        self.words.truncate(new_words_len);
+
+        let tail_len = new_len % WORD_BITS;
+        if tail_len != 0 {
+            let last = new_words_len - 1;
+            let word = *self.words.get(last).unwrap();
+
+            self.words.replace(last, word & (bit_mask(tail_len) - 1));
+        }
+
+        self.len = new_len;
+
+        Ok(())
+    }
+
+    /// Returns the number of set bits with indices less than `idx`
+    ///
+    /// Scans at most half of this [SBitVec].
+    ///
+    /// # Panics
+    /// Panics if `idx` is greater than the length.
+    pub fn rank(&self, idx: u64) -> u64 {
+        assert!(idx <= self.len, "out of bounds");
+
+        if idx > self.len / 2 {
+            self.ones - self.count_ones_in(idx, self.len)
+        } else {
+            self.count_ones_in(0, idx)
+        }
+    }
+
+    /// Returns the index of the `n`-th (starting from `0`) set bit
+    ///
+    /// Returns [None], if there are not enough set bits.
+    pub fn select(&self, mut n: u64) -> Option<u64> {
+        if n >= self.ones {
+            return None;
+        }
+
+        let mut from = 0;
+
+        loop {
+            let to = (from + CHUNK_WORDS).min(self.words.len());
+
+            for (i, mut word) in self.words.read_range(from..to).into_iter().enumerate() {
+                let ones = word.count_ones() as u64;
+
+                if n >= ones {
+                    n -= ones;
+                    continue;
+                }
+
+                for _ in 0..n {
+                    // unsets the lowest set bit
+                    word &= word - 1;
+                }
+
+                return Some((from + i) as u64 * WORD_BITS + word.trailing_zeros() as u64);
+            }
+
+            from = to;
+        }
+    }
+
+    /// Returns an iterator over indices of set bits in ascending order
+    #[inline]
+    pub fn iter_ones(&self) -> SBitVecOnesIter<'_> {
+        SBitVecOnesIter::new(self)
+    }
+
+    /// Removes all bits from this [SBitVec]
+    ///
+    /// Does not release any stable memory.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.words.clear();
+        self.len = 0;
+        self.ones = 0;
+    }
+
+    #[inline]
+    pub(crate) fn words_len(&self) -> usize {
+        self.words.len()
+    }
+
+    #[inline]
+    pub(crate) fn read_words(&self, from: usize, to: usize) -> Vec<u64> {
+        self.words.read_range(from..to)
+    }
+
+    // creates a bit vector of the provided length out of words, which have no set bits after the length
+    pub(crate) fn from_words(words: &[u64], len: u64) -> Result<Self, OutOfMemory> {
+        debug_assert_eq!(words.len(), words_len(len));
+
+        let mut it = Self::new();
+        it.words
+            .extend_from_iter(words.iter().copied())
+            .map_err(|e| e.reason)?;
+
+        it.len = len;
+        it.ones = count_ones(words);
+
+        Ok(it)
+    }
+
+    // overwrites all the words with a single write
+    pub(crate) fn write_words(&mut self, words: &[u64]) {
+        debug_assert_eq!(words.len(), self.words.len());
+
+        self.words.write_range(0, words);
+        self.ones = count_ones(words);
+    }
+
+    // counts set bits with indices in [from, to)
+    fn count_ones_in(&self, from: u64, to: u64) -> u64 {
+        if from >= to {
+            return 0;
+        }
+
+        let first = word_idx(from);
+        let last = word_idx(to - 1);
+
+        let mut count = 0;
+        let mut chunk_from = first;
+
+        while chunk_from <= last {
+            let chunk_to = (chunk_from + CHUNK_WORDS).min(last + 1);
+
+            for (i, mut word) in self
+                .read_words(chunk_from, chunk_to)
+                .into_iter()
+                .enumerate()
+            {
+                if chunk_from + i == first {
+                    word &= !(bit_mask(from) - 1);
+                }
+
+                if chunk_from + i == last && !to.is_multiple_of(WORD_BITS) {
+                    word &= bit_mask(to) - 1;
+                }
+
+                count += word.count_ones() as u64;
+            }
+
+            chunk_from = chunk_to;
+        }
+
+        count
+    }
+}
+
+#[inline]
+fn word_idx(idx: u64) -> usize {
+    (idx / WORD_BITS) as usize
+}
+
+#[inline]
+fn bit_mask(idx: u64) -> u64 {
+    1 << (idx % WORD_BITS)
+}
+
+#[inline]
+fn words_len(len: u64) -> usize {
+    len.div_ceil(WORD_BITS) as usize
+}
+
+#[inline]
+fn count_ones(words: &[u64]) -> u64 {
+    words.iter().map(|it| it.count_ones() as u64).sum()
+}
+
+impl Default for SBitVec {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsFixedSizeBytes for SBitVec {
+    const SIZE: usize = SVec::<u64>::SIZE + u64::SIZE * 2;
+    type Buf = [u8; SVec::<u64>::SIZE + u64::SIZE * 2];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.words
+            .as_fixed_size_bytes(&mut buf[0..SVec::<u64>::SIZE]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[SVec::<u64>::SIZE..(SVec::<u64>::SIZE + u64::SIZE)]);
+        self.ones.as_fixed_size_bytes(
+            &mut buf[(SVec::<u64>::SIZE + u64::SIZE)..(SVec::<u64>::SIZE + u64::SIZE * 2)],
+        );
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let words = SVec::<u64>::from_fixed_size_bytes(&arr[0..SVec::<u64>::SIZE]);
+        let len =
+            u64::from_fixed_size_bytes(&arr[SVec::<u64>::SIZE..(SVec::<u64>::SIZE + u64::SIZE)]);
+        let ones = u64::from_fixed_size_bytes(
+            &arr[(SVec::<u64>::SIZE + u64::SIZE)..(SVec::<u64>::SIZE + u64::SIZE * 2)],
+        );
+
+        Self { words, len, ones }
+    }
+}
+
+impl StableType for SBitVec {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.words.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.words.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.words.visit_allocations(visitor);
+    }
+}
+
+impl IncrementalDrop for SBitVec {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let done = self.words.clear_incremental(instruction_budget);
+
+        if done {
+            self.len = 0;
+            self.ones = 0;
+        }
+
+        done
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.words.pending_elements()
+    }
+}
+
+impl Debug for SBitVec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+
+        let mut from = 0;
+        while from < self.words.len() {
+            let to = (from + CHUNK_WORDS).min(self.words.len());
+
+            for (i, word) in self.read_words(from, to).into_iter().enumerate() {
+                let word_len = (self.len - (from + i) as u64 * WORD_BITS).min(WORD_BITS);
+
+                for j in 0..word_len {
+                    f.write_str(if word & (1 << j) != 0 { "1" } else { "0" })?;
+                }
+            }
+
+            from = to;
+        }
+
+        f.write_str("]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::bit_vec::SBitVec;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut bits = SBitVec::new();
+            let mut example = Vec::new();
+            let mut rng = thread_rng();
+
+            for _ in 0..3000 {
+                let bit = rng.gen_bool(0.3);
+
+                bits.push(bit).unwrap();
+                example.push(bit);
+            }
+
+            for _ in 0..1000 {
+                let idx = rng.gen_range(0..example.len());
+                let bit = rng.gen_bool(0.5);
+
+                assert_eq!(bits.set(idx as u64, bit), example[idx]);
+                example[idx] = bit;
+            }
+
+            let ones = example
+                .iter()
+                .enumerate()
+                .filter(|(_, it)| **it)
+                .map(|(idx, _)| idx as u64)
+                .collect::<Vec<_>>();
+
+            assert_eq!(bits.len(), example.len() as u64);
+            assert_eq!(bits.count_ones(), ones.len() as u64);
+            assert_eq!(bits.count_zeros(), (example.len() - ones.len()) as u64);
+            assert_eq!(bits.iter_ones().collect::<Vec<_>>(), ones);
+
+            for (idx, bit) in example.iter().enumerate() {
+                assert_eq!(bits.get(idx as u64), *bit);
+            }
+
+            for idx in 0..=example.len() {
+                let rank = example[..idx].iter().filter(|it| **it).count() as u64;
+                assert_eq!(bits.rank(idx as u64), rank);
+            }
+
+            for (n, idx) in ones.iter().enumerate() {
+                assert_eq!(bits.select(n as u64), Some(*idx));
+            }
+            assert_eq!(bits.select(ones.len() as u64), None);
+
+            bits.resize(1001).unwrap();
+            example.truncate(1001);
+            bits.resize(2000).unwrap();
+            example.resize(2000, false);
+
+            assert_eq!(
+                bits.count_ones(),
+                example.iter().filter(|it| **it).count() as u64
+            );
+            for (idx, bit) in example.iter().enumerate() {
+                assert_eq!(bits.get(idx as u64), *bit);
+            }
+
+            let buf = bits.as_new_fixed_size_bytes();
+            let bits_copy = SBitVec::from_fixed_size_bytes(buf._deref());
+            assert_eq!(bits_copy.len(), bits.len());
+            assert_eq!(bits_copy.count_ones(), bits.count_ones());
+
+            bits.clear();
+            assert!(bits.is_empty());
+            assert_eq!(bits.iter_ones().next(), None);
+
+            println!("{:?}", bits);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn big_bit_vecs_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let len = 1_000_000;
+            let mut bits = SBitVec::new_with_len(len).unwrap();
+
+            assert_eq!(bits.count_ones(), 0);
+            assert_eq!(bits.select(0), None);
+
+            for idx in (0..len).step_by(997) {
+                bits.set(idx, true);
+            }
+            bits.set(len - 1, true);
+
+            let ones = (0..len).step_by(997).chain([len - 1]).collect::<Vec<_>>();
+
+            assert_eq!(bits.iter_ones().collect::<Vec<_>>(), ones);
+            assert_eq!(bits.rank(len), ones.len() as u64);
+            assert_eq!(bits.rank(len / 2 + 1), (len / 2) / 997 + 1);
+            assert_eq!(bits.select(ones.len() as u64 - 1), Some(len - 1));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,644 @@
+use crate::collections::vec::SVec;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
+use crate::{allocate, deallocate, stable, OutOfMemory};
+use std::fmt::{Debug, Formatter};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+const BASE_CHUNK_SIZE: u64 = 256;
+const MAX_CHUNK_SIZE_EXP: usize = 12;
+const MAX_CHUNK_SIZE: u64 = BASE_CHUNK_SIZE << MAX_CHUNK_SIZE_EXP;
+
+// the largest length (and cursor position) of an SBlob, so any position can be reached back with
+// a signed SeekFrom offset
+const MAX_LEN: u64 = i64::MAX as u64;
+
+/// Growable byte blob, stored in stable memory in chunks
+///
+/// Unlike [SBox](crate::SBox)`<Vec<u8>>`, which reads and writes the whole buffer at once, [SBlob]
+/// only touches the requested byte range on each access. This makes it suitable for large assets,
+/// which are uploaded and served piece by piece.
+///
+/// The data is stored in a list of [SSlice]s (chunks). Chunks grow exponentially - each one is twice
+/// as big as the previous one, starting from 256 bytes, until they reach 1 MiB. Growing an [SBlob]
+/// never moves the data, it only allocates new chunks.
+///
+/// [SBlob] implements [Read], [Write] and [Seek], which operate on an in-heap cursor position. The
+/// position is not persisted and is reset to `0` when the [SBlob] is read from stable memory. An
+/// [SBlob] can't grow beyond `i64::MAX` bytes - seeks and writes past this limit fail with
+/// [ErrorKind::InvalidInput].
+///
+/// [SBlob] implements [StableType] and [AsFixedSizeBytes] and can be nested inside other stable data
+/// structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBlob;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut blob = SBlob::new();
+///
+/// blob.append(b"hello, ").expect("Out of memory");
+/// blob.append(b"world").expect("Out of memory");
+///
+/// let mut buf = [0u8; 5];
+/// blob.read_at(7, &mut buf);
+///
+/// assert_eq!(&buf, b"world");
+/// ```
+pub struct SBlob {
+    chunks: SVec<StablePtr>,
+    len: u64,
+    pos: u64,
+}
+
+impl SBlob {
+    /// Creates an empty [SBlob]
+    ///
+    /// Does not allocate any stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            chunks: SVec::new(),
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    /// Returns the length of this [SBlob] in bytes
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns [true] if this [SBlob] is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bytes this [SBlob] can hold without allocating new chunks
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        chunk_start(self.chunks.len())
+    }
+
+    /// Reads bytes starting from `offset` into `buf`, returning the number of bytes read
+    ///
+    /// Reads `min(buf.len(), len - offset)` bytes. If `offset` is beyond the end of this [SBlob],
+    /// returns `0`.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+
+        let n = (buf.len() as u64).min(self.len - offset) as usize;
+        let mut done = 0;
+
+        while done < n {
+            let (chunk_idx, inner_offset) = locate(offset + done as u64);
+            let size = ((chunk_size(chunk_idx) - inner_offset) as usize).min(n - done);
+
+            let ptr = SSlice::_offset(self.chunk_ptr(chunk_idx), inner_offset);
+            unsafe { crate::mem::read_bytes(ptr, &mut buf[done..(done + size)]) };
+
+            done += size;
+        }
+
+        n
+    }
+
+    /// Writes `data` starting from `offset`, growing this [SBlob] if needed
+    ///
+    /// If `offset` is beyond the end of this [SBlob], the gap is filled with zeroes. If the canister
+    /// is out of stable memory, returns [OutOfMemory] and leaves this [SBlob] unchanged.
+    ///
+    /// # Panics
+    /// Panics if the written range ends beyond `i64::MAX` bytes, which no [SBlob] can hold.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), OutOfMemory> {
+        let end = checked_end(offset, data.len()).expect("SBlob length overflow");
+        self.reserve(end)?;
+
+        if offset > self.len {
+            let zeroes = vec![0u8; (offset - self.len).min(MAX_CHUNK_SIZE) as usize];
+            let mut cur = self.len;
+
+            while cur < offset {
+                let size = ((offset - cur) as usize).min(zeroes.len());
+                self.write_in_capacity(cur, &zeroes[0..size]);
+
+                cur += size as u64;
+            }
+        }
+
+        self.write_in_capacity(offset, data);
+        self.len = self.len.max(end);
+
+        Ok(())
+    }
+
+    /// Appends `data` to the end of this [SBlob]
+    ///
+    /// See [SBlob::write_at]
+    #[inline]
+    pub fn append(&mut self, data: &[u8]) -> Result<(), OutOfMemory> {
+        self.write_at(self.len, data)
+    }
+
+    /// Shortens this [SBlob] to `new_len` bytes, deallocating chunks which are no longer needed
+    ///
+    /// If `new_len` is greater than the current length, does nothing.
+    pub fn truncate(&mut self, new_len: u64) {
+        if new_len >= self.len {
+            return;
+        }
+
+        self.len = new_len;
+
+        while !self.chunks.is_empty() && chunk_start(self.chunks.len() - 1) >= new_len {
+            let ptr = self.chunks.pop().unwrap();
+            unsafe { deallocate(SSlice::from_ptr(ptr).unwrap()) };
+        }
+    }
+
+    /// Removes all bytes from this [SBlob], deallocating all chunks
+    #[inline]
+    pub fn clear(&mut self) {
+        self.truncate(0);
+        self.pos = 0;
+    }
+
+    /// Reads the whole [SBlob] into a [Vec]
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.len as usize];
+        self.read_at(0, &mut buf);
+
+        buf
+    }
+
+    fn reserve(&mut self, new_len: u64) -> Result<(), OutOfMemory> {
+        let chunks_before = self.chunks.len();
+
+        while self.capacity() < new_len {
+            let size = chunk_size(self.chunks.len());
+
+            let res = match unsafe { allocate(size) } {
+                Ok(slice) => self.chunks.push(slice.as_ptr()).map_err(|e| {
+                    unsafe { deallocate(SSlice::from_ptr(e.value).unwrap()) };
+                    e.reason
+                }),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = res {
+                while self.chunks.len() > chunks_before {
+                    let ptr = self.chunks.pop().unwrap();
+                    unsafe { deallocate(SSlice::from_ptr(ptr).unwrap()) };
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_in_capacity(&mut self, offset: u64, data: &[u8]) {
+        let mut done = 0;
+
+        while done < data.len() {
+            let (chunk_idx, inner_offset) = locate(offset + done as u64);
+            let size = ((chunk_size(chunk_idx) - inner_offset) as usize).min(data.len() - done);
+
+            let ptr = SSlice::_offset(self.chunk_ptr(chunk_idx), inner_offset);
+            unsafe { crate::mem::write_bytes(ptr, &data[done..(done + size)]) };
+
+            done += size;
+        }
+    }
+
+    #[inline]
+    fn chunk_ptr(&self, idx: usize) -> StablePtr {
+        *self.chunks.get(idx).unwrap()
+    }
+}
+
+#[inline]
+fn chunk_size(idx: usize) -> u64 {
+    BASE_CHUNK_SIZE << idx.min(MAX_CHUNK_SIZE_EXP)
+}
+
+#[inline]
+fn chunk_start(idx: usize) -> u64 {
+    if idx <= MAX_CHUNK_SIZE_EXP {
+        BASE_CHUNK_SIZE * ((1 << idx) - 1)
+    } else {
+        chunk_start(MAX_CHUNK_SIZE_EXP) + (idx - MAX_CHUNK_SIZE_EXP) as u64 * MAX_CHUNK_SIZE
+    }
+}
+
+// returns the end of the range of `len` bytes starting from `offset`, if it fits into MAX_LEN
+#[inline]
+fn checked_end(offset: u64, len: usize) -> Option<u64> {
+    offset
+        .checked_add(len as u64)
+        .filter(|end| *end <= MAX_LEN)
+}
+
+// returns the index of the chunk, containing the offset, and the offset inside that chunk
+#[inline]
+fn locate(offset: u64) -> (usize, u64) {
+    let last_growing_start = chunk_start(MAX_CHUNK_SIZE_EXP);
+
+    let idx = if offset < last_growing_start {
+        (offset / BASE_CHUNK_SIZE + 1).ilog2() as usize
+    } else {
+        MAX_CHUNK_SIZE_EXP + ((offset - last_growing_start) / MAX_CHUNK_SIZE) as usize
+    };
+
+    (idx, offset - chunk_start(idx))
+}
+
+impl Read for SBlob {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.read_at(self.pos, buf);
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Write for SBlob {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if checked_end(self.pos, buf.len()).is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Write would grow the blob beyond its maximum length",
+            ));
+        }
+
+        self.write_at(self.pos, buf)
+            .map_err(|_| Error::new(ErrorKind::OutOfMemory, "Out of stable memory"))?;
+        self.pos += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SBlob {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(it) => Some(it),
+            SeekFrom::End(it) => self.len.checked_add_signed(it),
+            SeekFrom::Current(it) => self.pos.checked_add_signed(it),
+        }
+        .filter(|it| *it <= MAX_LEN);
+
+        match new_pos {
+            Some(it) => {
+                self.pos = it;
+                Ok(it)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative position or beyond the maximum length",
+            )),
+        }
+    }
+}
+
+impl Default for SBlob {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for SBlob {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SBlob")
+            .field("len", &self.len)
+            .field("chunks", &self.chunks.len())
+            .finish()
+    }
+}
+
+impl AsFixedSizeBytes for SBlob {
+    const SIZE: usize = SVec::<StablePtr>::SIZE + u64::SIZE;
+    type Buf = [u8; SVec::<StablePtr>::SIZE + u64::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.chunks
+            .as_fixed_size_bytes(&mut buf[0..SVec::<StablePtr>::SIZE]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[SVec::<StablePtr>::SIZE..Self::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let chunks = SVec::<StablePtr>::from_fixed_size_bytes(&arr[0..SVec::<StablePtr>::SIZE]);
+        let len = u64::from_fixed_size_bytes(&arr[SVec::<StablePtr>::SIZE..Self::SIZE]);
+
+        Self {
+            chunks,
+            len,
+            pos: 0,
+        }
+    }
+}
+
+impl StableType for SBlob {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.chunks.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.chunks.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.chunks.should_stable_drop()
+    }
+
+    unsafe fn stable_drop(&mut self) {
+        // the chunk list itself is released by its own drop
+        for ptr in self.chunks.iter() {
+            deallocate(SSlice::from_ptr(*ptr).unwrap());
+        }
+    }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.chunks.visit_allocations(visitor);
+
+        for ptr in self.chunks.iter() {
+            visitor(unsafe { SSlice::from_ptr(*ptr).unwrap() });
+        }
+    }
+}
+
+impl IncrementalDrop for SBlob {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        // chunks are released from the last one, the data before it stays readable
+        while !self.chunks.is_empty() && has_budget(start, instruction_budget) {
+            self.truncate(chunk_start(self.chunks.len() - 1));
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.chunks.len() as u64
+    }
+}
+
+impl Drop for SBlob {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::blob::{chunk_size, chunk_start, locate, SBlob};
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::s_box::SBox;
+    use crate::utils::mem_context::stable;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data,
+    };
+    use rand::{thread_rng, Rng};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn locate_works_fine() {
+        for idx in 0..20 {
+            let start = chunk_start(idx);
+
+            assert_eq!(chunk_start(idx + 1), start + chunk_size(idx));
+            assert_eq!(locate(start), (idx, 0));
+            assert_eq!(locate(start + 1), (idx, 1));
+            assert_eq!(
+                locate(start + chunk_size(idx) - 1),
+                (idx, chunk_size(idx) - 1)
+            );
+        }
+    }
+
+    #[test]
+    fn basic_flow_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut blob = SBlob::default();
+            assert!(blob.is_empty());
+            assert_eq!(blob.read_at(0, &mut [0u8; 10]), 0);
+
+            blob.append(b"hello").unwrap();
+            blob.append(b", world").unwrap();
+            assert_eq!(blob.len(), 12);
+            assert_eq!(blob.to_vec(), b"hello, world");
+
+            blob.write_at(7, b"there").unwrap();
+            assert_eq!(blob.to_vec(), b"hello, there");
+
+            let mut buf = [0u8; 10];
+            assert_eq!(blob.read_at(7, &mut buf), 5);
+            assert_eq!(&buf[0..5], b"there");
+
+            blob.write_at(14, b"!").unwrap();
+            assert_eq!(blob.to_vec(), b"hello, there\0\0!");
+
+            blob.truncate(5);
+            assert_eq!(blob.to_vec(), b"hello");
+
+            blob.clear();
+            assert!(blob.is_empty());
+            assert_eq!(blob.capacity(), 0);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn io_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+            let data = (0..3_000_000).map(|_| rng.gen::<u8>()).collect::<Vec<_>>();
+
+            let mut blob = SBlob::new();
+            for chunk in data.chunks(100_000) {
+                blob.write_all(chunk).unwrap();
+            }
+            assert_eq!(blob.len(), data.len() as u64);
+
+            assert_eq!(blob.seek(SeekFrom::Start(0)).unwrap(), 0);
+            let mut read = Vec::new();
+            blob.read_to_end(&mut read).unwrap();
+            assert_eq!(read, data);
+
+            assert_eq!(
+                blob.seek(SeekFrom::End(-10)).unwrap(),
+                data.len() as u64 - 10
+            );
+            let mut buf = [0u8; 20];
+            assert_eq!(blob.read(&mut buf).unwrap(), 10);
+            assert_eq!(&buf[0..10], &data[(data.len() - 10)..]);
+
+            assert_eq!(
+                blob.seek(SeekFrom::Current(-5)).unwrap(),
+                data.len() as u64 - 5
+            );
+            assert!(blob.seek(SeekFrom::Current(-(data.len() as i64))).is_err());
+
+            let err = blob.seek(SeekFrom::Start(u64::MAX)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+            let max = blob.seek(SeekFrom::Start(i64::MAX as u64)).unwrap();
+            let err = blob.write(b"overflow").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(blob.stream_position().unwrap(), max);
+            assert_eq!(blob.len(), data.len() as u64);
+
+            blob.truncate(1_000_000);
+            assert_eq!(blob.to_vec(), &data[0..1_000_000]);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn random_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+            let mut blob = SBlob::new();
+            let mut example = Vec::new();
+
+            for _ in 0..1_000 {
+                match rng.gen_range(0..10) {
+                    0..=6 => {
+                        let offset = rng.gen_range(0..(example.len() + 100));
+                        let data = (0..rng.gen_range(0..5_000))
+                            .map(|_| rng.gen::<u8>())
+                            .collect::<Vec<_>>();
+
+                        blob.write_at(offset as u64, &data).unwrap();
+
+                        if example.len() < offset + data.len() {
+                            example.resize(offset + data.len(), 0);
+                        }
+                        example[offset..(offset + data.len())].copy_from_slice(&data);
+                    }
+                    _ => {
+                        let new_len = rng.gen_range(0..(example.len() + 1));
+
+                        blob.truncate(new_len as u64);
+                        example.truncate(new_len);
+                    }
+                }
+
+                assert_eq!(blob.len(), example.len() as u64);
+
+                let offset = rng.gen_range(0..(example.len() + 1));
+                let mut buf = vec![0u8; rng.gen_range(0..10_000)];
+                let n = blob.read_at(offset as u64, &mut buf);
+
+                assert_eq!(n, buf.len().min(example.len() - offset));
+                assert_eq!(&buf[0..n], &example[offset..(offset + n)]);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn upgrade_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut blob = SBlob::new();
+            blob.append(&[42u8; 10_000]).unwrap();
+
+            let buf = blob.as_new_fixed_size_bytes();
+            store_custom_data(1, SBox::new(blob).unwrap());
+
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let mut blob = retrieve_custom_data::<SBlob>(1).unwrap().into_inner();
+            assert_eq!(blob.as_new_fixed_size_bytes()._deref(), buf._deref());
+            assert_eq!(blob.to_vec(), vec![42u8; 10_000]);
+
+            let mut first = [0u8; 3];
+            blob.read_exact(&mut first).unwrap();
+            assert_eq!(first, [42u8; 3]);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "SBlob length overflow")]
+    fn write_at_overflow_panics() {
+        stable::clear();
+        stable_memory_init();
+
+        let mut blob = SBlob::new();
+        let _ = blob.write_at(u64::MAX, b"overflow");
+    }
+
+    #[test]
+    fn out_of_memory_works_fine() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut blob = SBlob::new();
+            blob.append(b"hello").unwrap();
+
+            let capacity = blob.capacity();
+            assert!(blob.append(&vec![1u8; 1_000_000]).is_err());
+
+            assert_eq!(blob.capacity(), capacity);
+            assert_eq!(blob.to_vec(), b"hello");
+
+            let err = blob.write(&vec![1u8; 1_000_000]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
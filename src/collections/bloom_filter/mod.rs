@@ -0,0 +1,270 @@
+use crate::collections::bit_vec::SBitVec;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::StableType;
+use crate::utils::math::seeded_hash;
+use crate::{OutOfMemory, SSlice};
+use std::f64::consts::LN_2;
+use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+const MAX_HASHES: u32 = 32;
+
+/// Probabilistic set, answering whether an item was inserted into it, with possible false positives
+///
+/// A bit array of `m` bits, each item sets `k` of them, picked by its hash. If any of these bits is
+/// unset, the item was never inserted. If all of them are set, the item was probably inserted - the
+/// probability of a false positive grows with the number of items. `m` and `k` are picked at
+/// creation, so the false positive rate stays below the configured one, while there are no more
+/// items than the configured capacity. Takes about `-1.44 * log2(fp_rate)` bits per item, e.g.
+/// ~1.2 bytes per item for 1% false positive rate. Items can't be removed.
+///
+/// Items are hashed with [zwohash](https://github.com/jix/zwohash), which is deterministic between
+/// canister upgrades. Items are never stored, so `T` only has to implement [Hash] and can be
+/// unsized (e.g. `[u8]` or `str`). Bits are stored in a [SBitVec].
+///
+/// [SBloomFilter] implements [StableType] and [AsFixedSizeBytes], so you can nest it in other stable
+/// structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBloomFilter;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// // up to 10 000 transaction hashes with 1% false positives
+/// let mut seen = SBloomFilter::<[u8; 32]>::new(10_000, 0.01).expect("Out of memory");
+///
+/// let tx_hash = [1u8; 32];
+///
+/// assert!(!seen.contains(&tx_hash));
+/// assert!(!seen.insert(&tx_hash));
+///
+/// assert!(seen.contains(&tx_hash));
+/// assert_eq!(seen.len_estimate(), 1);
+/// ```
+pub struct SBloomFilter<T: Hash + ?Sized> {
+    bits: SBitVec,
+    hashes: u32,
+    capacity: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Hash + ?Sized> SBloomFilter<T> {
+    /// Creates a new [SBloomFilter] for up to `capacity` items with the false positive rate of at
+    /// most `fp_rate`
+    ///
+    /// Allocates all the stable memory the filter will ever need. If your canister is out of stable
+    /// memory, returns [OutOfMemory].
+    ///
+    /// # Panics
+    /// Panics if `capacity` is `0` or if `fp_rate` is not in `(0, 1)` range.
+    pub fn new(capacity: u64, fp_rate: f64) -> Result<Self, OutOfMemory> {
+        assert!(capacity > 0, "capacity should be positive");
+        assert!(
+            fp_rate > 0.0 && fp_rate < 1.0,
+            "false positive rate should be in (0, 1) range"
+        );
+
+        let bits_len = (-(capacity as f64) * fp_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let hashes = ((bits_len as f64 / capacity as f64) * LN_2).round() as u32;
+
+        Ok(Self {
+            bits: SBitVec::new_with_len(bits_len.max(u64::BITS as u64))?,
+            hashes: hashes.clamp(1, MAX_HASHES),
+            capacity,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Inserts the item into this [SBloomFilter]
+    ///
+    /// Returns `true` if the item was (probably) already present.
+    pub fn insert(&mut self, item: &T) -> bool {
+        let (h1, h2) = Self::hash(item);
+        let mut present = true;
+
+        for i in 0..self.hashes {
+            let idx = self.bit_idx(h1, h2, i);
+
+            present &= self.bits.set(idx, true);
+        }
+
+        present
+    }
+
+    /// Returns `true` if the item was probably inserted into this [SBloomFilter] and `false` if it
+    /// was definitely not
+    pub fn contains(&self, item: &T) -> bool {
+        let (h1, h2) = Self::hash(item);
+
+        (0..self.hashes).all(|i| self.bits.get(self.bit_idx(h1, h2, i)))
+    }
+
+    /// Returns an estimate of the number of distinct items, inserted into this [SBloomFilter]
+    ///
+    /// Calculated from the number of set bits, so it doesn't need to read the filter. Returns
+    /// [u64::MAX], if all the bits are set.
+    pub fn len_estimate(&self) -> u64 {
+        let bits_len = self.bits.len() as f64;
+        let ones = self.bits.count_ones() as f64;
+
+        (-(bits_len / self.hashes as f64) * (1.0 - ones / bits_len).ln()).round() as u64
+    }
+
+    /// Returns the capacity this [SBloomFilter] was created with
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Removes all the items from this [SBloomFilter]
+    pub fn clear(&mut self) {
+        let bits_len = self.bits.len();
+
+        self.bits.clear();
+        // the capacity of the bit vector stays the same, so this can't fail
+        self.bits.resize(bits_len).unwrap();
+    }
+
+    #[inline]
+    fn hash(item: &T) -> (u64, u64) {
+        (seeded_hash(item, 0), seeded_hash(item, 1))
+    }
+
+    // double hashing - the i-th bit is h1 + i * h2
+    #[inline]
+    fn bit_idx(&self, h1: u64, h2: u64, i: u32) -> u64 {
+        h1.wrapping_add((i as u64).wrapping_mul(h2)) % self.bits.len()
+    }
+}
+
+impl<T: Hash + ?Sized> AsFixedSizeBytes for SBloomFilter<T> {
+    const SIZE: usize = SBitVec::SIZE + u32::SIZE + u64::SIZE;
+    type Buf = [u8; SBitVec::SIZE + u32::SIZE + u64::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.bits.as_fixed_size_bytes(&mut buf[0..SBitVec::SIZE]);
+        self.hashes
+            .as_fixed_size_bytes(&mut buf[SBitVec::SIZE..(SBitVec::SIZE + u32::SIZE)]);
+        self.capacity.as_fixed_size_bytes(
+            &mut buf[(SBitVec::SIZE + u32::SIZE)..(SBitVec::SIZE + u32::SIZE + u64::SIZE)],
+        );
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        Self {
+            bits: SBitVec::from_fixed_size_bytes(&arr[0..SBitVec::SIZE]),
+            hashes: u32::from_fixed_size_bytes(&arr[SBitVec::SIZE..(SBitVec::SIZE + u32::SIZE)]),
+            capacity: u64::from_fixed_size_bytes(
+                &arr[(SBitVec::SIZE + u32::SIZE)..(SBitVec::SIZE + u32::SIZE + u64::SIZE)],
+            ),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Hash + ?Sized> StableType for SBloomFilter<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.bits.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.bits.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.bits.visit_allocations(visitor);
+    }
+}
+
+impl<T: Hash + ?Sized> Debug for SBloomFilter<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SBloomFilter")
+            .field("capacity", &self.capacity)
+            .field("bits", &self.bits.len())
+            .field("hashes", &self.hashes)
+            .field("len_estimate", &self.len_estimate())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::bloom_filter::SBloomFilter;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut filter = SBloomFilter::<u64>::new(10_000, 0.01).unwrap();
+            assert_eq!(filter.capacity(), 10_000);
+            assert_eq!(filter.len_estimate(), 0);
+
+            for i in 0..10_000u64 {
+                filter.insert(&i);
+            }
+
+            // no false negatives
+            for i in 0..10_000u64 {
+                assert!(filter.contains(&i));
+                assert!(filter.insert(&i));
+            }
+
+            let false_positives = (10_000..110_000u64).filter(|i| filter.contains(i)).count();
+            assert!(false_positives < 1_500, "{}", false_positives);
+
+            let estimate = filter.len_estimate();
+            assert!((9_500..10_500).contains(&estimate), "{}", estimate);
+
+            println!("{:?}", filter);
+
+            store_custom_data(0, SBox::new(filter).unwrap());
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let mut filter = retrieve_custom_data::<SBloomFilter<u64>>(0)
+                .unwrap()
+                .into_inner();
+
+            assert!((0..10_000u64).all(|i| filter.contains(&i)));
+
+            filter.clear();
+            assert_eq!(filter.len_estimate(), 0);
+            assert!(!filter.contains(&1));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn unsized_items_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut filter = SBloomFilter::<str>::new(100, 0.001).unwrap();
+
+            filter.insert("hello");
+            filter.insert("world");
+
+            assert!(filter.contains("hello"));
+            assert!(filter.contains("world"));
+            assert!(!filter.contains("hello world"));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
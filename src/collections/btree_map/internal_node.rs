@@ -1,4 +1,6 @@
-use crate::collections::btree_map::{BTreeNode, IBTreeNode};
+use crate::collections::btree_map::leaf_node::LeafBTreeNode;
+use crate::collections::btree_map::node_cache::on_internal_node_write;
+use crate::collections::btree_map::{binary_search_in_keys, BTreeNode, IBTreeNode};
 use crate::collections::btree_map::{
     B, CAPACITY, CHILDREN_CAPACITY, CHILDREN_MIN_LEN_AFTER_SPLIT, MIN_LEN_AFTER_SPLIT,
     NODE_TYPE_INTERNAL, NODE_TYPE_OFFSET,
@@ -19,6 +21,9 @@ use std::marker::PhantomData;
 // children: [u64; CHILDREN_CAPACITY]
 // keys: [K; CAPACITY]
 // root_hash: Hash -- ONLY IF certified == true
+// child_counts: [u64; CHILDREN_CAPACITY] -- ONLY IF counted == true (never together with root_hash)
+//
+// root_hash and child_counts share the same offset, so a node can't be both certified and counted
 
 const LEN_OFFSET: u64 = NODE_TYPE_OFFSET + u8::SIZE as u64;
 const CHILDREN_OFFSET: u64 = LEN_OFFSET + usize::SIZE as u64;
@@ -28,6 +33,10 @@ const fn root_hash_offset<K: AsFixedSizeBytes>() -> u64 {
     KEYS_OFFSET + (K::SIZE * CAPACITY) as u64
 }
 
+const fn child_counts_offset<K: AsFixedSizeBytes>() -> u64 {
+    root_hash_offset::<K>()
+}
+
 pub struct InternalBTreeNode<K> {
     ptr: u64,
     _marker_k: PhantomData<K>,
@@ -35,18 +44,26 @@ pub struct InternalBTreeNode<K> {
 
 impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
     #[inline]
-    pub const fn calc_byte_size(certified: bool) -> u64 {
+    pub const fn calc_byte_size(certified: bool, counted: bool) -> u64 {
+        debug_assert!(!(certified && counted));
+
         let mut size = root_hash_offset::<K>();
 
         if certified {
             size += Hash::SIZE as u64
         }
 
+        if counted {
+            size += (u64::SIZE * CHILDREN_CAPACITY) as u64
+        }
+
         size
     }
 
-    pub fn create_empty(certified: bool) -> Result<Self, OutOfMemory> {
-        let slice = unsafe { allocate(Self::calc_byte_size(certified))? };
+    pub fn create_empty(certified: bool, counted: bool) -> Result<Self, OutOfMemory> {
+        debug_assert!(!(certified && counted));
+
+        let slice = unsafe { allocate(Self::calc_byte_size(certified, counted))? };
         let mut it = Self {
             ptr: slice.as_ptr(),
             _marker_k: PhantomData::default(),
@@ -63,8 +80,11 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         lcp: &StablePtrBuf,
         rcp: &StablePtrBuf,
         certified: bool,
+        counted: bool,
     ) -> Result<Self, OutOfMemory> {
-        let slice = unsafe { allocate(Self::calc_byte_size(certified))? };
+        debug_assert!(!(certified && counted));
+
+        let slice = unsafe { allocate(Self::calc_byte_size(certified, counted))? };
         let mut it = Self {
             ptr: slice.as_ptr(),
             _marker_k: PhantomData::default(),
@@ -83,6 +103,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
     #[inline]
     pub fn destroy(self) {
+        on_internal_node_write(self.ptr);
+
         let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
         deallocate(slice);
     }
@@ -131,6 +153,51 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         }
     }
 
+    // the size of the part of the node, loaded by InternalBTreeNode::load_to_buf
+    #[inline]
+    pub const fn loaded_size() -> usize {
+        root_hash_offset::<K>() as usize
+    }
+
+    // loads the type, the length, child pointers and keys of this node with a single read
+    #[inline]
+    pub fn load_to_buf(&self, buf: &mut Vec<u8>) {
+        buf.resize(Self::loaded_size(), 0);
+
+        unsafe { crate::mem::read_bytes(SSlice::_offset(self.ptr, NODE_TYPE_OFFSET), buf) };
+    }
+
+    #[inline]
+    pub fn len_from_buf(buf: &[u8]) -> usize {
+        usize::from_fixed_size_bytes(&buf[(LEN_OFFSET as usize)..(CHILDREN_OFFSET as usize)])
+    }
+
+    #[inline]
+    pub fn keys_from_buf(buf: &[u8], len: usize) -> &[u8] {
+        &buf[(KEYS_OFFSET as usize)..(KEYS_OFFSET as usize + len * K::SIZE)]
+    }
+
+    #[inline]
+    pub fn children_from_buf(buf: &[u8], len: usize) -> &[u8] {
+        &buf[(CHILDREN_OFFSET as usize)..(CHILDREN_OFFSET as usize + (len + 1) * u64::SIZE)]
+    }
+
+    #[inline]
+    pub fn binary_search_in_buf<Q>(buf: &[u8], k: &Q, len: usize) -> Result<usize, usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        binary_search_in_keys::<K, Q>(Self::keys_from_buf(buf, len), k, len)
+    }
+
+    #[inline]
+    pub fn child_ptr_from_buf(buf: &[u8], idx: usize) -> StablePtr {
+        let from = CHILDREN_OFFSET as usize + idx * u64::SIZE;
+
+        u64::from_fixed_size_bytes(&buf[from..(from + u64::SIZE)])
+    }
+
     pub fn steal_from_left(
         &mut self,
         self_len: usize,
@@ -196,8 +263,9 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         &mut self,
         buf: &mut Vec<u8>,
         certified: bool,
+        counted: bool,
     ) -> Result<(InternalBTreeNode<K>, K::Buf), OutOfMemory> {
-        let mut right = InternalBTreeNode::<K>::create_empty(certified)?;
+        let mut right = InternalBTreeNode::<K>::create_empty(certified, counted)?;
 
         self.read_many_keys_to_buf(B, MIN_LEN_AFTER_SPLIT, buf);
         right.write_many_keys_from_buf(0, buf);
@@ -231,10 +299,14 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
             return;
         }
 
-        self.read_many_keys_to_buf(idx, len - idx, buf);
-        self.write_many_keys_from_buf(idx + 1, buf);
+        // the key and shifted keys are written at once
+        buf.resize((len - idx + 1) * K::SIZE, 0);
+        buf[0..K::SIZE].copy_from_slice(key._deref());
+
+        let ptr = SSlice::_offset(self.ptr, KEYS_OFFSET + (idx * K::SIZE) as u64);
+        unsafe { crate::mem::read_bytes(ptr, &mut buf[K::SIZE..]) };
 
-        self.write_key_buf(idx, key);
+        self.write_many_keys_from_buf(idx, buf);
     }
 
     pub fn remove_key_buf(&mut self, idx: usize, len: usize, buf: &mut Vec<u8>) {
@@ -263,10 +335,14 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
             return;
         }
 
-        self.read_many_child_ptrs_to_buf(idx, children_len - idx, buf);
-        self.write_many_child_ptrs_from_buf(idx + 1, buf);
+        // the pointer and shifted pointers are written at once
+        buf.resize((children_len - idx + 1) * u64::SIZE, 0);
+        buf[0..u64::SIZE].copy_from_slice(ptr_buf);
 
-        self.write_child_ptr_buf(idx, ptr_buf);
+        let ptr = SSlice::_offset(self.ptr, CHILDREN_OFFSET + (idx * u64::SIZE) as u64);
+        unsafe { crate::mem::read_bytes(ptr, &mut buf[u64::SIZE..]) };
+
+        self.write_many_child_ptrs_from_buf(idx, buf);
     }
 
     pub fn remove_child_ptr_buf(&mut self, idx: usize, children_len: usize, buf: &mut Vec<u8>) {
@@ -320,7 +396,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
     }
 
     #[inline]
-    fn read_many_keys_to_buf(&self, from_idx: usize, len: usize, buf: &mut Vec<u8>) {
+    pub fn read_many_keys_to_buf(&self, from_idx: usize, len: usize, buf: &mut Vec<u8>) {
         buf.resize(len * K::SIZE, 0);
         let ptr = SSlice::_offset(self.ptr, KEYS_OFFSET + (from_idx * K::SIZE) as u64);
 
@@ -338,7 +414,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
     }
 
     #[inline]
-    fn read_many_child_ptrs_to_buf(&self, from_idx: usize, len: usize, buf: &mut Vec<u8>) {
+    pub fn read_many_child_ptrs_to_buf(&self, from_idx: usize, len: usize, buf: &mut Vec<u8>) {
         buf.resize(len * u64::SIZE, 0);
         let ptr = SSlice::_offset(self.ptr, CHILDREN_OFFSET + (from_idx * u64::SIZE) as u64);
 
@@ -347,12 +423,16 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
     #[inline]
     pub fn write_key_buf(&mut self, idx: usize, key: &K::Buf) {
+        on_internal_node_write(self.ptr);
+
         let ptr = SSlice::_offset(self.ptr, KEYS_OFFSET + (idx * K::SIZE) as u64);
         unsafe { crate::mem::write_bytes(ptr, key._deref()) };
     }
 
     #[inline]
     fn write_many_keys_from_buf(&mut self, from_idx: usize, buf: &Vec<u8>) {
+        on_internal_node_write(self.ptr);
+
         let ptr = SSlice::_offset(self.ptr, KEYS_OFFSET + (from_idx * K::SIZE) as u64);
 
         unsafe { crate::mem::write_bytes(ptr, buf) };
@@ -360,6 +440,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
     #[inline]
     pub fn write_child_ptr_buf(&mut self, idx: usize, child_ptr: &StablePtrBuf) {
+        on_internal_node_write(self.ptr);
+
         let ptr = SSlice::_offset(self.ptr, CHILDREN_OFFSET + (idx * u64::SIZE) as u64);
 
         unsafe { crate::mem::write_bytes(ptr, child_ptr) };
@@ -367,6 +449,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
 
     #[inline]
     fn write_many_child_ptrs_from_buf(&mut self, from_idx: usize, buf: &Vec<u8>) {
+        on_internal_node_write(self.ptr);
+
         let ptr = SSlice::_offset(self.ptr, CHILDREN_OFFSET + (from_idx * u64::SIZE) as u64);
 
         unsafe { crate::mem::write_bytes(ptr, buf) };
@@ -391,8 +475,83 @@ impl<K: StableType + AsFixedSizeBytes + Ord> InternalBTreeNode<K> {
         buf
     }
 
+    // returns the number of entries in each of the first `children_len` subtrees
+    pub fn read_child_counts(&self, children_len: usize, counted: bool) -> Vec<u64> {
+        debug_assert!(counted);
+
+        let mut buf = vec![0u8; children_len * u64::SIZE];
+        let ptr = SSlice::_offset(self.ptr, child_counts_offset::<K>());
+        unsafe { crate::mem::read_bytes(ptr, &mut buf) };
+
+        buf.chunks_exact(u64::SIZE)
+            .map(u64::from_fixed_size_bytes)
+            .collect()
+    }
+
+    #[inline]
+    pub fn read_total_count(&self, counted: bool) -> u64 {
+        self.read_child_counts(self.read_len() + 1, counted)
+            .iter()
+            .sum()
+    }
+
+    // adds `delta` to the number of entries in the subtree of the child; the addition wraps, so
+    // `u64::MAX` subtracts one
+    pub fn add_to_child_count(&mut self, idx: usize, delta: u64, counted: bool) {
+        debug_assert!(counted);
+
+        let ptr = SSlice::_offset(
+            self.ptr,
+            child_counts_offset::<K>() + (idx * u64::SIZE) as u64,
+        );
+
+        unsafe {
+            let count: u64 = crate::mem::read_fixed_for_reference(ptr);
+            crate::mem::write_fixed(ptr, &mut count.wrapping_add(delta));
+        }
+    }
+
+    // recalculates the number of entries in each subtree, reading them from children
+    pub fn recount<V: StableType + AsFixedSizeBytes>(&mut self, counted: bool) {
+        debug_assert!(counted);
+
+        let children_len = self.read_len() + 1;
+
+        let mut buf = Vec::new();
+        self.read_many_child_ptrs_to_buf(0, children_len, &mut buf);
+
+        // all children of a node are of the same type
+        let leaf_children = matches!(
+            BTreeNode::<K, V>::from_ptr(Self::child_ptr_from_children_buf(&buf, 0)),
+            BTreeNode::Leaf(_)
+        );
+
+        let mut counts = Vec::with_capacity(children_len * u64::SIZE);
+        for idx in 0..children_len {
+            let child_ptr = Self::child_ptr_from_children_buf(&buf, idx);
+
+            let count = if leaf_children {
+                unsafe { LeafBTreeNode::<K, V>::from_ptr(child_ptr) }.read_len() as u64
+            } else {
+                unsafe { Self::from_ptr(child_ptr) }.read_total_count(counted)
+            };
+
+            counts.extend_from_slice(&count.as_new_fixed_size_bytes());
+        }
+
+        let ptr = SSlice::_offset(self.ptr, child_counts_offset::<K>());
+        unsafe { crate::mem::write_bytes(ptr, &counts) };
+    }
+
+    #[inline]
+    fn child_ptr_from_children_buf(buf: &[u8], idx: usize) -> StablePtr {
+        u64::from_fixed_size_bytes(&buf[(idx * u64::SIZE)..((idx + 1) * u64::SIZE)])
+    }
+
     #[inline]
     pub fn write_len(&mut self, mut len: usize) {
+        on_internal_node_write(self.ptr);
+
         let ptr = SSlice::_offset(self.ptr, LEN_OFFSET);
 
         unsafe { crate::mem::write_fixed(ptr, &mut len) };
@@ -492,7 +651,7 @@ mod tests {
         stable_memory_init();
 
         {
-            let mut node = InternalBTreeNode::<u64>::create_empty(false).unwrap();
+            let mut node = InternalBTreeNode::<u64>::create_empty(false, false).unwrap();
             let mut buf = Vec::default();
 
             for i in 0..CAPACITY {
@@ -527,7 +686,7 @@ mod tests {
             println!("{}", node.to_string());
             println!();
 
-            let (mut right, mid) = node.split_max_len(&mut buf, false).unwrap();
+            let (mut right, mid) = node.split_max_len(&mut buf, false, false).unwrap();
 
             node.write_len(MIN_LEN_AFTER_SPLIT);
             right.write_len(MIN_LEN_AFTER_SPLIT);
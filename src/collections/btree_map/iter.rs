@@ -1,6 +1,7 @@
 use crate::collections::btree_map::leaf_node::LeafBTreeNode;
 use crate::collections::btree_map::{BTreeNode, IBTreeNode, SBTreeMap};
 use crate::encoding::AsFixedSizeBytes;
+use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::StableType;
 
@@ -9,6 +10,7 @@ pub struct SBTreeMapIter<'a, K, V> {
     node: Option<LeafBTreeNode<K, V>>,
     node_idx: usize,
     node_len: usize,
+    last_key_ptr: Option<StablePtr>,
 }
 
 impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
@@ -21,8 +23,35 @@ impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeByte
             node: None,
             node_idx: 0,
             node_len: 0,
+            last_key_ptr: None,
         }
     }
+
+    #[inline]
+    pub(crate) fn new_at(
+        map: &'a SBTreeMap<K, V>,
+        node: LeafBTreeNode<K, V>,
+        node_idx: usize,
+        node_len: usize,
+    ) -> Self {
+        Self {
+            root: &map.root,
+            node: Some(node),
+            node_idx,
+            node_len,
+            last_key_ptr: None,
+        }
+    }
+
+    /// Returns the key of the entry, last returned by [Iterator::next]
+    ///
+    /// Returns [None], if [Iterator::next] was never called or if it has never returned an entry.
+    /// Entries returned by [DoubleEndedIterator::next_back] don't affect the cursor. Serialize this
+    /// key and pass it to [SBTreeMap::iter_from_cursor] to continue the iteration from this point.
+    #[inline]
+    pub fn cursor(&self) -> Option<SRef<'a, K>> {
+        self.last_key_ptr.map(|ptr| unsafe { SRef::new(ptr) })
+    }
 }
 
 impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Iterator
@@ -51,6 +80,7 @@ impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeByte
                 .as_ref()
                 .map(|it| (it.get_key(self.node_idx), it.get_value(self.node_idx)));
 
+            self.last_key_ptr = self.node.as_ref().map(|it| it.get_key_ptr(self.node_idx));
             self.node_idx += 1;
 
             res
@@ -138,3 +168,38 @@ impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeByte
         }
     }
 }
+
+/// A forward-only iterator over entries of an [SBTreeMap], returned by [SBTreeMap::iter_from_cursor]
+///
+/// It starts in the middle of the map, so it can't be reversed.
+pub struct SBTreeMapForwardIter<'a, K, V> {
+    inner: SBTreeMapIter<'a, K, V>,
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes>
+    SBTreeMapForwardIter<'a, K, V>
+{
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapIter<'a, K, V>) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the key of the entry, last returned by [Iterator::next]
+    ///
+    /// See [SBTreeMapIter::cursor].
+    #[inline]
+    pub fn cursor(&self) -> Option<SRef<'a, K>> {
+        self.inner.cursor()
+    }
+}
+
+impl<'a, K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Iterator
+    for SBTreeMapForwardIter<'a, K, V>
+{
+    type Item = (SRef<'a, K>, SRef<'a, V>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
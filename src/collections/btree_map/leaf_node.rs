@@ -1,6 +1,7 @@
 use crate::collections::btree_map::internal_node::InternalBTreeNode;
 use crate::collections::btree_map::{
-    IBTreeNode, B, CAPACITY, MIN_LEN_AFTER_SPLIT, NODE_TYPE_LEAF, NODE_TYPE_OFFSET,
+    binary_search_in_keys, IBTreeNode, B, CAPACITY, MIN_LEN_AFTER_SPLIT, NODE_TYPE_LEAF,
+    NODE_TYPE_OFFSET,
 };
 use crate::encoding::{AsFixedSizeBytes, Buffer};
 use crate::mem::{stable_ptr_buf, StablePtrBuf};
@@ -119,6 +120,36 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
         }
     }
 
+    // the size of the part of the node, loaded by LeafBTreeNode::load_to_buf
+    #[inline]
+    pub const fn loaded_size() -> usize {
+        values_offset::<K>() as usize
+    }
+
+    // loads the type, sibling pointers, the length and keys of this node with a single read
+    #[inline]
+    pub fn load_to_buf(&self, buf: &mut Vec<u8>) {
+        buf.resize(Self::loaded_size(), 0);
+
+        unsafe { crate::mem::read_bytes(SSlice::_offset(self.ptr, NODE_TYPE_OFFSET), buf) };
+    }
+
+    #[inline]
+    pub fn len_from_buf(buf: &[u8]) -> usize {
+        usize::from_fixed_size_bytes(&buf[(LEN_OFFSET as usize)..(KEYS_OFFSET as usize)])
+    }
+
+    #[inline]
+    pub fn binary_search_in_buf<Q>(buf: &[u8], k: &Q, len: usize) -> Result<usize, usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let keys = &buf[(KEYS_OFFSET as usize)..(KEYS_OFFSET as usize + len * K::SIZE)];
+
+        binary_search_in_keys::<K, Q>(keys, k, len)
+    }
+
     pub fn steal_from_left(
         &mut self,
         self_len: usize,
@@ -246,10 +277,13 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
             return;
         }
 
-        self.read_many_keys_to_buf(idx, len - idx, buf);
-        self.write_many_keys_from_buf(idx + 1, buf);
+        // the key and shifted keys are written at once
+        buf.resize((len - idx + 1) * K::SIZE, 0);
+        buf[0..K::SIZE].copy_from_slice(key._deref());
+
+        unsafe { crate::mem::read_bytes(self.get_key_ptr(idx), &mut buf[K::SIZE..]) };
 
-        self.write_key_buf(idx, key);
+        self.write_many_keys_from_buf(idx, buf);
     }
 
     fn remove_key_buf(&mut self, idx: usize, len: usize, buf: &mut Vec<u8>) {
@@ -272,10 +306,13 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
             return;
         }
 
-        self.read_many_values_to_buf(idx, len - idx, buf);
-        self.write_many_values_from_buf(idx + 1, buf);
+        // the value and shifted values are written at once
+        buf.resize((len - idx + 1) * V::SIZE, 0);
+        buf[0..V::SIZE].copy_from_slice(value._deref());
 
-        self.write_value_buf(idx, value);
+        unsafe { crate::mem::read_bytes(self.get_value_ptr(idx), &mut buf[V::SIZE..]) };
+
+        self.write_many_values_from_buf(idx, buf);
     }
 
     fn remove_value_buf(&mut self, idx: usize, len: usize, buf: &mut Vec<u8>) {
@@ -333,7 +370,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> L
     }
 
     #[inline]
-    fn get_key_ptr(&self, idx: usize) -> u64 {
+    pub(crate) fn get_key_ptr(&self, idx: usize) -> u64 {
         SSlice::_offset(self.ptr, KEYS_OFFSET + (idx * K::SIZE) as u64)
     }
 
@@ -1,18 +1,26 @@
 use crate::collections::btree_map::internal_node::InternalBTreeNode;
-use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::iter::{SBTreeMapForwardIter, SBTreeMapIter};
 use crate::collections::btree_map::leaf_node::LeafBTreeNode;
+use crate::collections::btree_map::node_cache::{
+    track_writes, CachedNode, InternalSearchResult, NodeCache,
+};
 use crate::encoding::AsFixedSizeBytes;
 use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::deferred_drop::IncrementalDrop;
 use crate::mem::free_block::FreeBlock;
 use crate::mem::{StablePtr, StablePtrBuf};
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
 use crate::utils::math::shuffle_bits;
-use crate::{isoprint, make_sure_can_allocate, OutOfMemory, SSlice};
+use crate::{isoprint, reserve, stable, OutOfMemory, Rejected, SSlice};
 use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
 use std::mem;
+use std::ops::{Bound, RangeBounds};
 
 pub(crate) const B: usize = 8;
 pub(crate) const CAPACITY: usize = 2 * B - 1;
@@ -25,9 +33,16 @@ pub(crate) const NODE_TYPE_INTERNAL: u8 = 127;
 pub(crate) const NODE_TYPE_LEAF: u8 = 255;
 pub(crate) const NODE_TYPE_OFFSET: u64 = 0;
 
+// the encoded size of any SBTreeMap, usable where generic parameters are not (e.g. in array lengths)
+pub(crate) const SBTREE_MAP_SIZE: usize = u64::SIZE * 2 + bool::SIZE;
+
 pub(crate) mod internal_node;
 pub mod iter;
 pub(crate) mod leaf_node;
+pub(crate) mod node_cache;
+
+// The leaf, its length and the result of the key search in it
+type LeafSearchResult<K, V> = (LeafBTreeNode<K, V>, usize, Result<usize, usize>);
 
 /// Right-biased B-plus tree based map data structure
 ///
@@ -46,9 +61,11 @@ pub struct SBTreeMap<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsF
     root: Option<BTreeNode<K, V>>,
     len: u64,
     certified: bool,
+    counted: bool,
     stable_drop_flag: bool,
     _stack: Vec<(InternalBTreeNode<K>, usize, usize)>,
     _buf: Vec<u8>,
+    node_cache: Option<RefCell<NodeCache<K>>>,
 }
 
 impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> SBTreeMap<K, V> {
@@ -61,9 +78,52 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             root: None,
             len: 0,
             certified: false,
+            counted: false,
+            stable_drop_flag: true,
+            _stack: Vec::default(),
+            _buf: Vec::default(),
+            node_cache: None,
+        }
+    }
+
+    /// Creates a new [SBTreeMap], which maintains the number of entries in each of its subtrees
+    ///
+    /// Such a map supports order-statistics queries: [SBTreeMap::nth], [SBTreeMap::rank],
+    /// [SBTreeMap::count_range] and [SBTreeMap::random_entry]. Counts are stored inside internal
+    /// nodes, which makes them bigger, and are updated on each insertion and removal, which makes
+    /// these operations more expensive. The map remembers this setting between upgrades.
+    ///
+    /// Does not allocate any heap or stable memory.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new_with_counts();
+    ///
+    /// for i in 0..100u64 {
+    ///     map.insert(i * 10, i).expect("Out of memory");
+    /// }
+    ///
+    /// let (key, value) = map.nth(42).unwrap();
+    /// assert_eq!((*key, *value), (420, 42));
+    ///
+    /// assert_eq!(map.rank(&425), 43);
+    /// assert_eq!(map.count_range(100..200), 10);
+    /// ```
+    #[inline]
+    pub fn new_with_counts() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            certified: false,
+            counted: true,
             stable_drop_flag: true,
             _stack: Vec::default(),
             _buf: Vec::default(),
+            node_cache: None,
         }
     }
 
@@ -73,16 +133,18 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             root: None,
             len: 0,
             certified: true,
+            counted: false,
             stable_drop_flag: true,
             _stack: Vec::default(),
             _buf: Vec::default(),
+            node_cache: None,
         }
     }
 
     /// Inserts the provided key-value pair into this [SBTreeMap]
     ///
     /// May allocate stable and heap memory. If your canister is out of stable memory, will return
-    /// [Err] with the key-value pair that was about to get inserted.
+    /// [Rejected] with the key-value pair that was about to get inserted.
     ///
     /// If the insertion is successful, returns [Option] with a value, that was previously stored
     /// under this key.
@@ -90,19 +152,21 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
     /// # Example
     /// ```rust
     /// # use ic_stable_memory::collections::SBTreeMap;
-    /// # use ic_stable_memory::stable_memory_init;
+    /// # use ic_stable_memory::{stable_memory_init, Rejected};
     /// # unsafe { ic_stable_memory::mem::clear(); }
     /// # stable_memory_init();
     /// let mut map = SBTreeMap::new();
     ///
     /// match map.insert(10u64, 100u64) {
     ///     Ok(prev) => println!("Success! Previous value == {prev:?}"),
-    ///     Err((k, v)) => println!("Out of memory. Unable to insert pair: {k}, {v}"),
+    ///     Err(Rejected { value: (k, v), reason }) => {
+    ///         println!("Unable to insert pair: {k}, {v}. {reason}")
+    ///     }
     /// };
     /// ```
     #[inline]
-    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
-        self._insert(key, value, &mut LeveledList::None)
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Rejected<(K, V)>> {
+        self.modify(|it, modified| it._insert(key, value, modified))
     }
 
     pub(crate) fn _insert(
@@ -110,61 +174,49 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         key: K,
         value: V,
         modified: &mut LeveledList,
-    ) -> Result<Option<V>, (K, V)> {
-        if let Ok(mut node) = self.get_or_create_root() {
-            let mut leaf = loop {
-                match unsafe { node.copy() } {
-                    BTreeNode::Internal(internal_node) => {
-                        let node_len = internal_node.read_len();
-                        let child_idx = match internal_node.binary_search(&key, node_len) {
-                            Ok(idx) => idx + 1,
-                            Err(idx) => idx,
-                        };
-
-                        let child_ptr = internal_node.read_child_ptr_buf(child_idx);
-                        self.push_stack(internal_node, node_len, child_idx);
-
-                        node = BTreeNode::<K, V>::from_ptr(u64::from_fixed_size_bytes(&child_ptr));
-                    }
-                    BTreeNode::Leaf(leaf_node) => break unsafe { leaf_node.copy() },
-                }
-            };
+    ) -> Result<Option<V>, Rejected<(K, V)>> {
+        if let Err(e) = self.get_or_create_root() {
+            return Err(Rejected::new((key, value), e));
+        }
 
-            // this call makes sure there is enough free stable memory to allocate everything else
-            // if it returns Ok - every other allocation after that should simply .unwrap()
-            let right_leaf = match self.insert_leaf(&mut leaf, key, value, modified)? {
-                Ok(v) => {
-                    self.clear_stack(modified);
+        let mut stack = mem::take(&mut self._stack);
+        let mut buf = mem::take(&mut self._buf);
 
-                    return Ok(Some(v));
-                }
-                Err(right_leaf_opt) => {
-                    if let Some(right_leaf) = right_leaf_opt {
-                        right_leaf
-                    } else {
-                        self.clear_stack(modified);
-                        self.len += 1;
+        let (mut leaf, leaf_len, leaf_res) = self
+            .descend(&key, &mut buf, |internal_node, node_len, res| {
+                let child_idx = match res {
+                    Ok(idx) => idx + 1,
+                    Err(idx) => idx,
+                };
 
-                        return Ok(None);
-                    }
-                }
-            };
+                stack.push((internal_node, node_len, child_idx));
 
-            let mut key_to_index = right_leaf.read_key_buf(0);
-            let mut ptr = right_leaf.as_ptr();
+                true
+            })
+            .unwrap();
 
-            while let Some((mut parent, parent_len, idx)) = self.pop_stack() {
-                if let Some((right, _k)) = self.insert_internal(
-                    &mut parent,
-                    parent_len,
-                    idx,
-                    key_to_index,
-                    ptr.as_new_fixed_size_bytes(),
-                    modified,
-                ) {
-                    key_to_index = _k;
-                    ptr = right.as_ptr();
-                    node = BTreeNode::Internal(parent);
+        self._stack = stack;
+        self._buf = buf;
+
+        let mut node = BTreeNode::Leaf(unsafe { leaf.copy() });
+
+        // this call makes sure there is enough free stable memory to allocate everything else
+        // if it returns Ok - every other allocation after that should simply .unwrap()
+        let res = self.insert_leaf(&mut leaf, leaf_len, leaf_res, key, value, modified);
+        if res.is_err() {
+            // nothing is inserted, but the next operation expects an empty stack
+            self._stack.clear();
+        }
+
+        let right_leaf = match res? {
+            Ok(v) => {
+                self.clear_stack(modified);
+
+                return Ok(Some(v));
+            }
+            Err(right_leaf_opt) => {
+                if let Some(right_leaf) = right_leaf_opt {
+                    right_leaf
                 } else {
                     self.clear_stack(modified);
                     self.len += 1;
@@ -172,26 +224,48 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                     return Ok(None);
                 }
             }
+        };
 
-            // stack is empty now
+        let mut key_to_index = right_leaf.read_key_buf(0);
+        let mut ptr = right_leaf.as_ptr();
 
-            let new_root = InternalBTreeNode::<K>::create(
-                &key_to_index,
-                &node.as_ptr().as_new_fixed_size_bytes(),
-                &ptr.as_new_fixed_size_bytes(),
-                self.certified,
-            )
-            .unwrap();
+        while let Some((mut parent, parent_len, idx)) = self.pop_stack() {
+            if let Some((right, _k)) = self.insert_internal(
+                &mut parent,
+                parent_len,
+                idx,
+                key_to_index,
+                ptr.as_new_fixed_size_bytes(),
+                modified,
+            ) {
+                key_to_index = _k;
+                ptr = right.as_ptr();
+                node = BTreeNode::Internal(parent);
+            } else {
+                self.clear_stack(modified);
+                self.len += 1;
+
+                return Ok(None);
+            }
+        }
 
-            modified.insert_root(new_root.as_ptr());
+        // stack is empty now
 
-            self.root = Some(BTreeNode::Internal(new_root));
-            self.len += 1;
+        let new_root = InternalBTreeNode::<K>::create(
+            &key_to_index,
+            &node.as_ptr().as_new_fixed_size_bytes(),
+            &ptr.as_new_fixed_size_bytes(),
+            self.certified,
+            self.counted,
+        )
+        .unwrap();
 
-            Ok(None)
-        } else {
-            Err((key, value))
-        }
+        modified.insert_root(new_root.as_ptr());
+
+        self.root = Some(BTreeNode::Internal(new_root));
+        self.len += 1;
+
+        Ok(None)
     }
 
     /// Removes a key-value pair by the provided key
@@ -234,7 +308,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         K: Borrow<Q>,
         Q: Ord + ?Sized,
     {
-        self._remove(key, &mut LeveledList::None)
+        self.modify(|it, modified| it._remove(key, modified))
     }
 
     pub(crate) fn _remove<Q>(&mut self, key: &Q, modified: &mut LeveledList) -> Option<V>
@@ -244,36 +318,42 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
     {
         self.root.as_ref()?;
 
-        let mut node = unsafe { self.get_or_create_root().unwrap_unchecked() };
+        let mut stack = mem::take(&mut self._stack);
+        let mut buf = mem::take(&mut self._buf);
         let mut found_internal_node = None;
 
         // lookup for the leaf that may contain the key
-        let mut leaf = loop {
-            match node {
-                BTreeNode::Internal(internal_node) => {
-                    let node_len = internal_node.read_len();
-                    let child_idx = match internal_node.binary_search(key, node_len) {
-                        Ok(idx) => {
-                            debug_assert!(found_internal_node.is_none());
-                            found_internal_node = Some((unsafe { internal_node.copy() }, idx));
+        let (mut leaf, leaf_len, leaf_res) = self
+            .descend(key, &mut buf, |internal_node, node_len, res| {
+                let child_idx = match res {
+                    Ok(idx) => {
+                        debug_assert!(found_internal_node.is_none());
+                        found_internal_node = Some((unsafe { internal_node.copy() }, idx));
+
+                        idx + 1
+                    }
+                    Err(idx) => idx,
+                };
 
-                            idx + 1
-                        }
-                        Err(idx) => idx,
-                    };
+                stack.push((internal_node, node_len, child_idx));
 
-                    let child_ptr = internal_node.read_child_ptr_buf(child_idx);
-                    self.push_stack(internal_node, node_len, child_idx);
+                true
+            })
+            .unwrap();
 
-                    node = BTreeNode::<K, V>::from_ptr(u64::from_fixed_size_bytes(&child_ptr));
-                }
-                BTreeNode::Leaf(leaf_node) => break unsafe { leaf_node.copy() },
+        self._stack = stack;
+        self._buf = buf;
+
+        let idx = match leaf_res {
+            Ok(idx) => idx,
+            Err(_) => {
+                // nothing is modified, but the next operation expects an empty stack
+                self._stack.clear();
+
+                return None;
             }
         };
 
-        let leaf_len = leaf.read_len();
-        let idx = leaf.binary_search(key, leaf_len).ok()?;
-
         self.len -= 1;
 
         // if possible to simply remove the key without violating - return early
@@ -383,6 +463,110 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         }
     }
 
+    /// Returns the key-value pair at the provided position (in the ascending order of keys)
+    ///
+    /// If `idx` is out of bounds, returns [None]. Only visits a single node per level of the tree,
+    /// reading its child pointers and the entry counts of its subtrees.
+    ///
+    /// # Panics
+    /// Panics if this map was not created with [SBTreeMap::new_with_counts].
+    pub fn nth(&self, mut idx: u64) -> Option<(SRef<'_, K>, SRef<'_, V>)> {
+        self.assert_counted();
+
+        if idx >= self.len {
+            return None;
+        }
+
+        let mut buf = Vec::new();
+        let mut node = BTreeNode::<K, V>::load(self.get_root()?.as_ptr(), self.certified, &mut buf);
+
+        loop {
+            match node {
+                BTreeNode::Internal(internal_node) => {
+                    let len = InternalBTreeNode::<K>::len_from_buf(&buf);
+                    let counts = internal_node.read_child_counts(len + 1, self.counted);
+
+                    let mut child_idx = 0;
+                    while idx >= counts[child_idx] {
+                        idx -= counts[child_idx];
+                        child_idx += 1;
+                    }
+
+                    let child_ptr = InternalBTreeNode::<K>::child_ptr_from_buf(&buf, child_idx);
+                    node = BTreeNode::load(child_ptr, self.certified, &mut buf);
+                }
+                BTreeNode::Leaf(leaf_node) => {
+                    let idx = idx as usize;
+
+                    return Some((leaf_node.get_key(idx), leaf_node.get_value(idx)));
+                }
+            }
+        }
+    }
+
+    /// Returns the number of keys, which are less than the provided one
+    ///
+    /// If the key is present in this map, this is its position, as in [SBTreeMap::nth].
+    ///
+    /// Borrowed type is also accepted. If your key type is, for example, [SBox] of [String],
+    /// then you can rank by [String].
+    ///
+    /// # Panics
+    /// Panics if this map was not created with [SBTreeMap::new_with_counts].
+    #[inline]
+    pub fn rank<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.count_before(key, false)
+    }
+
+    /// Returns the number of keys, which lie in the provided range
+    ///
+    /// Unlike iteration, this only takes two lookups, no matter how many keys there are in the range.
+    ///
+    /// # Panics
+    /// Panics if this map was not created with [SBTreeMap::new_with_counts].
+    pub fn count_range<Q, R>(&self, range: R) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let from = match range.start_bound() {
+            Bound::Included(key) => self.count_before(key, false),
+            Bound::Excluded(key) => self.count_before(key, true),
+            Bound::Unbounded => 0,
+        };
+
+        let to = match range.end_bound() {
+            Bound::Included(key) => self.count_before(key, true),
+            Bound::Excluded(key) => self.count_before(key, false),
+            Bound::Unbounded => self.len,
+        };
+
+        to.saturating_sub(from)
+    }
+
+    /// Returns a random key-value pair, deterministically deriving the randomness from the seed
+    ///
+    /// Unlike [SBTreeMap::get_random_key], each entry is equally likely to be returned, as long as
+    /// the seed is uniformly distributed. If the collection is empty, returns [None].
+    ///
+    /// # Panics
+    /// Panics if this map was not created with [SBTreeMap::new_with_counts].
+    #[inline]
+    pub fn random_entry(&self, seed: u64) -> Option<(SRef<'_, K>, SRef<'_, V>)> {
+        self.assert_counted();
+
+        if self.is_empty() {
+            return None;
+        }
+
+        self.nth(seed % self.len)
+    }
+
     /// Returns a mutable reference [SRefMut] to a value stored by the key
     ///
     /// See also [SBTreeMap::get].
@@ -408,42 +592,26 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
     {
         if modified.is_some() {
             let mut modified_buf = Vec::new();
+            let mut buf = mem::take(&mut self._buf);
 
-            let mut level = 0;
-            let mut node = self.get_root()?;
-            loop {
-                match node {
-                    BTreeNode::Internal(internal_node) => {
-                        let child_idx =
-                            match internal_node.binary_search(key, internal_node.read_len()) {
-                                Ok(idx) => idx + 1,
-                                Err(idx) => idx,
-                            };
+            let found = self.descend(key, &mut buf, |internal_node, _, _| {
+                modified_buf.push(internal_node.as_ptr());
 
-                        modified_buf.push((level, internal_node.as_ptr()));
-                        level += 1;
+                true
+            });
 
-                        let child_ptr = u64::from_fixed_size_bytes(
-                            &internal_node.read_child_ptr_buf(child_idx),
-                        );
-                        node = BTreeNode::from_ptr(child_ptr);
-                    }
-                    BTreeNode::Leaf(mut leaf_node) => {
-                        return match leaf_node.binary_search(key, leaf_node.read_len()) {
-                            Ok(idx) => {
-                                for (l, ptr) in modified_buf {
-                                    modified.push(l, ptr);
-                                }
+            self._buf = buf;
 
-                                modified.push(level, leaf_node.as_ptr());
+            let (mut leaf_node, _, res) = found?;
+            let idx = res.ok()?;
 
-                                Some(leaf_node.get_value_mut(idx))
-                            }
-                            _ => None,
-                        }
-                    }
-                }
+            for (level, ptr) in modified_buf.iter().enumerate() {
+                modified.push(level, *ptr);
             }
+
+            modified.push(modified_buf.len(), leaf_node.as_ptr());
+
+            return Some(leaf_node.get_value_mut(idx));
         }
 
         let (mut leaf_node, idx) = self.lookup(key, false)?;
@@ -520,6 +688,79 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         SBTreeMapIter::<K, V>::new(self)
     }
 
+    /// Returns an iterator over entries of this [SBTreeMap], starting right after the `cursor` key
+    ///
+    /// The first returned entry is the one with the smallest key, which is greater than `cursor`.
+    /// Cursors are returned by [SBTreeMapIter::cursor]. Since this is just a key, it can be sent to a
+    /// client and used to resume the iteration in a later call, even if the map was modified in between.
+    ///
+    /// Borrowed type is also accepted. The returned iterator can't be reversed.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    ///
+    /// for i in 0..100u64 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut iter = map.iter();
+    /// let first_page = iter.by_ref().take(10).count();
+    /// let cursor: u64 = *iter.cursor().unwrap();
+    ///
+    /// map.remove(&10);
+    ///
+    /// let second_page = map.iter_from_cursor(&cursor).take(10).map(|(k, _)| *k).collect::<Vec<_>>();
+    /// assert_eq!(second_page, (11..21).collect::<Vec<_>>());
+    /// ```
+    #[inline]
+    pub fn iter_from_cursor<Q>(&self, cursor: &Q) -> SBTreeMapForwardIter<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.iter_from(|k| k.borrow() <= cursor)
+    }
+
+    // `is_before` should be monotone: `true` for some prefix of keys (in ascending order) and `false`
+    // for the rest. The returned iterator starts from the first key of the rest.
+    pub(crate) fn iter_from<F>(&self, mut is_before: F) -> SBTreeMapForwardIter<'_, K, V>
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let mut node = match self.get_root() {
+            Some(root) => root,
+            None => return SBTreeMapForwardIter::new(self.iter()),
+        };
+
+        loop {
+            match node {
+                BTreeNode::Internal(internal_node) => {
+                    let len = internal_node.read_len();
+                    let child_idx = partition_point(len, |i| {
+                        is_before(&internal_node.read_key_as_reference(i))
+                    });
+
+                    let child_ptr =
+                        u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
+                    node = BTreeNode::from_ptr(child_ptr);
+                }
+                BTreeNode::Leaf(leaf_node) => {
+                    let len = leaf_node.read_len();
+                    let idx = partition_point(len, |i| is_before(&leaf_node.read_key_as_reference(i)));
+
+                    return SBTreeMapForwardIter::new(SBTreeMapIter::<K, V>::new_at(
+                        self, leaf_node, idx, len,
+                    ));
+                }
+            }
+        }
+    }
+
     /// Returns the length of this [SBTreeMap]
     #[inline]
     pub fn len(&self) -> u64 {
@@ -538,16 +779,113 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         let mut old = mem::replace(self, Self::new());
         self.stable_drop_flag = old.stable_drop_flag;
         self.certified = old.certified;
+        self.counted = old.counted;
+        self.node_cache = old.node_cache.take().map(|mut cache| {
+            cache.get_mut().clear();
+            cache
+        });
 
         unsafe { old.stable_drop() };
     }
 
+    /// Enables a heap-side cache of internal nodes of this [SBTreeMap], holding up to `capacity` nodes
+    ///
+    /// Each lookup walks the tree from the root to a leaf, reading keys of every internal node on its
+    /// way from stable memory. With the cache enabled, internal nodes are kept decoded in heap memory,
+    /// so a lookup only reads the leaf. Nodes are cached while there is free space, which means that
+    /// upper levels of the tree, visited by every lookup, get there first. Once a node is modified
+    /// (e.g. by a split, a merge or a steal), it is removed from the cache and gets cached again by the
+    /// next lookup passing through it. A cached node takes up to `15 * K::SIZE + 128` bytes of heap.
+    ///
+    /// The cache lives in heap memory only and is never persisted. A map read from stable memory
+    /// (e.g. after an upgrade) has it disabled - call this method again (for example, in
+    /// `post_upgrade`) and the cache will be filled lazily. Calling this method on a map with the
+    /// cache enabled resets the cache.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SBTreeMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SBTreeMap::new();
+    /// map.enable_node_cache(1024);
+    ///
+    /// for i in 0..1000u64 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// for i in 0..1000u64 {
+    ///     assert_eq!(*map.get(&i).unwrap(), i);
+    /// }
+    ///
+    /// assert!(map.node_cache_len() > 0);
+    /// ```
+    pub fn enable_node_cache(&mut self, capacity: usize) {
+        self.node_cache = Some(RefCell::new(NodeCache::new(capacity)));
+    }
+
+    /// Disables the cache of internal nodes, releasing the heap memory it occupies
+    ///
+    /// See [SBTreeMap::enable_node_cache].
+    #[inline]
+    pub fn disable_node_cache(&mut self) {
+        self.node_cache = None;
+    }
+
+    /// Returns the capacity of the node cache, or [None] if it is disabled
+    ///
+    /// See [SBTreeMap::enable_node_cache].
+    #[inline]
+    pub fn node_cache_capacity(&self) -> Option<usize> {
+        self.node_cache
+            .as_ref()
+            .map(|cache| cache.borrow().capacity())
+    }
+
+    /// Returns the number of internal nodes, which are currently cached
+    ///
+    /// See [SBTreeMap::enable_node_cache].
+    #[inline]
+    pub fn node_cache_len(&self) -> usize {
+        self.node_cache
+            .as_ref()
+            .map(|cache| cache.borrow().len())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn read_last_key_as_reference(&self) -> Option<K> {
+        let mut node = unsafe { self.root.as_ref()?.copy() };
+
+        loop {
+            match node {
+                BTreeNode::Internal(internal) => {
+                    let child_ptr_raw = internal.read_child_ptr_buf(internal.read_len());
+                    let child_ptr = u64::from_fixed_size_bytes(&child_ptr_raw);
+
+                    node = BTreeNode::<K, V>::from_ptr(child_ptr);
+                }
+                BTreeNode::Leaf(leaf) => {
+                    let len = leaf.read_len();
+                    if len == 0 {
+                        return None;
+                    }
+
+                    return Some(leaf.read_key_as_reference(len - 1));
+                }
+            }
+        }
+    }
+
     #[inline]
     fn clear_stack(&mut self, modified: &mut LeveledList) {
         match modified {
             LeveledList::None => {
                 self._stack.clear();
             }
+            // remaining ancestors keep their structure - their subtree counts are updated by
+            // `modify`, once it knows how many entries were added or removed
+            LeveledList::Some(_) if self.counted => {}
             LeveledList::Some(_) => {
                 while let Some((p, _, _)) = self._stack.pop() {
                     modified.push(self.current_depth(), p.as_ptr());
@@ -556,14 +894,17 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
         }
     }
 
+    // marks the parent (the top of the stack) as modified, after its children exchanged entries
     #[inline]
-    fn current_depth(&self) -> usize {
-        self._stack.len()
+    fn push_parent(&self, modified: &mut LeveledList) {
+        if let Some((parent, _, _)) = self.peek_stack() {
+            modified.push(self.current_depth() - 1, parent.as_ptr());
+        }
     }
 
     #[inline]
-    fn push_stack(&mut self, node: InternalBTreeNode<K>, len: usize, child_idx: usize) {
-        self._stack.push((node, len, child_idx));
+    fn current_depth(&self) -> usize {
+        self._stack.len()
     }
 
     #[inline]
@@ -584,46 +925,225 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
+    {
+        let mut buf = Vec::new();
+        let mut found_early = false;
+
+        let found = self.descend(key, &mut buf, |_, _, res| {
+            found_early = res.is_ok() && return_early;
+
+            !found_early
+        });
+
+        if found_early {
+            return unsafe { Some((LeafBTreeNode::from_ptr(0), 0)) };
+        }
+
+        let (leaf_node, _, res) = found?;
+
+        res.ok().map(|idx| (leaf_node, idx))
+    }
+
+    #[inline]
+    fn assert_counted(&self) {
+        assert!(self.counted, "This map doesn't maintain subtree counts");
+    }
+
+    // returns the number of keys, less than (or equal to, if `inclusive`) the provided one
+    fn count_before<Q>(&self, key: &Q, inclusive: bool) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.assert_counted();
+
+        let mut buf = Vec::new();
+        let mut count = 0;
+
+        let found = self.descend(key, &mut buf, |internal_node, _, res| {
+            let child_idx = match res {
+                Ok(idx) => idx + 1,
+                Err(idx) => idx,
+            };
+
+            if child_idx > 0 {
+                count += internal_node
+                    .read_child_counts(child_idx, self.counted)
+                    .iter()
+                    .sum::<u64>();
+            }
+
+            true
+        });
+
+        match found {
+            Some((_, _, Ok(idx))) if inclusive => count + idx as u64 + 1,
+            Some((_, _, Ok(idx))) | Some((_, _, Err(idx))) => count + idx as u64,
+            None => 0,
+        }
+    }
+
+    // walks from the root to the leaf, that may contain the key, loading each node with a single
+    // read and searching it in memory; returns the leaf, its length and the search result in it
+    //
+    // `on_internal` is called for each passed internal node with its length and the search result,
+    // the walk stops (returning None), once it returns false
+    fn descend<Q, F>(
+        &self,
+        key: &Q,
+        buf: &mut Vec<u8>,
+        mut on_internal: F,
+    ) -> Option<LeafSearchResult<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        F: FnMut(InternalBTreeNode<K>, usize, Result<usize, usize>) -> bool,
     {
         let mut node = self.get_root()?;
+        let mut loaded = false;
+
         loop {
             match node {
                 BTreeNode::Internal(internal_node) => {
-                    let child_idx = match internal_node.binary_search(key, internal_node.read_len())
-                    {
-                        Ok(idx) => {
-                            if return_early {
-                                return unsafe { Some((LeafBTreeNode::from_ptr(0), 0)) };
-                            } else {
-                                idx + 1
-                            }
-                        }
-                        Err(idx) => idx,
-                    };
+                    let ((len, res, child), child_loaded) =
+                        self.search_internal(&internal_node, key, loaded, buf);
 
-                    let child_ptr =
-                        u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(child_idx));
-                    node = BTreeNode::from_ptr(child_ptr);
+                    if !on_internal(internal_node, len, res) {
+                        return None;
+                    }
+
+                    node = child;
+                    loaded = child_loaded;
                 }
                 BTreeNode::Leaf(leaf_node) => {
-                    return match leaf_node.binary_search(key, leaf_node.read_len()) {
-                        Ok(idx) => Some((leaf_node, idx)),
-                        _ => None,
+                    if !loaded {
+                        leaf_node.load_to_buf(buf);
                     }
+
+                    let len = LeafBTreeNode::<K, V>::len_from_buf(buf);
+                    let res = LeafBTreeNode::<K, V>::binary_search_in_buf(buf, key, len);
+
+                    return Some((leaf_node, len, res));
                 }
             }
         }
     }
 
+    // searches the key in the internal node, using the node cache, if it is enabled; unless the
+    // node is already `loaded` into the buffer, loads it first
+    //
+    // returns true, if the returned child is loaded into the buffer
+    fn search_internal<Q>(
+        &self,
+        node: &InternalBTreeNode<K>,
+        key: &Q,
+        loaded: bool,
+        buf: &mut Vec<u8>,
+    ) -> (InternalSearchResult<K, V>, bool)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut to_cache = None;
+
+        if let Some(cache) = &self.node_cache {
+            let cache = cache.borrow();
+
+            if let Some(found) = cache.search(node, key) {
+                return (found, false);
+            }
+
+            if !cache.is_full() {
+                if !loaded {
+                    node.load_to_buf(buf);
+                }
+
+                to_cache = Some(CachedNode::from_buf::<K>(buf));
+            }
+        }
+
+        if !loaded && to_cache.is_none() {
+            node.load_to_buf(buf);
+        }
+
+        let len = InternalBTreeNode::<K>::len_from_buf(buf);
+        let res = InternalBTreeNode::<K>::binary_search_in_buf(buf, key, len);
+        let child_idx = match res {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        };
+
+        let child_ptr = InternalBTreeNode::<K>::child_ptr_from_buf(buf, child_idx);
+        let child = BTreeNode::load(child_ptr, self.certified, buf);
+
+        if let (Some(cached), Some(cache)) = (to_cache, &self.node_cache) {
+            // all children of a node are of the same type
+            let leaf_children = matches!(child, BTreeNode::Leaf(_));
+
+            cache
+                .borrow_mut()
+                .insert(node.as_ptr(), cached, leaf_children);
+        }
+
+        ((len, res, child), true)
+    }
+
+    // runs the modification, updating subtree counts of modified internal nodes, if this map is
+    // counted
+    fn modify<T, F: FnOnce(&mut Self, &mut LeveledList) -> T>(&mut self, f: F) -> T {
+        if !self.counted {
+            return self.write_through(|it| f(it, &mut LeveledList::None));
+        }
+
+        let len = self.len;
+        let mut modified = LeveledList::new();
+        let result = self.write_through(|it| f(it, &mut modified));
+
+        // ancestors, left on the stack, only gain or lose the added or removed entries
+        let delta = self.len.wrapping_sub(len);
+        while let Some((mut node, _, child_idx)) = self._stack.pop() {
+            if delta != 0 {
+                node.add_to_child_count(child_idx, delta, self.counted);
+            }
+        }
+
+        // restructured nodes are recounted completely, the deepest nodes go first, so children are
+        // always recounted before their parents
+        while let Some(ptr) = modified.pop() {
+            if let BTreeNode::Internal(mut node) = BTreeNode::<K, V>::from_ptr(ptr) {
+                node.recount::<V>(self.counted);
+            }
+        }
+
+        result
+    }
+
+    // runs the modification, removing all modified internal nodes from the node cache
+    #[inline]
+    fn write_through<T, F: FnOnce(&mut Self) -> T>(&mut self, f: F) -> T {
+        if self.node_cache.is_none() {
+            return f(self);
+        }
+
+        let (result, written) = track_writes(|| f(self));
+
+        if let Some(cache) = &mut self.node_cache {
+            cache.get_mut().invalidate(&written);
+        }
+
+        result
+    }
+
     fn insert_leaf(
         &mut self,
         leaf_node: &mut LeafBTreeNode<K, V>,
+        leaf_node_len: usize,
+        search_res: Result<usize, usize>,
         mut key: K,
         mut value: V,
         modified: &mut LeveledList,
-    ) -> Result<Result<V, Option<LeafBTreeNode<K, V>>>, (K, V)> {
-        let leaf_node_len = leaf_node.read_len();
-        let insert_idx = match leaf_node.binary_search(&key, leaf_node_len) {
+    ) -> Result<Result<V, Option<LeafBTreeNode<K, V>>>, Rejected<(K, V)>> {
+        let insert_idx = match search_res {
             Ok(existing_idx) => {
                 // if there is already a key like that, return early
                 let prev_value: V = leaf_node.read_and_disown_value(existing_idx);
@@ -664,12 +1184,15 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
         // cheking if it is possible to allocate worst-case scenario amount of memory
         let memory_to_allocate = (self._stack.len() + 1) as u64
-            * FreeBlock::to_total_size(InternalBTreeNode::<K>::calc_byte_size(self.certified))
+            * FreeBlock::to_total_size(InternalBTreeNode::<K>::calc_byte_size(
+                self.certified,
+                self.counted,
+            ))
             + FreeBlock::to_total_size(LeafBTreeNode::<K, V>::calc_size_bytes(self.certified));
 
         // we can unwrap all OutOfMemory errors if this check passes, without any consequences
-        if !make_sure_can_allocate(memory_to_allocate) {
-            return Err((key, value));
+        if let Err(e) = reserve(memory_to_allocate) {
+            return Err(Rejected::new((key, value), e));
         }
 
         unsafe { key.stable_drop_flag_off() };
@@ -729,7 +1252,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
         // TODO: possible to optimize when idx == MIN_LEN_AFTER_SPLIT
         let (mut right, mid) = internal_node
-            .split_max_len(&mut self._buf, self.certified)
+            .split_max_len(&mut self._buf, self.certified, self.counted)
             .unwrap();
 
         if idx <= MIN_LEN_AFTER_SPLIT {
@@ -787,6 +1310,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 modified.push(self.current_depth(), leaf_node.as_ptr());
                 modified.push(self.current_depth(), left_sibling.as_ptr());
 
+                self.push_parent(modified);
+
                 return true;
             }
         }
@@ -811,6 +1336,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 modified.push(self.current_depth(), leaf_node.as_ptr());
                 modified.push(self.current_depth(), right_sibling.as_ptr());
 
+                self.push_parent(modified);
+
                 return true;
             }
         }
@@ -904,6 +1431,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 modified.push(self.current_depth(), internal_node.as_ptr());
                 modified.push(self.current_depth(), left_sibling.as_ptr());
 
+                self.push_parent(modified);
+
                 return true;
             }
         }
@@ -928,6 +1457,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 modified.push(self.current_depth(), internal_node.as_ptr());
                 modified.push(self.current_depth(), right_sibling.as_ptr());
 
+                self.push_parent(modified);
+
                 return true;
             }
         }
@@ -1020,6 +1551,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
                 modified.push(self.current_depth(), leaf.as_ptr());
                 modified.push(self.current_depth(), left_sibling.as_ptr());
+
+                self.push_parent(modified);
                 self.clear_stack(modified);
 
                 return Some(v);
@@ -1049,6 +1582,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
                     modified.push(self.current_depth(), leaf.as_ptr());
                     modified.push(self.current_depth(), right_sibling.as_ptr());
+
+                    self.push_parent(modified);
                     self.clear_stack(modified);
 
                     return Some(v);
@@ -1090,6 +1625,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
 
                 modified.push(self.current_depth(), leaf.as_ptr());
                 modified.push(self.current_depth(), right_sibling.as_ptr());
+
+                self.push_parent(modified);
                 self.clear_stack(modified);
 
                 return Some(v);
@@ -1269,6 +1806,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 if left_sibling_len > MIN_LEN_AFTER_SPLIT {
                     modified.push(self.current_depth(), node.as_ptr());
                     modified.push(self.current_depth(), left_sibling.as_ptr());
+                    self.push_parent(modified);
 
                     self.steal_from_left_sibling_internal(
                         node,
@@ -1295,6 +1833,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                     if right_sibling_len > MIN_LEN_AFTER_SPLIT {
                         modified.push(self.current_depth(), node.as_ptr());
                         modified.push(self.current_depth(), right_sibling.as_ptr());
+                        self.push_parent(modified);
 
                         self.steal_from_right_sibling_internal(
                             node,
@@ -1355,6 +1894,7 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
                 if right_sibling_len > MIN_LEN_AFTER_SPLIT {
                     modified.push(self.current_depth(), node.as_ptr());
                     modified.push(self.current_depth(), right_sibling.as_ptr());
+                    self.push_parent(modified);
 
                     self.steal_from_right_sibling_internal(
                         node,
@@ -1563,6 +2103,63 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> S
             new_nodes = Vec::new();
         }
     }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        let mut nodes = match &self.root {
+            Some(root) => vec![unsafe { root.copy() }],
+            None => return,
+        };
+
+        while let Some(node) = nodes.pop() {
+            visitor(unsafe { SSlice::from_ptr(node.as_ptr()).unwrap() });
+
+            match node {
+                BTreeNode::Internal(internal) => {
+                    for j in 0..(internal.read_len() + 1) {
+                        let child_ptr_raw = internal.read_child_ptr_buf(j);
+                        let child_ptr = u64::from_fixed_size_bytes(&child_ptr_raw);
+
+                        nodes.push(BTreeNode::<K, V>::from_ptr(child_ptr));
+                    }
+                }
+                BTreeNode::Leaf(leaf) => {
+                    for j in 0..leaf.read_len() {
+                        leaf.get_key(j).visit_allocations(visitor);
+                        leaf.get_value(j).visit_allocations(visitor);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> IncrementalDrop
+    for SBTreeMap<K, V>
+{
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        while has_budget(start, instruction_budget) {
+            match self.read_last_key_as_reference() {
+                Some(key) => {
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+
+        if self.is_empty() {
+            // releases the empty root node
+            self.clear();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len()
+    }
 }
 
 impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Drop
@@ -1648,8 +2245,8 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> D
 impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> AsFixedSizeBytes
     for SBTreeMap<K, V>
 {
-    const SIZE: usize = u64::SIZE * 2;
-    type Buf = [u8; u64::SIZE * 2];
+    const SIZE: usize = SBTREE_MAP_SIZE;
+    type Buf = [u8; SBTREE_MAP_SIZE];
 
     fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
         let ptr = if let Some(root) = &self.root {
@@ -1661,11 +2258,14 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> A
         ptr.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
         self.len
             .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE * 2)]);
+        self.counted
+            .as_fixed_size_bytes(&mut buf[(u64::SIZE * 2)..SBTREE_MAP_SIZE]);
     }
 
     fn from_fixed_size_bytes(buf: &[u8]) -> Self {
         let ptr = u64::from_fixed_size_bytes(&buf[0..u64::SIZE]);
         let len = u64::from_fixed_size_bytes(&buf[u64::SIZE..(u64::SIZE * 2)]);
+        let counted = bool::from_fixed_size_bytes(&buf[(u64::SIZE * 2)..SBTREE_MAP_SIZE]);
 
         Self {
             root: if ptr == EMPTY_PTR {
@@ -1674,10 +2274,12 @@ impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> A
                 Some(BTreeNode::from_ptr(ptr))
             },
             certified: false,
+            counted,
             len,
             stable_drop_flag: false,
             _buf: Vec::default(),
             _stack: Vec::default(),
+            node_cache: None,
         }
     }
 }
@@ -1812,6 +2414,48 @@ impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSize
     }
 }
 
+// binary search over `len` keys, stored one after another in the buffer
+pub(crate) fn binary_search_in_keys<K, Q>(keys: &[u8], key: &Q, len: usize) -> Result<usize, usize>
+where
+    K: StableType + AsFixedSizeBytes + Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    let mut min = 0;
+    let mut max = len;
+
+    while min < max {
+        let mid = (min + max) / 2;
+
+        let mut k = K::from_fixed_size_bytes(&keys[(mid * K::SIZE)..((mid + 1) * K::SIZE)]);
+        unsafe { k.stable_drop_flag_off() };
+
+        match k.borrow().cmp(key) {
+            Ordering::Equal => return Ok(mid),
+            Ordering::Greater => max = mid,
+            Ordering::Less => min = mid + 1,
+        }
+    }
+
+    Err(min)
+}
+
+fn partition_point<F: FnMut(usize) -> bool>(len: usize, mut pred: F) -> usize {
+    let mut min = 0;
+    let mut max = len;
+
+    while min < max {
+        let mid = (max - min) / 2 + min;
+
+        if pred(mid) {
+            min = mid + 1;
+        } else {
+            max = mid;
+        }
+    }
+
+    min
+}
+
 pub(crate) trait IBTreeNode {
     unsafe fn from_ptr(ptr: StablePtr) -> Self;
     fn as_ptr(&self) -> StablePtr;
@@ -1852,9 +2496,45 @@ impl<K, V> BTreeNode<K, V> {
     }
 }
 
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> BTreeNode<K, V> {
+    // loads the node into the buffer with a single read, the same way InternalBTreeNode::load_to_buf
+    // or LeafBTreeNode::load_to_buf would do
+    pub(crate) fn load(ptr: StablePtr, certified: bool, buf: &mut Vec<u8>) -> Self {
+        // the type is unknown yet, so no more than the smallest of two nodes can be read
+        let size = usize::min(
+            InternalBTreeNode::<K>::loaded_size(),
+            LeafBTreeNode::<K, V>::calc_size_bytes(certified) as usize,
+        );
+
+        buf.resize(size, 0);
+        unsafe { crate::mem::read_bytes(SSlice::_offset(ptr, NODE_TYPE_OFFSET), buf) };
+
+        unsafe {
+            match buf[NODE_TYPE_OFFSET as usize] {
+                NODE_TYPE_INTERNAL => {
+                    let node = InternalBTreeNode::<K>::from_ptr(ptr);
+
+                    // only happens for very small values
+                    if size < InternalBTreeNode::<K>::loaded_size() {
+                        node.load_to_buf(buf);
+                    }
+
+                    Self::Internal(node)
+                }
+                NODE_TYPE_LEAF => Self::Leaf(LeafBTreeNode::<K, V>::from_ptr(ptr)),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::collections::btree_map::SBTreeMap;
+    use crate::collections::btree_map::internal_node::InternalBTreeNode;
+    use crate::collections::btree_map::{BTreeNode, IBTreeNode, SBTreeMap};
+    use crate::collections::btree_set::SBTreeSet;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::StableType;
     use crate::utils::test::generate_random_string;
     use crate::{
         _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
@@ -1865,6 +2545,315 @@ mod tests {
     use rand::seq::SliceRandom;
     use rand::{thread_rng, Rng};
     use std::collections::BTreeMap;
+    use std::ops::Bound::{Excluded, Unbounded};
+
+    fn validate_node_cache<
+        K: StableType + AsFixedSizeBytes + Ord,
+        V: StableType + AsFixedSizeBytes,
+    >(
+        map: &SBTreeMap<K, V>,
+    ) {
+        let mut reachable = Vec::new();
+        let mut level = map.get_root().into_iter().collect::<Vec<_>>();
+
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+
+            for node in level {
+                if let BTreeNode::Internal(internal) = node {
+                    reachable.push(internal.as_ptr());
+
+                    for i in 0..=internal.read_len() {
+                        let child_ptr = u64::from_fixed_size_bytes(&internal.read_child_ptr_buf(i));
+                        next_level.push(BTreeNode::<K, V>::from_ptr(child_ptr));
+                    }
+                }
+            }
+
+            level = next_level;
+        }
+
+        if let Some(cache) = &map.node_cache {
+            cache.borrow().debug_validate(&reachable);
+        }
+    }
+
+    #[test]
+    fn node_cache_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            for capacity in [0, 4, 16, 10_000] {
+                let mut map = SBTreeMap::<u64, u64>::new();
+                let mut example = BTreeMap::new();
+                let mut rng = thread_rng();
+
+                map.enable_node_cache(capacity);
+                assert_eq!(map.node_cache_capacity(), Some(capacity));
+
+                for _ in 0..5_000 {
+                    let key = rng.gen_range(0..1_000u64);
+
+                    match rng.gen_range(0..3) {
+                        0 => assert_eq!(
+                            map.insert(key, key * 2).unwrap(),
+                            example.insert(key, key * 2)
+                        ),
+                        1 => assert_eq!(map.remove(&key), example.remove(&key)),
+                        _ => assert_eq!(map.get(&key).map(|it| *it), example.get(&key).copied()),
+                    }
+
+                    assert_eq!(map.contains_key(&key), example.contains_key(&key));
+                    assert!(map.node_cache_len() <= capacity);
+                    assert!(map._stack.is_empty());
+                    validate_node_cache(&map);
+                }
+
+                assert_eq!(map.len(), example.len() as u64);
+                for (k, v) in example.iter() {
+                    assert_eq!(*map.get(k).unwrap(), *v);
+                }
+
+                map.clear();
+                assert_eq!(map.node_cache_capacity(), Some(capacity));
+                assert_eq!(map.node_cache_len(), 0);
+            }
+
+            let mut map = SBTreeMap::<u64, u64>::new();
+            for i in 0..10_000 {
+                map.insert(i, i).unwrap();
+            }
+
+            let start = stable::instruction_counter();
+            for i in 0..10_000 {
+                assert_eq!(*map.get(&i).unwrap(), i);
+            }
+            let uncached = stable::instruction_counter() - start;
+
+            map.enable_node_cache(10_000);
+            for i in 0..10_000 {
+                assert_eq!(*map.get(&i).unwrap(), i);
+            }
+
+            let start = stable::instruction_counter();
+            for i in 0..10_000 {
+                assert_eq!(*map.get(&i).unwrap(), i);
+            }
+            let cached = stable::instruction_counter() - start;
+
+            assert!(cached < uncached / 2);
+
+            // the cache is never persisted, but can be enabled again
+            let mut buf =
+                <SBTreeMap<u64, u64> as AsFixedSizeBytes>::Buf::new(SBTreeMap::<u64, u64>::SIZE);
+            map.as_fixed_size_bytes(&mut buf);
+            let mut restored = SBTreeMap::<u64, u64>::from_fixed_size_bytes(&buf);
+            assert_eq!(restored.node_cache_capacity(), None);
+            assert_eq!(restored.len(), 10_000);
+
+            restored.enable_node_cache(10_000);
+            assert_eq!(*restored.get(&10).unwrap(), 10);
+            assert!(restored.node_cache_len() > 0);
+
+            map.disable_node_cache();
+            assert_eq!(map.node_cache_len(), 0);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    fn validate_counts(node: &BTreeNode<u64, u64>) -> u64 {
+        match node {
+            BTreeNode::Internal(internal_node) => {
+                let len = internal_node.read_len();
+                let counts = internal_node.read_child_counts(len + 1, true);
+
+                for (idx, count) in counts.iter().enumerate() {
+                    let child_ptr =
+                        u64::from_fixed_size_bytes(&internal_node.read_child_ptr_buf(idx));
+                    assert_eq!(validate_counts(&BTreeNode::from_ptr(child_ptr)), *count);
+                }
+
+                counts.iter().sum()
+            }
+            BTreeNode::Leaf(leaf_node) => leaf_node.read_len() as u64,
+        }
+    }
+
+    #[test]
+    fn counts_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new_with_counts();
+            let mut example = BTreeMap::new();
+            let mut rng = thread_rng();
+
+            assert!(map.nth(0).is_none());
+            assert!(map.random_entry(10).is_none());
+            assert_eq!(map.rank(&10), 0);
+            assert_eq!(map.count_range(..), 0);
+
+            for i in 0..10_000 {
+                let key = rng.gen_range(0..3_000u64);
+
+                if rng.gen_bool(0.6) {
+                    assert_eq!(
+                        map.insert(key, key * 2).unwrap(),
+                        example.insert(key, key * 2)
+                    );
+                } else {
+                    assert_eq!(map.remove(&key), example.remove(&key));
+                }
+
+                if i % 100 == 0 {
+                    if let Some(root) = map.get_root() {
+                        assert_eq!(validate_counts(&root), map.len());
+                    }
+
+                    for (idx, (k, v)) in example.iter().enumerate() {
+                        let (k1, v1) = map.nth(idx as u64).unwrap();
+                        assert_eq!((*k1, *v1), (*k, *v));
+                    }
+                    assert!(map.nth(map.len()).is_none());
+                }
+
+                let from = rng.gen_range(0..3_000u64);
+                let to = rng.gen_range(from..3_000u64);
+
+                assert_eq!(map.rank(&from), example.range(..from).count() as u64);
+                assert_eq!(
+                    map.count_range(from..to),
+                    example.range(from..to).count() as u64
+                );
+                assert_eq!(
+                    map.count_range(from..=to),
+                    example.range(from..=to).count() as u64
+                );
+                assert_eq!(
+                    map.count_range((Excluded(from), Unbounded)),
+                    example.range((Excluded(from), Unbounded)).count() as u64
+                );
+                assert_eq!(map.count_range(to..from), 0);
+
+                let seed = rng.gen::<u64>();
+                if let Some((k, v)) = map.random_entry(seed) {
+                    assert_eq!(example.get(&k), Some(&*v));
+                }
+            }
+
+            // the setting survives serialization
+            let mut buf =
+                <SBTreeMap<u64, u64> as AsFixedSizeBytes>::Buf::new(SBTreeMap::<u64, u64>::SIZE);
+            map.as_fixed_size_bytes(&mut buf);
+            let mut restored = SBTreeMap::<u64, u64>::from_fixed_size_bytes(&buf);
+            unsafe { restored.stable_drop_flag_off() };
+            assert_eq!(restored.len(), map.len());
+            assert_eq!(restored.count_range(..), map.len());
+
+            // the setting survives clearing
+            map.clear();
+            assert!(map.nth(0).is_none());
+            assert_eq!(map.rank(&10), 0);
+            for i in 0..100u64 {
+                map.insert(i, i).unwrap();
+            }
+            assert_eq!(*map.nth(50).unwrap().0, 50);
+            assert_eq!(map.rank(&10), 10);
+
+            let mut set = SBTreeSet::<u64>::new_with_counts();
+            set.insert(1).unwrap();
+            set.clear();
+            set.insert(2).unwrap();
+            assert_eq!(*set.nth(0).unwrap(), 2);
+            assert_eq!(set.rank(&3), 1);
+
+            // each entry is equally likely to be picked
+            let mut map = SBTreeMap::<u64, ()>::new_with_counts();
+            for i in 0..1_000 {
+                map.insert(i, ()).unwrap();
+            }
+            let mut hits = vec![0u32; 1_000];
+            for seed in 0..100_000 {
+                hits[*map.random_entry(seed).unwrap().0 as usize] += 1;
+            }
+            assert!(hits.iter().all(|it| *it == 100));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn nth_without_counts_should_panic() {
+        stable::clear();
+        stable_memory_init();
+
+        let map = SBTreeMap::<u64, u64>::new();
+        map.nth(0);
+    }
+
+    #[test]
+    fn nodes_are_loaded_with_single_read() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+            for i in 0..10_000 {
+                map.insert(i, i).unwrap();
+            }
+
+            let mut depth = 0;
+            let mut node = map.get_root().unwrap();
+            while let BTreeNode::Internal(internal_node) = node {
+                depth += 1;
+                node = BTreeNode::from_ptr(u64::from_fixed_size_bytes(
+                    &internal_node.read_child_ptr_buf(0),
+                ));
+            }
+            assert!(depth > 1);
+
+            // each stable memory access costs 100 instructions plus the number of accessed bytes
+            let node_load = 100 + InternalBTreeNode::<u64>::loaded_size() as u64;
+            let value_read = 100 + u64::SIZE as u64;
+
+            for i in 0..10_000 {
+                let start = stable::instruction_counter();
+                assert_eq!(*map.get(&i).unwrap(), i);
+                let spent = stable::instruction_counter() - start;
+
+                assert!(spent <= (depth + 1) * node_load + value_read);
+
+                let start = stable::instruction_counter();
+                assert!(map.contains_key(&i));
+                let spent = stable::instruction_counter() - start;
+
+                assert!(spent <= (depth + 1) * node_load);
+            }
+
+            // cached internal nodes are not loaded at all
+            map.enable_node_cache(10_000);
+            for i in 0..10_000 {
+                map.get(&i);
+            }
+
+            for i in 0..10_000 {
+                let start = stable::instruction_counter();
+                assert_eq!(*map.get(&i).unwrap(), i);
+                let spent = stable::instruction_counter() - start;
+
+                assert!(spent <= node_load + value_read);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
 
     #[test]
     fn random_works_fine() {
@@ -1984,6 +2973,125 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn missing_key_leaves_stack_empty() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+            for i in 0..1_000 {
+                map.insert(i * 2, i * 2).unwrap();
+            }
+
+            assert_eq!(map.remove(&1), None);
+            assert!(map._stack.is_empty());
+
+            // stale ancestors of the missing key would be merged by the next removal
+            for i in 0..1_000 {
+                assert_eq!(map.remove(&(i * 2)), Some(i * 2));
+                assert_eq!(map.remove(&(i * 2 + 1)), None);
+            }
+
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn out_of_memory_leaves_stack_empty() {
+        stable::clear();
+        init_allocator(1);
+
+        {
+            let mut map = SBTreeMap::<u64, u64>::new();
+
+            let mut len = 0;
+            while map.insert(len, len).is_ok() {
+                len += 1;
+            }
+
+            assert!(map._stack.is_empty());
+
+            for i in 0..len {
+                assert_eq!(map.remove(&i), Some(i));
+            }
+
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn cursors_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<SBox<String>, u64>::new();
+            let mut example = BTreeMap::new();
+
+            for i in 0..500u64 {
+                let key = format!("{:05}", i * 2);
+
+                map.insert(SBox::new(key.clone()).unwrap(), i).unwrap();
+                example.insert(key, i);
+            }
+
+            assert!(map.iter().cursor().is_none());
+
+            // only forward iteration moves the cursor
+            let mut iter = map.iter();
+            iter.next_back();
+            assert!(iter.cursor().is_none());
+
+            let mut iter = map.iter();
+            iter.next();
+            iter.next();
+            iter.next_back();
+            assert_eq!(iter.cursor().unwrap().as_str(), "00002");
+
+            let mut iter = map.iter();
+            let mut result = iter.by_ref().take(13).map(|(_, v)| *v).collect::<Vec<_>>();
+            let mut cursor = iter.cursor().map(|it| (**it).clone()).unwrap();
+
+            loop {
+                let mut iter = map.iter_from_cursor(&cursor);
+
+                let page = iter.by_ref().take(13).map(|(_, v)| *v).collect::<Vec<_>>();
+                if page.is_empty() {
+                    break;
+                }
+
+                result.extend(page);
+                cursor = iter.cursor().map(|it| (**it).clone()).unwrap();
+            }
+
+            assert_eq!(result, example.values().copied().collect::<Vec<_>>());
+
+            // cursor keys don't have to be present in the map
+            for i in 0..1001u64 {
+                let key = format!("{:05}", i);
+
+                let expected = example
+                    .range::<String, _>((Excluded(&key), Unbounded))
+                    .next()
+                    .map(|(_, v)| *v);
+
+                let actual = map.iter_from_cursor(&key).next().map(|(_, v)| *v);
+
+                assert_eq!(actual, expected);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[derive(Debug)]
     enum Action {
         Insert,
@@ -2081,8 +3189,8 @@ mod tests {
 
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(map) => {
-                        self.map = Some(map);
+                    Err(e) => {
+                        self.map = Some(e.into_inner());
                     }
                 },
             }
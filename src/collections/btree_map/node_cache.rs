@@ -0,0 +1,194 @@
+use crate::collections::btree_map::internal_node::InternalBTreeNode;
+use crate::collections::btree_map::leaf_node::LeafBTreeNode;
+use crate::collections::btree_map::{binary_search_in_keys, BTreeNode, IBTreeNode};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+thread_local! {
+    static WRITTEN_NODES: RefCell<Option<Vec<StablePtr>>> = const { RefCell::new(None) };
+}
+
+// The length of an internal node, the result of the key search in it and the child to descend into
+pub(crate) type InternalSearchResult<K, V> = (usize, Result<usize, usize>, BTreeNode<K, V>);
+
+// Called by InternalBTreeNode each time it is modified or destroyed
+#[inline]
+pub(crate) fn on_internal_node_write(ptr: StablePtr) {
+    WRITTEN_NODES.with(|it| {
+        if let Some(written) = it.borrow_mut().as_mut() {
+            written.push(ptr);
+        }
+    });
+}
+
+// Runs `f`, returning pointers of all internal nodes, modified or destroyed by it
+pub(crate) fn track_writes<T, F: FnOnce() -> T>(f: F) -> (T, Vec<StablePtr>) {
+    let prev = WRITTEN_NODES.with(|it| it.borrow_mut().replace(Vec::new()));
+
+    let result = f();
+
+    let written = WRITTEN_NODES.with(|it| {
+        let mut it = it.borrow_mut();
+        let written = it.take().unwrap_or_default();
+
+        // outer calls should see these writes too
+        if let Some(mut prev) = prev {
+            prev.extend_from_slice(&written);
+            *it = Some(prev);
+        }
+
+        written
+    });
+
+    (result, written)
+}
+
+// A copy of an internal node, stored on the heap
+pub(crate) struct CachedNode {
+    len: usize,
+    keys: Vec<u8>,
+    children: Vec<u8>,
+    leaf_children: bool,
+}
+
+impl CachedNode {
+    // copies the node, loaded by InternalBTreeNode::load_to_buf
+    pub(crate) fn from_buf<K: StableType + AsFixedSizeBytes + Ord>(buf: &[u8]) -> Self {
+        let len = InternalBTreeNode::<K>::len_from_buf(buf);
+
+        Self {
+            len,
+            keys: InternalBTreeNode::<K>::keys_from_buf(buf, len).to_vec(),
+            children: InternalBTreeNode::<K>::children_from_buf(buf, len).to_vec(),
+            leaf_children: false,
+        }
+    }
+
+    fn search<K, V, Q>(&self, key: &Q) -> InternalSearchResult<K, V>
+    where
+        K: StableType + AsFixedSizeBytes + Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let result = binary_search_in_keys::<K, Q>(&self.keys, key, self.len);
+
+        let child_idx = match result {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        };
+
+        let child_ptr = u64::from_fixed_size_bytes(
+            &self.children[(child_idx * u64::SIZE)..((child_idx + 1) * u64::SIZE)],
+        );
+
+        let child = unsafe {
+            if self.leaf_children {
+                BTreeNode::Leaf(LeafBTreeNode::from_ptr(child_ptr))
+            } else {
+                BTreeNode::Internal(InternalBTreeNode::from_ptr(child_ptr))
+            }
+        };
+
+        (self.len, result, child)
+    }
+}
+
+// A bounded heap-side cache of internal nodes of a single SBTreeMap.
+//
+// Nodes are cached, while there is free space - since each lookup starts from the root, the upper
+// levels of the tree get cached first. A node is removed from the cache once it is modified or
+// destroyed, and gets cached again by the next lookup passing through it.
+pub(crate) struct NodeCache<K> {
+    capacity: usize,
+    nodes: HashMap<StablePtr, CachedNode>,
+    _marker: PhantomData<K>,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord> NodeCache<K> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            nodes: HashMap::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    // returns None, if the node is not cached
+    pub(crate) fn search<V, Q>(
+        &self,
+        node: &InternalBTreeNode<K>,
+        key: &Q,
+    ) -> Option<InternalSearchResult<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.nodes
+            .get(&node.as_ptr())
+            .map(|cached| cached.search(key))
+    }
+
+    #[inline]
+    pub(crate) fn is_full(&self) -> bool {
+        self.nodes.len() >= self.capacity
+    }
+
+    pub(crate) fn insert(&mut self, ptr: StablePtr, mut node: CachedNode, leaf_children: bool) {
+        debug_assert!(!self.is_full());
+
+        node.leaf_children = leaf_children;
+        self.nodes.insert(ptr, node);
+    }
+
+    pub(crate) fn invalidate(&mut self, written: &[StablePtr]) {
+        for ptr in written {
+            self.nodes.remove(ptr);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn debug_validate(&self, reachable: &[StablePtr]) {
+        for (ptr, cached) in &self.nodes {
+            assert!(
+                reachable.contains(ptr),
+                "cached node {} is not in the tree",
+                ptr
+            );
+
+            let mut buf = Vec::new();
+            unsafe { InternalBTreeNode::<K>::from_ptr(*ptr) }.load_to_buf(&mut buf);
+
+            let actual = CachedNode::from_buf::<K>(&buf);
+            assert_eq!(cached.len, actual.len);
+            assert_eq!(cached.keys, actual.keys);
+            assert_eq!(cached.children, actual.children);
+
+            // all nodes of the same level are of the same type
+            let first_child = InternalBTreeNode::<K>::child_ptr_from_buf(&buf, 0);
+            let leaf_children = matches!(
+                BTreeNode::<K, ()>::from_ptr(first_child),
+                BTreeNode::Leaf(_)
+            );
+            assert_eq!(cached.leaf_children, leaf_children);
+        }
+    }
+}
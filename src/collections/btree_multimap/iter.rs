@@ -0,0 +1,88 @@
+use crate::collections::btree_map::iter::{SBTreeMapForwardIter, SBTreeMapIter};
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use std::borrow::Borrow;
+
+pub struct SBTreeMultiMapIter<'a, K, V> {
+    inner: SBTreeMapIter<'a, (K, V), ()>,
+}
+
+impl<'a, K, V> SBTreeMultiMapIter<'a, K, V> {
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapIter<'a, (K, V), ()>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, K, V> Iterator for SBTreeMultiMapIter<'a, K, V>
+where
+    K: StableType + AsFixedSizeBytes + Ord,
+    V: StableType + AsFixedSizeBytes + Ord,
+{
+    type Item = (SRef<'a, K>, SRef<'a, V>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(entry, _)| split_entry(entry))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for SBTreeMultiMapIter<'a, K, V>
+where
+    K: StableType + AsFixedSizeBytes + Ord,
+    V: StableType + AsFixedSizeBytes + Ord,
+{
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(entry, _)| split_entry(entry))
+    }
+}
+
+pub struct SBTreeMultiMapValuesIter<'a, K, V, Q: ?Sized> {
+    inner: SBTreeMapForwardIter<'a, (K, V), ()>,
+    key: &'a Q,
+    finished: bool,
+}
+
+impl<'a, K, V, Q: ?Sized> SBTreeMultiMapValuesIter<'a, K, V, Q> {
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapForwardIter<'a, (K, V), ()>, key: &'a Q) -> Self {
+        Self {
+            inner,
+            key,
+            finished: false,
+        }
+    }
+}
+
+impl<'a, K, V, Q> Iterator for SBTreeMultiMapValuesIter<'a, K, V, Q>
+where
+    K: StableType + AsFixedSizeBytes + Ord + Borrow<Q>,
+    V: StableType + AsFixedSizeBytes + Ord,
+    Q: Ord + ?Sized,
+{
+    type Item = SRef<'a, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let (entry, _) = self.inner.next()?;
+
+        if entry.0.borrow() == self.key {
+            Some(split_entry(entry).1)
+        } else {
+            self.finished = true;
+
+            None
+        }
+    }
+}
+
+// entries are stored as (K, V) tuples - the key goes first, then goes the value
+#[inline]
+fn split_entry<'a, K: AsFixedSizeBytes, V>(entry: SRef<'a, (K, V)>) -> (SRef<'a, K>, SRef<'a, V>) {
+    unsafe { (entry.project(0), entry.project(K::SIZE)) }
+}
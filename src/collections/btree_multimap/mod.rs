@@ -0,0 +1,432 @@
+use crate::collections::btree_map::SBTreeMap;
+use crate::collections::btree_multimap::iter::{SBTreeMultiMapIter, SBTreeMultiMapValuesIter};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::primitive::{lookup_copy, StableType};
+use crate::Rejected;
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+
+pub mod iter;
+
+/// B-plus tree based multimap data structure - each key can have many values
+///
+/// Internally is an [SBTreeMap]`<(K, V), ()>`, read its documentation for more info on the
+/// internals. Entries are stored in ascending order of their keys, values of the same key are stored
+/// in ascending order too. Each key-value pair is stored only once.
+///
+/// Both `K` and `V` have to implement [StableType], [AsFixedSizeBytes] and [Ord]. [SBTreeMultiMap]
+/// also implements [StableType] and [AsFixedSizeBytes], so you can nest it in other stable
+/// structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMultiMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// // user id -> ids of their posts
+/// let mut posts = SBTreeMultiMap::new();
+///
+/// posts.insert(1u64, 30u64).expect("Out of memory");
+/// posts.insert(1, 10).expect("Out of memory");
+/// posts.insert(2, 20).expect("Out of memory");
+///
+/// let user_1_posts: Vec<u64> = posts.get_all(&1).map(|it| *it).collect();
+/// assert_eq!(user_1_posts, vec![10, 30]);
+///
+/// assert_eq!(posts.count(&2), 1);
+/// assert!(posts.remove(&2, &20));
+/// assert!(!posts.contains_key(&2));
+/// ```
+pub struct SBTreeMultiMap<
+    K: StableType + AsFixedSizeBytes + Ord,
+    V: StableType + AsFixedSizeBytes + Ord,
+> {
+    map: SBTreeMap<(K, V), ()>,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes + Ord>
+    SBTreeMultiMap<K, V>
+{
+    /// Creates a new [SBTreeMultiMap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            map: SBTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of key-value pairs in this [SBTreeMultiMap]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns [true] if there are no entries in this [SBTreeMultiMap]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Inserts a key-value pair into this [SBTreeMultiMap]
+    ///
+    /// Returns `true` if this exact pair was already present (then the map stays the same).
+    /// If your canister is out of stable memory, returns [Rejected] with the pair.
+    #[inline]
+    pub fn insert(&mut self, key: K, value: V) -> Result<bool, Rejected<(K, V)>> {
+        self.map
+            .insert((key, value), ())
+            .map(|it| it.is_some())
+            .map_err(|e| e.map(|(entry, _)| entry))
+    }
+
+    /// Removes a single key-value pair from this [SBTreeMultiMap]
+    ///
+    /// Returns `true` if the pair was present. Borrowed types are also accepted, both for the key
+    /// and for the value.
+    pub fn remove<Q, P>(&mut self, key: &Q, value: &P) -> bool
+    where
+        K: Borrow<Q>,
+        V: Borrow<P>,
+        Q: Ord + ?Sized,
+        P: Ord + ?Sized,
+    {
+        match self.find(key, Some(value)) {
+            Some(entry) => self.map.remove(&entry).is_some(),
+            None => false,
+        }
+    }
+
+    /// Removes all values of the key from this [SBTreeMultiMap]
+    ///
+    /// Returns the number of removed values.
+    pub fn remove_all<Q>(&mut self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut removed = 0;
+
+        while let Some(entry) = self.find::<Q, V>(key, None) {
+            self.map.remove(&entry);
+            removed += 1;
+        }
+
+        removed
+    }
+
+    /// Returns an iterator over values of the key, in ascending order
+    ///
+    /// Borrowed type is also accepted. If your key type is, for example, [SBox](crate::SBox) of
+    /// [String], then you can get values by [String].
+    #[inline]
+    pub fn get_all<'a, Q>(&'a self, key: &'a Q) -> SBTreeMultiMapValuesIter<'a, K, V, Q>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let inner = self.map.iter_from(|(k, _)| k.borrow() < key);
+
+        SBTreeMultiMapValuesIter::new(inner, key)
+    }
+
+    /// Returns the number of values of the key
+    ///
+    /// Iterates over these values.
+    #[inline]
+    pub fn count<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get_all(key).count() as u64
+    }
+
+    /// Returns [true] if there is at least one value of the key
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get_all(key).next().is_some()
+    }
+
+    /// Returns [true] if this exact key-value pair is present
+    #[inline]
+    pub fn contains<Q, P>(&self, key: &Q, value: &P) -> bool
+    where
+        K: Borrow<Q>,
+        V: Borrow<P>,
+        Q: Ord + ?Sized,
+        P: Ord + ?Sized,
+    {
+        self.find(key, Some(value)).is_some()
+    }
+
+    /// Removes all entries from this [SBTreeMultiMap]
+    #[inline]
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Returns an iterator over all key-value pairs of this [SBTreeMultiMap]
+    ///
+    /// Pairs are presented in ascending order of keys, and then in ascending order of values.
+    #[inline]
+    pub fn iter(&self) -> SBTreeMultiMapIter<'_, K, V> {
+        SBTreeMultiMapIter::new(self.map.iter())
+    }
+
+    // returns a copy of the first pair of the key (with this exact value, if provided)
+    //
+    // the copy doesn't own anything, so it can only be used to lookup the original pair
+    fn find<Q, P>(&self, key: &Q, value: Option<&P>) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        V: Borrow<P>,
+        Q: Ord + ?Sized,
+        P: Ord + ?Sized,
+    {
+        let (entry, _) = self
+            .map
+            .iter_from(|(k, v)| match value {
+                Some(value) => (k.borrow(), v.borrow()) < (key, value),
+                None => k.borrow() < key,
+            })
+            .next()?;
+
+        let matches =
+            entry.0.borrow() == key && value.map(|it| entry.1.borrow() == it).unwrap_or(true);
+        if !matches {
+            return None;
+        }
+
+        Some(lookup_copy(&*entry))
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes + Ord> Default
+    for SBTreeMultiMap<K, V>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes + Ord>
+    AsFixedSizeBytes for SBTreeMultiMap<K, V>
+{
+    const SIZE: usize = SBTreeMap::<(K, V), ()>::SIZE;
+    type Buf = <SBTreeMap<(K, V), ()> as AsFixedSizeBytes>::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.map.as_fixed_size_bytes(buf);
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        Self {
+            map: SBTreeMap::from_fixed_size_bytes(buf),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes + Ord> StableType
+    for SBTreeMultiMap<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.map.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.map.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.map.visit_allocations(visitor);
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes + Ord> IncrementalDrop
+    for SBTreeMultiMap<K, V>
+{
+    #[inline]
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        self.map.clear_incremental(instruction_budget)
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.map.pending_elements()
+    }
+}
+
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + Debug,
+        V: StableType + AsFixedSizeBytes + Ord + Debug,
+    > Debug for SBTreeMultiMap<K, V>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+
+        for (idx, (k, v)) in self.iter().enumerate() {
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if (idx as u64) < self.len() - 1 {
+                f.write_str(", ")?;
+            }
+        }
+
+        f.write_str("}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::btree_multimap::SBTreeMultiMap;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::StableType;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init, SBox};
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMultiMap::<u64, u64>::default();
+            assert!(map.is_empty());
+            assert_eq!(map.count(&1), 0);
+            assert!(map.get_all(&1).next().is_none());
+
+            for k in 0..10u64 {
+                for v in (0..k).rev() {
+                    assert!(!map.insert(k, v * 10).unwrap());
+                }
+            }
+            assert!(map.insert(5, 10).unwrap());
+
+            assert_eq!(map.len(), 45);
+            assert_eq!(map.count(&0), 0);
+            assert_eq!(map.count(&7), 7);
+            assert!(!map.contains_key(&0));
+            assert!(map.contains_key(&1));
+            assert!(map.contains(&3, &20));
+            assert!(!map.contains(&3, &30));
+
+            let values: Vec<_> = map.get_all(&4).map(|it| *it).collect();
+            assert_eq!(values, vec![0, 10, 20, 30]);
+
+            assert!(map.remove(&4, &10));
+            assert!(!map.remove(&4, &10));
+            assert!(!map.remove(&100, &10));
+            let values: Vec<_> = map.get_all(&4).map(|it| *it).collect();
+            assert_eq!(values, vec![0, 20, 30]);
+
+            assert_eq!(map.remove_all(&9), 9);
+            assert_eq!(map.remove_all(&9), 0);
+            assert_eq!(map.len(), 35);
+
+            let pairs: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(pairs.len(), 35);
+            assert_eq!(pairs[0], (1, 0));
+            assert_eq!(pairs[pairs.len() - 1], (8, 70));
+            assert_eq!(map.iter().next_back().map(|(k, v)| (*k, *v)), Some((8, 70)));
+
+            let mut buf = <SBTreeMultiMap<u64, u64> as AsFixedSizeBytes>::Buf::new(
+                SBTreeMultiMap::<u64, u64>::SIZE,
+            );
+            map.as_fixed_size_bytes(buf._deref_mut());
+            let mut map1 = SBTreeMultiMap::<u64, u64>::from_fixed_size_bytes(buf._deref());
+            unsafe { map1.stable_drop_flag_off() };
+            assert_eq!(map1.count(&8), 8);
+
+            map.clear();
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn boxed_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMultiMap::new();
+            let mut example = BTreeSet::new();
+            let mut rng = thread_rng();
+
+            for _ in 0..2_000 {
+                let key = format!("key {}", rng.gen_range(0..20));
+                let value = format!("value {}", rng.gen_range(0..50));
+
+                match rng.gen_range(0..10) {
+                    0..=5 => {
+                        let existed = map
+                            .insert(
+                                SBox::new(key.clone()).unwrap(),
+                                SBox::new(value.clone()).unwrap(),
+                            )
+                            .unwrap();
+
+                        assert_eq!(existed, !example.insert((key, value)));
+                    }
+                    6..=8 => {
+                        assert_eq!(map.remove(&key, &value), example.remove(&(key, value)));
+                    }
+                    _ => {
+                        let expected = example.iter().filter(|(k, _)| *k == key).count();
+                        example.retain(|(k, _)| *k != key);
+
+                        assert_eq!(map.remove_all(&key), expected as u64);
+                    }
+                }
+
+                assert_eq!(map.len(), example.len() as u64);
+            }
+
+            for (k, v) in &example {
+                assert!(map.contains(k, v));
+            }
+
+            let pairs: Vec<_> = map
+                .iter()
+                .map(|(k, v)| ((**k).clone(), (**v).clone()))
+                .collect();
+            assert_eq!(pairs, example.iter().cloned().collect::<Vec<_>>());
+
+            for i in 0..20 {
+                let key = format!("key {}", i);
+                let values: Vec<_> = map.get_all(&key).map(|it| (**it).clone()).collect();
+                let expected: Vec<_> = example
+                    .iter()
+                    .filter(|(k, _)| *k == key)
+                    .map(|(_, v)| v.clone())
+                    .collect();
+
+                assert_eq!(values, expected);
+                assert_eq!(map.count(&key), expected.len() as u64);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -1,10 +1,14 @@
 use crate::collections::btree_map::SBTreeMap;
 use crate::collections::btree_set::iter::SBTreeSetIter;
 use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::StableType;
+use crate::Rejected;
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
+use std::ops::RangeBounds;
 
 pub mod iter;
 
@@ -24,6 +28,14 @@ impl<T: Ord + StableType + AsFixedSizeBytes> SBTreeSet<T> {
         }
     }
 
+    /// See [SBTreeMap::new_with_counts]
+    #[inline]
+    pub fn new_with_counts() -> Self {
+        Self {
+            map: SBTreeMap::new_with_counts(),
+        }
+    }
+
     /// See [SBTreeMap::len]
     #[inline]
     pub fn len(&self) -> u64 {
@@ -38,11 +50,11 @@ impl<T: Ord + StableType + AsFixedSizeBytes> SBTreeSet<T> {
 
     /// See [SBTreeMap::insert]
     #[inline]
-    pub fn insert(&mut self, value: T) -> Result<bool, T> {
+    pub fn insert(&mut self, value: T) -> Result<bool, Rejected<T>> {
         self.map
             .insert(value, ())
             .map(|it| it.is_some())
-            .map_err(|(k, _)| k)
+            .map_err(|e| e.map(|(k, _)| k))
     }
 
     /// See [SBTreeMap::remove]
@@ -77,11 +89,68 @@ impl<T: Ord + StableType + AsFixedSizeBytes> SBTreeSet<T> {
         self.map.get_random_key(seed)
     }
 
+    /// See [SBTreeMap::nth]
+    ///
+    /// # Panics
+    /// Panics if this set was not created with [SBTreeSet::new_with_counts].
+    #[inline]
+    pub fn nth(&self, idx: u64) -> Option<SRef<'_, T>> {
+        self.map.nth(idx).map(|(k, _)| k)
+    }
+
+    /// See [SBTreeMap::rank]
+    ///
+    /// # Panics
+    /// Panics if this set was not created with [SBTreeSet::new_with_counts].
+    #[inline]
+    pub fn rank<Q>(&self, value: &Q) -> u64
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.map.rank(value)
+    }
+
+    /// See [SBTreeMap::count_range]
+    ///
+    /// # Panics
+    /// Panics if this set was not created with [SBTreeSet::new_with_counts].
+    #[inline]
+    pub fn count_range<Q, R>(&self, range: R) -> u64
+    where
+        T: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self.map.count_range(range)
+    }
+
+    /// See [SBTreeMap::random_entry]
+    ///
+    /// # Panics
+    /// Panics if this set was not created with [SBTreeSet::new_with_counts].
+    #[inline]
+    pub fn random(&self, seed: u64) -> Option<SRef<'_, T>> {
+        self.map.random_entry(seed).map(|(k, _)| k)
+    }
+
     /// See [SBTreeMap::iter]
     #[inline]
     pub fn iter(&self) -> SBTreeSetIter<T> {
         SBTreeSetIter::new(self)
     }
+
+    /// See [SBTreeMap::enable_node_cache]
+    #[inline]
+    pub fn enable_node_cache(&mut self, capacity: usize) {
+        self.map.enable_node_cache(capacity);
+    }
+
+    /// See [SBTreeMap::disable_node_cache]
+    #[inline]
+    pub fn disable_node_cache(&mut self) {
+        self.map.disable_node_cache();
+    }
 }
 
 impl<T: Ord + StableType + AsFixedSizeBytes> Default for SBTreeSet<T> {
@@ -117,6 +186,23 @@ impl<T: StableType + AsFixedSizeBytes + Ord> StableType for SBTreeSet<T> {
     unsafe fn stable_drop_flag_off(&mut self) {
         self.map.stable_drop_flag_off()
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.map.visit_allocations(visitor);
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord> IncrementalDrop for SBTreeSet<T> {
+    #[inline]
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        self.map.clear_incremental(instruction_budget)
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.map.pending_elements()
+    }
 }
 
 impl<T: StableType + AsFixedSizeBytes + Ord + Debug> Debug for SBTreeSet<T> {
@@ -165,6 +251,16 @@ mod tests {
             assert!(!set.remove(&10));
 
             let set = SBTreeSet::<u64>::new();
+
+            let mut set = SBTreeSet::new_with_counts();
+            for i in 0..100u64 {
+                set.insert(i * 2).unwrap();
+            }
+
+            assert_eq!(*set.nth(10).unwrap(), 20);
+            assert_eq!(set.rank(&21), 11);
+            assert_eq!(set.count_range(10..=20), 6);
+            assert!(set.random(100).is_some());
         }
 
         _debug_validate_allocator();
@@ -299,8 +395,8 @@ mod tests {
 
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(set) => {
-                        self.set = Some(set);
+                    Err(e) => {
+                        self.set = Some(e.into_inner());
                     }
                 },
             }
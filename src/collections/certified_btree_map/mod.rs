@@ -3,13 +3,17 @@ use crate::collections::btree_map::iter::SBTreeMapIter;
 use crate::collections::btree_map::leaf_node::LeafBTreeNode;
 use crate::collections::btree_map::{BTreeNode, LeveledList, SBTreeMap};
 use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
 use crate::utils::certification::{
     empty_hash, labeled, labeled_hash, pruned, AsHashTree, AsHashableBytes, Hash, HashForker,
     HashTree, WitnessForker,
 };
+use crate::{stable, Rejected};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
@@ -216,7 +220,7 @@ impl<
     /// * See also [SCertifiedBTreeMap::insert_and_commit]
     /// * See also [SBTreeMap::insert]
     #[inline]
-    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Rejected<(K, V)>> {
         let res = self.inner._insert(key, value, &mut self.modified);
 
         if res.is_ok() && !self.uncommited {
@@ -231,7 +235,7 @@ impl<
     ///
     /// See also [SCertifiedBTreeMap::insert]
     #[inline]
-    pub fn insert_and_commit(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+    pub fn insert_and_commit(&mut self, key: K, value: V) -> Result<Option<V>, Rejected<(K, V)>> {
         let it = self.insert(key, value)?;
         self.commit();
 
@@ -619,6 +623,43 @@ impl<
     unsafe fn stable_drop_flag_off(&mut self) {
         self.inner.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.inner.visit_allocations(visitor);
+    }
+}
+
+// leaves the map in the `uncommited` state between calls
+impl<
+        K: StableType + AsFixedSizeBytes + Ord + AsHashableBytes,
+        V: StableType + AsFixedSizeBytes + AsHashTree,
+    > IncrementalDrop for SCertifiedBTreeMap<K, V>
+{
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        while has_budget(start, instruction_budget) {
+            match self.inner.read_last_key_as_reference() {
+                Some(key) => {
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+
+        if self.is_empty() {
+            // releases the empty root node and commits
+            self.clear();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len()
+    }
 }
 
 impl<
@@ -1473,8 +1514,8 @@ mod tests {
 
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(map) => {
-                        self.map = Some(map);
+                    Err(e) => {
+                        self.map = Some(e.into_inner());
                     }
                 },
                 100..=101 => {
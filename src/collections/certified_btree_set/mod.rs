@@ -1,10 +1,12 @@
 use crate::collections::certified_btree_map::SCertifiedBTreeMap;
 use crate::collections::certified_btree_set::iter::SCertifiedBTreeSetIter;
 use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::StableType;
 use crate::utils::certification::HashTree;
-use crate::{AsHashTree, AsHashableBytes};
+use crate::{AsHashTree, AsHashableBytes, Rejected};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 
@@ -41,20 +43,20 @@ impl<T: Ord + StableType + AsFixedSizeBytes + AsHashableBytes> SCertifiedBTreeSe
 
     /// See [SCertifiedBTreeMap::insert]
     #[inline]
-    pub fn insert(&mut self, value: T) -> Result<bool, T> {
+    pub fn insert(&mut self, value: T) -> Result<bool, Rejected<T>> {
         self.map
             .insert(value, ())
             .map(|it| it.is_some())
-            .map_err(|(k, _)| k)
+            .map_err(|e| e.map(|(k, _)| k))
     }
 
     /// See [SCertifiedBTreeMap::insert_and_commit]
     #[inline]
-    pub fn insert_and_commit(&mut self, value: T) -> Result<bool, T> {
+    pub fn insert_and_commit(&mut self, value: T) -> Result<bool, Rejected<T>> {
         self.map
             .insert_and_commit(value, ())
             .map(|it| it.is_some())
-            .map_err(|(k, _)| k)
+            .map_err(|e| e.map(|(k, _)| k))
     }
 
     /// See [SCertifiedBTreeMap::remove]
@@ -193,6 +195,25 @@ impl<T: StableType + AsFixedSizeBytes + Ord + AsHashableBytes> StableType
     unsafe fn stable_drop_flag_off(&mut self) {
         self.map.stable_drop_flag_off()
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.map.visit_allocations(visitor);
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Ord + AsHashableBytes> IncrementalDrop
+    for SCertifiedBTreeSet<T>
+{
+    #[inline]
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        self.map.clear_incremental(instruction_budget)
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.map.pending_elements()
+    }
 }
 
 impl<T: StableType + AsFixedSizeBytes + Ord + Debug + AsHashableBytes> Debug
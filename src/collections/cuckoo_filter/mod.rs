@@ -0,0 +1,500 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::StablePtr;
+use crate::primitive::StableType;
+use crate::utils::math::{ceil_div, seeded_hash, shuffle_bits};
+use crate::{allocate, deallocate, OutOfMemory, SSlice, PAGE_SIZE_BYTES};
+use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+// Layout:
+// BUCKETS: [[fingerprint; BUCKET_SIZE]; buckets], where each fingerprint is 1 or 2 bytes long and
+// an empty slot is 0
+
+const BUCKET_SIZE: usize = 4;
+const MAX_KICKS: u32 = 500;
+// the share of slots, expected to be filled at the capacity
+const LOAD_FACTOR: f64 = 0.95;
+
+/// Probabilistic set, supporting removal of items, with possible false positives
+///
+/// A table of buckets of 4 slots, each slot holding a short fingerprint of an item. An item can be
+/// stored in one of two buckets, so a lookup only reads two buckets. When both buckets are full,
+/// one of the fingerprints is moved to its alternative bucket (it "kicks" another one, and so on).
+/// Fingerprints are 1 byte long for false positive rates of at least 3.2%, otherwise they are 2
+/// bytes long, giving the false positive rate of ~0.012%. Takes about `fingerprint size / 0.95`
+/// bytes per item of capacity.
+///
+/// Unlike [SBloomFilter](crate::collections::SBloomFilter), items can be removed. An item can be
+/// inserted several times, and then has to be removed the same number of times. Only remove items
+/// that were inserted, otherwise a fingerprint of another item may be removed instead.
+///
+/// Items are hashed with [zwohash](https://github.com/jix/zwohash), which is deterministic between
+/// canister upgrades. Items are never stored, so `T` only has to implement [Hash] and can be
+/// unsized (e.g. `[u8]` or `str`).
+///
+/// [SCuckooFilter] implements [StableType] and [AsFixedSizeBytes], so you can nest it in other
+/// stable structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SCuckooFilter;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut pending = SCuckooFilter::<str>::new(1_000, 0.001).expect("Out of memory");
+///
+/// assert!(pending.insert("alice"));
+/// assert!(pending.insert("bob"));
+/// assert!(pending.contains("alice"));
+///
+/// assert!(pending.remove("alice"));
+/// assert!(!pending.contains("alice"));
+/// assert_eq!(pending.len_estimate(), 1);
+/// ```
+pub struct SCuckooFilter<T: Hash + ?Sized> {
+    ptr: StablePtr,
+    buckets: u64,
+    capacity: u64,
+    len: u64,
+    fingerprint_size: u8,
+    // a fingerprint, that didn't fit into the table, and one of its buckets
+    victim: Option<(u64, u16)>,
+    stable_drop_flag: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Hash + ?Sized> SCuckooFilter<T> {
+    /// Creates a new [SCuckooFilter] for up to `capacity` items with the false positive rate of at
+    /// most `fp_rate` (or ~0.012%, if `fp_rate` is lower than that)
+    ///
+    /// Allocates all the stable memory the filter will ever need. If your canister is out of stable
+    /// memory, returns [OutOfMemory].
+    ///
+    /// # Panics
+    /// Panics if `capacity` is `0` or if `fp_rate` is not in `(0, 1)` range.
+    pub fn new(capacity: u64, fp_rate: f64) -> Result<Self, OutOfMemory> {
+        assert!(capacity > 0, "capacity should be positive");
+        assert!(
+            fp_rate > 0.0 && fp_rate < 1.0,
+            "false positive rate should be in (0, 1) range"
+        );
+
+        // a lookup compares the fingerprint with 2 * BUCKET_SIZE others
+        let fingerprint_size = if fp_rate >= (2 * BUCKET_SIZE) as f64 / u8::MAX as f64 {
+            1
+        } else {
+            2
+        };
+
+        let slots = (capacity as f64 / LOAD_FACTOR).ceil() as u64;
+        let buckets = ceil_div(slots, BUCKET_SIZE as u64).next_power_of_two();
+
+        let slice = unsafe { allocate(buckets * BUCKET_SIZE as u64 * fingerprint_size as u64)? };
+
+        let mut it = Self {
+            ptr: slice.as_ptr(),
+            buckets,
+            capacity,
+            len: 0,
+            fingerprint_size,
+            victim: None,
+            stable_drop_flag: true,
+            _marker: PhantomData,
+        };
+
+        it.clear();
+
+        Ok(it)
+    }
+
+    /// Inserts the item into this [SCuckooFilter]
+    ///
+    /// Returns `false` if the filter is full - in this case it stays the same. Inserts may start to
+    /// fail a little before the filter reaches its capacity.
+    pub fn insert(&mut self, item: &T) -> bool {
+        if self.victim.is_some() {
+            return false;
+        }
+
+        let (idx, fingerprint) = self.locate(item);
+        self.put(idx, fingerprint);
+        self.len += 1;
+
+        true
+    }
+
+    /// Returns `true` if the item was probably inserted into this [SCuckooFilter] and `false` if it
+    /// was definitely not
+    pub fn contains(&self, item: &T) -> bool {
+        let (idx, fingerprint) = self.locate(item);
+        let alt_idx = self.alt_idx(idx, fingerprint);
+
+        if let Some((victim_idx, victim_fingerprint)) = self.victim {
+            if victim_fingerprint == fingerprint && (victim_idx == idx || victim_idx == alt_idx) {
+                return true;
+            }
+        }
+
+        self.read_bucket(idx).contains(&fingerprint)
+            || self.read_bucket(alt_idx).contains(&fingerprint)
+    }
+
+    /// Removes the item from this [SCuckooFilter]
+    ///
+    /// Returns `true` if a fingerprint of the item was found and removed.
+    pub fn remove(&mut self, item: &T) -> bool {
+        let (idx, fingerprint) = self.locate(item);
+        let alt_idx = self.alt_idx(idx, fingerprint);
+
+        if let Some((victim_idx, victim_fingerprint)) = self.victim {
+            if victim_fingerprint == fingerprint && (victim_idx == idx || victim_idx == alt_idx) {
+                self.victim = None;
+                self.len -= 1;
+
+                return true;
+            }
+        }
+
+        for i in [idx, alt_idx] {
+            if let Some(slot) = self.read_bucket(i).iter().position(|it| *it == fingerprint) {
+                self.write_fingerprint(i, slot, 0);
+                self.len -= 1;
+
+                // there is a free slot now, so the victim has a chance to fit
+                if let Some((victim_idx, victim_fingerprint)) = self.victim.take() {
+                    self.put(victim_idx, victim_fingerprint);
+                }
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns the number of items in this [SCuckooFilter]
+    ///
+    /// This is the number of stored fingerprints, which only differs from the number of items when
+    /// an item, that was never inserted, gets removed.
+    #[inline]
+    pub fn len_estimate(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the capacity this [SCuckooFilter] was created with
+    #[inline]
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Removes all the items from this [SCuckooFilter]
+    pub fn clear(&mut self) {
+        let size = self.buckets * BUCKET_SIZE as u64 * self.fingerprint_size as u64;
+        let zeroes = vec![0u8; size.min(PAGE_SIZE_BYTES) as usize];
+
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(zeroes.len() as u64);
+
+            unsafe {
+                crate::mem::write_bytes(SSlice::_offset(self.ptr, offset), &zeroes[..len as usize])
+            };
+
+            offset += len;
+        }
+
+        self.len = 0;
+        self.victim = None;
+    }
+
+    // returns the main bucket of the item and its fingerprint, which is never 0
+    #[inline]
+    fn locate(&self, item: &T) -> (u64, u16) {
+        let max_fingerprint = if self.fingerprint_size == 1 {
+            u8::MAX as u64
+        } else {
+            u16::MAX as u64
+        };
+
+        let idx = seeded_hash(item, 0) & (self.buckets - 1);
+        let fingerprint = (seeded_hash(item, 1) % max_fingerprint + 1) as u16;
+
+        (idx, fingerprint)
+    }
+
+    // xor makes it symmetrical - the alternative bucket of the alternative bucket is the main one
+    #[inline]
+    fn alt_idx(&self, idx: u64, fingerprint: u16) -> u64 {
+        (idx ^ seeded_hash(&fingerprint, 2)) & (self.buckets - 1)
+    }
+
+    // puts the fingerprint into one of its buckets, kicking other fingerprints out, if needed
+    fn put(&mut self, idx: u64, fingerprint: u16) {
+        let alt_idx = self.alt_idx(idx, fingerprint);
+
+        if self.try_put(idx, fingerprint) || self.try_put(alt_idx, fingerprint) {
+            return;
+        }
+
+        let mut idx = if fingerprint.is_multiple_of(2) {
+            idx
+        } else {
+            alt_idx
+        };
+        let mut fingerprint = fingerprint;
+
+        for kick in 0..MAX_KICKS {
+            let slot = shuffle_bits((fingerprint as u32) << 16 | kick) as usize % BUCKET_SIZE;
+
+            let evicted = self.read_bucket(idx)[slot];
+            self.write_fingerprint(idx, slot, fingerprint);
+
+            fingerprint = evicted;
+            idx = self.alt_idx(idx, fingerprint);
+
+            if self.try_put(idx, fingerprint) {
+                return;
+            }
+        }
+
+        // the table is almost full - the last evicted fingerprint is kept aside, so no item is lost
+        self.victim = Some((idx, fingerprint));
+    }
+
+    fn try_put(&mut self, idx: u64, fingerprint: u16) -> bool {
+        match self.read_bucket(idx).iter().position(|it| *it == 0) {
+            Some(slot) => {
+                self.write_fingerprint(idx, slot, fingerprint);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn read_bucket(&self, idx: u64) -> [u16; BUCKET_SIZE] {
+        let fingerprint_size = self.fingerprint_size as usize;
+
+        let mut buf = [0u8; BUCKET_SIZE * u16::SIZE];
+        let buf = &mut buf[..BUCKET_SIZE * fingerprint_size];
+
+        unsafe { crate::mem::read_bytes(SSlice::_offset(self.ptr, idx * buf.len() as u64), buf) };
+
+        let mut bucket = [0u16; BUCKET_SIZE];
+        for (fingerprint, bytes) in bucket.iter_mut().zip(buf.chunks_exact(fingerprint_size)) {
+            *fingerprint = if fingerprint_size == 1 {
+                bytes[0] as u16
+            } else {
+                u16::from_fixed_size_bytes(bytes)
+            };
+        }
+
+        bucket
+    }
+
+    fn write_fingerprint(&mut self, idx: u64, slot: usize, fingerprint: u16) {
+        let fingerprint_size = self.fingerprint_size as u64;
+        let offset = (idx * BUCKET_SIZE as u64 + slot as u64) * fingerprint_size;
+
+        let buf = fingerprint.as_new_fixed_size_bytes();
+
+        unsafe {
+            crate::mem::write_bytes(
+                SSlice::_offset(self.ptr, offset),
+                &buf[..fingerprint_size as usize],
+            )
+        };
+    }
+}
+
+impl<T: Hash + ?Sized> AsFixedSizeBytes for SCuckooFilter<T> {
+    const SIZE: usize = u64::SIZE * 5 + u8::SIZE + u16::SIZE;
+    type Buf = [u8; u64::SIZE * 5 + u8::SIZE + u16::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.ptr.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.buckets
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE * 2)]);
+        self.capacity
+            .as_fixed_size_bytes(&mut buf[(u64::SIZE * 2)..(u64::SIZE * 3)]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[(u64::SIZE * 3)..(u64::SIZE * 4)]);
+
+        // fingerprints are never 0, so 0 means "no victim"
+        let (victim_idx, victim_fingerprint) = self.victim.unwrap_or((0, 0));
+        victim_idx.as_fixed_size_bytes(&mut buf[(u64::SIZE * 4)..(u64::SIZE * 5)]);
+
+        buf[u64::SIZE * 5] = self.fingerprint_size;
+        victim_fingerprint.as_fixed_size_bytes(
+            &mut buf[(u64::SIZE * 5 + u8::SIZE)..(u64::SIZE * 5 + u8::SIZE + u16::SIZE)],
+        );
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let victim_idx = u64::from_fixed_size_bytes(&arr[(u64::SIZE * 4)..(u64::SIZE * 5)]);
+        let victim_fingerprint = u16::from_fixed_size_bytes(
+            &arr[(u64::SIZE * 5 + u8::SIZE)..(u64::SIZE * 5 + u8::SIZE + u16::SIZE)],
+        );
+
+        Self {
+            ptr: u64::from_fixed_size_bytes(&arr[0..u64::SIZE]),
+            buckets: u64::from_fixed_size_bytes(&arr[u64::SIZE..(u64::SIZE * 2)]),
+            capacity: u64::from_fixed_size_bytes(&arr[(u64::SIZE * 2)..(u64::SIZE * 3)]),
+            len: u64::from_fixed_size_bytes(&arr[(u64::SIZE * 3)..(u64::SIZE * 4)]),
+            fingerprint_size: arr[u64::SIZE * 5],
+            victim: if victim_fingerprint == 0 {
+                None
+            } else {
+                Some((victim_idx, victim_fingerprint))
+            },
+            stable_drop_flag: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Hash + ?Sized> StableType for SCuckooFilter<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    #[inline]
+    unsafe fn stable_drop(&mut self) {
+        deallocate(SSlice::from_ptr(self.ptr).unwrap());
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        visitor(unsafe { SSlice::from_ptr(self.ptr).unwrap() });
+    }
+}
+
+impl<T: Hash + ?Sized> Drop for SCuckooFilter<T> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<T: Hash + ?Sized> Debug for SCuckooFilter<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SCuckooFilter")
+            .field("capacity", &self.capacity)
+            .field("buckets", &self.buckets)
+            .field("fingerprint_size", &self.fingerprint_size)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::cuckoo_filter::SCuckooFilter;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            for (fp_rate, max_false_positives) in [(0.05, 6_000), (0.001, 100)] {
+                let mut filter = SCuckooFilter::<u64>::new(10_000, fp_rate).unwrap();
+                assert_eq!(filter.capacity(), 10_000);
+
+                for i in 0..10_000u64 {
+                    assert!(filter.insert(&i));
+                }
+                assert_eq!(filter.len_estimate(), 10_000);
+
+                // no false negatives
+                assert!((0..10_000u64).all(|i| filter.contains(&i)));
+
+                let false_positives = (10_000..110_000u64).filter(|i| filter.contains(i)).count();
+                assert!(false_positives < max_false_positives, "{}", false_positives);
+
+                for i in (0..10_000u64).step_by(2) {
+                    assert!(filter.remove(&i));
+                }
+                assert_eq!(filter.len_estimate(), 5_000);
+                assert!((1..10_000u64).step_by(2).all(|i| filter.contains(&i)));
+
+                println!("{:?}", filter);
+
+                store_custom_data(0, SBox::new(filter).unwrap());
+                stable_memory_pre_upgrade().unwrap();
+                stable_memory_post_upgrade();
+
+                let mut filter = retrieve_custom_data::<SCuckooFilter<u64>>(0)
+                    .unwrap()
+                    .into_inner();
+
+                assert!((1..10_000u64).step_by(2).all(|i| filter.contains(&i)));
+
+                filter.clear();
+                assert_eq!(filter.len_estimate(), 0);
+                assert!(!filter.contains(&1));
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn full_filter_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut filter = SCuckooFilter::<str>::new(100, 0.001).unwrap();
+            let mut inserted = Vec::new();
+
+            loop {
+                let item = format!("item-{}", inserted.len());
+
+                if !filter.insert(&item) {
+                    break;
+                }
+
+                inserted.push(item);
+            }
+
+            // all the slots (128) and the victim
+            assert!(inserted.len() >= 100, "{}", inserted.len());
+            assert!(inserted.len() <= 129, "{}", inserted.len());
+            assert_eq!(filter.len_estimate(), inserted.len() as u64);
+            assert!(inserted.iter().all(|it| filter.contains(it)));
+
+            // removal makes space for new items
+            for item in &inserted[..50] {
+                assert!(filter.remove(item));
+            }
+            assert!(filter.insert("new item"));
+            assert!(filter.contains("new item"));
+            assert!(inserted[50..].iter().all(|it| filter.contains(it)));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -19,6 +19,21 @@ impl<'a, K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSi
     pub fn new(map: &'a SHashMap<K, V>) -> Self {
         Self { map, i: 0 }
     }
+
+    pub(crate) fn new_at(map: &'a SHashMap<K, V>, cursor: usize) -> Self {
+        Self {
+            map,
+            i: cursor.min(map.capacity()),
+        }
+    }
+
+    /// Returns the index of the bucket, from which the search for the next entry will start
+    ///
+    /// Pass it to [SHashMap::iter_from_cursor] to continue the iteration from this point.
+    #[inline]
+    pub fn cursor(&self) -> usize {
+        self.i
+    }
 }
 
 impl<'a, K: StableType + AsFixedSizeBytes + Eq + Hash, V: StableType + AsFixedSizeBytes> Iterator
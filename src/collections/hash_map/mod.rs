@@ -1,12 +1,14 @@
 use crate::collections::hash_map::iter::SHashMapIter;
 use crate::encoding::{AsFixedSizeBytes, Buffer};
 use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::deferred_drop::IncrementalDrop;
 use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
 use crate::utils::DebuglessUnwrap;
-use crate::{allocate, deallocate, OutOfMemory, SSlice};
+use crate::{allocate, deallocate, stable, OutOfMemory, Rejected, SSlice};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
@@ -52,6 +54,7 @@ pub struct SHashMap<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType
     len: usize,
     cap: usize,
     stable_drop_flag: bool,
+    _clear_cursor: usize,
     _marker_k: PhantomData<K>,
     _marker_v: PhantomData<V>,
 }
@@ -79,6 +82,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
             len: 0,
             cap: DEFAULT_CAPACITY,
             stable_drop_flag: true,
+            _clear_cursor: usize::MAX,
             _marker_k: PhantomData::default(),
             _marker_v: PhantomData::default(),
         }
@@ -113,6 +117,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
             len: 0,
             cap: capacity,
             stable_drop_flag: true,
+            _clear_cursor: usize::MAX,
             _marker_k: PhantomData::default(),
             _marker_v: PhantomData::default(),
         })
@@ -121,7 +126,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
     /// Inserts a key-value pair in this [SHashMap]
     ///
     /// Will try to reallocate, if `length == capacity * 3/4` and there is no key-value pair stored by the
-    /// same key. If the canister is out of stable memory, will return [Err] with the key-value pair
+    /// same key. If the canister is out of stable memory, will return [Rejected] with the key-value pair
     /// that was about to get inserted.
     ///
     /// If the insertion was successful, returns [Option] with a previous value stored by this key,
@@ -132,26 +137,29 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
     /// # Example
     /// ```rust
     /// # use ic_stable_memory::collections::SHashMap;
-    /// # use ic_stable_memory::stable_memory_init;
+    /// # use ic_stable_memory::{stable_memory_init, Rejected};
     /// # unsafe { ic_stable_memory::mem::clear(); }
     /// # stable_memory_init();
     /// let mut map = SHashMap::new();
     ///
     /// match map.insert(1, 10) {
     ///     Ok(prev) => println!("Success! Previous value == {prev:?}"),
-    ///     Err((k, v)) => println!("Out of memory. Unable to insert: {k}, {v}"),
+    ///     Err(Rejected { value: (k, v), reason }) => {
+    ///         println!("Unable to insert: {k}, {v}. {reason}")
+    ///     }
     /// };
     /// ```
-    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Rejected<(K, V)>> {
         if self.table_ptr == EMPTY_PTR {
             let size = (1 + K::SIZE + V::SIZE) * self.capacity();
-            if let Ok(table) = unsafe { allocate(size as u64) } {
-                let zeroed = vec![0u8; size];
-                unsafe { crate::mem::write_bytes(table.offset(0), &zeroed) };
+            match unsafe { allocate(size as u64) } {
+                Ok(table) => {
+                    let zeroed = vec![0u8; size];
+                    unsafe { crate::mem::write_bytes(table.offset(0), &zeroed) };
 
-                self.table_ptr = table.as_ptr();
-            } else {
-                return Err((key, value));
+                    self.table_ptr = table.as_ptr();
+                }
+                Err(e) => return Err(Rejected::new((key, value), e)),
             }
         }
 
@@ -177,31 +185,30 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
                     if self.is_full() {
                         // since we're allocating a new map with "new_with_capacity()" method, it should have
                         // enough space to fit all elements without throwing an OutOfMemory error
-                        if let Ok(mut new) =
-                            Self::new_with_capacity(self.capacity().checked_mul(2).unwrap() - 1)
-                        {
-                            for i in 0..self.cap {
-                                if let Some(k) = self.read_and_disown_key(i) {
-                                    let v = self.read_and_disown_val(i);
-
-                                    new.insert(k, v).debugless_unwrap();
+                        match Self::new_with_capacity(self.capacity().checked_mul(2).unwrap() - 1) {
+                            Ok(mut new) => {
+                                for i in 0..self.cap {
+                                    if let Some(k) = self.read_and_disown_key(i) {
+                                        let v = self.read_and_disown_val(i);
+
+                                        new.insert(k, v).debugless_unwrap();
+                                    }
                                 }
-                            }
 
-                            let res = new.insert(key, value).debugless_unwrap();
-                            let slice = unsafe { SSlice::from_ptr(self.table_ptr).unwrap() };
-                            deallocate(slice);
+                                let res = new.insert(key, value).debugless_unwrap();
+                                let slice = unsafe { SSlice::from_ptr(self.table_ptr).unwrap() };
+                                deallocate(slice);
 
-                            // dirty hack to make it not call stable_drop() when it is dropped
-                            // it is safe to use, since we've moved all the data inside into the new map
-                            // and deallocated the underlying slice
-                            unsafe { self.stable_drop_flag_off() };
+                                // dirty hack to make it not call stable_drop() when it is dropped
+                                // it is safe to use, since we've moved all the data inside into the new map
+                                // and deallocated the underlying slice
+                                unsafe { self.stable_drop_flag_off() };
 
-                            *self = new;
+                                *self = new;
 
-                            return Ok(res);
-                        } else {
-                            return Err((key, value));
+                                return Ok(res);
+                            }
+                            Err(e) => return Err(Rejected::new((key, value), e)),
                         }
                     }
 
@@ -374,6 +381,37 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
         SHashMapIter::new(self)
     }
 
+    /// Returns an iterator over entries of this [SHashMap], starting from the bucket at the `cursor` index
+    ///
+    /// Cursors are returned by [SHashMapIter::cursor]. They are plain bucket indices, so they can be
+    /// sent to a client and used to resume the iteration in a later call. If the map was modified in
+    /// between, some entries may be skipped or returned twice, since inserts may rehash the whole
+    /// table and removes may shift entries between buckets.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SHashMap;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut map = SHashMap::new();
+    ///
+    /// for i in 0..100 {
+    ///     map.insert(i, i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut iter = map.iter();
+    /// let first_page = iter.by_ref().take(10).count();
+    /// let cursor = iter.cursor();
+    ///
+    /// let rest = map.iter_from_cursor(cursor).count();
+    /// assert_eq!(first_page + rest, 100);
+    /// ```
+    #[inline]
+    pub fn iter_from_cursor(&self, cursor: usize) -> SHashMapIter<'_, K, V> {
+        SHashMapIter::new_at(self, cursor)
+    }
+
     /// Removes all elements from this [SHashMap]
     pub fn clear(&mut self) {
         if self.is_empty() {
@@ -658,6 +696,7 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
             len,
             cap,
             stable_drop_flag: false,
+            _clear_cursor: usize::MAX,
             _marker_k: PhantomData::default(),
             _marker_v: PhantomData::default(),
         }
@@ -690,6 +729,54 @@ impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBy
             deallocate(slice);
         }
     }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        if self.table_ptr == EMPTY_PTR {
+            return;
+        }
+
+        visitor(unsafe { SSlice::from_ptr(self.table_ptr).unwrap() });
+
+        for (k, v) in self.iter() {
+            k.visit_allocations(visitor);
+            v.visit_allocations(visitor);
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBytes> IncrementalDrop
+    for SHashMap<K, V>
+{
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        // Entries are removed from the end of the table, so each removal shifts nothing, except for
+        // the very first one. Slots above the cursor were emptied by previous calls. This is only a
+        // hint - if something was inserted there in between, the scan just starts over.
+        let mut idx = self._clear_cursor.min(self.cap);
+
+        while !self.is_empty() && has_budget(start, instruction_budget) {
+            if idx == 0 {
+                idx = self.cap;
+            }
+
+            if self.read_key_for_reference(idx - 1).is_some() {
+                // the slot is checked again, since a neighbour may get shifted into it
+                self.remove_by_idx(idx - 1);
+            } else {
+                idx -= 1;
+            }
+        }
+
+        self._clear_cursor = idx;
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len() as u64
+    }
 }
 
 impl<K: StableType + AsFixedSizeBytes + Hash + Eq, V: StableType + AsFixedSizeBytes> Drop
@@ -917,6 +1004,41 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn cursors_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SHashMap::new();
+            for i in 0..100u64 {
+                map.insert(i, i).unwrap();
+            }
+
+            let mut cursor = 0;
+            let mut result = Vec::new();
+
+            loop {
+                let mut iter = map.iter_from_cursor(cursor);
+                let page = iter.by_ref().take(7).map(|(k, _)| *k).collect::<Vec<_>>();
+
+                if page.is_empty() {
+                    break;
+                }
+
+                result.extend(page);
+                cursor = iter.cursor();
+            }
+
+            result.sort();
+            assert_eq!(result, (0..100).collect::<Vec<_>>());
+            assert!(map.iter_from_cursor(usize::MAX).next().is_none());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn sboxes_work_fine() {
         stable::clear();
@@ -1036,8 +1158,8 @@ mod tests {
 
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(map) => {
-                        self.map = Some(map);
+                    Err(e) => {
+                        self.map = Some(e.into_inner());
                     }
                 },
             }
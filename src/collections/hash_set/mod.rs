@@ -1,8 +1,10 @@
 use crate::collections::hash_map::SHashMap;
 use crate::collections::hash_set::iter::SHashSetIter;
 use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
 use crate::primitive::StableType;
-use crate::OutOfMemory;
+use crate::{OutOfMemory, Rejected};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Formatter};
 use std::hash::Hash;
@@ -36,11 +38,11 @@ impl<T: StableType + AsFixedSizeBytes + Hash + Eq> SHashSet<T> {
 
     /// See [SHashMap::insert]
     #[inline]
-    pub fn insert(&mut self, value: T) -> Result<bool, T> {
+    pub fn insert(&mut self, value: T) -> Result<bool, Rejected<T>> {
         self.map
             .insert(value, ())
             .map(|it| it.is_some())
-            .map_err(|(k, _)| k)
+            .map_err(|e| e.map(|(k, _)| k))
     }
 
     /// See [SHashMap::remove]
@@ -133,6 +135,23 @@ impl<T: StableType + AsFixedSizeBytes + Hash + Eq> StableType for SHashSet<T> {
     unsafe fn stable_drop_flag_on(&mut self) {
         self.map.stable_drop_flag_on();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.map.visit_allocations(visitor);
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Hash + Eq> IncrementalDrop for SHashSet<T> {
+    #[inline]
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        self.map.clear_incremental(instruction_budget)
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.map.pending_elements()
+    }
 }
 
 impl<T: StableType + AsFixedSizeBytes + Hash + Eq + Debug> Debug for SHashSet<T> {
@@ -330,8 +349,8 @@ mod tests {
 
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(set) => {
-                        self.set = Some(set);
+                    Err(e) => {
+                        self.set = Some(e.into_inner());
                     }
                 },
             }
@@ -0,0 +1,59 @@
+use crate::collections::btree_map::iter::SBTreeMapForwardIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::StableType;
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+pub(crate) type BoundPair<'a, Q> = (Bound<&'a Q>, Bound<&'a Q>);
+
+pub struct SIndexIter<'a, K, PK, Q: ?Sized, R> {
+    inner: SBTreeMapForwardIter<'a, (K, PK), ()>,
+    range: R,
+    finished: bool,
+    _marker_q: PhantomData<&'a Q>,
+}
+
+impl<'a, K, PK, Q: ?Sized, R> SIndexIter<'a, K, PK, Q, R> {
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapForwardIter<'a, (K, PK), ()>, range: R) -> Self {
+        Self {
+            inner,
+            range,
+            finished: false,
+            _marker_q: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, PK, Q, R> Iterator for SIndexIter<'a, K, PK, Q, R>
+where
+    K: StableType + AsFixedSizeBytes + Ord + Borrow<Q>,
+    PK: StableType + AsFixedSizeBytes + Ord + Clone,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    type Item = PK;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let (entry, _) = self.inner.next()?;
+
+        let in_range = match self.range.end_bound() {
+            Bound::Included(to) => entry.0.borrow() <= to,
+            Bound::Excluded(to) => entry.0.borrow() < to,
+            Bound::Unbounded => true,
+        };
+
+        if in_range {
+            Some(entry.1.clone())
+        } else {
+            self.finished = true;
+
+            None
+        }
+    }
+}
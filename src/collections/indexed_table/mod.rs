@@ -0,0 +1,959 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::btree_map::{SBTreeMap, SBTREE_MAP_SIZE};
+use crate::collections::indexed_table::iter::{BoundPair, SIndexIter};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::{lookup_copy, StableType};
+use crate::utils::budget::has_budget;
+use crate::{stable, OutOfMemory, Rejected};
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+pub mod iter;
+
+/// Describes how to extract a secondary index key out of a row of [SIndexedTable]
+///
+/// Implement this trait for a marker type (one per index) and use it as a type parameter of [SIndex].
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::IndexExtractor;
+/// // rows are (created_at, balance) pairs, keyed by user id
+/// struct ByBalance;
+///
+/// impl IndexExtractor<u64, (u64, u64)> for ByBalance {
+///     type Key = u64;
+///
+///     fn index_key(_pk: &u64, row: &(u64, u64)) -> u64 {
+///         row.1
+///     }
+/// }
+/// ```
+pub trait IndexExtractor<PK, Row> {
+    /// Type of the secondary key
+    type Key: StableType + AsFixedSizeBytes + Ord;
+
+    /// Extracts the secondary key from a row
+    ///
+    /// This function should be pure - the same row should always produce the same key. If the key
+    /// allocates stable memory (e.g. it is an [SBox](crate::SBox)), this function is the right place
+    /// to panic, if the canister is out of stable memory.
+    fn index_key(pk: &PK, row: &Row) -> Self::Key;
+}
+
+/// Secondary index of [SIndexedTable]
+///
+/// Internally is an [SBTreeMap]`<(E::Key, PK), ()>`, so many rows can share the same secondary key.
+/// Entries are only modified by the table itself, users can only query them.
+pub struct SIndex<PK, Row, E>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    E: IndexExtractor<PK, Row>,
+{
+    map: SBTreeMap<(E::Key, PK), ()>,
+    _marker_row: PhantomData<Row>,
+    _marker_e: PhantomData<E>,
+}
+
+impl<PK, Row, E> SIndex<PK, Row, E>
+where
+    PK: StableType + AsFixedSizeBytes + Ord + Clone,
+    E: IndexExtractor<PK, Row>,
+{
+    /// Creates an empty [SIndex]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            map: SBTreeMap::new(),
+            _marker_row: PhantomData,
+            _marker_e: PhantomData,
+        }
+    }
+
+    /// Returns the number of entries of this index (equal to the number of rows in the table)
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    /// Returns [true] if the length of this index is `0`
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns an iterator over primary keys of rows, which secondary keys are inside the range
+    ///
+    /// Primary keys are returned in ascending order of their secondary keys (and then in ascending
+    /// order of primary keys themselves).
+    ///
+    /// Borrowed type is also accepted. If your secondary key type is, for example, [SBox](crate::SBox)
+    /// of [String], then you can query the range by [String].
+    #[inline]
+    pub fn range<Q, R>(&self, range: R) -> SIndexIter<'_, E::Key, PK, Q, R>
+    where
+        E::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let inner = self.map.iter_from(|(k, _)| match range.start_bound() {
+            Bound::Included(from) => k.borrow() < from,
+            Bound::Excluded(from) => k.borrow() <= from,
+            Bound::Unbounded => false,
+        });
+
+        SIndexIter::new(inner, range)
+    }
+
+    /// Returns an iterator over primary keys of rows with this exact secondary key
+    #[inline]
+    pub fn get<'a, Q>(&'a self, key: &'a Q) -> SIndexIter<'a, E::Key, PK, Q, BoundPair<'a, Q>>
+    where
+        E::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.range((Bound::Included(key), Bound::Included(key)))
+    }
+
+    /// Returns the primary key of the first row with this exact secondary key
+    ///
+    /// Useful for unique indexes.
+    #[inline]
+    pub fn first<Q>(&self, key: &Q) -> Option<PK>
+    where
+        E::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).next()
+    }
+
+    /// Returns an iterator over all entries of this index
+    #[inline]
+    pub fn iter(&self) -> SBTreeMapIter<'_, (E::Key, PK), ()> {
+        self.map.iter()
+    }
+
+    // moves the key into this index, unless it is equal to `prev`; the key is left disowned, so it
+    // can still be used to look the entry up
+    fn insert_changed(
+        &mut self,
+        pk: &PK,
+        key: &mut E::Key,
+        prev: Option<&E::Key>,
+    ) -> Result<(), OutOfMemory> {
+        if matches!(prev, Some(it) if it == key) {
+            return Ok(());
+        }
+
+        self.map
+            .insert((lookup_copy(key), pk.clone()), ())
+            .map_err(OutOfMemory::from)?;
+
+        unsafe { key.stable_drop_flag_off() };
+
+        Ok(())
+    }
+
+    fn remove_changed(&mut self, pk: &PK, key: &E::Key, next: Option<&E::Key>) {
+        if matches!(next, Some(it) if it == key) {
+            return;
+        }
+
+        self.map.remove(&(lookup_copy(key), pk.clone()));
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl<PK, Row, E> Default for SIndex<PK, Row, E>
+where
+    PK: StableType + AsFixedSizeBytes + Ord + Clone,
+    E: IndexExtractor<PK, Row>,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<PK, Row, E> AsFixedSizeBytes for SIndex<PK, Row, E>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    E: IndexExtractor<PK, Row>,
+{
+    const SIZE: usize = SBTreeMap::<(E::Key, PK), ()>::SIZE;
+    type Buf = <SBTreeMap<(E::Key, PK), ()> as AsFixedSizeBytes>::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.map.as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        Self {
+            map: SBTreeMap::from_fixed_size_bytes(buf),
+            _marker_row: PhantomData,
+            _marker_e: PhantomData,
+        }
+    }
+}
+
+impl<PK, Row, E> StableType for SIndex<PK, Row, E>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    E: IndexExtractor<PK, Row>,
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.map.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.map.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.map.visit_allocations(visitor);
+    }
+}
+
+// the size of 4 indexes - SIndexedTable always reserves space for them, to have a fixed size
+const MAX_INDEXES_SIZE: usize = SBTREE_MAP_SIZE * 4;
+
+/// A set of secondary indexes of [SIndexedTable]
+///
+/// Implemented for `()` (no indexes) and for tuples of up to 4 [SIndex]es. You don't need to
+/// implement it yourself. [SIndexedTable] reserves space for 4 indexes, so a larger implementation
+/// fails to compile.
+pub trait SIndexes<PK, Row>: StableType + AsFixedSizeBytes + Default {
+    /// Secondary keys of a single row - one per index
+    type Keys;
+
+    /// Extracts secondary keys of a row for every index
+    fn index_keys(pk: &PK, row: &Row) -> Self::Keys;
+
+    /// Inserts every secondary key that differs from `prev`
+    ///
+    /// Inserted keys are moved into indexes, `keys` can only be used to look them up afterwards (e.g.
+    /// to pass them to [SIndexes::remove_keys]). Either all keys get inserted, or none of them (in
+    /// case of [OutOfMemory]). Rolling back doesn't allocate.
+    fn insert_keys(
+        &mut self,
+        pk: &PK,
+        keys: &mut Self::Keys,
+        prev: Option<&Self::Keys>,
+    ) -> Result<(), OutOfMemory>;
+
+    /// Removes every secondary key that differs from `next`
+    fn remove_keys(&mut self, pk: &PK, keys: &Self::Keys, next: Option<&Self::Keys>);
+
+    /// Removes all entries from every index
+    fn clear(&mut self);
+}
+
+impl<PK, Row> SIndexes<PK, Row> for () {
+    type Keys = ();
+
+    #[inline]
+    fn index_keys(_: &PK, _: &Row) -> Self::Keys {}
+
+    #[inline]
+    fn insert_keys(
+        &mut self,
+        _: &PK,
+        _: &mut Self::Keys,
+        _: Option<&Self::Keys>,
+    ) -> Result<(), OutOfMemory> {
+        Ok(())
+    }
+
+    #[inline]
+    fn remove_keys(&mut self, _: &PK, _: &Self::Keys, _: Option<&Self::Keys>) {}
+
+    #[inline]
+    fn clear(&mut self) {}
+}
+
+macro_rules! impl_indexes_for_tuple {
+    ($($idx:tt $e:ident),+) => {
+        impl<PK, Row, $($e: IndexExtractor<PK, Row>),+> SIndexes<PK, Row> for ($(SIndex<PK, Row, $e>,)+)
+        where
+            PK: StableType + AsFixedSizeBytes + Ord + Clone,
+        {
+            type Keys = ($($e::Key,)+);
+
+            #[inline]
+            fn index_keys(pk: &PK, row: &Row) -> Self::Keys {
+                ($($e::index_key(pk, row),)+)
+            }
+
+            fn insert_keys(
+                &mut self,
+                pk: &PK,
+                keys: &mut Self::Keys,
+                prev: Option<&Self::Keys>,
+            ) -> Result<(), OutOfMemory> {
+                let mut inserted = 0usize;
+
+                let res = 'insert: {
+                    $(
+                        let key = &mut keys.$idx;
+                        if let Err(e) = self.$idx.insert_changed(pk, key, prev.map(|it| &it.$idx)) {
+                            break 'insert Err(e);
+                        }
+                        inserted += 1;
+                    )+
+
+                    Ok(())
+                };
+
+                // rolling back partially inserted keys, looking them up by the same keys
+                if res.is_err() {
+                    $(
+                        if $idx < inserted {
+                            self.$idx.remove_changed(pk, &keys.$idx, prev.map(|it| &it.$idx));
+                        }
+                    )+
+                }
+
+                res
+            }
+
+            #[inline]
+            fn remove_keys(&mut self, pk: &PK, keys: &Self::Keys, next: Option<&Self::Keys>) {
+                $(
+                    self.$idx.remove_changed(pk, &keys.$idx, next.map(|it| &it.$idx));
+                )+
+            }
+
+            #[inline]
+            fn clear(&mut self) {
+                $(
+                    self.$idx.clear();
+                )+
+            }
+        }
+    };
+}
+
+impl_indexes_for_tuple!(0 A);
+impl_indexes_for_tuple!(0 A, 1 B);
+impl_indexes_for_tuple!(0 A, 1 B, 2 C);
+impl_indexes_for_tuple!(0 A, 1 B, 2 C, 3 D);
+
+/// A table of rows, keyed by a primary key, with automatically maintained secondary indexes
+///
+/// Rows are stored in an [SBTreeMap]`<PK, Row>`. Each secondary index is an [SIndex], which key is
+/// extracted from rows by an [IndexExtractor]. Every index gets updated on each insert and remove,
+/// so indexes never drift out of sync with the rows. If any of indexes can't be updated because
+/// the canister is out of stable memory, the whole operation is rolled back.
+///
+/// `PK` has to implement [Clone], since it is stored inside each of the indexes. Use plain values
+/// (numbers, [Principal](candid::Principal) etc.) as primary keys, not stable structures.
+///
+/// This data structure implements [StableType] and [AsFixedSizeBytes], so it can be nested inside
+/// other stable structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::{IndexExtractor, SIndex, SIndexedTable};
+/// # use ic_stable_memory::{SBox, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// // a row is (email, created_at)
+/// type User = (SBox<String>, u64);
+///
+/// struct ByEmail;
+/// impl IndexExtractor<u64, User> for ByEmail {
+///     type Key = SBox<String>;
+///
+///     fn index_key(_: &u64, row: &User) -> SBox<String> {
+///         SBox::new(row.0.clone()).expect("Out of memory")
+///     }
+/// }
+///
+/// struct ByCreatedAt;
+/// impl IndexExtractor<u64, User> for ByCreatedAt {
+///     type Key = u64;
+///
+///     fn index_key(_: &u64, row: &User) -> u64 {
+///         row.1
+///     }
+/// }
+///
+/// let mut users = SIndexedTable::<u64, User, (SIndex<u64, User, ByEmail>, SIndex<u64, User, ByCreatedAt>)>::new();
+///
+/// let email = SBox::new(String::from("alice@example.com")).expect("Out of memory");
+/// users.insert(1, (email, 100)).expect("Out of memory");
+///
+/// let id = users.indexes().0.first(&String::from("alice@example.com")).unwrap();
+/// assert_eq!(id, 1);
+///
+/// let created_recently: Vec<_> = users.indexes().1.range(50..).collect();
+/// assert_eq!(created_recently, vec![1]);
+/// ```
+pub struct SIndexedTable<PK, Row, I = ()>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    rows: SBTreeMap<PK, Row>,
+    indexes: I,
+}
+
+impl<PK, Row, I> SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord + Clone,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    /// Creates an empty [SIndexedTable]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        let () = Self::INDEXES_FIT;
+
+        Self {
+            rows: SBTreeMap::new(),
+            indexes: I::default(),
+        }
+    }
+
+    /// Inserts a row, updating every index
+    ///
+    /// If there already is a row with the same primary key, it gets replaced and returned. Only
+    /// those secondary keys that have changed are updated in this case.
+    ///
+    /// If the canister is out of stable memory, returns [Rejected] with the primary key and the row.
+    /// In this case neither the table, nor any of its indexes are modified.
+    pub fn insert(&mut self, pk: PK, row: Row) -> Result<Option<Row>, Rejected<(PK, Row)>> {
+        let prev_keys = self.rows.get(&pk).map(|it| I::index_keys(&pk, &it));
+        let mut keys = I::index_keys(&pk, &row);
+
+        if let Err(e) = self.indexes.insert_keys(&pk, &mut keys, prev_keys.as_ref()) {
+            return Err(Rejected::new((pk, row), e));
+        }
+
+        match self.rows.insert(pk.clone(), row) {
+            Ok(prev) => {
+                if let Some(prev_keys) = &prev_keys {
+                    self.indexes.remove_keys(&pk, prev_keys, Some(&keys));
+                }
+
+                Ok(prev)
+            }
+            Err(e) => {
+                self.indexes.remove_keys(&pk, &keys, prev_keys.as_ref());
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes a row by its primary key, updating every index
+    ///
+    /// Returns [None] if there is no such row.
+    pub fn remove(&mut self, pk: &PK) -> Option<Row> {
+        let row = self.rows.remove(pk)?;
+
+        let keys = I::index_keys(pk, &row);
+        self.indexes.remove_keys(pk, &keys, None);
+
+        Some(row)
+    }
+
+    /// Returns an immutable reference [SRef] to a row by its primary key
+    ///
+    /// There is no `get_mut()` method, since mutating a row in-place may desync the indexes. Use
+    /// [SIndexedTable::insert] to replace the row instead.
+    #[inline]
+    pub fn get(&self, pk: &PK) -> Option<SRef<'_, Row>> {
+        self.rows.get(pk)
+    }
+
+    /// Returns [true] if there is a row with this primary key
+    #[inline]
+    pub fn contains_key(&self, pk: &PK) -> bool {
+        self.rows.contains_key(pk)
+    }
+
+    /// Returns the number of rows in this table
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.rows.len()
+    }
+
+    /// Returns [true] if there are no rows in this table
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Returns secondary indexes of this table
+    ///
+    /// Use it to perform lookups, for example `table.indexes().0.range(a..b)`.
+    #[inline]
+    pub fn indexes(&self) -> &I {
+        &self.indexes
+    }
+
+    /// Returns an iterator over rows of this table in ascending order of their primary keys
+    #[inline]
+    pub fn iter(&self) -> SBTreeMapIter<'_, PK, Row> {
+        self.rows.iter()
+    }
+
+    /// Removes all rows and index entries, releasing occupied stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        self.indexes.clear();
+        self.rows.clear();
+    }
+}
+
+impl<PK, Row, I> Default for SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord + Clone,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<PK, Row, I> SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    // evaluated at compile time, once referenced
+    const INDEXES_FIT: () = assert!(I::SIZE <= MAX_INDEXES_SIZE, "Too many indexes");
+}
+
+impl<PK, Row, I> AsFixedSizeBytes for SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    // rows, followed by indexes, padded to MAX_INDEXES_SIZE
+    const SIZE: usize = SBTREE_MAP_SIZE + MAX_INDEXES_SIZE;
+    type Buf = [u8; SBTREE_MAP_SIZE + MAX_INDEXES_SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let () = Self::INDEXES_FIT;
+        let rows_size = SBTreeMap::<PK, Row>::SIZE;
+
+        self.rows.as_fixed_size_bytes(&mut buf[0..rows_size]);
+        self.indexes
+            .as_fixed_size_bytes(&mut buf[rows_size..(rows_size + I::SIZE)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let () = Self::INDEXES_FIT;
+        let rows_size = SBTreeMap::<PK, Row>::SIZE;
+
+        Self {
+            rows: SBTreeMap::from_fixed_size_bytes(&buf[0..rows_size]),
+            indexes: I::from_fixed_size_bytes(&buf[rows_size..(rows_size + I::SIZE)]),
+        }
+    }
+}
+
+impl<PK, Row, I> StableType for SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.rows.stable_drop_flag_on();
+        self.indexes.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.rows.stable_drop_flag_off();
+        self.indexes.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.rows.visit_allocations(visitor);
+        self.indexes.visit_allocations(visitor);
+    }
+}
+
+impl<PK, Row, I> IncrementalDrop for SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord + Clone,
+    Row: StableType + AsFixedSizeBytes,
+    I: SIndexes<PK, Row>,
+{
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        // rows are removed along with their index entries, so indexes stay in sync between calls
+        while has_budget(start, instruction_budget) {
+            let pk = match self.rows.iter().next_back() {
+                Some((pk, _)) => lookup_copy(&*pk),
+                None => break,
+            };
+
+            self.remove(&pk);
+        }
+
+        if self.is_empty() {
+            // releases empty root nodes
+            self.clear();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len()
+    }
+}
+
+impl<PK, Row, I> Debug for SIndexedTable<PK, Row, I>
+where
+    PK: StableType + AsFixedSizeBytes + Ord + Debug,
+    Row: StableType + AsFixedSizeBytes + Debug,
+    I: SIndexes<PK, Row>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.rows.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::indexed_table::{IndexExtractor, SIndex, SIndexedTable};
+    use crate::primitive::s_box::SBox;
+    use crate::utils::DebuglessUnwrap;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, make_sure_can_allocate,
+        retrieve_custom_data, stable, stable_memory_init, stable_memory_post_upgrade,
+        stable_memory_pre_upgrade, store_custom_data,
+    };
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::cell::Cell;
+    use std::collections::{BTreeMap, VecDeque};
+
+    type User = (SBox<String>, u64);
+
+    thread_local! {
+        // the number of extracted email keys
+        static EMAIL_KEYS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    struct ByEmail;
+
+    impl IndexExtractor<u64, User> for ByEmail {
+        type Key = SBox<String>;
+
+        fn index_key(_: &u64, row: &User) -> Self::Key {
+            EMAIL_KEYS.with(|it| it.set(it.get() + 1));
+
+            SBox::new(row.0.clone()).unwrap()
+        }
+    }
+
+    struct ByCreatedAt;
+
+    impl IndexExtractor<u64, User> for ByCreatedAt {
+        type Key = u64;
+
+        fn index_key(_: &u64, row: &User) -> Self::Key {
+            row.1
+        }
+    }
+
+    type Users =
+        SIndexedTable<u64, User, (SIndex<u64, User, ByEmail>, SIndex<u64, User, ByCreatedAt>)>;
+
+    struct ByFirst;
+
+    impl IndexExtractor<u64, (u64, u64)> for ByFirst {
+        type Key = u64;
+
+        fn index_key(_: &u64, row: &(u64, u64)) -> Self::Key {
+            row.0
+        }
+    }
+
+    struct BySecond;
+
+    impl IndexExtractor<u64, (u64, u64)> for BySecond {
+        type Key = u64;
+
+        fn index_key(_: &u64, row: &(u64, u64)) -> Self::Key {
+            row.1
+        }
+    }
+
+    type Pairs = SIndexedTable<
+        u64,
+        (u64, u64),
+        (
+            SIndex<u64, (u64, u64), ByFirst>,
+            SIndex<u64, (u64, u64), BySecond>,
+        ),
+    >;
+
+    fn user(email: &str, created_at: u64) -> User {
+        (SBox::new(String::from(email)).unwrap(), created_at)
+    }
+
+    #[test]
+    fn basic_flow_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut users = Users::new();
+            assert!(users.is_empty());
+
+            assert!(users.insert(1, user("alice@a.com", 10)).unwrap().is_none());
+            assert!(users.insert(2, user("bob@b.com", 20)).unwrap().is_none());
+            assert!(users.insert(3, user("carol@c.com", 20)).unwrap().is_none());
+
+            assert_eq!(users.len(), 3);
+            assert_eq!(users.indexes().0.len(), 3);
+            assert_eq!(users.indexes().1.len(), 3);
+
+            assert_eq!(users.indexes().0.first(&String::from("bob@b.com")), Some(2));
+            assert_eq!(users.indexes().1.get(&20).collect::<Vec<_>>(), vec![2, 3]);
+            assert_eq!(users.indexes().1.range(..20).collect::<Vec<_>>(), vec![1]);
+            assert_eq!(
+                users.indexes().1.range(10..=20).collect::<Vec<_>>(),
+                vec![1, 2, 3]
+            );
+
+            // update
+            let prev = users.insert(2, user("bobby@b.com", 20)).unwrap().unwrap();
+            assert_eq!(prev.0.as_str(), "bob@b.com");
+
+            assert_eq!(users.indexes().0.first(&String::from("bob@b.com")), None);
+            assert_eq!(
+                users.indexes().0.first(&String::from("bobby@b.com")),
+                Some(2)
+            );
+            assert_eq!(users.indexes().1.get(&20).collect::<Vec<_>>(), vec![2, 3]);
+            assert_eq!(users.indexes().0.len(), 3);
+
+            let removed = users.remove(&1).unwrap();
+            assert_eq!(removed.1, 10);
+            assert!(users.remove(&1).is_none());
+
+            assert_eq!(users.indexes().0.first(&String::from("alice@a.com")), None);
+            assert_eq!(users.indexes().1.range(..).collect::<Vec<_>>(), vec![2, 3]);
+
+            users.clear();
+            assert!(users.is_empty());
+            assert!(users.indexes().0.is_empty());
+            assert!(users.indexes().1.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn random_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut users = Users::new();
+            let mut example = BTreeMap::new();
+
+            let mut rng = thread_rng();
+            let mut ids = (0..500u64).collect::<Vec<_>>();
+            ids.shuffle(&mut rng);
+
+            for id in ids.iter().copied() {
+                let created_at = rng.gen_range(0..50u64);
+                let email = format!("user{}@example.com", rng.gen_range(0..1000u64));
+
+                users.insert(id, user(&email, created_at)).unwrap();
+                example.insert(id, (email, created_at));
+            }
+
+            for id in ids.iter().take(200) {
+                if rng.gen_bool(0.5) {
+                    users.remove(id);
+                    example.remove(id);
+                } else {
+                    let created_at = rng.gen_range(0..50u64);
+                    users
+                        .insert(*id, user("updated@example.com", created_at))
+                        .unwrap();
+                    example.insert(*id, (String::from("updated@example.com"), created_at));
+                }
+            }
+
+            assert_eq!(users.len(), example.len() as u64);
+
+            for from in 0..50u64 {
+                let to = from + 7;
+
+                let mut expected = example
+                    .iter()
+                    .filter(|(_, (_, c))| *c >= from && *c < to)
+                    .map(|(id, (_, c))| (*c, *id))
+                    .collect::<Vec<_>>();
+                expected.sort();
+
+                let actual = users.indexes().1.range(from..to).collect::<Vec<_>>();
+                assert_eq!(
+                    actual,
+                    expected.into_iter().map(|(_, id)| id).collect::<Vec<_>>()
+                );
+            }
+
+            let updated = users
+                .indexes()
+                .0
+                .get(&String::from("updated@example.com"))
+                .collect::<Vec<_>>();
+            let expected = example
+                .iter()
+                .filter(|(_, (e, _))| e == "updated@example.com")
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+
+            assert_eq!(updated, expected);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn upgrade_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut users = Users::new();
+            for i in 0..100 {
+                users
+                    .insert(i, user(&format!("{i}@a.com"), i % 10))
+                    .unwrap();
+            }
+
+            store_custom_data(1, SBox::new(users).debugless_unwrap());
+
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let users = retrieve_custom_data::<Users>(1).unwrap().into_inner();
+
+            assert_eq!(users.len(), 100);
+            assert_eq!(users.indexes().0.first(&String::from("42@a.com")), Some(42));
+            assert_eq!(users.indexes().1.get(&3).count(), 10);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn out_of_memory_rolls_back() {
+        stable::clear();
+        init_allocator(1);
+
+        {
+            let mut pairs = Pairs::new();
+            let mut i = 0u64;
+
+            while pairs.insert(i, (i % 7, u64::MAX - i)).is_ok() {
+                i += 1;
+            }
+
+            assert_eq!(pairs.len(), i);
+            assert_eq!(pairs.indexes().0.len(), i);
+            assert_eq!(pairs.indexes().1.len(), i);
+
+            assert_eq!(pairs.indexes().1.first(&(u64::MAX - i)), None);
+            assert_eq!(pairs.indexes().1.first(&u64::MAX), Some(0));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn out_of_memory_rolls_back_boxed_keys() {
+        stable::clear();
+        init_allocator(1);
+
+        {
+            // adjacent blocks - releasing them one by one slowly grows a single free block, so
+            // every index gets its chance to be the one that runs out of memory
+            let mut ballast = (0..100)
+                .map(|_| SBox::new("0".repeat(100)).unwrap())
+                .collect::<VecDeque<_>>();
+
+            let mut users = Users::new();
+            let mut i = 0u64;
+            let mut rejected = 0;
+
+            loop {
+                // emails are tiny, but they still need some room
+                if make_sure_can_allocate(256) {
+                    let before = EMAIL_KEYS.with(Cell::get);
+                    let res = users.insert(i, user(&i.to_string(), i));
+
+                    // secondary keys are extracted once, the rollback reuses them
+                    assert_eq!(EMAIL_KEYS.with(Cell::get) - before, 1);
+
+                    if res.is_ok() {
+                        i += 1;
+                        continue;
+                    }
+
+                    rejected += 1;
+                }
+
+                assert_eq!(users.len(), i);
+                assert_eq!(users.indexes().0.len(), i);
+                assert_eq!(users.indexes().1.len(), i);
+                assert_eq!(users.indexes().0.first(&i.to_string()), None);
+                assert_eq!(users.indexes().1.first(&i), None);
+
+                if ballast.pop_front().is_none() {
+                    break;
+                }
+            }
+
+            assert!(rejected > 1);
+            assert_eq!(users.indexes().0.first(&String::from("0")), Some(0));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -11,9 +11,17 @@ struct CurSector {
     idx: u64,
 }
 
+/// An iterator over elements of [SLog], from the last one to the first one
+///
+/// Elements of each sector are read from stable memory in chunks of up to 64 KB.
 pub struct SLogIter<'a, T: StableType + AsFixedSizeBytes> {
     log: &'a SLog<T>,
     cur_sector: Option<CurSector>,
+    left: u64,
+    buf: Vec<u8>,
+    buf_sector_ptr: StablePtr,
+    buf_from_idx: u64,
+    chunk_len: usize,
 }
 
 impl<'a, T: StableType + AsFixedSizeBytes> SLogIter<'a, T> {
@@ -21,9 +29,74 @@ impl<'a, T: StableType + AsFixedSizeBytes> SLogIter<'a, T> {
         Self {
             log,
             cur_sector: None,
+            left: log.len(),
+            buf: Vec::new(),
+            buf_sector_ptr: EMPTY_PTR,
+            buf_from_idx: 0,
+            chunk_len: 0,
         }
     }
 
+    pub(crate) fn new_at(log: &'a SLog<T>, cursor: u64) -> Self {
+        let left = cursor.min(log.len());
+
+        let cur_sector = if left == 0 {
+            None
+        } else {
+            log.find_sector_for_idx(left - 1)
+                .map(|(sector, first_idx)| {
+                    let len = if sector.as_ptr() == log.cur_sector_ptr {
+                        log.cur_sector_len
+                    } else {
+                        sector.read_capacity()
+                    };
+
+                    CurSector {
+                        ptr: sector.as_ptr(),
+                        len,
+                        idx: left - 1 - first_idx,
+                    }
+                })
+        };
+
+        Self {
+            log,
+            cur_sector,
+            left,
+            buf: Vec::new(),
+            buf_sector_ptr: EMPTY_PTR,
+            buf_from_idx: 0,
+            chunk_len: 0,
+        }
+    }
+
+    /// Returns the number of elements, which are not yet returned by this iterator
+    ///
+    /// Since the iteration goes from the last element to the first one, this is also the index
+    /// right after the next element. Pass it to [SLog::rev_iter_from_cursor] to continue the
+    /// iteration from this point.
+    #[inline]
+    pub fn cursor(&self) -> u64 {
+        self.left
+    }
+
+    // reads elements [idx - chunk_len + 1, idx] of the sector, since the iteration goes backwards
+    fn read_chunk(&mut self, sector: &Sector<T>, idx: u64) {
+        self.chunk_len = crate::mem::next_read_chunk_len(self.chunk_len, T::SIZE);
+
+        let from_idx = (idx + 1).saturating_sub(self.chunk_len as u64);
+        self.buf.resize((idx + 1 - from_idx) as usize * T::SIZE, 0);
+        self.buf_sector_ptr = sector.as_ptr();
+        self.buf_from_idx = from_idx;
+
+        unsafe {
+            crate::mem::read_bytes(
+                sector.get_element_ptr(from_idx * T::SIZE as u64),
+                &mut self.buf,
+            )
+        };
+    }
+
     fn get_cur_sector_mut(&mut self) -> &mut CurSector {
         self.cur_sector.as_mut().unwrap()
     }
@@ -46,7 +119,7 @@ impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SLogIter<'a, T> {
     type Item = SRef<'a, T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.log.is_empty() {
+        if self.left == 0 {
             return None;
         }
 
@@ -55,14 +128,28 @@ impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SLogIter<'a, T> {
         let p = self.log.cur_sector_ptr;
         let c = self.log.cur_sector_capacity;
 
-        let cur_sector = self.get_cur_sector_mut();
+        let (sector_ptr, idx) = {
+            let cur_sector = self.get_cur_sector_mut();
+
+            (cur_sector.ptr, cur_sector.idx)
+        };
 
-        if cur_sector.ptr == EMPTY_PTR {
+        if sector_ptr == EMPTY_PTR {
             return None;
         }
 
-        let sector = Sector::<T>::from_ptr(cur_sector.ptr);
-        let ptr = sector.get_element_ptr(cur_sector.idx * T::SIZE as u64);
+        let sector = Sector::<T>::from_ptr(sector_ptr);
+        let ptr = sector.get_element_ptr(idx * T::SIZE as u64);
+
+        if self.buf_sector_ptr != sector_ptr || idx < self.buf_from_idx {
+            self.read_chunk(&sector, idx);
+        }
+
+        let from = (idx - self.buf_from_idx) as usize * T::SIZE;
+        let mut it = T::from_fixed_size_bytes(&self.buf[from..(from + T::SIZE)]);
+        unsafe { it.stable_drop_flag_off() };
+
+        let cur_sector = self.get_cur_sector_mut();
 
         if cur_sector.idx == 0 {
             cur_sector.len = if cur_sector.ptr == p {
@@ -76,6 +163,8 @@ impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SLogIter<'a, T> {
             cur_sector.idx -= 1;
         }
 
-        unsafe { Some(SRef::new(ptr)) }
+        self.left -= 1;
+
+        unsafe { Some(SRef::new_loaded(ptr, it)) }
     }
 }
@@ -1,11 +1,13 @@
 use crate::collections::log::iter::SLogIter;
 use crate::encoding::AsFixedSizeBytes;
 use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::deferred_drop::IncrementalDrop;
 use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
-use crate::{allocate, deallocate, OutOfMemory, SSlice};
+use crate::utils::budget::has_budget;
+use crate::{allocate, deallocate, stable, OutOfMemory, Rejected, SSlice};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
@@ -63,7 +65,7 @@ impl<T: StableType + AsFixedSizeBytes> SLog<T> {
 
     /// Inserts a new element at the end of the [SLog]
     ///
-    /// May allocate a new `Sector`. If the canister is out of stable memory, will return [Err] with
+    /// May allocate a new `Sector`. If the canister is out of stable memory, will return [Rejected] with
     /// the element that was about to get inserted.
     ///
     /// # Example
@@ -76,21 +78,22 @@ impl<T: StableType + AsFixedSizeBytes> SLog<T> {
     ///
     /// log.push(10u64).expect("Out of memory");
     /// ```
-    pub fn push(&mut self, it: T) -> Result<(), T> {
-        if let Ok(mut sector) = self.get_or_create_current_sector() {
-            if self.move_to_next_sector_if_needed(&mut sector).is_ok() {
-                sector.write_and_own_element(self.cur_sector_last_item_offset, it);
-                self.cur_sector_last_item_offset += T::SIZE as u64;
-                self.cur_sector_len += 1;
-                self.len += 1;
-
-                Ok(())
-            } else {
-                Err(it)
-            }
-        } else {
-            Err(it)
+    pub fn push(&mut self, it: T) -> Result<(), Rejected<T>> {
+        let mut sector = match self.get_or_create_current_sector() {
+            Ok(s) => s,
+            Err(e) => return Err(Rejected::new(it, e)),
+        };
+
+        if let Err(e) = self.move_to_next_sector_if_needed(&mut sector) {
+            return Err(Rejected::new(it, e));
         }
+
+        sector.write_and_own_element(self.cur_sector_last_item_offset, it);
+        self.cur_sector_last_item_offset += T::SIZE as u64;
+        self.cur_sector_len += 1;
+        self.len += 1;
+
+        Ok(())
     }
 
     /// Removes an element from the end of the [SLog]
@@ -246,6 +249,39 @@ impl<T: StableType + AsFixedSizeBytes> SLog<T> {
         SLogIter::new(self)
     }
 
+    /// Returns a back-to-front iterator over this [SLog], starting right before the `cursor` index
+    ///
+    /// The first returned element is the one at `cursor - 1`. Cursors are returned by
+    /// [SLogIter::cursor]. They are plain indices, so they can be sent to a client and used to resume
+    /// the iteration in a later call. Pushing new elements in between does not affect them. If the
+    /// cursor is bigger than the length of this [SLog], the iteration starts from the last element.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SLog;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut log = SLog::new();
+    ///
+    /// for i in 0..100 {
+    ///     log.push(i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut iter = log.rev_iter();
+    /// let first_page = iter.by_ref().take(10).map(|it| *it).collect::<Vec<_>>();
+    /// let cursor = iter.cursor();
+    ///
+    /// log.push(100).expect("Out of memory");
+    ///
+    /// let second_page = log.rev_iter_from_cursor(cursor).take(10).map(|it| *it).collect::<Vec<_>>();
+    /// assert_eq!(second_page, (80..90).rev().collect::<Vec<_>>());
+    /// ```
+    #[inline]
+    pub fn rev_iter_from_cursor(&self, cursor: u64) -> SLogIter<'_, T> {
+        SLogIter::new_at(self, cursor)
+    }
+
     fn find_sector_for_idx(&self, idx: u64) -> Option<(Sector<T>, u64)> {
         if idx >= self.len || self.len == 0 {
             return None;
@@ -331,15 +367,14 @@ impl<T: StableType + AsFixedSizeBytes> SLog<T> {
 
         let mut next_sector_capacity = self.cur_sector_capacity.checked_mul(2).unwrap();
         let mut new_sector = loop {
-            if next_sector_capacity <= DEFAULT_CAPACITY {
-                return Err(OutOfMemory);
-            }
-
             match Sector::<T>::new(next_sector_capacity, sector.as_ptr()) {
                 Ok(s) => break s,
-                Err(_) => {
+                Err(e) => {
                     next_sector_capacity /= 2;
-                    continue;
+
+                    if next_sector_capacity <= DEFAULT_CAPACITY {
+                        return Err(e);
+                    }
                 }
             };
         };
@@ -583,6 +618,37 @@ impl<T: StableType + AsFixedSizeBytes> StableType for SLog<T> {
             sector.destroy();
         }
     }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        let mut sector_ptr = self.first_sector_ptr;
+
+        while sector_ptr != EMPTY_PTR {
+            visitor(unsafe { SSlice::from_ptr(sector_ptr).unwrap() });
+
+            sector_ptr = Sector::<T>::from_ptr(sector_ptr).read_next_ptr();
+        }
+
+        for elem in self.rev_iter() {
+            elem.visit_allocations(visitor);
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> IncrementalDrop for SLog<T> {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        while !self.is_empty() && has_budget(start, instruction_budget) {
+            self.pop();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len()
+    }
 }
 
 impl<T: StableType + AsFixedSizeBytes> Drop for SLog<T> {
@@ -702,6 +768,70 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn chunked_reads_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut log = SLog::new();
+
+            for i in 0..100_000u64 {
+                log.push(i).unwrap();
+            }
+
+            let start = stable::instruction_counter();
+            let elems = log.rev_iter().map(|it| *it).collect::<Vec<_>>();
+            let spent = stable::instruction_counter() - start;
+
+            assert_eq!(elems, (0..100_000).rev().collect::<Vec<_>>());
+            // way less than a separate read per element
+            assert!(spent < 100_000 * 108 / 10);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn cursors_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut log = SLog::new();
+
+            for i in 0..300u64 {
+                log.push(i).unwrap();
+            }
+
+            for cursor in 0..=300 {
+                let elems = log
+                    .rev_iter_from_cursor(cursor)
+                    .map(|it| *it)
+                    .collect::<Vec<_>>();
+
+                assert_eq!(elems, (0..cursor).rev().collect::<Vec<_>>());
+            }
+
+            let mut iter = log.rev_iter();
+            assert_eq!(iter.cursor(), 300);
+
+            let first_page = iter.by_ref().take(150).count();
+            assert_eq!(first_page, 150);
+            assert_eq!(iter.cursor(), 150);
+
+            let cursor = iter.cursor();
+            log.push(300).unwrap();
+
+            assert_eq!(*log.rev_iter_from_cursor(cursor).next().unwrap(), 149);
+            assert_eq!(*log.rev_iter_from_cursor(1000).next().unwrap(), 300);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     enum Action {
         Push,
         Pop,
@@ -773,8 +903,8 @@ mod tests {
 
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(log) => {
-                        self.state = Some(log);
+                    Err(e) => {
+                        self.state = Some(e.into_inner());
                     }
                 },
             }
@@ -1,25 +1,61 @@
 #[doc(hidden)]
+pub mod binary_heap;
+#[doc(hidden)]
+pub mod bit_vec;
+#[doc(hidden)]
+pub mod blob;
+#[doc(hidden)]
+pub mod bloom_filter;
+#[doc(hidden)]
 pub mod btree_map;
 #[doc(hidden)]
+pub mod btree_multimap;
+#[doc(hidden)]
 pub mod btree_set;
 #[doc(hidden)]
 pub mod certified_btree_map;
 #[doc(hidden)]
 pub mod certified_btree_set;
 #[doc(hidden)]
+pub mod cuckoo_filter;
+#[doc(hidden)]
 pub mod hash_map;
 #[doc(hidden)]
 pub mod hash_set;
 #[doc(hidden)]
+pub mod indexed_table;
+#[doc(hidden)]
 pub mod log;
 #[doc(hidden)]
+pub mod ring_log;
+#[doc(hidden)]
+pub mod roaring_bitmap;
+#[doc(hidden)]
+pub mod trie;
+#[doc(hidden)]
+pub mod ttl_map;
+#[doc(hidden)]
 pub mod vec;
+#[doc(hidden)]
+pub mod vec_deque;
 
+pub use binary_heap::SBinaryHeap;
+pub use bit_vec::SBitVec;
+pub use blob::SBlob;
+pub use bloom_filter::SBloomFilter;
 pub use btree_map::SBTreeMap;
+pub use btree_multimap::SBTreeMultiMap;
 pub use btree_set::SBTreeSet;
 pub use certified_btree_map::SCertifiedBTreeMap;
 pub use certified_btree_set::SCertifiedBTreeSet;
+pub use cuckoo_filter::SCuckooFilter;
 pub use hash_map::SHashMap;
 pub use hash_set::SHashSet;
+pub use indexed_table::{IndexExtractor, SIndex, SIndexedTable, SIndexes};
 pub use log::SLog;
+pub use ring_log::SRingLog;
+pub use roaring_bitmap::SRoaringBitmap;
+pub use trie::STrie;
+pub use ttl_map::STtlMap;
 pub use vec::SVec;
+pub use vec_deque::SVecDeque;
@@ -0,0 +1,58 @@
+use crate::collections::ring_log::SRingLog;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+
+pub struct SRingLogIter<'a, T: StableType + AsFixedSizeBytes> {
+    log: &'a SRingLog<T>,
+    front_seq: u64,
+    back_seq: u64,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> SRingLogIter<'a, T> {
+    pub(crate) fn new(log: &'a SRingLog<T>) -> Self {
+        Self {
+            log,
+            front_seq: log.first_seq().unwrap_or(log.next_seq()),
+            back_seq: log.next_seq(),
+        }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SRingLogIter<'a, T> {
+    type Item = (u64, SRef<'a, T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front_seq == self.back_seq {
+            return None;
+        }
+
+        let seq = self.front_seq;
+        let ptr = self.log.get_entry_ptr(seq)?;
+        self.front_seq += 1;
+
+        unsafe { Some((seq, SRef::new(ptr))) }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.back_seq - self.front_seq) as usize;
+
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> DoubleEndedIterator for SRingLogIter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front_seq == self.back_seq {
+            return None;
+        }
+
+        self.back_seq -= 1;
+        let ptr = self.log.get_entry_ptr(self.back_seq)?;
+
+        unsafe { Some((self.back_seq, SRef::new(ptr))) }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> ExactSizeIterator for SRingLogIter<'a, T> {}
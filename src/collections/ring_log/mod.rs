@@ -0,0 +1,489 @@
+use crate::collections::ring_log::iter::SRingLogIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
+use crate::{allocate, deallocate, stable, OutOfMemory};
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+
+#[doc(hidden)]
+pub mod iter;
+
+/// Fixed-capacity ring buffer, which only keeps the last `capacity` entries
+///
+/// Allocates a single block of stable memory of `capacity * T::SIZE` bytes once, when created, and
+/// never allocates again. Because of that, [SRingLog::push] can't fail - when the buffer is full,
+/// the oldest entry gets overwritten (and stable-dropped).
+///
+/// Each pushed entry receives a sequence number. Sequence numbers start from `0` and grow
+/// monotonically for the whole lifetime of the [SRingLog], even after [SRingLog::clear]. Entries are
+/// accessed by their sequence numbers.
+///
+/// `T` has to implement both [StableType] and [AsFixedSizeBytes]. [SRingLog] itself implements these
+/// traits and can be nested inside other stable data structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SRingLog;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut log = SRingLog::<u64>::new(2).expect("Out of memory");
+///
+/// assert_eq!(log.push(10), 0);
+/// assert_eq!(log.push(20), 1);
+/// assert_eq!(log.push(30), 2);
+///
+/// // the oldest entry was overwritten
+/// assert!(log.get(0).is_none());
+/// assert_eq!(*log.get(2).unwrap(), 30);
+/// assert_eq!(log.first_seq(), Some(1));
+/// ```
+pub struct SRingLog<T: StableType + AsFixedSizeBytes> {
+    ptr: u64,
+    cap: usize,
+    len: usize,
+    next_seq: u64,
+    stable_drop_flag: bool,
+    _marker_t: PhantomData<T>,
+}
+
+impl<T: StableType + AsFixedSizeBytes> SRingLog<T> {
+    /// Creates a [SRingLog] which keeps up to `capacity` last entries
+    ///
+    /// Allocates the whole buffer right away, returning [OutOfMemory] if there is not enough stable
+    /// memory.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is `0` or if it is bigger than [SRingLog::max_capacity].
+    #[inline]
+    pub fn new(capacity: usize) -> Result<Self, OutOfMemory> {
+        assert!(capacity > 0 && capacity <= Self::max_capacity());
+
+        Ok(Self {
+            ptr: unsafe { allocate((capacity * T::SIZE) as u64)?.as_ptr() },
+            cap: capacity,
+            len: 0,
+            next_seq: 0,
+            stable_drop_flag: true,
+            _marker_t: PhantomData,
+        })
+    }
+
+    /// Returns the capacity of this [SRingLog]
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Returns the number of entries currently stored in this [SRingLog]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns [true] if there are no entries in this [SRingLog]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns [true] if the next [SRingLog::push] will overwrite the oldest entry
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.len == self.cap
+    }
+
+    /// Returns the maximum possible capacity of this [SRingLog]
+    #[inline]
+    pub const fn max_capacity() -> usize {
+        // zero-sized entries don't occupy any memory
+        match (u32::MAX as usize).checked_div(T::SIZE) {
+            Some(it) => it,
+            None => u32::MAX as usize,
+        }
+    }
+
+    /// Pushes a new entry into this [SRingLog], returning its sequence number
+    ///
+    /// If the [SRingLog] is full, the oldest entry is overwritten and stable-dropped.
+    pub fn push(&mut self, mut element: T) -> u64 {
+        let seq = self.next_seq;
+        let ptr = self.seq_ptr(seq);
+
+        if self.is_full() {
+            let oldest = unsafe { crate::mem::read_fixed_for_move::<T>(ptr) };
+            drop(oldest);
+        } else {
+            self.len += 1;
+        }
+
+        unsafe { crate::mem::write_fixed(ptr, &mut element) };
+        self.next_seq += 1;
+
+        seq
+    }
+
+    /// Returns the sequence number of the oldest entry
+    ///
+    /// If the [SRingLog] is empty, returns [None].
+    #[inline]
+    pub fn first_seq(&self) -> Option<u64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.next_seq - self.len as u64)
+        }
+    }
+
+    /// Returns the sequence number of the most recent entry
+    ///
+    /// If the [SRingLog] is empty, returns [None].
+    #[inline]
+    pub fn last_seq(&self) -> Option<u64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.next_seq - 1)
+        }
+    }
+
+    /// Returns the sequence number the next pushed entry will receive
+    #[inline]
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Returns an immutable reference [SRef] to the entry with the requested sequence number
+    ///
+    /// If there is no such entry (it was not pushed yet or was already overwritten), returns [None].
+    #[inline]
+    pub fn get(&self, seq: u64) -> Option<SRef<'_, T>> {
+        let ptr = self.get_entry_ptr(seq)?;
+
+        unsafe { Some(SRef::new(ptr)) }
+    }
+
+    /// Returns a mutable reference [SRefMut] to the entry with the requested sequence number
+    ///
+    /// If there is no such entry (it was not pushed yet or was already overwritten), returns [None].
+    #[inline]
+    pub fn get_mut(&mut self, seq: u64) -> Option<SRefMut<'_, T>> {
+        let ptr = self.get_entry_ptr(seq)?;
+
+        unsafe { Some(SRefMut::new(ptr)) }
+    }
+
+    /// Returns an immutable reference [SRef] to the most recent entry
+    #[inline]
+    pub fn last(&self) -> Option<SRef<'_, T>> {
+        self.get(self.last_seq()?)
+    }
+
+    /// Returns an iterator over `(sequence number, entry)` pairs, from the oldest to the most recent
+    #[inline]
+    pub fn iter(&self) -> SRingLogIter<'_, T> {
+        SRingLogIter::new(self)
+    }
+
+    /// Removes all entries from this [SRingLog]
+    ///
+    /// Does not deallocate the buffer. Sequence numbers keep growing from where they were.
+    pub fn clear(&mut self) {
+        if let Some(first_seq) = self.first_seq() {
+            for seq in first_seq..self.next_seq {
+                let it = unsafe { crate::mem::read_fixed_for_move::<T>(self.seq_ptr(seq)) };
+                drop(it);
+            }
+        }
+
+        self.len = 0;
+    }
+
+    #[inline]
+    fn seq_ptr(&self, seq: u64) -> StablePtr {
+        let idx = (seq % self.cap as u64) as usize;
+
+        SSlice::_offset(self.ptr, (idx * T::SIZE) as u64)
+    }
+
+    pub(crate) fn get_entry_ptr(&self, seq: u64) -> Option<StablePtr> {
+        if seq >= self.first_seq()? && seq < self.next_seq {
+            Some(self.seq_ptr(seq))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Debug> Debug for SRingLog<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (idx, (seq, item)) in self.iter().enumerate() {
+            seq.fmt(f)?;
+            f.write_str(": ")?;
+            item.fmt(f)?;
+
+            if idx < self.len - 1 {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("]")
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> AsFixedSizeBytes for SRingLog<T> {
+    const SIZE: usize = u64::SIZE * 2 + usize::SIZE * 2;
+    type Buf = [u8; u64::SIZE * 2 + usize::SIZE * 2];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.ptr.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.cap
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE + usize::SIZE)]);
+        self.len.as_fixed_size_bytes(
+            &mut buf[(u64::SIZE + usize::SIZE)..(u64::SIZE + usize::SIZE * 2)],
+        );
+        self.next_seq.as_fixed_size_bytes(
+            &mut buf[(u64::SIZE + usize::SIZE * 2)..(u64::SIZE * 2 + usize::SIZE * 2)],
+        );
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let ptr = u64::from_fixed_size_bytes(&arr[0..u64::SIZE]);
+        let cap = usize::from_fixed_size_bytes(&arr[u64::SIZE..(u64::SIZE + usize::SIZE)]);
+        let len = usize::from_fixed_size_bytes(
+            &arr[(u64::SIZE + usize::SIZE)..(u64::SIZE + usize::SIZE * 2)],
+        );
+        let next_seq = u64::from_fixed_size_bytes(
+            &arr[(u64::SIZE + usize::SIZE * 2)..(u64::SIZE * 2 + usize::SIZE * 2)],
+        );
+
+        Self {
+            ptr,
+            cap,
+            len,
+            next_seq,
+            stable_drop_flag: false,
+            _marker_t: PhantomData,
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> StableType for SRingLog<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    unsafe fn stable_drop(&mut self) {
+        self.clear();
+
+        let slice = SSlice::from_ptr(self.ptr).unwrap();
+
+        deallocate(slice);
+    }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        visitor(unsafe { SSlice::from_ptr(self.ptr).unwrap() });
+
+        for (_, elem) in self.iter() {
+            elem.visit_allocations(visitor);
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> IncrementalDrop for SRingLog<T> {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        // entries are released from the oldest one, so sequence numbers stay valid
+        while let Some(first_seq) = self.first_seq() {
+            if !has_budget(start, instruction_budget) {
+                break;
+            }
+
+            let it = unsafe { crate::mem::read_fixed_for_move::<T>(self.seq_ptr(first_seq)) };
+            drop(it);
+
+            self.len -= 1;
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len as u64
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> Drop for SRingLog<T> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::ring_log::SRingLog;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::s_box::SBox;
+    use crate::utils::mem_context::stable;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data,
+    };
+
+    #[test]
+    fn basic_flow_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut log = SRingLog::new(3).unwrap();
+            assert!(log.is_empty());
+            assert!(log.first_seq().is_none());
+            assert!(log.last_seq().is_none());
+            assert!(log.last().is_none());
+            assert!(log.get(0).is_none());
+
+            for i in 0..10u64 {
+                assert_eq!(log.push(i * 10), i);
+                assert_eq!(log.len(), (i as usize + 1).min(3));
+                assert_eq!(*log.last().unwrap(), i * 10);
+            }
+
+            assert!(log.is_full());
+            assert_eq!(log.first_seq(), Some(7));
+            assert_eq!(log.last_seq(), Some(9));
+            assert_eq!(log.next_seq(), 10);
+
+            assert!(log.get(6).is_none());
+            assert!(log.get(10).is_none());
+            assert_eq!(*log.get(8).unwrap(), 80);
+
+            *log.get_mut(8).unwrap() = 81;
+
+            assert_eq!(
+                log.iter().map(|(seq, it)| (seq, *it)).collect::<Vec<_>>(),
+                vec![(7, 70), (8, 81), (9, 90)]
+            );
+            assert_eq!(
+                log.iter().rev().map(|(seq, _)| seq).collect::<Vec<_>>(),
+                vec![9, 8, 7]
+            );
+
+            log.clear();
+            assert!(log.is_empty());
+            assert_eq!(log.iter().count(), 0);
+            assert_eq!(log.push(100), 10);
+            assert_eq!(log.first_seq(), Some(10));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn zero_sized_entries_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            assert_eq!(SRingLog::<()>::max_capacity(), u32::MAX as usize);
+
+            let mut log = SRingLog::new(2).unwrap();
+            for i in 0..5u64 {
+                assert_eq!(log.push(()), i);
+            }
+
+            assert_eq!(log.len(), 2);
+            assert_eq!(log.first_seq(), Some(3));
+            assert_eq!(log.iter().count(), 2);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn never_allocates_after_creation() {
+        stable::clear();
+        init_allocator(1);
+
+        {
+            let mut log = SRingLog::new(100).unwrap();
+
+            // exhausting the rest of stable memory
+            let mut garbage = Vec::new();
+            while let Ok(it) = SBox::new(0u64) {
+                garbage.push(it);
+            }
+
+            for i in 0..1000u64 {
+                log.push(i);
+            }
+
+            assert_eq!(log.len(), 100);
+            assert_eq!(*log.get(999).unwrap(), 999);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn sboxes_and_upgrades_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut log = SRingLog::new(10).unwrap();
+
+            for i in 0..25 {
+                log.push(SBox::new(format!("entry {}", i)).unwrap());
+            }
+
+            let buf = log.as_new_fixed_size_bytes();
+            store_custom_data(1, SBox::new(log).unwrap());
+
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let mut log = retrieve_custom_data::<SRingLog<SBox<String>>>(1)
+                .unwrap()
+                .into_inner();
+            assert_eq!(log.as_new_fixed_size_bytes()._deref(), buf._deref());
+
+            for (seq, it) in log.iter() {
+                assert_eq!(**it, format!("entry {}", seq));
+            }
+
+            log.push(SBox::new(String::from("entry 25")).unwrap());
+            assert_eq!(log.first_seq(), Some(16));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
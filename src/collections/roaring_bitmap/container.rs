@@ -0,0 +1,196 @@
+use crate::collections::bit_vec::SBitVec;
+use crate::collections::vec::SVec;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::StableType;
+use crate::{OutOfMemory, SSlice};
+
+// containers with more values are stored as bitmaps
+pub(crate) const ARRAY_MAX_LEN: usize = 4096;
+pub(crate) const BITMAP_BITS: u64 = 1 << 16;
+pub(crate) const BITMAP_WORDS: usize = (BITMAP_BITS / u64::BITS as u64) as usize;
+
+const ARRAY_TAG: u8 = 0;
+const BITMAP_TAG: u8 = 1;
+
+const PAYLOAD_SIZE: usize = if SVec::<u16>::SIZE > SBitVec::SIZE {
+    SVec::<u16>::SIZE
+} else {
+    SBitVec::SIZE
+};
+
+// Low 16 bits of all the values with the same high 16 bits
+pub(crate) enum Container {
+    // sorted values, used for sparse containers
+    Array(SVec<u16>),
+    // 2^16 bits, used for dense containers
+    Bitmap(SBitVec),
+}
+
+impl Container {
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            Container::Array(array) => array.len(),
+            Container::Bitmap(bits) => bits.count_ones() as usize,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, low: u16) -> bool {
+        match self {
+            Container::Array(array) => array.binary_search_by(|it| it.cmp(&low)).is_ok(),
+            Container::Bitmap(bits) => bits.get(low as u64),
+        }
+    }
+
+    // reads all the values into heap memory
+    pub fn values(&self) -> Vec<u16> {
+        match self {
+            Container::Array(array) => array.read_range(..),
+            Container::Bitmap(bits) => values_from_words(&bits.read_words(0, BITMAP_WORDS)),
+        }
+    }
+
+    // reads the container into heap memory as 2^16 bits
+    pub fn to_words(&self) -> Vec<u64> {
+        match self {
+            Container::Array(array) => words_from_values(&array.read_range(..)),
+            Container::Bitmap(bits) => bits.read_words(0, BITMAP_WORDS),
+        }
+    }
+
+    // picks the representation by the number of values
+    pub fn from_words(words: &[u64]) -> Result<Self, OutOfMemory> {
+        if count_ones(words) <= ARRAY_MAX_LEN {
+            let mut array = SVec::new();
+            array
+                .extend_from_iter(values_from_words(words))
+                .map_err(|e| e.reason)?;
+
+            Ok(Container::Array(array))
+        } else {
+            Ok(Container::Bitmap(SBitVec::from_words(words, BITMAP_BITS)?))
+        }
+    }
+
+    // replaces the values of this container, reusing its stable memory when possible
+    //
+    // never fails, if the new set of values is a subset of the current one
+    pub fn set_words(&mut self, words: &[u64]) -> Result<(), OutOfMemory> {
+        let len = count_ones(words);
+
+        match self {
+            Container::Array(array) if len <= ARRAY_MAX_LEN => {
+                let values = values_from_words(words);
+
+                if values.len() > array.len() {
+                    array
+                        .extend_from_iter(values[array.len()..].iter().copied())
+                        .map_err(|e| e.reason)?;
+                } else {
+                    array.truncate(values.len());
+                }
+
+                array.write_range(0, &values);
+            }
+            Container::Bitmap(bits) if len > ARRAY_MAX_LEN => bits.write_words(words),
+            _ => match Container::from_words(words) {
+                Ok(it) => *self = it,
+                Err(e) => match self {
+                    // a bitmap is only less compact than an array, so it is fine to keep it
+                    Container::Bitmap(bits) => bits.write_words(words),
+                    Container::Array(_) => return Err(e),
+                },
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[inline]
+pub(crate) fn count_ones(words: &[u64]) -> usize {
+    words.iter().map(|it| it.count_ones() as usize).sum()
+}
+
+pub(crate) fn words_from_values(values: &[u16]) -> Vec<u64> {
+    let mut words = vec![0u64; BITMAP_WORDS];
+
+    for value in values {
+        words[*value as usize / u64::BITS as usize] |= 1 << (*value as u32 % u64::BITS);
+    }
+
+    words
+}
+
+pub(crate) fn values_from_words(words: &[u64]) -> Vec<u16> {
+    let mut values = Vec::with_capacity(count_ones(words));
+
+    for (idx, word) in words.iter().enumerate() {
+        let mut word = *word;
+
+        while word != 0 {
+            values.push((idx as u32 * u64::BITS + word.trailing_zeros()) as u16);
+
+            // unsets the lowest set bit
+            word &= word - 1;
+        }
+    }
+
+    values
+}
+
+impl AsFixedSizeBytes for Container {
+    const SIZE: usize = u8::SIZE + PAYLOAD_SIZE;
+    type Buf = [u8; u8::SIZE + PAYLOAD_SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        match self {
+            Container::Array(array) => {
+                buf[0] = ARRAY_TAG;
+                array.as_fixed_size_bytes(&mut buf[u8::SIZE..(u8::SIZE + SVec::<u16>::SIZE)]);
+            }
+            Container::Bitmap(bits) => {
+                buf[0] = BITMAP_TAG;
+                bits.as_fixed_size_bytes(&mut buf[u8::SIZE..(u8::SIZE + SBitVec::SIZE)]);
+            }
+        }
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        match arr[0] {
+            ARRAY_TAG => Container::Array(SVec::from_fixed_size_bytes(
+                &arr[u8::SIZE..(u8::SIZE + SVec::<u16>::SIZE)],
+            )),
+            _ => Container::Bitmap(SBitVec::from_fixed_size_bytes(
+                &arr[u8::SIZE..(u8::SIZE + SBitVec::SIZE)],
+            )),
+        }
+    }
+}
+
+impl StableType for Container {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        match self {
+            Container::Array(array) => array.stable_drop_flag_on(),
+            Container::Bitmap(bits) => bits.stable_drop_flag_on(),
+        }
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        match self {
+            Container::Array(array) => array.stable_drop_flag_off(),
+            Container::Bitmap(bits) => bits.stable_drop_flag_off(),
+        }
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        match self {
+            Container::Array(array) => array.visit_allocations(visitor),
+            Container::Bitmap(bits) => bits.visit_allocations(visitor),
+        }
+    }
+}
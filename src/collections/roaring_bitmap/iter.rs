@@ -0,0 +1,41 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::collections::roaring_bitmap::container::Container;
+
+pub struct SRoaringBitmapIter<'a> {
+    containers: SBTreeMapIter<'a, u16, Container>,
+    high: u32,
+    // low bits of values of the current container
+    values: Vec<u16>,
+    idx: usize,
+}
+
+impl<'a> SRoaringBitmapIter<'a> {
+    #[inline]
+    pub(crate) fn new(containers: SBTreeMapIter<'a, u16, Container>) -> Self {
+        Self {
+            containers,
+            high: 0,
+            values: Vec::new(),
+            idx: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for SRoaringBitmapIter<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.idx == self.values.len() {
+            let (high, container) = self.containers.next()?;
+
+            self.high = *high as u32;
+            self.values = container.values();
+            self.idx = 0;
+        }
+
+        let low = self.values[self.idx];
+        self.idx += 1;
+
+        Some(self.high << 16 | low as u32)
+    }
+}
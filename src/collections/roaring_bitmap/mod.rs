@@ -0,0 +1,667 @@
+use crate::collections::bit_vec::SBitVec;
+use crate::collections::btree_map::SBTreeMap;
+use crate::collections::roaring_bitmap::container::{
+    count_ones, words_from_values, Container, ARRAY_MAX_LEN, BITMAP_BITS,
+};
+use crate::collections::roaring_bitmap::iter::SRoaringBitmapIter;
+use crate::collections::vec::SVec;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::primitive::StableType;
+use crate::{OutOfMemory, SSlice};
+use std::fmt::{Debug, Formatter};
+
+pub(crate) mod container;
+#[doc(hidden)]
+pub mod iter;
+
+/// Compressed bitmap of [u32] values
+///
+/// Values are split by their high 16 bits into containers, stored in a [SBTreeMap]. A container
+/// with up to 4096 values is a sorted [SVec]`<u16>` of their low bits, a container with more values
+/// is a [SBitVec] of 2^16 bits (8KB). This way both sparse and dense sets take little space - at
+/// most 2 bytes per value, e.g. ~12.5MB for any set of values less than 100M.
+///
+/// [SRoaringBitmap::union_with] and [SRoaringBitmap::intersect_with] process containers one by
+/// one, reading each of them into heap memory as 8KB of bits.
+///
+/// [SRoaringBitmap] implements [StableType] and [AsFixedSizeBytes], so you can nest it in other
+/// stable structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SRoaringBitmap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut minted = SRoaringBitmap::new();
+/// let mut listed = SRoaringBitmap::new();
+///
+/// for id in 0..10_000u32 {
+///     minted.insert(id).expect("Out of memory");
+/// }
+/// listed.insert(5).expect("Out of memory");
+/// listed.insert(20_000).expect("Out of memory");
+///
+/// assert!(minted.contains(9_999));
+/// assert_eq!(minted.len(), 10_000);
+///
+/// // listed, but not yet minted tokens are filtered out
+/// listed.intersect_with(&minted);
+/// assert_eq!(listed.iter().collect::<Vec<_>>(), vec![5]);
+/// ```
+pub struct SRoaringBitmap {
+    containers: SBTreeMap<u16, Container>,
+    len: u64,
+}
+
+impl SRoaringBitmap {
+    /// Creates a new empty [SRoaringBitmap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            containers: SBTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Returns the number of values in this [SRoaringBitmap]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns [true] if there are no values in this [SRoaringBitmap]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts the value into this [SRoaringBitmap]
+    ///
+    /// Returns `true` if the value was already present. If your canister is out of stable memory,
+    /// returns [OutOfMemory], leaving the [SRoaringBitmap] untouched.
+    pub fn insert(&mut self, value: u32) -> Result<bool, OutOfMemory> {
+        let (high, low) = split(value);
+
+        if !self.containers.contains_key(&high) {
+            let mut array = SVec::new();
+            array.push(low).map_err(|e| e.reason)?;
+
+            self.containers
+                .insert(high, Container::Array(array))
+                .map_err(|e| e.reason)?;
+            self.len += 1;
+
+            return Ok(false);
+        }
+
+        let mut container = self.containers.get_mut(&high).unwrap();
+
+        let replacement = match &mut *container {
+            Container::Array(array) => {
+                let idx = match array.binary_search_by(|it| it.cmp(&low)) {
+                    Ok(_) => return Ok(true),
+                    Err(idx) => idx,
+                };
+
+                if array.len() < ARRAY_MAX_LEN {
+                    array.insert(idx, low).map_err(|e| e.reason)?;
+
+                    None
+                } else {
+                    let mut words = words_from_values(&array.read_range(..));
+                    words[low as usize / u64::BITS as usize] |= 1 << (low as u32 % u64::BITS);
+
+                    Some(Container::Bitmap(SBitVec::from_words(&words, BITMAP_BITS)?))
+                }
+            }
+            Container::Bitmap(bits) => {
+                if bits.set(low as u64, true) {
+                    return Ok(true);
+                }
+
+                None
+            }
+        };
+
+        if let Some(it) = replacement {
+            // the array gets stable-dropped here
+            *container = it;
+        }
+
+        self.len += 1;
+
+        Ok(false)
+    }
+
+    /// Removes the value from this [SRoaringBitmap]
+    ///
+    /// Returns `true` if the value was present.
+    pub fn remove(&mut self, value: u32) -> bool {
+        let (high, low) = split(value);
+
+        let mut container = match self.containers.get_mut(&high) {
+            Some(it) => it,
+            None => return false,
+        };
+
+        let removed = match &mut *container {
+            Container::Array(array) => match array.binary_search_by(|it| it.cmp(&low)) {
+                Ok(idx) => {
+                    array.remove(idx);
+                    true
+                }
+                Err(_) => false,
+            },
+            Container::Bitmap(bits) => bits.set(low as u64, false),
+        };
+
+        if !removed {
+            return false;
+        }
+
+        self.len -= 1;
+
+        if container.len() == 0 {
+            drop(container);
+            self.containers.remove(&high);
+
+            return true;
+        }
+
+        let replacement = match &*container {
+            Container::Bitmap(bits) if bits.count_ones() as usize <= ARRAY_MAX_LEN => {
+                let mut array = SVec::new();
+
+                // an array is only more compact than a bitmap, so it is fine to skip this
+                array
+                    .extend_from_iter(bits.iter_ones().map(|it| it as u16))
+                    .ok()
+                    .map(|_| Container::Array(array))
+            }
+            _ => None,
+        };
+
+        if let Some(it) = replacement {
+            // the bitmap gets stable-dropped here
+            *container = it;
+        }
+
+        true
+    }
+
+    /// Returns [true] if the value is present in this [SRoaringBitmap]
+    #[inline]
+    pub fn contains(&self, value: u32) -> bool {
+        let (high, low) = split(value);
+
+        match self.containers.get(&high) {
+            Some(container) => container.contains(low),
+            None => false,
+        }
+    }
+
+    /// Adds all the values of the other [SRoaringBitmap] to this one
+    ///
+    /// May allocate new containers or reallocate existing ones. If your canister is out of stable
+    /// memory, returns [OutOfMemory]. In this case, this [SRoaringBitmap] is left valid, but may
+    /// contain only some of the values of the other one.
+    pub fn union_with(&mut self, other: &SRoaringBitmap) -> Result<(), OutOfMemory> {
+        for (high, other_container) in other.containers.iter() {
+            if !self.containers.contains_key(&*high) {
+                let container = Container::from_words(&other_container.to_words())?;
+                let len = container.len() as u64;
+
+                self.containers
+                    .insert(*high, container)
+                    .map_err(|e| e.reason)?;
+                self.len += len;
+
+                continue;
+            }
+
+            let mut container = self.containers.get_mut(&*high).unwrap();
+
+            let mut words = container.to_words();
+            for (word, other_word) in words.iter_mut().zip(other_container.to_words()) {
+                *word |= other_word;
+            }
+
+            let old_len = container.len();
+            let new_len = count_ones(&words);
+
+            if new_len != old_len {
+                container.set_words(&words)?;
+                self.len += (new_len - old_len) as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes all the values, that are not present in the other [SRoaringBitmap], from this one
+    ///
+    /// Never allocates new stable memory.
+    pub fn intersect_with(&mut self, other: &SRoaringBitmap) {
+        let highs = self
+            .containers
+            .iter()
+            .map(|(high, _)| *high)
+            .collect::<Vec<_>>();
+
+        for high in highs {
+            let other_words = match other.containers.get(&high) {
+                Some(it) => it.to_words(),
+                None => {
+                    let container = self.containers.remove(&high).unwrap();
+                    self.len -= container.len() as u64;
+
+                    continue;
+                }
+            };
+
+            let mut container = self.containers.get_mut(&high).unwrap();
+
+            let mut words = container.to_words();
+            for (word, other_word) in words.iter_mut().zip(other_words) {
+                *word &= other_word;
+            }
+
+            let old_len = container.len();
+            let new_len = count_ones(&words);
+
+            if new_len == old_len {
+                continue;
+            }
+
+            self.len -= (old_len - new_len) as u64;
+
+            if new_len == 0 {
+                drop(container);
+                self.containers.remove(&high);
+            } else {
+                // the container only loses values, this can't fail
+                container.set_words(&words).unwrap();
+            }
+        }
+    }
+
+    /// Returns an iterator over all the values of this [SRoaringBitmap] in ascending order
+    ///
+    /// Reads one container into heap memory at a time.
+    #[inline]
+    pub fn iter(&self) -> SRoaringBitmapIter<'_> {
+        SRoaringBitmapIter::new(self.containers.iter())
+    }
+
+    /// Removes all the values from this [SRoaringBitmap], releasing its stable memory
+    #[inline]
+    pub fn clear(&mut self) {
+        self.containers.clear();
+        self.len = 0;
+    }
+}
+
+#[inline]
+fn split(value: u32) -> (u16, u16) {
+    ((value >> 16) as u16, value as u16)
+}
+
+impl Default for SRoaringBitmap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsFixedSizeBytes for SRoaringBitmap {
+    const SIZE: usize = SBTreeMap::<u16, Container>::SIZE + u64::SIZE;
+    type Buf = [u8; SBTreeMap::<u16, Container>::SIZE + u64::SIZE];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let map_size = SBTreeMap::<u16, Container>::SIZE;
+
+        self.containers.as_fixed_size_bytes(&mut buf[0..map_size]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[map_size..(map_size + u64::SIZE)]);
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let map_size = SBTreeMap::<u16, Container>::SIZE;
+
+        Self {
+            containers: SBTreeMap::from_fixed_size_bytes(&arr[0..map_size]),
+            len: u64::from_fixed_size_bytes(&arr[map_size..(map_size + u64::SIZE)]),
+        }
+    }
+}
+
+impl StableType for SRoaringBitmap {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.containers.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.containers.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.containers.visit_allocations(visitor);
+    }
+}
+
+impl IncrementalDrop for SRoaringBitmap {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let done = self.containers.clear_incremental(instruction_budget);
+
+        if done {
+            self.len = 0;
+        }
+
+        done
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.containers.pending_elements()
+    }
+}
+
+impl Debug for SRoaringBitmap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("(")?;
+        for (idx, elem) in self.iter().enumerate() {
+            elem.fmt(f)?;
+
+            if (idx as u64) < self.len() - 1 {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str(")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::roaring_bitmap::SRoaringBitmap;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut bitmap = SRoaringBitmap::new();
+            let mut example = BTreeSet::new();
+            let mut rng = thread_rng();
+
+            // a dense container, a sparse container and a couple of lonely values
+            for value in (0..20_000u32)
+                .chain((1 << 16)..(1 << 16) + 300)
+                .chain([u32::MAX, 1 << 20])
+            {
+                assert!(!bitmap.insert(value).unwrap());
+                example.insert(value);
+            }
+            assert!(bitmap.insert(5).unwrap());
+
+            assert_eq!(bitmap.len(), example.len() as u64);
+            assert!(bitmap.contains(u32::MAX));
+            assert!(!bitmap.contains(20_000));
+            assert_eq!(
+                bitmap.iter().collect::<Vec<_>>(),
+                example.iter().copied().collect::<Vec<_>>()
+            );
+
+            // the dense container turns back into an array
+            for _ in 0..40_000 {
+                let value = rng.gen_range(0..20_000u32);
+
+                assert_eq!(bitmap.remove(value), example.remove(&value));
+            }
+
+            assert!(!bitmap.remove(20_000));
+            assert_eq!(bitmap.len(), example.len() as u64);
+            assert_eq!(
+                bitmap.iter().collect::<Vec<_>>(),
+                example.iter().copied().collect::<Vec<_>>()
+            );
+
+            for value in example.clone() {
+                assert!(bitmap.remove(value));
+            }
+
+            assert!(bitmap.is_empty());
+            assert_eq!(bitmap.iter().next(), None);
+
+            println!("{:?}", bitmap);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn union_and_intersection_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+
+            for density in [10, 1_000, 30_000] {
+                let mut a = SRoaringBitmap::new();
+                let mut b = SRoaringBitmap::new();
+                let mut example_a = BTreeSet::new();
+                let mut example_b = BTreeSet::new();
+
+                for _ in 0..density {
+                    let value = rng.gen_range(0..(3 << 16));
+                    a.insert(value).unwrap();
+                    example_a.insert(value);
+
+                    let value = rng.gen_range((1 << 16)..(4 << 16));
+                    b.insert(value).unwrap();
+                    example_b.insert(value);
+                }
+
+                let mut union = SRoaringBitmap::new();
+                union.union_with(&a).unwrap();
+                union.union_with(&b).unwrap();
+
+                let expected = example_a.union(&example_b).copied().collect::<Vec<_>>();
+                assert_eq!(union.len(), expected.len() as u64);
+                assert_eq!(union.iter().collect::<Vec<_>>(), expected);
+
+                a.intersect_with(&b);
+
+                let expected = example_a
+                    .intersection(&example_b)
+                    .copied()
+                    .collect::<Vec<_>>();
+                assert_eq!(a.len(), expected.len() as u64);
+                assert_eq!(a.iter().collect::<Vec<_>>(), expected);
+
+                union.intersect_with(&SRoaringBitmap::new());
+                assert!(union.is_empty());
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[derive(Debug)]
+    enum Action {
+        Insert,
+        Remove,
+        Union,
+        Intersection,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        bitmap: Option<SRoaringBitmap>,
+        example: BTreeSet<u32>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Fuzzer {
+            Fuzzer {
+                bitmap: Some(SRoaringBitmap::new()),
+                example: BTreeSet::new(),
+                rng: thread_rng(),
+                log: Vec::new(),
+            }
+        }
+
+        fn bitmap(&mut self) -> &mut SRoaringBitmap {
+            self.bitmap.as_mut().unwrap()
+        }
+
+        fn random_value(&mut self) -> u32 {
+            self.rng.gen_range(0..(4 << 16))
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // INSERT ~60%
+                0..=59 => {
+                    for _ in 0..100 {
+                        let value = self.random_value();
+
+                        if let Ok(existed) = self.bitmap().insert(value) {
+                            assert_eq!(existed, !self.example.insert(value));
+                        }
+                    }
+
+                    self.log.push(Action::Insert);
+                }
+                // REMOVE
+                60..=89 => {
+                    for _ in 0..100 {
+                        let value = self.random_value();
+
+                        assert_eq!(self.bitmap().remove(value), self.example.remove(&value));
+                    }
+
+                    self.log.push(Action::Remove);
+                }
+                // UNION
+                90..=94 => {
+                    let mut other = SRoaringBitmap::new();
+                    let from = self.random_value();
+
+                    for value in from..(from + 5_000) {
+                        if other.insert(value).is_err() {
+                            return;
+                        }
+                    }
+
+                    if self.bitmap().union_with(&other).is_ok() {
+                        self.example.extend(from..(from + 5_000));
+                    } else {
+                        // some values may have been added
+                        self.example = self.bitmap().iter().collect();
+                    }
+
+                    self.log.push(Action::Union);
+                }
+                // INTERSECTION
+                95..=96 => {
+                    let mut other = SRoaringBitmap::new();
+                    let from = self.random_value() / 2;
+
+                    for value in from..(from + 100_000) {
+                        if other.insert(value).is_err() {
+                            return;
+                        }
+                    }
+
+                    self.bitmap().intersect_with(&other);
+                    self.example
+                        .retain(|it| (from..(from + 100_000)).contains(it));
+
+                    self.log.push(Action::Intersection);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.bitmap.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.bitmap =
+                            retrieve_custom_data::<SRoaringBitmap>(1).map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(e) => {
+                        self.bitmap = Some(e.into_inner());
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.bitmap().len() as usize, self.example.len());
+            assert!(self
+                .bitmap
+                .as_ref()
+                .unwrap()
+                .iter()
+                .eq(self.example.iter().copied()));
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..300 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..300 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::collections::trie::node::TrieNode;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+use std::marker::PhantomData;
+
+pub struct STrieIter<'a, V> {
+    // nodes left to visit, each one with the part of the key that goes before its prefix
+    stack: Vec<(StablePtr, Vec<u8>)>,
+    _marker: PhantomData<&'a V>,
+}
+
+impl<'a, V> STrieIter<'a, V> {
+    #[inline]
+    pub(crate) fn new(start: Option<(StablePtr, Vec<u8>)>) -> Self {
+        Self {
+            stack: start.into_iter().collect(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, V: StableType + AsFixedSizeBytes> Iterator for STrieIter<'a, V> {
+    type Item = (Vec<u8>, SRef<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((ptr, mut key)) = self.stack.pop() {
+            let node = TrieNode::<V>::from_ptr(ptr);
+            let data = node.load();
+
+            key.extend_from_slice(&data.prefix);
+
+            // pushed in reverse, so the smallest child is visited first
+            for child in data.children.iter().rev() {
+                self.stack.push((*child, key.clone()));
+            }
+
+            // a node's own key is smaller than any key in its subtree
+            if data.has_value {
+                return Some((key, node.get_value()));
+            }
+        }
+
+        None
+    }
+}
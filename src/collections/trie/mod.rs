@@ -0,0 +1,914 @@
+use crate::collections::trie::iter::STrieIter;
+use crate::collections::trie::node::{NodeData, TrieNode};
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
+use crate::{stable, OutOfMemory, Rejected, SSlice};
+use std::fmt::{Debug, Formatter};
+use std::mem;
+
+pub mod iter;
+pub(crate) mod node;
+
+// A node on the way from the root, its data and the index of the child the way continues with
+type PathEntry<V> = (TrieNode<V>, NodeData, usize);
+
+/// Radix tree (compressed trie) based map data structure with byte-string keys
+///
+/// Each node stores a common prefix of all the keys in its subtree, an optional value and pointers
+/// to its children, ordered by the first byte of their prefixes. Chains of nodes without values
+/// are compressed into a single node, so a lookup visits at most one node per branching point of
+/// the key. Each node is a single stable memory allocation - it is reallocated when it grows.
+///
+/// Entries are iterated in lexicographic order of their keys. Besides regular map operations, this
+/// data structure can efficiently list all the entries with the given prefix and find the longest
+/// prefix of a key, present in the map.
+///
+/// `V` has to implement [StableType] and [AsFixedSizeBytes]. [STrie] also implements these traits,
+/// so you can nest it in other stable structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::STrie;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut routes = STrie::new();
+///
+/// routes.insert(b"/api", 1u64).expect("Out of memory");
+/// routes.insert(b"/api/users", 2).expect("Out of memory");
+/// routes.insert(b"/static", 3).expect("Out of memory");
+///
+/// let (len, route) = routes.longest_prefix_match(b"/api/users/10").unwrap();
+/// assert_eq!(len, 10);
+/// assert_eq!(*route, 2);
+///
+/// let api: Vec<Vec<u8>> = routes.iter_prefix(b"/api").map(|(key, _)| key).collect();
+/// assert_eq!(api, vec![b"/api".to_vec(), b"/api/users".to_vec()]);
+/// ```
+pub struct STrie<V: StableType + AsFixedSizeBytes> {
+    root: Option<TrieNode<V>>,
+    len: u64,
+    stable_drop_flag: bool,
+}
+
+impl<V: StableType + AsFixedSizeBytes> STrie<V> {
+    /// Creates a new [STrie]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            stable_drop_flag: true,
+        }
+    }
+
+    /// Inserts the value by the key
+    ///
+    /// Returns the previous value, if there was one. May allocate up to two new nodes and
+    /// reallocate an existing one. If your canister is out of stable memory, returns [Rejected]
+    /// with the value - the trie stays the same in this case.
+    pub fn insert(&mut self, key: &[u8], value: V) -> Result<Option<V>, Rejected<V>> {
+        let mut node = match self.get_or_create_root() {
+            Ok(it) => it,
+            Err(e) => return Err(Rejected::new(value, e)),
+        };
+        let mut parent: Option<PathEntry<V>> = None;
+        let mut rest = key;
+
+        loop {
+            let mut data = node.load();
+            let common = common_prefix_len(&data.prefix, rest);
+
+            if common < data.prefix.len() {
+                return self
+                    .split_and_insert(parent, node, data, common, &rest[common..], value)
+                    .map(|_| None);
+            }
+
+            rest = &rest[common..];
+
+            if rest.is_empty() {
+                let prev = if data.has_value {
+                    Some(node.read_and_disown_value())
+                } else {
+                    node.write_has_value(true);
+                    self.len += 1;
+
+                    None
+                };
+
+                node.write_and_own_value(value);
+
+                return Ok(prev);
+            }
+
+            match data.find_child(rest[0]) {
+                Ok(idx) => {
+                    let child = TrieNode::from_ptr(data.children[idx]);
+
+                    parent = Some((node, data, idx));
+                    node = child;
+                }
+                Err(idx) => {
+                    let leaf_data = NodeData {
+                        has_value: true,
+                        prefix: rest.to_vec(),
+                        ..Default::default()
+                    };
+
+                    let mut leaf = match TrieNode::<V>::create(&leaf_data) {
+                        Ok(it) => it,
+                        Err(e) => return Err(Rejected::new(value, e)),
+                    };
+
+                    data.labels.insert(idx, rest[0]);
+                    data.children.insert(idx, leaf.as_ptr());
+
+                    let old_ptr = node.as_ptr();
+                    if let Err(e) = node.rewrite(&data) {
+                        leaf.destroy();
+
+                        return Err(Rejected::new(value, e));
+                    }
+
+                    leaf.write_and_own_value(value);
+
+                    if node.as_ptr() != old_ptr {
+                        self.relink(parent, &node);
+                    }
+
+                    self.len += 1;
+
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Removes the key-value pair by the key
+    ///
+    /// Returns the value, if the key was present. Nodes left without a value and children are
+    /// released, a node left without a value and with a single child is merged with that child.
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let mut path = Vec::new();
+        let mut node = unsafe { self.root.as_ref()?.copy() };
+        let mut rest = key;
+
+        let mut data = loop {
+            let data = node.load();
+
+            if !rest.starts_with(&data.prefix) {
+                return None;
+            }
+
+            rest = &rest[data.prefix.len()..];
+
+            if rest.is_empty() {
+                break data;
+            }
+
+            let idx = data.find_child(rest[0]).ok()?;
+            let child = TrieNode::from_ptr(data.children[idx]);
+
+            path.push((node, data, idx));
+            node = child;
+        };
+
+        if !data.has_value {
+            return None;
+        }
+
+        let value = node.read_and_disown_value();
+        node.write_has_value(false);
+        data.has_value = false;
+
+        self.len -= 1;
+
+        if self.is_empty() {
+            // releases nodes without values
+            self.clear();
+        } else {
+            self.compact(path, node, data);
+        }
+
+        Some(value)
+    }
+
+    /// Returns an immutable reference [SRef] to the value by the key
+    pub fn get(&self, key: &[u8]) -> Option<SRef<'_, V>> {
+        self.find(key).map(|node| node.get_value())
+    }
+
+    /// Returns a mutable reference [SRefMut] to the value by the key
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<SRefMut<'_, V>> {
+        self.find(key).map(|mut node| node.get_value_mut())
+    }
+
+    /// Returns [true] if there is a value by the key
+    #[inline]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Finds the longest prefix of the key, which is present in this [STrie]
+    ///
+    /// Returns the length of the prefix and an immutable reference [SRef] to its value.
+    pub fn longest_prefix_match(&self, key: &[u8]) -> Option<(usize, SRef<'_, V>)> {
+        let mut node = unsafe { self.root.as_ref()?.copy() };
+        let mut consumed = 0;
+        let mut result = None;
+
+        loop {
+            let data = node.load();
+
+            if !key[consumed..].starts_with(&data.prefix) {
+                return result;
+            }
+
+            consumed += data.prefix.len();
+
+            if data.has_value {
+                result = Some((consumed, node.get_value()));
+            }
+
+            if consumed == key.len() {
+                return result;
+            }
+
+            match data.find_child(key[consumed]) {
+                Ok(idx) => node = TrieNode::from_ptr(data.children[idx]),
+                Err(_) => return result,
+            }
+        }
+    }
+
+    /// Returns an iterator over all entries, which keys start with the prefix
+    ///
+    /// Entries are yielded in lexicographic order of their keys. Keys are yielded as [Vec]`<u8>`.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> STrieIter<'_, V> {
+        STrieIter::new(self.find_prefix_start(prefix))
+    }
+
+    /// Returns an iterator over all entries of this [STrie] in lexicographic order of their keys
+    #[inline]
+    pub fn iter(&self) -> STrieIter<'_, V> {
+        self.iter_prefix(&[])
+    }
+
+    /// Returns the number of entries in this [STrie]
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns [true] if there are no entries in this [STrie]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all entries from this [STrie], releasing all of its nodes
+    #[inline]
+    pub fn clear(&mut self) {
+        let mut old = mem::take(self);
+        self.stable_drop_flag = old.stable_drop_flag;
+
+        unsafe { old.stable_drop() };
+    }
+
+    fn find(&self, key: &[u8]) -> Option<TrieNode<V>> {
+        let mut node = unsafe { self.root.as_ref()?.copy() };
+        let mut rest = key;
+
+        loop {
+            let data = node.load();
+
+            rest = rest.strip_prefix(data.prefix.as_slice())?;
+
+            if rest.is_empty() {
+                return if data.has_value { Some(node) } else { None };
+            }
+
+            let idx = data.find_child(rest[0]).ok()?;
+            node = TrieNode::from_ptr(data.children[idx]);
+        }
+    }
+
+    // returns the highest node, which subtree holds all the keys starting with the prefix,
+    // together with the part of the prefix that goes before the node's own prefix
+    fn find_prefix_start(&self, prefix: &[u8]) -> Option<(StablePtr, Vec<u8>)> {
+        let mut node = unsafe { self.root.as_ref()?.copy() };
+        let mut consumed = 0;
+
+        loop {
+            let data = node.load();
+            let rest = &prefix[consumed..];
+            let common = common_prefix_len(&data.prefix, rest);
+
+            if common == rest.len() {
+                return Some((node.as_ptr(), prefix[..consumed].to_vec()));
+            }
+
+            if common < data.prefix.len() {
+                return None;
+            }
+
+            consumed += common;
+
+            let idx = data.find_child(prefix[consumed]).ok()?;
+            node = TrieNode::from_ptr(data.children[idx]);
+        }
+    }
+
+    // the node's prefix is longer than the common part with the rest of the key, so the node gets
+    // split in two - a new node with the common part on top and the old node with the remainder
+    fn split_and_insert(
+        &mut self,
+        parent: Option<PathEntry<V>>,
+        mut node: TrieNode<V>,
+        mut data: NodeData,
+        common: usize,
+        rest: &[u8],
+        value: V,
+    ) -> Result<(), Rejected<V>> {
+        let mut top_data = NodeData {
+            has_value: rest.is_empty(),
+            prefix: data.prefix[..common].to_vec(),
+            labels: vec![data.prefix[common]],
+            children: vec![node.as_ptr()],
+        };
+
+        let leaf = if rest.is_empty() {
+            None
+        } else {
+            let leaf_data = NodeData {
+                has_value: true,
+                prefix: rest.to_vec(),
+                ..Default::default()
+            };
+
+            let leaf = match TrieNode::<V>::create(&leaf_data) {
+                Ok(it) => it,
+                Err(e) => return Err(Rejected::new(value, e)),
+            };
+
+            let idx = top_data.find_child(rest[0]).unwrap_err();
+            top_data.labels.insert(idx, rest[0]);
+            top_data.children.insert(idx, leaf.as_ptr());
+
+            Some(leaf)
+        };
+
+        let mut top = match TrieNode::<V>::create(&top_data) {
+            Ok(it) => it,
+            Err(e) => {
+                if let Some(leaf) = leaf {
+                    leaf.destroy();
+                }
+
+                return Err(Rejected::new(value, e));
+            }
+        };
+
+        // the prefix only gets shorter, so the node fits into its allocation
+        data.prefix.drain(..common);
+        node.write(&data);
+
+        match leaf {
+            Some(mut leaf) => leaf.write_and_own_value(value),
+            None => top.write_and_own_value(value),
+        }
+
+        self.relink(parent, &top);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn compact(&mut self, mut path: Vec<PathEntry<V>>, mut node: TrieNode<V>, mut data: NodeData) {
+        // the root always stays, even without children
+        while !data.has_value && data.children.is_empty() {
+            let (mut parent, mut parent_data, idx) = match path.pop() {
+                Some(it) => it,
+                None => return,
+            };
+
+            node.destroy();
+
+            // the parent only gets smaller, so it fits into its allocation
+            parent_data.labels.remove(idx);
+            parent_data.children.remove(idx);
+            parent.write(&parent_data);
+
+            node = parent;
+            data = parent_data;
+        }
+
+        if path.is_empty() || data.has_value || data.children.len() != 1 {
+            return;
+        }
+
+        let mut child = TrieNode::<V>::from_ptr(data.children[0]);
+        let mut child_data = child.load();
+
+        let mut prefix = data.prefix;
+        prefix.extend_from_slice(&child_data.prefix);
+        child_data.prefix = prefix;
+
+        // merging is only an optimization, it is fine to skip it when out of stable memory
+        if child.rewrite(&child_data).is_err() {
+            return;
+        }
+
+        self.relink(path.pop(), &child);
+        node.destroy();
+    }
+
+    // points the parent (or the root, if there is no parent) to the node
+    fn relink(&mut self, parent: Option<PathEntry<V>>, node: &TrieNode<V>) {
+        match parent {
+            Some((mut parent, parent_data, idx)) => {
+                parent.write_child_ptr(&parent_data, idx, node.as_ptr())
+            }
+            None => self.root = Some(unsafe { node.copy() }),
+        }
+    }
+
+    fn get_or_create_root(&mut self) -> Result<TrieNode<V>, OutOfMemory> {
+        match &self.root {
+            Some(r) => unsafe { Ok(r.copy()) },
+            None => {
+                let new_root = TrieNode::<V>::create(&NodeData::default())?;
+
+                self.root = Some(new_root);
+                unsafe { Ok(self.root.as_ref().unwrap_unchecked().copy()) }
+            }
+        }
+    }
+}
+
+#[inline]
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+impl<V: StableType + AsFixedSizeBytes> Default for STrie<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> AsFixedSizeBytes for STrie<V> {
+    const SIZE: usize = u64::SIZE * 2;
+    type Buf = [u8; u64::SIZE * 2];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let ptr = if let Some(root) = &self.root {
+            root.as_ptr()
+        } else {
+            EMPTY_PTR
+        };
+
+        ptr.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.len
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE * 2)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let ptr = u64::from_fixed_size_bytes(&buf[0..u64::SIZE]);
+        let len = u64::from_fixed_size_bytes(&buf[u64::SIZE..(u64::SIZE * 2)]);
+
+        Self {
+            root: if ptr == EMPTY_PTR {
+                None
+            } else {
+                Some(TrieNode::from_ptr(ptr))
+            },
+            len,
+            stable_drop_flag: false,
+        }
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> StableType for STrie<V> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    unsafe fn stable_drop(&mut self) {
+        let mut nodes = match self.root.take() {
+            Some(root) => vec![root],
+            None => return,
+        };
+
+        while let Some(mut node) = nodes.pop() {
+            let data = node.load();
+
+            if data.has_value {
+                node.read_and_disown_value();
+            }
+
+            nodes.extend(data.children.into_iter().map(TrieNode::from_ptr));
+            node.destroy();
+        }
+    }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        let mut nodes = match &self.root {
+            Some(root) => vec![unsafe { root.copy() }],
+            None => return,
+        };
+
+        while let Some(node) = nodes.pop() {
+            visitor(unsafe { SSlice::from_ptr(node.as_ptr()).unwrap() });
+
+            let data = node.load();
+
+            if data.has_value {
+                node.get_value().visit_allocations(visitor);
+            }
+
+            nodes.extend(data.children.into_iter().map(TrieNode::from_ptr));
+        }
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> IncrementalDrop for STrie<V> {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        while has_budget(start, instruction_budget) {
+            let key = match self.iter().next() {
+                Some((key, _)) => key,
+                None => break,
+            };
+
+            self.remove(&key);
+        }
+
+        if self.is_empty() {
+            // releases the empty root node
+            self.clear();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len()
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes> Drop for STrie<V> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+impl<V: StableType + AsFixedSizeBytes + Debug> Debug for STrie<V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+
+        for (idx, (k, v)) in self.iter().enumerate() {
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+
+            if (idx as u64) < self.len() - 1 {
+                f.write_str(", ")?;
+            }
+        }
+
+        f.write_str("}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::trie::STrie;
+    use crate::mem::deferred_drop::IncrementalDrop;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable, stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut trie = STrie::new();
+            assert!(trie.is_empty());
+            assert!(trie.get(b"").is_none());
+            assert!(trie.remove(b"a").is_none());
+            assert!(trie.longest_prefix_match(b"abc").is_none());
+
+            assert_eq!(trie.insert(b"romane", 1u64).unwrap(), None);
+            assert_eq!(trie.insert(b"romanus", 2).unwrap(), None);
+            assert_eq!(trie.insert(b"romulus", 3).unwrap(), None);
+            assert_eq!(trie.insert(b"rubens", 4).unwrap(), None);
+            assert_eq!(trie.insert(b"ruber", 5).unwrap(), None);
+            assert_eq!(trie.insert(b"rom", 6).unwrap(), None);
+            assert_eq!(trie.insert(b"", 7).unwrap(), None);
+            assert_eq!(trie.insert(b"ruber", 8).unwrap(), Some(5));
+
+            assert_eq!(trie.len(), 7);
+            assert_eq!(*trie.get(b"ruber").unwrap(), 8);
+            assert_eq!(*trie.get(b"").unwrap(), 7);
+            assert!(trie.get(b"ro").is_none());
+            assert!(trie.get(b"romanes").is_none());
+            assert!(!trie.contains_key(b"r"));
+
+            *trie.get_mut(b"rom").unwrap() = 60;
+            assert_eq!(*trie.get(b"rom").unwrap(), 60);
+
+            let keys = trie.iter().map(|(k, _)| k).collect::<Vec<_>>();
+            assert_eq!(
+                keys,
+                vec![
+                    b"".to_vec(),
+                    b"rom".to_vec(),
+                    b"romane".to_vec(),
+                    b"romanus".to_vec(),
+                    b"romulus".to_vec(),
+                    b"rubens".to_vec(),
+                    b"ruber".to_vec()
+                ]
+            );
+
+            let values = trie
+                .iter_prefix(b"roma")
+                .map(|(_, v)| *v)
+                .collect::<Vec<_>>();
+            assert_eq!(values, vec![1, 2]);
+            assert_eq!(trie.iter_prefix(b"rom").count(), 4);
+            assert_eq!(trie.iter_prefix(b"rub").count(), 2);
+            assert_eq!(trie.iter_prefix(b"romx").count(), 0);
+            assert_eq!(trie.iter_prefix(b"romulusx").count(), 0);
+
+            let (len, v) = trie.longest_prefix_match(b"romanesque").unwrap();
+            assert_eq!((len, *v), (6, 1));
+            let (len, v) = trie.longest_prefix_match(b"romanu").unwrap();
+            assert_eq!((len, *v), (3, 60));
+            let (len, v) = trie.longest_prefix_match(b"xyz").unwrap();
+            assert_eq!((len, *v), (0, 7));
+
+            assert_eq!(trie.remove(b"rom"), Some(60));
+            assert_eq!(trie.remove(b"rom"), None);
+            assert_eq!(trie.remove(b"romane"), Some(1));
+            assert_eq!(trie.remove(b""), Some(7));
+            assert_eq!(trie.len(), 4);
+            assert_eq!(*trie.get(b"romanus").unwrap(), 2);
+
+            println!("{:?}", trie);
+
+            for key in [b"romanus".as_slice(), b"romulus", b"rubens", b"ruber"] {
+                assert!(trie.remove(key).is_some());
+            }
+
+            assert!(trie.is_empty());
+            assert!(trie.iter().next().is_none());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn boxed_values_and_incremental_drop_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut trie = STrie::new();
+
+            for i in 0..300u64 {
+                let key = format!("key-{}", i);
+                trie.insert(key.as_bytes(), SBox::new(key.clone()).unwrap())
+                    .unwrap();
+            }
+
+            for i in (0..300u64).step_by(3) {
+                let key = format!("key-{}", i);
+                assert_eq!(trie.remove(key.as_bytes()).unwrap().into_inner(), key);
+            }
+
+            _debug_validate_allocator();
+
+            while !trie.clear_incremental(10_000) {}
+
+            assert!(trie.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[derive(Debug)]
+    enum Action {
+        Insert,
+        Remove,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        trie: Option<STrie<SBox<u64>>>,
+        example: BTreeMap<Vec<u8>, u64>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Fuzzer {
+            Fuzzer {
+                trie: Some(STrie::new()),
+                example: BTreeMap::new(),
+                rng: thread_rng(),
+                log: Vec::new(),
+            }
+        }
+
+        fn trie(&mut self) -> &mut STrie<SBox<u64>> {
+            self.trie.as_mut().unwrap()
+        }
+
+        // short keys from a small alphabet, so they share prefixes a lot
+        fn random_key(&mut self) -> Vec<u8> {
+            let len = self.rng.gen_range(0..8);
+
+            (0..len).map(|_| self.rng.gen_range(b'a'..b'e')).collect()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // INSERT ~60%
+                0..=59 => {
+                    let key = self.random_key();
+                    let value: u64 = self.rng.gen();
+
+                    if let Ok(data) = SBox::new(value) {
+                        match self.trie().insert(&key, data) {
+                            Ok(prev) => {
+                                assert_eq!(
+                                    prev.map(|it| it.into_inner()),
+                                    self.example.insert(key, value)
+                                );
+                            }
+                            Err(e) => {
+                                assert_eq!(*e.into_inner(), value);
+                            }
+                        }
+
+                        self.log.push(Action::Insert);
+                    }
+                }
+                // REMOVE
+                60..=89 => {
+                    let key = match self.rng.gen_bool(0.8) {
+                        true if !self.example.is_empty() => {
+                            let idx = self.rng.gen_range(0..self.example.len());
+                            self.example.keys().nth(idx).unwrap().clone()
+                        }
+                        _ => self.random_key(),
+                    };
+
+                    assert_eq!(
+                        self.trie().remove(&key).map(|it| it.into_inner()),
+                        self.example.remove(&key)
+                    );
+
+                    self.log.push(Action::Remove);
+                }
+                // CLEAR
+                90..=91 => {
+                    self.trie().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.trie.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.trie =
+                            retrieve_custom_data::<STrie<SBox<u64>>>(1).map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(e) => {
+                        self.trie = Some(e.into_inner());
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.trie().len() as usize, self.example.len());
+
+            let entries = self
+                .trie()
+                .iter()
+                .map(|(k, v)| (k, **v))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                entries,
+                self.example
+                    .iter()
+                    .map(|(k, v)| (k.clone(), *v))
+                    .collect::<Vec<_>>()
+            );
+
+            let key = self.random_key();
+
+            let expected = self
+                .example
+                .iter()
+                .filter(|(k, _)| k.starts_with(&key))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect::<Vec<_>>();
+            let actual = self
+                .trie()
+                .iter_prefix(&key)
+                .map(|(k, v)| (k, **v))
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+
+            let expected = (0..=key.len())
+                .rev()
+                .find_map(|len| self.example.get(&key[..len]).map(|v| (len, *v)));
+            let actual = self
+                .trie()
+                .longest_prefix_match(&key)
+                .map(|(len, v)| (len, **v));
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::{allocate, deallocate, reallocate, OutOfMemory, SSlice};
+use std::marker::PhantomData;
+
+// LAYOUT:
+// has_value: u8
+// prefix_len: u32
+// children_len: u16
+// value: V -- garbage, if has_value == 0
+// prefix: [u8; prefix_len]
+// labels: [u8; children_len] -- first bytes of prefixes of children, in ascending order
+// children: [u64; children_len]
+
+const HAS_VALUE_OFFSET: u64 = 0;
+const PREFIX_LEN_OFFSET: u64 = HAS_VALUE_OFFSET + u8::SIZE as u64;
+const CHILDREN_LEN_OFFSET: u64 = PREFIX_LEN_OFFSET + u32::SIZE as u64;
+const VALUE_OFFSET: u64 = CHILDREN_LEN_OFFSET + u16::SIZE as u64;
+
+const HEADER_SIZE: usize = VALUE_OFFSET as usize;
+
+const fn prefix_offset<V: AsFixedSizeBytes>() -> u64 {
+    VALUE_OFFSET + V::SIZE as u64
+}
+
+// The structural part of a node, read into heap memory
+#[derive(Debug, Default)]
+pub(crate) struct NodeData {
+    pub has_value: bool,
+    pub prefix: Vec<u8>,
+    pub labels: Vec<u8>,
+    pub children: Vec<StablePtr>,
+}
+
+impl NodeData {
+    #[inline]
+    pub fn find_child(&self, label: u8) -> Result<usize, usize> {
+        self.labels.binary_search(&label)
+    }
+
+    #[inline]
+    fn tail_size(&self) -> usize {
+        self.prefix.len() + self.labels.len() + self.children.len() * u64::SIZE
+    }
+}
+
+pub(crate) struct TrieNode<V> {
+    ptr: StablePtr,
+    _marker: PhantomData<V>,
+}
+
+impl<V: StableType + AsFixedSizeBytes> TrieNode<V> {
+    #[inline]
+    pub fn calc_size_bytes(data: &NodeData) -> u64 {
+        prefix_offset::<V>() + data.tail_size() as u64
+    }
+
+    pub fn create(data: &NodeData) -> Result<Self, OutOfMemory> {
+        let slice = unsafe { allocate(Self::calc_size_bytes(data))? };
+        let mut it = Self::from_ptr(slice.as_ptr());
+
+        it.write(data);
+
+        Ok(it)
+    }
+
+    #[inline]
+    pub fn destroy(self) {
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+        deallocate(slice);
+    }
+
+    // reads the node with two reads - the header first and then everything after the value
+    pub fn load(&self) -> NodeData {
+        let mut header = [0u8; HEADER_SIZE];
+        unsafe { crate::mem::read_bytes(SSlice::_offset(self.ptr, HAS_VALUE_OFFSET), &mut header) };
+
+        let has_value = header[HAS_VALUE_OFFSET as usize] == 1;
+        let prefix_len = u32::from_fixed_size_bytes(
+            &header[PREFIX_LEN_OFFSET as usize..CHILDREN_LEN_OFFSET as usize],
+        ) as usize;
+        let children_len =
+            u16::from_fixed_size_bytes(&header[CHILDREN_LEN_OFFSET as usize..HEADER_SIZE]) as usize;
+
+        let mut tail = vec![0u8; prefix_len + children_len * (1 + u64::SIZE)];
+        unsafe {
+            crate::mem::read_bytes(SSlice::_offset(self.ptr, prefix_offset::<V>()), &mut tail)
+        };
+
+        let children_offset = prefix_len + children_len;
+        let children = (0..children_len)
+            .map(|i| {
+                let from = children_offset + i * u64::SIZE;
+                u64::from_fixed_size_bytes(&tail[from..(from + u64::SIZE)])
+            })
+            .collect();
+
+        tail.truncate(children_offset);
+        let labels = tail.split_off(prefix_len);
+
+        NodeData {
+            has_value,
+            prefix: tail,
+            labels,
+            children,
+        }
+    }
+
+    // the allocation should be big enough to fit the data
+    pub fn write(&mut self, data: &NodeData) {
+        let mut header = [0u8; HEADER_SIZE];
+        header[HAS_VALUE_OFFSET as usize] = data.has_value as u8;
+        (data.prefix.len() as u32).as_fixed_size_bytes(
+            &mut header[PREFIX_LEN_OFFSET as usize..CHILDREN_LEN_OFFSET as usize],
+        );
+        (data.children.len() as u16)
+            .as_fixed_size_bytes(&mut header[CHILDREN_LEN_OFFSET as usize..HEADER_SIZE]);
+
+        unsafe { crate::mem::write_bytes(SSlice::_offset(self.ptr, HAS_VALUE_OFFSET), &header) };
+
+        let mut tail = Vec::with_capacity(data.tail_size());
+        tail.extend_from_slice(&data.prefix);
+        tail.extend_from_slice(&data.labels);
+        for child in &data.children {
+            tail.extend_from_slice(child.as_new_fixed_size_bytes()._deref());
+        }
+
+        unsafe { crate::mem::write_bytes(SSlice::_offset(self.ptr, prefix_offset::<V>()), &tail) };
+    }
+
+    // grows the allocation, if needed, and writes the data - the node may move in this case,
+    // but the value stays intact
+    pub fn rewrite(&mut self, data: &NodeData) -> Result<(), OutOfMemory> {
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+        let slice = unsafe { reallocate(slice, Self::calc_size_bytes(data))? };
+
+        self.ptr = slice.as_ptr();
+        self.write(data);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn write_has_value(&mut self, has_value: bool) {
+        unsafe {
+            crate::mem::write_bytes(
+                SSlice::_offset(self.ptr, HAS_VALUE_OFFSET),
+                &[has_value as u8],
+            )
+        };
+    }
+
+    #[inline]
+    pub fn write_child_ptr(&mut self, data: &NodeData, idx: usize, ptr: StablePtr) {
+        let offset =
+            prefix_offset::<V>() + (data.prefix.len() + data.labels.len() + idx * u64::SIZE) as u64;
+
+        unsafe {
+            crate::mem::write_bytes(
+                SSlice::_offset(self.ptr, offset),
+                ptr.as_new_fixed_size_bytes()._deref(),
+            )
+        };
+    }
+
+    #[inline]
+    pub fn get_value<'a>(&self) -> SRef<'a, V> {
+        unsafe { SRef::new(self.get_value_ptr()) }
+    }
+
+    #[inline]
+    pub fn get_value_mut<'a>(&mut self) -> SRefMut<'a, V> {
+        unsafe { SRefMut::new(self.get_value_ptr()) }
+    }
+
+    #[inline]
+    pub fn write_and_own_value(&mut self, mut value: V) {
+        unsafe { crate::mem::write_fixed(self.get_value_ptr(), &mut value) };
+    }
+
+    #[inline]
+    pub fn read_and_disown_value(&mut self) -> V {
+        unsafe { crate::mem::read_fixed_for_move(self.get_value_ptr()) }
+    }
+
+    #[inline]
+    fn get_value_ptr(&self) -> StablePtr {
+        SSlice::_offset(self.ptr, VALUE_OFFSET)
+    }
+}
+
+impl<V> TrieNode<V> {
+    #[inline]
+    pub fn from_ptr(ptr: StablePtr) -> Self {
+        Self {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> StablePtr {
+        self.ptr
+    }
+
+    #[inline]
+    pub unsafe fn copy(&self) -> Self {
+        Self::from_ptr(self.ptr)
+    }
+}
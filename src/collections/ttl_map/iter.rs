@@ -0,0 +1,36 @@
+use crate::collections::btree_map::iter::SBTreeMapIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+
+pub struct STtlMapIter<'a, K, V> {
+    inner: SBTreeMapIter<'a, K, (V, u64)>,
+    now: u64,
+}
+
+impl<'a, K, V> STtlMapIter<'a, K, V> {
+    #[inline]
+    pub(crate) fn new(inner: SBTreeMapIter<'a, K, (V, u64)>, now: u64) -> Self {
+        Self { inner, now }
+    }
+}
+
+impl<'a, K, V> Iterator for STtlMapIter<'a, K, V>
+where
+    K: StableType + AsFixedSizeBytes + Ord,
+    V: StableType + AsFixedSizeBytes,
+{
+    type Item = (SRef<'a, K>, SRef<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, entry) = self.inner.next()?;
+
+            // entries are stored as (V, u64) tuples - the value goes first, then goes the deadline
+            let expires_at = unsafe { entry.project::<u64>(V::SIZE) };
+            if *expires_at > self.now {
+                return Some((key, unsafe { entry.project(0) }));
+            }
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use crate::collections::btree_map::{SBTreeMap, SBTREE_MAP_SIZE};
+use crate::collections::ttl_map::iter::STtlMapIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::{lookup_copy, StableType};
+use crate::utils::budget::has_budget;
+use crate::{stable, Rejected};
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::mem;
+
+pub mod iter;
+
+/// Map, which entries expire after some time
+///
+/// Internally are two [SBTreeMap]s: one maps keys to `(value, expires_at)` pairs, the other one
+/// indexes keys by their expiration time, so expired entries can be found without scanning the whole
+/// map. Each key is stored in both of them, so inserting requires `K` to implement [Clone].
+///
+/// This map does not know what time it is - the current time (in any units you like, e.g.
+/// nanoseconds of `ic_cdk::api::time()`) is passed to every method that needs it. An entry expires
+/// once `now` reaches its `expires_at`. Expired entries are never returned, but they keep occupying
+/// stable memory, until they are removed by [STtlMap::sweep_expired]. Call it from a timer with a
+/// reasonable `max_items`, so a single call never exceeds the instruction limit.
+///
+/// [STtlMap] implements [StableType] and [AsFixedSizeBytes], so you can nest it in other stable
+/// structures.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::STtlMap;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// // session id -> user id
+/// let mut sessions = STtlMap::new();
+///
+/// let now = 1_000;
+/// sessions.insert_with_ttl(1u64, 10u64, 500, now).expect("Out of memory");
+/// sessions.insert_with_ttl(2, 20, 100, now).expect("Out of memory");
+///
+/// assert_eq!(*sessions.get(&2, now + 50).unwrap(), 20);
+/// assert!(sessions.get(&2, now + 100).is_none());
+///
+/// // from a timer
+/// assert_eq!(sessions.sweep_expired(now + 100, 1000), 1);
+/// assert_eq!(sessions.len(), 1);
+/// ```
+pub struct STtlMap<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> {
+    entries: SBTreeMap<K, (V, u64)>,
+    expiry: SBTreeMap<(u64, K), ()>,
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> STtlMap<K, V> {
+    /// Creates a new [STtlMap]
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            entries: SBTreeMap::new(),
+            expiry: SBTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of entries in this [STtlMap], including expired ones, which are not yet
+    /// swept
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    /// Returns [true] if there are no entries in this [STtlMap] (not even expired ones)
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Inserts a key-value pair, which expires `ttl` after `now`
+    ///
+    /// If the key is already present, replaces both its value and its expiration time. Returns the
+    /// previous value, if it was not yet expired (an expired one is simply stable-dropped).
+    ///
+    /// If your canister is out of stable memory, returns [Rejected] with the pair. The map stays
+    /// the same in that case.
+    pub fn insert_with_ttl(
+        &mut self,
+        key: K,
+        value: V,
+        ttl: u64,
+        now: u64,
+    ) -> Result<Option<V>, Rejected<(K, V)>>
+    where
+        K: Clone,
+    {
+        let expires_at = now.saturating_add(ttl);
+
+        let prev_expires_at = match self.expires_at(&key) {
+            Some(it) => it,
+            None => {
+                if let Err(e) = self.expiry.insert((expires_at, key.clone()), ()) {
+                    return Err(Rejected::new((key, value), e.reason));
+                }
+
+                return match self.entries.insert(key, (value, expires_at)) {
+                    Ok(_) => Ok(None),
+                    Err(e) => {
+                        self.expiry.remove(&(expires_at, lookup_copy(&e.value.0)));
+
+                        Err(e.map(|(k, (v, _))| (k, v)))
+                    }
+                };
+            }
+        };
+
+        if prev_expires_at != expires_at {
+            if let Err(e) = self.expiry.insert((expires_at, key.clone()), ()) {
+                return Err(Rejected::new((key, value), e.reason));
+            }
+
+            self.expiry.remove(&(prev_expires_at, lookup_copy(&key)));
+        }
+
+        let mut entry = self.entries.get_mut(&key).unwrap();
+        let (prev, _) = mem::replace(&mut *entry, (value, expires_at));
+
+        Ok(if prev_expires_at > now {
+            Some(prev)
+        } else {
+            None
+        })
+    }
+
+    /// Returns a reference to the value of the key, if it is present and not yet expired
+    ///
+    /// Borrowed type is also accepted.
+    #[inline]
+    pub fn get<Q>(&self, key: &Q, now: u64) -> Option<SRef<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entry = self.entries.get(key)?;
+
+        if *split_entry(&entry).1 > now {
+            Some(split_entry(&entry).0)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the value of the key, if it is present and not yet expired
+    ///
+    /// Does not change the expiration time of the entry.
+    #[inline]
+    pub fn get_mut<Q>(&mut self, key: &Q, now: u64) -> Option<SRefMut<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if !self.contains_key(key, now) {
+            return None;
+        }
+
+        let entry = self.entries.get_mut(key)?;
+
+        Some(unsafe { entry.project(0) })
+    }
+
+    /// Returns [true] if the key is present and not yet expired
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q, now: u64) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key, now).is_some()
+    }
+
+    /// Returns the expiration time of the key, if it is present (even if it is already expired)
+    #[inline]
+    pub fn expires_at<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let entry = self.entries.get(key)?;
+        let expires_at = *split_entry(&entry).1;
+
+        Some(expires_at)
+    }
+
+    /// Returns the earliest expiration time among all entries of this [STtlMap]
+    ///
+    /// Useful to schedule the next [STtlMap::sweep_expired] call.
+    #[inline]
+    pub fn next_expiration(&self) -> Option<u64> {
+        self.expiry.iter().next().map(|(entry, _)| entry.0)
+    }
+
+    /// Removes the key from this [STtlMap]
+    ///
+    /// Returns the value of the key, even if it is already expired.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (stored_key, expires_at) = self.find_key(key)?;
+
+        // the copy points to the key, stored in entries, so expiry goes first
+        self.expiry.remove(&(expires_at, stored_key));
+        let (value, _) = self.entries.remove(key)?;
+
+        Some(value)
+    }
+
+    /// Removes up to `max_items` expired entries from this [STtlMap]
+    ///
+    /// Entries are removed in order of their expiration time. Returns the number of removed entries -
+    /// if it equals `max_items`, there may be more expired entries left.
+    pub fn sweep_expired(&mut self, now: u64, max_items: u64) -> u64 {
+        let mut swept = 0;
+
+        while swept < max_items && self.sweep_one(now) {
+            swept += 1;
+        }
+
+        swept
+    }
+
+    /// Removes all entries from this [STtlMap]
+    #[inline]
+    pub fn clear(&mut self) {
+        self.expiry.clear();
+        self.entries.clear();
+    }
+
+    /// Returns an iterator over entries, which are not yet expired, in ascending order of keys
+    #[inline]
+    pub fn iter(&self, now: u64) -> STtlMapIter<'_, K, V> {
+        STtlMapIter::new(self.entries.iter(), now)
+    }
+
+    // returns a lookup copy of the stored key, equal to the provided one, and its expiration time
+    fn find_key<Q>(&self, key: &Q) -> Option<(K, u64)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (k, entry) = self.entries.iter_from(|k| k.borrow() < key).next()?;
+
+        if (*k).borrow() == key {
+            Some((lookup_copy(&*k), *split_entry(&entry).1))
+        } else {
+            None
+        }
+    }
+
+    fn sweep_one(&mut self, now: u64) -> bool {
+        let entry = match self.expiry.iter().next() {
+            Some((entry, _)) if entry.0 <= now => lookup_copy(&*entry),
+            _ => return false,
+        };
+
+        // the copy points to the key, stored in expiry, so entries go first
+        self.entries.remove(&entry.1);
+        self.expiry.remove(&entry);
+
+        true
+    }
+}
+
+// entries are stored as (V, u64) tuples - the value goes first, then goes the deadline
+#[inline]
+fn split_entry<'a, V: AsFixedSizeBytes>(
+    entry: &SRef<'a, (V, u64)>,
+) -> (SRef<'a, V>, SRef<'a, u64>) {
+    unsafe { (entry.project(0), entry.project(V::SIZE)) }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> Default
+    for STtlMap<K, V>
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> AsFixedSizeBytes
+    for STtlMap<K, V>
+{
+    const SIZE: usize = SBTREE_MAP_SIZE * 2;
+    type Buf = [u8; SBTREE_MAP_SIZE * 2];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let size = SBTreeMap::<K, (V, u64)>::SIZE;
+
+        self.entries.as_fixed_size_bytes(&mut buf[0..size]);
+        self.expiry.as_fixed_size_bytes(&mut buf[size..(size * 2)]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let size = SBTreeMap::<K, (V, u64)>::SIZE;
+
+        Self {
+            entries: SBTreeMap::from_fixed_size_bytes(&buf[0..size]),
+            expiry: SBTreeMap::from_fixed_size_bytes(&buf[size..(size * 2)]),
+        }
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> StableType
+    for STtlMap<K, V>
+{
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.entries.stable_drop_flag_on();
+        self.expiry.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.entries.stable_drop_flag_off();
+        self.expiry.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.entries.visit_allocations(visitor);
+        self.expiry.visit_allocations(visitor);
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord, V: StableType + AsFixedSizeBytes> IncrementalDrop
+    for STtlMap<K, V>
+{
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        // removes entries in pairs, so the map stays consistent between calls
+        while has_budget(start, instruction_budget) && self.sweep_one(u64::MAX) {}
+
+        if self.expiry.is_empty() {
+            // releases empty root nodes
+            self.clear();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.entries.len()
+    }
+}
+
+impl<K: StableType + AsFixedSizeBytes + Ord + Debug, V: StableType + AsFixedSizeBytes + Debug> Debug
+    for STtlMap<K, V>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("{")?;
+
+        for (idx, (k, entry)) in self.entries.iter().enumerate() {
+            let (v, expires_at) = split_entry(&entry);
+
+            k.fmt(f)?;
+            f.write_str(": ")?;
+            v.fmt(f)?;
+            f.write_str(" (expires at ")?;
+            expires_at.fmt(f)?;
+            f.write_str(")")?;
+
+            if (idx as u64) < self.entries.len() - 1 {
+                f.write_str(", ")?;
+            }
+        }
+
+        f.write_str("}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::ttl_map::STtlMap;
+    use crate::encoding::AsFixedSizeBytes;
+    use crate::mem::deferred_drop::IncrementalDrop;
+    use crate::primitive::StableType;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init, SBox};
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn it_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = STtlMap::<u64, u64>::default();
+            assert!(map.is_empty());
+            assert!(map.get(&1, 0).is_none());
+            assert_eq!(map.next_expiration(), None);
+            assert_eq!(map.sweep_expired(u64::MAX, 10), 0);
+
+            for i in 0..100u64 {
+                assert!(map
+                    .insert_with_ttl(i, i * 10, i + 1, 100)
+                    .unwrap()
+                    .is_none());
+            }
+
+            assert_eq!(map.len(), 100);
+            assert_eq!(map.next_expiration(), Some(101));
+            assert_eq!(map.expires_at(&5), Some(106));
+            assert_eq!(*map.get(&5, 105).unwrap(), 50);
+            assert!(map.get(&5, 106).is_none());
+            assert!(!map.contains_key(&5, 200));
+
+            *map.get_mut(&7, 100).unwrap() = 700;
+            assert_eq!(*map.get(&7, 100).unwrap(), 700);
+            assert_eq!(map.expires_at(&7), Some(108));
+            assert!(map.get_mut(&7, 108).is_none());
+
+            // prolongs the entry
+            assert_eq!(map.insert_with_ttl(0, 1, 1000, 100).unwrap(), Some(0));
+            assert_eq!(map.next_expiration(), Some(102));
+            // an expired value is not returned
+            assert_eq!(map.insert_with_ttl(1, 2, 1000, 200).unwrap(), None);
+            // the same expiration time
+            assert_eq!(map.insert_with_ttl(1, 3, 1000, 200).unwrap(), Some(2));
+
+            let live: Vec<_> = map.iter(150).map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(live.len(), 52);
+            assert_eq!(live[0], (0, 1));
+            assert_eq!(live[1], (1, 3));
+            assert_eq!(live[2], (50, 500));
+
+            assert_eq!(map.sweep_expired(150, 20), 20);
+            assert_eq!(map.len(), 80);
+            assert_eq!(map.sweep_expired(150, 100), 28);
+            assert_eq!(map.sweep_expired(150, 100), 0);
+            assert_eq!(map.len(), 52);
+
+            assert_eq!(map.remove(&50), Some(500));
+            assert_eq!(map.remove(&50), None);
+            // expired, but not yet swept
+            assert!(map.get(&51, 200).is_none());
+            assert_eq!(map.remove(&51), Some(510));
+            assert_eq!(map.len(), 50);
+
+            let mut buf = vec![0u8; STtlMap::<u64, u64>::SIZE];
+            map.as_fixed_size_bytes(&mut buf);
+            let mut map1 = STtlMap::<u64, u64>::from_fixed_size_bytes(&buf);
+            unsafe { map1.stable_drop_flag_off() };
+            assert_eq!(*map1.get(&1, 1000).unwrap(), 3);
+
+            println!("{:?}", map);
+
+            assert_eq!(map.sweep_expired(u64::MAX, u64::MAX), 50);
+            assert!(map.is_empty());
+            assert_eq!(map.next_expiration(), None);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = STtlMap::new();
+            let mut example = BTreeMap::new();
+            let mut rng = thread_rng();
+            let mut now = 0u64;
+
+            for _ in 0..5_000 {
+                let key = rng.gen_range(0..500u64);
+
+                match rng.gen_range(0..10) {
+                    0..=5 => {
+                        let ttl = rng.gen_range(0..100);
+                        let value = format!("value {}", rng.gen::<u32>());
+
+                        let prev = map
+                            .insert_with_ttl(key, SBox::new(value.clone()).unwrap(), ttl, now)
+                            .unwrap()
+                            .map(|it| it.into_inner());
+                        let expected = example
+                            .insert(key, (value, now + ttl))
+                            .filter(|(_, expires_at)| *expires_at > now)
+                            .map(|(v, _)| v);
+
+                        assert_eq!(prev, expected);
+                    }
+                    6 => {
+                        let removed = map.remove(&key).map(|it| it.into_inner());
+                        assert_eq!(removed, example.remove(&key).map(|(v, _)| v));
+                    }
+                    7 => {
+                        let max_items = rng.gen_range(0..20);
+                        let expected = example
+                            .values()
+                            .filter(|(_, expires_at)| *expires_at <= now)
+                            .count()
+                            .min(max_items as usize);
+
+                        assert_eq!(map.sweep_expired(now, max_items), expected as u64);
+
+                        // the earliest entries should be gone
+                        let mut expired: Vec<_> = example
+                            .iter()
+                            .filter(|(_, (_, expires_at))| *expires_at <= now)
+                            .map(|(k, (_, expires_at))| (*expires_at, *k))
+                            .collect();
+                        expired.sort();
+
+                        for (_, k) in expired.into_iter().take(expected) {
+                            example.remove(&k);
+                        }
+                    }
+                    _ => {
+                        now += rng.gen_range(0..10);
+                    }
+                }
+
+                assert_eq!(map.len(), example.len() as u64);
+
+                let expected = example
+                    .get(&key)
+                    .filter(|(_, expires_at)| *expires_at > now)
+                    .map(|(v, _)| v.clone());
+                assert_eq!(map.get(&key, now).map(|it| (**it).clone()), expected);
+            }
+
+            let live: Vec<_> = map.iter(now).map(|(k, v)| (*k, (**v).clone())).collect();
+            let expected: Vec<_> = example
+                .iter()
+                .filter(|(_, (_, expires_at))| *expires_at > now)
+                .map(|(k, (v, _))| (*k, v.clone()))
+                .collect();
+            assert_eq!(live, expected);
+
+            while !map.clear_incremental(10_000) {}
+            assert!(map.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
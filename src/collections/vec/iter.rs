@@ -4,10 +4,17 @@ use crate::primitive::s_ref::SRef;
 use crate::primitive::StableType;
 use crate::SSlice;
 
+/// An iterator over elements of [SVec]
+///
+/// Elements are read from stable memory in chunks of up to 64 KB, so iterating over a big [SVec]
+/// costs only a few stable memory reads.
 pub struct SVecIter<'a, T: StableType + AsFixedSizeBytes> {
     svec: &'a SVec<T>,
     offset: usize,
     max_offset: usize,
+    buf: Vec<u8>,
+    buf_offset: usize,
+    chunk_len: usize,
 }
 
 impl<'a, T: AsFixedSizeBytes + StableType> SVecIter<'a, T> {
@@ -19,8 +26,48 @@ impl<'a, T: AsFixedSizeBytes + StableType> SVecIter<'a, T> {
             svec,
             offset,
             max_offset,
+            buf: Vec::new(),
+            buf_offset: offset,
+            chunk_len: 0,
         }
     }
+
+    pub(crate) fn new_at(svec: &'a SVec<T>, cursor: usize) -> Self {
+        let max_offset = svec.len() * T::SIZE;
+        let offset = cursor.min(svec.len()) * T::SIZE;
+
+        Self {
+            svec,
+            offset,
+            max_offset,
+            buf: Vec::new(),
+            buf_offset: offset,
+            chunk_len: 0,
+        }
+    }
+
+    /// Returns the index of the element, which will be returned next
+    ///
+    /// Pass it to [SVec::iter_from_cursor] to continue the iteration from this point.
+    #[inline]
+    pub fn cursor(&self) -> usize {
+        self.offset.checked_div(T::SIZE).unwrap_or_default()
+    }
+
+    fn read_chunk(&mut self) {
+        self.chunk_len = crate::mem::next_read_chunk_len(self.chunk_len, T::SIZE);
+
+        let len = (self.chunk_len * T::SIZE).min(self.max_offset - self.offset);
+        self.buf.resize(len, 0);
+        self.buf_offset = self.offset;
+
+        unsafe {
+            crate::mem::read_bytes(
+                SSlice::_offset(self.svec.ptr, self.offset as u64),
+                &mut self.buf,
+            )
+        };
+    }
 }
 
 impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SVecIter<'a, T> {
@@ -31,9 +78,88 @@ impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SVecIter<'a, T> {
             return None;
         }
 
+        if self.offset >= self.buf_offset + self.buf.len() {
+            self.read_chunk();
+        }
+
         let ptr = SSlice::_offset(self.svec.ptr, self.offset as u64);
+        let from = self.offset - self.buf_offset;
+        let it = SVec::<T>::read_as_reference(&self.buf[from..(from + T::SIZE)]);
+
         self.offset += T::SIZE;
 
-        unsafe { Some(SRef::new(ptr)) }
+        unsafe { Some(SRef::new_loaded(ptr, it)) }
+    }
+}
+
+/// An iterator over elements removed from [SVec] by [SVec::drain]
+pub struct SVecDrain<'a, T: StableType + AsFixedSizeBytes> {
+    svec: &'a mut SVec<T>,
+    start: usize,
+    idx: usize,
+    end: usize,
+    tail_len: usize,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> SVecDrain<'a, T> {
+    pub(crate) fn new(svec: &'a mut SVec<T>, start: usize, end: usize) -> Self {
+        let tail_len = svec.len - end;
+
+        // elements after the range are leaked, if this iterator is leaked
+        svec.len = start;
+
+        Self {
+            svec,
+            start,
+            idx: start,
+            end,
+            tail_len,
+        }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SVecDrain<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx == self.end {
+            return None;
+        }
+
+        let ptr = SSlice::_offset(self.svec.ptr, (self.idx * T::SIZE) as u64);
+        self.idx += 1;
+
+        unsafe { Some(crate::mem::read_fixed_for_move(ptr)) }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.idx;
+
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> ExactSizeIterator for SVecDrain<'a, T> {}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Drop for SVecDrain<'a, T> {
+    fn drop(&mut self) {
+        self.svec.drop_range(self.idx, self.end);
+
+        if self.tail_len > 0 && self.start != self.end {
+            let mut buf = vec![0u8; self.tail_len * T::SIZE];
+
+            unsafe {
+                crate::mem::read_bytes(
+                    SSlice::_offset(self.svec.ptr, (self.end * T::SIZE) as u64),
+                    &mut buf,
+                );
+                crate::mem::write_bytes(
+                    SSlice::_offset(self.svec.ptr, (self.start * T::SIZE) as u64),
+                    &buf,
+                );
+            }
+        }
+
+        self.svec.len = self.start + self.tail_len;
     }
 }
@@ -1,15 +1,18 @@
-use crate::collections::vec::iter::SVecIter;
+use crate::collections::vec::iter::{SVecDrain, SVecIter};
 use crate::encoding::{AsFixedSizeBytes, Buffer};
 use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::deferred_drop::IncrementalDrop;
 use crate::mem::s_slice::SSlice;
 use crate::mem::StablePtr;
 use crate::primitive::s_ref::SRef;
 use crate::primitive::s_ref_mut::SRefMut;
 use crate::primitive::StableType;
-use crate::{allocate, deallocate, reallocate, OutOfMemory};
+use crate::utils::budget::has_budget;
+use crate::{allocate, deallocate, reallocate, stable, OutOfMemory, Rejected, PAGE_SIZE_BYTES};
 use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
 #[doc(hidden)]
 pub mod iter;
@@ -115,19 +118,19 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
     /// Inserts a new element at the end of this [SVec]
     ///
     /// Will try to reallocate if `capacity == length`. If the canister is out of stable memory,
-    /// will return [Err] with the element that was about to get inserted.
+    /// will return [Rejected] with the element that was about to get inserted.
     #[inline]
-    pub fn push(&mut self, mut element: T) -> Result<(), T> {
-        if self.maybe_reallocate().is_ok() {
-            let elem_ptr = SSlice::_offset(self.ptr, (self.len * T::SIZE) as u64);
-            unsafe { crate::mem::write_fixed(elem_ptr, &mut element) };
+    pub fn push(&mut self, mut element: T) -> Result<(), Rejected<T>> {
+        if let Err(e) = self.maybe_reallocate() {
+            return Err(Rejected::new(element, e));
+        }
 
-            self.len += 1;
+        let elem_ptr = SSlice::_offset(self.ptr, (self.len * T::SIZE) as u64);
+        unsafe { crate::mem::write_fixed(elem_ptr, &mut element) };
 
-            Ok(())
-        } else {
-            Err(element)
-        }
+        self.len += 1;
+
+        Ok(())
     }
 
     /// Removes the last element of the [SVec]
@@ -229,34 +232,34 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
     /// Inserts a new element at the requested index, forward-shifting all elements after it
     ///
     /// Will try to reallocate, if `capacity == length`. If the canister is out of stable memory,
-    /// will return [Err] with the element that was about to get inserted.
+    /// will return [Rejected] with the element that was about to get inserted.
     ///
     /// # Panics
     /// Panics if out of bounds.
-    pub fn insert(&mut self, idx: usize, mut element: T) -> Result<(), T> {
+    pub fn insert(&mut self, idx: usize, mut element: T) -> Result<(), Rejected<T>> {
         if idx == self.len {
             return self.push(element);
         }
 
         assert!(idx < self.len, "out of bounds");
 
-        if self.maybe_reallocate().is_ok() {
-            let elem_ptr = SSlice::_offset(self.ptr, (idx * T::SIZE) as u64);
+        if let Err(e) = self.maybe_reallocate() {
+            return Err(Rejected::new(element, e));
+        }
+
+        let elem_ptr = SSlice::_offset(self.ptr, (idx * T::SIZE) as u64);
 
-            // moving elements after idx one slot to the right
-            let mut buf = vec![0u8; (self.len - idx) * T::SIZE];
-            unsafe { crate::mem::read_bytes(elem_ptr, &mut buf) };
-            unsafe { crate::mem::write_bytes(elem_ptr + T::SIZE as u64, &buf) };
+        // moving elements after idx one slot to the right
+        let mut buf = vec![0u8; (self.len - idx) * T::SIZE];
+        unsafe { crate::mem::read_bytes(elem_ptr, &mut buf) };
+        unsafe { crate::mem::write_bytes(elem_ptr + T::SIZE as u64, &buf) };
 
-            // writing the element
-            unsafe { crate::mem::write_fixed(elem_ptr, &mut element) };
+        // writing the element
+        unsafe { crate::mem::write_fixed(elem_ptr, &mut element) };
 
-            self.len += 1;
+        self.len += 1;
 
-            Ok(())
-        } else {
-            Err(element)
-        }
+        Ok(())
     }
 
     /// Removes element at the requested index, back-shifting all elements after it
@@ -310,7 +313,366 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
     /// Does not reallocate or shrink the underlying memory block.
     #[inline]
     pub fn clear(&mut self) {
-        while self.pop().is_some() {}
+        self.truncate(0);
+    }
+
+    /// Reserves capacity for at least `additional` more elements
+    ///
+    /// Reallocates at most once. If the canister is out of stable memory, returns [OutOfMemory],
+    /// leaving the [SVec] untouched.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), OutOfMemory> {
+        if additional == 0 {
+            return Ok(());
+        }
+
+        let required = self.len.checked_add(additional).unwrap();
+        assert!(required <= Self::max_capacity());
+
+        if self.ptr == EMPTY_PTR {
+            let cap = required.max(self.cap);
+
+            self.ptr = unsafe { allocate((cap * T::SIZE) as u64)?.as_ptr() };
+            self.cap = cap;
+
+            return Ok(());
+        }
+
+        if required <= self.cap {
+            return Ok(());
+        }
+
+        let new_cap = required.max(self.cap * 2).min(Self::max_capacity());
+        let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+
+        // capacity only changes if the reallocation succeeds
+        self.ptr = unsafe { reallocate(slice, (new_cap * T::SIZE) as u64)?.as_ptr() };
+        self.cap = new_cap;
+
+        Ok(())
+    }
+
+    /// Inserts all elements of the iterator at the end of this [SVec]
+    ///
+    /// Reallocates at most once and writes all elements with a single stable memory write. If the
+    /// canister is out of stable memory, returns [Rejected] with all the elements, none of which
+    /// were inserted.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter(0..100u64).expect("Out of memory");
+    ///
+    /// assert_eq!(vec.len(), 100);
+    /// assert_eq!(*vec.get(99).unwrap(), 99);
+    /// ```
+    pub fn extend_from_iter<I: IntoIterator<Item = T>>(
+        &mut self,
+        iter: I,
+    ) -> Result<(), Rejected<Vec<T>>> {
+        let mut elements = iter.into_iter().collect::<Vec<_>>();
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = self.reserve(elements.len()) {
+            return Err(Rejected::new(elements, e));
+        }
+
+        let mut buf = vec![0u8; elements.len() * T::SIZE];
+        for (elem, elem_buf) in elements.iter_mut().zip(buf.chunks_exact_mut(T::SIZE)) {
+            unsafe { elem.stable_drop_flag_off() };
+            elem.as_fixed_size_bytes(elem_buf);
+        }
+
+        let ptr = SSlice::_offset(self.ptr, (self.len * T::SIZE) as u64);
+        unsafe { crate::mem::write_bytes(ptr, &buf) };
+
+        self.len += elements.len();
+
+        Ok(())
+    }
+
+    /// Shortens this [SVec], keeping the first `len` elements and stable-dropping the rest in reverse order
+    ///
+    /// Does nothing, if `len` is greater or equal to the current length. Does not reallocate or
+    /// shrink the underlying memory block.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        let old_len = self.len;
+        self.len = len;
+
+        self.drop_range(len, old_len);
+    }
+
+    /// Retains only elements for which the provided lambda returns [true]
+    ///
+    /// Removed elements are stable-dropped. Elements are read and written in chunks, each element is
+    /// moved at most once. Works the same way as in [Vec].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter(0..10u64).expect("Out of memory");
+    ///
+    /// vec.retain(|it| *it % 2 == 0);
+    ///
+    /// assert_eq!(vec.iter().map(|it| *it).collect::<Vec<_>>(), vec![0, 2, 4, 6, 8]);
+    /// ```
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.retain_raw(|buf| f(&Self::read_as_reference(buf)));
+    }
+
+    /// Removes consecutive elements, for which the provided lambda returns [true]
+    ///
+    /// The lambda is passed the current element and the last retained element. Removed elements are
+    /// stable-dropped. Works the same way as in [Vec].
+    pub fn dedup_by<F>(&mut self, mut same_bucket: F)
+    where
+        F: FnMut(&T, &T) -> bool,
+    {
+        let mut prev: Option<T> = None;
+
+        self.retain_raw(|buf| {
+            let elem = Self::read_as_reference(buf);
+
+            if let Some(prev) = &prev {
+                if same_bucket(&elem, prev) {
+                    return false;
+                }
+            }
+
+            prev = Some(elem);
+
+            true
+        });
+    }
+
+    /// Removes consecutive elements, that resolve to the same key
+    ///
+    /// Removed elements are stable-dropped. Works the same way as in [Vec].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter([10u64, 11, 20, 21, 22, 10]).expect("Out of memory");
+    ///
+    /// vec.dedup_by_key(|it| *it / 10);
+    ///
+    /// assert_eq!(vec.iter().map(|it| *it).collect::<Vec<_>>(), vec![10, 20, 10]);
+    /// ```
+    #[inline]
+    pub fn dedup_by_key<K, F>(&mut self, mut key: F)
+    where
+        K: PartialEq,
+        F: FnMut(&T) -> K,
+    {
+        self.dedup_by(|a, b| key(a) == key(b));
+    }
+
+    /// Removes consecutive equal elements
+    ///
+    /// Removed elements are stable-dropped. Works the same way as in [Vec].
+    #[inline]
+    pub fn dedup(&mut self)
+    where
+        T: PartialEq,
+    {
+        self.dedup_by(|a, b| a == b);
+    }
+
+    /// Splits this [SVec] into two at the given index
+    ///
+    /// Returns a new [SVec] containing elements `[at, len)`, while this one keeps elements `[0, at)`.
+    /// Elements are moved with a single stable memory read and write. If the canister is out of stable
+    /// memory, returns [OutOfMemory], leaving this [SVec] untouched.
+    ///
+    /// # Panics
+    /// Panics if `at > len`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter(0..10u64).expect("Out of memory");
+    ///
+    /// let tail = vec.split_off(7).expect("Out of memory");
+    ///
+    /// assert_eq!(vec.len(), 7);
+    /// assert_eq!(tail.iter().map(|it| *it).collect::<Vec<_>>(), vec![7, 8, 9]);
+    /// ```
+    pub fn split_off(&mut self, at: usize) -> Result<Self, OutOfMemory> {
+        assert!(at <= self.len, "out of bounds");
+
+        let tail_len = self.len - at;
+        if tail_len == 0 {
+            return Ok(Self::new());
+        }
+
+        let mut other = Self::new_with_capacity(tail_len)?;
+
+        let mut buf = vec![0u8; tail_len * T::SIZE];
+        unsafe {
+            crate::mem::read_bytes(SSlice::_offset(self.ptr, (at * T::SIZE) as u64), &mut buf)
+        };
+        unsafe { crate::mem::write_bytes(SSlice::_offset(other.ptr, 0), &buf) };
+
+        other.len = tail_len;
+        self.len = at;
+
+        Ok(other)
+    }
+
+    /// Removes the range of elements, returning them in an iterator
+    ///
+    /// Elements, which were not consumed by the iterator, are stable-dropped when the iterator is
+    /// dropped. Elements after the range are then back-shifted with a single stable memory read and
+    /// write. If the iterator is leaked (e.g. with [std::mem::forget]), elements after the range are
+    /// leaked as well.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds or if its start is greater than its end.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter(0..10u64).expect("Out of memory");
+    ///
+    /// let drained = vec.drain(2..5).collect::<Vec<_>>();
+    ///
+    /// assert_eq!(drained, vec![2, 3, 4]);
+    /// assert_eq!(vec.iter().map(|it| *it).collect::<Vec<_>>(), vec![0, 1, 5, 6, 7, 8, 9]);
+    /// ```
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> SVecDrain<'_, T> {
+        let (start, end) = self.to_bounds(range);
+
+        SVecDrain::new(self, start, end)
+    }
+
+    /// Reads elements of the range into a [Vec] with a single stable memory read
+    ///
+    /// Handy for aggregations or exports of big chunks of data. Only available for [Copy] elements,
+    /// since those can't own any stable memory. Use [SVec::iter] for other types.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter(0..10u64).expect("Out of memory");
+    ///
+    /// assert_eq!(vec.read_range(2..5), vec![2, 3, 4]);
+    /// assert_eq!(vec.read_range(..).iter().sum::<u64>(), 45);
+    /// ```
+    pub fn read_range<R: RangeBounds<usize>>(&self, range: R) -> Vec<T>
+    where
+        T: Copy,
+    {
+        let (start, end) = self.to_bounds(range);
+
+        self.read_range_as_reference(start, end)
+    }
+
+    /// Overwrites elements starting from the `start` index with the provided ones, using a single stable
+    /// memory write
+    ///
+    /// Only available for [Copy] elements, since those can't own any stable memory.
+    ///
+    /// # Panics
+    /// Panics if `start + elements.len()` is greater than the length of this [SVec].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter(0..5u64).expect("Out of memory");
+    ///
+    /// vec.write_range(1, &[10, 20, 30]);
+    ///
+    /// assert_eq!(vec.read_range(..), vec![0, 10, 20, 30, 4]);
+    /// ```
+    pub fn write_range(&mut self, start: usize, elements: &[T])
+    where
+        T: Copy,
+    {
+        let end = start.checked_add(elements.len()).unwrap();
+        assert!(end <= self.len, "out of bounds");
+
+        self.write_at(start, elements);
+    }
+
+    /// Sorts this [SVec] with the provided comparator
+    ///
+    /// The sort is stable. Elements are read into heap with a single stable memory read, sorted there
+    /// and written back with a single stable memory write, so this function needs `len * T::SIZE`
+    /// bytes of heap memory. Elements themselves are only moved, nothing gets stable-dropped.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    /// vec.extend_from_iter([3u64, 1, 2]).expect("Out of memory");
+    ///
+    /// vec.sort_by(|a, b| b.cmp(a));
+    ///
+    /// assert_eq!(vec.iter().map(|it| *it).collect::<Vec<_>>(), vec![3, 2, 1]);
+    /// ```
+    pub fn sort_by<F>(&mut self, compare: F)
+    where
+        F: FnMut(&T, &T) -> Ordering,
+    {
+        let mut elements = self.read_range_as_reference(0, self.len);
+        elements.sort_by(compare);
+
+        self.write_at(0, &elements);
+    }
+
+    /// Sorts this [SVec] with the provided comparator, but might not preserve the order of equal elements
+    ///
+    /// See [SVec::sort_by] for details.
+    pub fn sort_unstable_by<F>(&mut self, compare: F)
+    where
+        F: FnMut(&T, &T) -> Ordering,
+    {
+        let mut elements = self.read_range_as_reference(0, self.len);
+        elements.sort_unstable_by(compare);
+
+        self.write_at(0, &elements);
     }
 
     /// Performs binary search on a sorted [SVec], using the provided lambda
@@ -402,6 +764,36 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         SVecIter::new(self)
     }
 
+    /// Returns an immutable iterator over this collection, starting from the element at the `cursor` index
+    ///
+    /// Cursors are returned by [SVecIter::cursor]. They are plain indices, so they can be sent to a
+    /// client and used to resume the iteration in a later call. If the cursor is out of bounds, the
+    /// iterator is empty.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::collections::SVec;
+    /// # use ic_stable_memory::stable_memory_init;
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let mut vec = SVec::new();
+    ///
+    /// for i in 0..100 {
+    ///     vec.push(i).expect("Out of memory");
+    /// }
+    ///
+    /// let mut iter = vec.iter();
+    /// let first_page = iter.by_ref().take(10).map(|it| *it).collect::<Vec<_>>();
+    /// let cursor = iter.cursor();
+    ///
+    /// let second_page = vec.iter_from_cursor(cursor).take(10).map(|it| *it).collect::<Vec<_>>();
+    /// assert_eq!(second_page, (10..20).collect::<Vec<_>>());
+    /// ```
+    #[inline]
+    pub fn iter_from_cursor(&self, cursor: usize) -> SVecIter<'_, T> {
+        SVecIter::new_at(self, cursor)
+    }
+
     /// Prints byte representation of this collection
     ///
     /// Useful for tests
@@ -433,17 +825,167 @@ impl<T: StableType + AsFixedSizeBytes> SVec<T> {
         }
 
         if self.len() == self.capacity() {
-            self.cap = self.cap.checked_mul(2).unwrap();
-            assert!(self.cap <= Self::max_capacity());
+            let new_cap = self.cap.checked_mul(2).unwrap();
+            assert!(new_cap <= Self::max_capacity());
 
             let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
 
-            self.ptr = unsafe { reallocate(slice, (self.cap * T::SIZE) as u64)?.as_ptr() };
+            // capacity only changes if the reallocation succeeds
+            self.ptr = unsafe { reallocate(slice, (new_cap * T::SIZE) as u64)?.as_ptr() };
+            self.cap = new_cap;
         }
 
         Ok(())
     }
 
+    // the number of elements read or written at once by chunked operations
+    #[inline]
+    fn chunk_len() -> usize {
+        (PAGE_SIZE_BYTES as usize / T::SIZE.max(1)).max(1)
+    }
+
+    #[inline]
+    fn read_as_reference(buf: &[u8]) -> T {
+        let mut it = T::from_fixed_size_bytes(buf);
+        unsafe { it.stable_drop_flag_off() };
+
+        it
+    }
+
+    // stable-drops elements of [from, to) range in reverse order
+    pub(crate) fn drop_range(&self, from: usize, to: usize) {
+        let mut buf = Vec::new();
+        let mut end = to;
+
+        while end > from {
+            let start = end.saturating_sub(Self::chunk_len()).max(from);
+
+            buf.resize((end - start) * T::SIZE, 0);
+            unsafe {
+                crate::mem::read_bytes(
+                    SSlice::_offset(self.ptr, (start * T::SIZE) as u64),
+                    &mut buf,
+                )
+            };
+
+            for elem_buf in buf.chunks_exact(T::SIZE).rev() {
+                let mut elem = T::from_fixed_size_bytes(elem_buf);
+                unsafe { elem.stable_drop_flag_on() };
+
+                drop(elem);
+            }
+
+            end = start;
+        }
+    }
+
+    // keeps elements for which `keep` returns true, compacting them chunk by chunk
+    fn retain_raw<F: FnMut(&[u8]) -> bool>(&mut self, mut keep: F) {
+        let len = self.len;
+
+        // if `keep` panics, the rest of elements is leaked instead of being dropped twice
+        self.len = 0;
+
+        let mut buf = Vec::new();
+        let mut read_idx = 0;
+        let mut write_idx = 0;
+
+        while read_idx < len {
+            let chunk_len = Self::chunk_len().min(len - read_idx);
+
+            buf.resize(chunk_len * T::SIZE, 0);
+            unsafe {
+                crate::mem::read_bytes(
+                    SSlice::_offset(self.ptr, (read_idx * T::SIZE) as u64),
+                    &mut buf,
+                )
+            };
+
+            let mut kept = 0;
+            for i in 0..chunk_len {
+                let range = (i * T::SIZE)..((i + 1) * T::SIZE);
+
+                if keep(&buf[range.clone()]) {
+                    buf.copy_within(range, kept * T::SIZE);
+                    kept += 1;
+                } else {
+                    let mut elem = T::from_fixed_size_bytes(&buf[range]);
+                    unsafe { elem.stable_drop_flag_on() };
+
+                    drop(elem);
+                }
+            }
+
+            if kept > 0 && (write_idx != read_idx || kept != chunk_len) {
+                unsafe {
+                    crate::mem::write_bytes(
+                        SSlice::_offset(self.ptr, (write_idx * T::SIZE) as u64),
+                        &buf[0..(kept * T::SIZE)],
+                    )
+                };
+            }
+
+            read_idx += chunk_len;
+            write_idx += kept;
+        }
+
+        self.len = write_idx;
+    }
+
+    fn to_bounds<R: RangeBounds<usize>>(&self, range: R) -> (usize, usize) {
+        let start = match range.start_bound() {
+            Bound::Included(it) => *it,
+            Bound::Excluded(it) => it.checked_add(1).unwrap(),
+            Bound::Unbounded => 0,
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(it) => it.checked_add(1).unwrap(),
+            Bound::Excluded(it) => *it,
+            Bound::Unbounded => self.len,
+        };
+
+        assert!(start <= end && end <= self.len, "out of bounds");
+
+        (start, end)
+    }
+
+    fn read_range_as_reference(&self, start: usize, end: usize) -> Vec<T> {
+        debug_assert!(start <= end && end <= self.len);
+
+        let mut buf = vec![0u8; (end - start) * T::SIZE];
+        if !buf.is_empty() {
+            unsafe {
+                crate::mem::read_bytes(
+                    SSlice::_offset(self.ptr, (start * T::SIZE) as u64),
+                    &mut buf,
+                )
+            };
+        }
+
+        buf.chunks_exact(T::SIZE)
+            .map(Self::read_as_reference)
+            .collect()
+    }
+
+    // overwrites elements without dropping the old ones
+    fn write_at(&mut self, start: usize, elements: &[T]) {
+        debug_assert!(start + elements.len() <= self.len);
+
+        if elements.is_empty() {
+            return;
+        }
+
+        let mut buf = vec![0u8; elements.len() * T::SIZE];
+        for (elem, elem_buf) in elements.iter().zip(buf.chunks_exact_mut(T::SIZE)) {
+            elem.as_fixed_size_bytes(elem_buf);
+        }
+
+        unsafe {
+            crate::mem::write_bytes(SSlice::_offset(self.ptr, (start * T::SIZE) as u64), &buf)
+        };
+    }
+
     pub(crate) fn get_element_ptr(&self, idx: usize) -> Option<StablePtr> {
         if idx < self.len() {
             Some(SSlice::_offset(self.ptr, (idx * T::SIZE) as u64))
@@ -529,6 +1071,35 @@ impl<T: StableType + AsFixedSizeBytes> StableType for SVec<T> {
             deallocate(slice);
         }
     }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        if self.ptr == EMPTY_PTR {
+            return;
+        }
+
+        visitor(unsafe { SSlice::from_ptr(self.ptr).unwrap() });
+
+        for elem in self.iter() {
+            elem.visit_allocations(visitor);
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> IncrementalDrop for SVec<T> {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        while !self.is_empty() && has_budget(start, instruction_budget) {
+            self.pop();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len() as u64
+    }
 }
 
 impl<T: StableType + AsFixedSizeBytes> Drop for SVec<T> {
@@ -855,6 +1426,211 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn cursors_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::new();
+            for i in 0..100u64 {
+                vec.push(i).unwrap();
+            }
+
+            let mut cursor = 0;
+            let mut result = Vec::new();
+
+            loop {
+                let mut iter = vec.iter_from_cursor(cursor);
+                let page = iter.by_ref().take(7).map(|it| *it).collect::<Vec<_>>();
+
+                if page.is_empty() {
+                    break;
+                }
+
+                result.extend(page);
+                cursor = iter.cursor();
+            }
+
+            assert_eq!(result, (0..100).collect::<Vec<_>>());
+            assert_eq!(cursor, 100);
+            assert!(vec.iter_from_cursor(1000).next().is_none());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn rich_api_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let to_vec = |vec: &SVec<SBox<String>>| {
+                vec.iter().map(|it| (**it).clone()).collect::<Vec<String>>()
+            };
+
+            let mut example = (0..3000)
+                .map(|it| (it % 700).to_string())
+                .collect::<Vec<_>>();
+
+            let mut vec = SVec::new();
+            vec.extend_from_iter(example.iter().map(|it| SBox::new(it.clone()).unwrap()))
+                .unwrap();
+            assert_eq!(vec.capacity(), 3000);
+            assert_eq!(to_vec(&vec), example);
+
+            vec.extend_from_iter(Vec::new()).unwrap();
+            assert_eq!(vec.len(), 3000);
+
+            vec.retain(|it| !it.ends_with('3'));
+            example.retain(|it| !it.ends_with('3'));
+            assert_eq!(to_vec(&vec), example);
+
+            vec.sort_by(|a, b| a.cmp(b));
+            example.sort();
+            assert_eq!(to_vec(&vec), example);
+
+            vec.dedup();
+            example.dedup();
+            assert_eq!(to_vec(&vec), example);
+
+            vec.dedup_by_key(|it| it.len());
+            example.dedup_by_key(|it| it.len());
+            assert_eq!(to_vec(&vec), example);
+
+            let mut vec = SVec::new();
+            let mut example = Vec::new();
+            for i in 0..1000u64 {
+                vec.push(SBox::new(i.to_string()).unwrap()).unwrap();
+                example.push(i.to_string());
+            }
+
+            let drained = vec
+                .drain(100..200)
+                .map(|it| it.into_inner())
+                .collect::<Vec<_>>();
+            assert_eq!(drained, example.drain(100..200).collect::<Vec<_>>());
+            assert_eq!(to_vec(&vec), example);
+
+            // partially consumed
+            let mut drain = vec.drain(..10);
+            assert_eq!(drain.len(), 10);
+            assert_eq!(*drain.next().unwrap(), "0");
+            drop(drain);
+            example.drain(..10);
+            assert_eq!(to_vec(&vec), example);
+
+            vec.drain(800..);
+            example.drain(800..);
+            assert_eq!(to_vec(&vec), example);
+
+            let tail = vec.split_off(500).unwrap();
+            let example_tail = example.split_off(500);
+            assert_eq!(to_vec(&vec), example);
+            assert_eq!(to_vec(&tail), example_tail);
+            assert!(vec.split_off(500).unwrap().is_empty());
+
+            vec.truncate(100);
+            example.truncate(100);
+            assert_eq!(to_vec(&vec), example);
+            vec.truncate(1000);
+            assert_eq!(vec.len(), 100);
+
+            vec.sort_unstable_by(|a, b| b.cmp(a));
+            example.sort_unstable_by(|a, b| b.cmp(a));
+            assert_eq!(to_vec(&vec), example);
+
+            let mut empty = SVec::<u64>::new();
+            empty.sort_by(|a, b| a.cmp(b));
+            empty.retain(|_| false);
+            empty.drain(..);
+            empty.reserve(0).unwrap();
+            assert!(empty.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn reserve_works_fine() {
+        stable::clear();
+        init_allocator(1);
+
+        {
+            let mut vec = SVec::<u64>::new();
+            vec.reserve(100).unwrap();
+            assert_eq!(vec.capacity(), 100);
+
+            vec.extend_from_iter(0..100).unwrap();
+            vec.reserve(1).unwrap();
+            assert_eq!(vec.capacity(), 200);
+
+            let err = vec.reserve(100_000).unwrap_err();
+            assert_eq!(vec.capacity(), 200);
+            assert!(err.requested_size() >= 100_100 * u64::SIZE as u64);
+
+            let rejected = vec.extend_from_iter(0..100_000).unwrap_err();
+            assert_eq!(rejected.value.len(), 100_000);
+            assert_eq!(vec.len(), 100);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn chunked_reads_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<u64>::new();
+            vec.extend_from_iter(0..100_000).unwrap();
+
+            let start = stable::instruction_counter();
+            let sum = vec.iter().map(|it| *it).sum::<u64>();
+            let spent = stable::instruction_counter() - start;
+
+            assert_eq!(sum, (0..100_000).sum::<u64>());
+            // way less than a separate read per element
+            assert!(spent < 100_000 * (100 + u64::SIZE as u64) / 10);
+
+            assert_eq!(
+                vec.iter_from_cursor(99_990)
+                    .map(|it| *it)
+                    .collect::<Vec<_>>(),
+                (99_990..100_000).collect::<Vec<_>>()
+            );
+
+            assert_eq!(vec.read_range(..), (0..100_000).collect::<Vec<_>>());
+            assert_eq!(vec.read_range(10..=12), vec![10, 11, 12]);
+            assert!(vec.read_range(5..5).is_empty());
+
+            vec.write_range(99_998, &[1, 2]);
+            vec.write_range(0, &[]);
+            assert_eq!(vec.read_range(99_997..), vec![99_997, 1, 2]);
+            assert_eq!(vec.len(), 100_000);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn write_range_out_of_bounds_should_panic() {
+        stable::clear();
+        stable_memory_init();
+
+        let mut vec = SVec::<u64>::new();
+        vec.extend_from_iter(0..10).unwrap();
+
+        vec.write_range(9, &[1, 2]);
+    }
+
     #[test]
     fn random_works_fine() {
         stable::clear();
@@ -1157,8 +1933,8 @@ mod tests {
                             .map(|it| it.into_inner());
                         self.log.push(Action::CanisterUpgrade);
                     }
-                    Err(vec) => {
-                        self.vec = Some(vec);
+                    Err(e) => {
+                        self.vec = Some(e.into_inner());
                     }
                 },
             }
@@ -0,0 +1,57 @@
+use crate::collections::vec_deque::SVecDeque;
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::StableType;
+
+pub struct SVecDequeIter<'a, T: StableType + AsFixedSizeBytes> {
+    deque: &'a SVecDeque<T>,
+    front_idx: usize,
+    back_idx: usize,
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> SVecDequeIter<'a, T> {
+    pub(crate) fn new(deque: &'a SVecDeque<T>) -> Self {
+        Self {
+            deque,
+            front_idx: 0,
+            back_idx: deque.len(),
+        }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> Iterator for SVecDequeIter<'a, T> {
+    type Item = SRef<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front_idx == self.back_idx {
+            return None;
+        }
+
+        let ptr = self.deque.get_element_ptr(self.front_idx)?;
+        self.front_idx += 1;
+
+        unsafe { Some(SRef::new(ptr)) }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back_idx - self.front_idx;
+
+        (len, Some(len))
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> DoubleEndedIterator for SVecDequeIter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front_idx == self.back_idx {
+            return None;
+        }
+
+        self.back_idx -= 1;
+        let ptr = self.deque.get_element_ptr(self.back_idx)?;
+
+        unsafe { Some(SRef::new(ptr)) }
+    }
+}
+
+impl<'a, T: StableType + AsFixedSizeBytes> ExactSizeIterator for SVecDequeIter<'a, T> {}
@@ -0,0 +1,676 @@
+use crate::collections::vec_deque::iter::SVecDequeIter;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::EMPTY_PTR;
+use crate::mem::deferred_drop::IncrementalDrop;
+use crate::mem::s_slice::SSlice;
+use crate::mem::StablePtr;
+use crate::primitive::s_ref::SRef;
+use crate::primitive::s_ref_mut::SRefMut;
+use crate::primitive::StableType;
+use crate::utils::budget::has_budget;
+use crate::{allocate, deallocate, reallocate, stable, OutOfMemory, Rejected};
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+
+#[doc(hidden)]
+pub mod iter;
+
+const DEFAULT_CAPACITY: usize = 4;
+
+/// Stable analog of [VecDeque](std::collections::VecDeque) - a double-ended queue
+///
+/// Implemented as a ring buffer, so pushing and popping from both ends is `O(1)`. May reallocate
+/// on pushes, in this case will copy the underlying data to a new location.
+///
+/// This is a "finite" data structure, it can only hold up to [u32::MAX] / `T::SIZE` elements.
+/// Putting more elements inside will panic.
+///
+/// `T` has to implement both [StableType] and [AsFixedSizeBytes]. [SVecDeque] itself implements
+/// these traits and can be nested inside other stable data structures.
+///
+/// When [SVecDeque] is stable-dropped, its elements are also stable-dropped, from back to front.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVecDeque;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut queue = SVecDeque::<u64>::new();
+///
+/// queue.push_back(1).expect("Out of memory");
+/// queue.push_back(2).expect("Out of memory");
+/// queue.push_front(0).expect("Out of memory");
+///
+/// assert_eq!(queue.pop_front(), Some(0));
+/// assert_eq!(queue.pop_back(), Some(2));
+/// assert_eq!(*queue.get(0).unwrap(), 1);
+/// ```
+pub struct SVecDeque<T: StableType + AsFixedSizeBytes> {
+    ptr: u64,
+    head: usize,
+    len: usize,
+    cap: usize,
+    stable_drop_flag: bool,
+    _marker_t: PhantomData<T>,
+}
+
+impl<T: StableType + AsFixedSizeBytes> SVecDeque<T> {
+    /// Creates a [SVecDeque] of capacity equal to 4 elements.
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            ptr: EMPTY_PTR,
+            head: 0,
+            len: 0,
+            cap: DEFAULT_CAPACITY,
+            stable_drop_flag: true,
+            _marker_t: PhantomData,
+        }
+    }
+
+    /// Creates a [SVecDeque] of requested capacity.
+    ///
+    /// Does allocate stable memory, returning [OutOfMemory] if there is not enough of it.
+    /// If this function returns [Ok], you are guaranteed to have enough stable memory to store at
+    /// least `capacity` elements in it.
+    #[inline]
+    pub fn new_with_capacity(capacity: usize) -> Result<Self, OutOfMemory> {
+        assert!(capacity > 0 && capacity <= Self::max_capacity());
+
+        Ok(Self {
+            ptr: unsafe { allocate((capacity * T::SIZE) as u64)?.as_ptr() },
+            head: 0,
+            len: 0,
+            cap: capacity,
+            stable_drop_flag: true,
+            _marker_t: PhantomData,
+        })
+    }
+
+    /// Returns the capacity of this [SVecDeque]
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Returns the length of this [SVecDeque]
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns [true] if the length of this [SVecDeque] is `0`
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum possible capacity of this [SVecDeque]
+    #[inline]
+    pub const fn max_capacity() -> usize {
+        u32::MAX as usize / T::SIZE
+    }
+
+    /// Inserts a new element at the end of this [SVecDeque]
+    ///
+    /// Will try to reallocate if `capacity == length`. If the canister is out of stable memory,
+    /// will return [Rejected] with the element that was about to get inserted.
+    #[inline]
+    pub fn push_back(&mut self, mut element: T) -> Result<(), Rejected<T>> {
+        if let Err(e) = self.maybe_reallocate() {
+            return Err(Rejected::new(element, e));
+        }
+
+        let elem_ptr = self.physical_ptr(self.len);
+        unsafe { crate::mem::write_fixed(elem_ptr, &mut element) };
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Inserts a new element at the beginning of this [SVecDeque]
+    ///
+    /// Will try to reallocate if `capacity == length`. If the canister is out of stable memory,
+    /// will return [Rejected] with the element that was about to get inserted.
+    #[inline]
+    pub fn push_front(&mut self, mut element: T) -> Result<(), Rejected<T>> {
+        if let Err(e) = self.maybe_reallocate() {
+            return Err(Rejected::new(element, e));
+        }
+
+        self.head = if self.head == 0 {
+            self.cap - 1
+        } else {
+            self.head - 1
+        };
+
+        let elem_ptr = self.physical_ptr(0);
+        unsafe { crate::mem::write_fixed(elem_ptr, &mut element) };
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes the last element of this [SVecDeque]
+    ///
+    /// If the [SVecDeque] is empty, returns [None].
+    #[inline]
+    pub fn pop_back(&mut self) -> Option<T> {
+        let elem_ptr = self.get_element_ptr(self.len.checked_sub(1)?)?;
+        self.len -= 1;
+
+        Some(unsafe { crate::mem::read_fixed_for_move(elem_ptr) })
+    }
+
+    /// Removes the first element of this [SVecDeque]
+    ///
+    /// If the [SVecDeque] is empty, returns [None].
+    #[inline]
+    pub fn pop_front(&mut self) -> Option<T> {
+        let elem_ptr = self.get_element_ptr(0)?;
+
+        self.head = (self.head + 1) % self.cap;
+        self.len -= 1;
+
+        Some(unsafe { crate::mem::read_fixed_for_move(elem_ptr) })
+    }
+
+    /// Returns a [SRef] pointing to the element at requested index, counting from the front
+    ///
+    /// If out of bounds, returns [None]
+    #[inline]
+    pub fn get(&self, idx: usize) -> Option<SRef<'_, T>> {
+        let ptr = self.get_element_ptr(idx)?;
+
+        unsafe { Some(SRef::new(ptr)) }
+    }
+
+    /// Returns a [SRefMut] pointing to the element at requested index, counting from the front
+    ///
+    /// If out of bounds, returns [None]
+    #[inline]
+    pub fn get_mut(&mut self, idx: usize) -> Option<SRefMut<'_, T>> {
+        let ptr = self.get_element_ptr(idx)?;
+
+        unsafe { Some(SRefMut::new(ptr)) }
+    }
+
+    /// Returns a [SRef] pointing to the first element of this [SVecDeque]
+    #[inline]
+    pub fn front(&self) -> Option<SRef<'_, T>> {
+        self.get(0)
+    }
+
+    /// Returns a [SRef] pointing to the last element of this [SVecDeque]
+    #[inline]
+    pub fn back(&self) -> Option<SRef<'_, T>> {
+        self.get(self.len.checked_sub(1)?)
+    }
+
+    /// Clears the [SVecDeque] from elements
+    ///
+    /// Does not reallocate or shrink the underlying memory block.
+    #[inline]
+    pub fn clear(&mut self) {
+        while self.pop_back().is_some() {}
+
+        self.head = 0;
+    }
+
+    /// Returns an immutable iterator over this collection, from front to back
+    #[inline]
+    pub fn iter(&self) -> SVecDequeIter<'_, T> {
+        SVecDequeIter::new(self)
+    }
+
+    fn maybe_reallocate(&mut self) -> Result<(), OutOfMemory> {
+        if self.ptr == EMPTY_PTR {
+            self.ptr = unsafe { allocate((self.cap * T::SIZE) as u64)?.as_ptr() };
+            return Ok(());
+        }
+
+        if self.len == self.cap {
+            let old_cap = self.cap;
+            let new_cap = old_cap.checked_mul(2).unwrap();
+            assert!(new_cap <= Self::max_capacity());
+
+            let slice = unsafe { SSlice::from_ptr(self.ptr).unwrap() };
+
+            self.ptr = unsafe { reallocate(slice, (new_cap * T::SIZE) as u64)?.as_ptr() };
+            self.cap = new_cap;
+
+            // the elements before the head are the wrapped tail - moving them right after the old end
+            if self.head > 0 {
+                let mut buf = vec![0u8; self.head * T::SIZE];
+                unsafe { crate::mem::read_bytes(SSlice::_offset(self.ptr, 0), &mut buf) };
+                unsafe {
+                    crate::mem::write_bytes(
+                        SSlice::_offset(self.ptr, (old_cap * T::SIZE) as u64),
+                        &buf,
+                    )
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn physical_ptr(&self, idx: usize) -> StablePtr {
+        let physical_idx = (self.head + idx) % self.cap;
+
+        SSlice::_offset(self.ptr, (physical_idx * T::SIZE) as u64)
+    }
+
+    pub(crate) fn get_element_ptr(&self, idx: usize) -> Option<StablePtr> {
+        if idx < self.len {
+            Some(self.physical_ptr(idx))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> Default for SVecDeque<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes + Debug> Debug for SVecDeque<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (idx, item) in self.iter().enumerate() {
+            item.fmt(f)?;
+
+            if idx < self.len - 1 {
+                f.write_str(", ")?;
+            }
+        }
+        f.write_str("]")
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> AsFixedSizeBytes for SVecDeque<T> {
+    const SIZE: usize = u64::SIZE + usize::SIZE * 3;
+    type Buf = [u8; u64::SIZE + usize::SIZE * 3];
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.ptr.as_fixed_size_bytes(&mut buf[0..u64::SIZE]);
+        self.head
+            .as_fixed_size_bytes(&mut buf[u64::SIZE..(u64::SIZE + usize::SIZE)]);
+        self.len.as_fixed_size_bytes(
+            &mut buf[(u64::SIZE + usize::SIZE)..(u64::SIZE + usize::SIZE * 2)],
+        );
+        self.cap.as_fixed_size_bytes(
+            &mut buf[(u64::SIZE + usize::SIZE * 2)..(u64::SIZE + usize::SIZE * 3)],
+        );
+    }
+
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let ptr = u64::from_fixed_size_bytes(&arr[0..u64::SIZE]);
+        let head = usize::from_fixed_size_bytes(&arr[u64::SIZE..(u64::SIZE + usize::SIZE)]);
+        let len = usize::from_fixed_size_bytes(
+            &arr[(u64::SIZE + usize::SIZE)..(u64::SIZE + usize::SIZE * 2)],
+        );
+        let cap = usize::from_fixed_size_bytes(
+            &arr[(u64::SIZE + usize::SIZE * 2)..(u64::SIZE + usize::SIZE * 3)],
+        );
+
+        Self {
+            ptr,
+            head,
+            len,
+            cap,
+            stable_drop_flag: false,
+            _marker_t: PhantomData,
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> StableType for SVecDeque<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.stable_drop_flag = false;
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.stable_drop_flag = true;
+    }
+
+    #[inline]
+    fn should_stable_drop(&self) -> bool {
+        self.stable_drop_flag
+    }
+
+    unsafe fn stable_drop(&mut self) {
+        if self.ptr != EMPTY_PTR {
+            self.clear();
+
+            let slice = SSlice::from_ptr(self.ptr).unwrap();
+
+            deallocate(slice);
+        }
+    }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        if self.ptr == EMPTY_PTR {
+            return;
+        }
+
+        visitor(unsafe { SSlice::from_ptr(self.ptr).unwrap() });
+
+        for elem in self.iter() {
+            elem.visit_allocations(visitor);
+        }
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> IncrementalDrop for SVecDeque<T> {
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool {
+        let start = stable::instruction_counter();
+
+        while !self.is_empty() && has_budget(start, instruction_budget) {
+            self.pop_back();
+        }
+
+        self.is_empty()
+    }
+
+    #[inline]
+    fn pending_elements(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl<T: StableType + AsFixedSizeBytes> Drop for SVecDeque<T> {
+    fn drop(&mut self) {
+        if self.should_stable_drop() {
+            unsafe {
+                self.stable_drop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::vec_deque::SVecDeque;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::s_box::SBox;
+    use crate::utils::mem_context::stable;
+    use crate::utils::test::generate_random_string;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, init_allocator, retrieve_custom_data,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data,
+    };
+    use rand::rngs::ThreadRng;
+    use rand::{thread_rng, Rng};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn basic_flow_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut deque = SVecDeque::default();
+            assert!(deque.is_empty());
+            assert!(deque.pop_front().is_none());
+            assert!(deque.pop_back().is_none());
+            assert!(deque.front().is_none());
+            assert!(deque.back().is_none());
+
+            deque.push_back(1u64).unwrap();
+            deque.push_back(2).unwrap();
+            deque.push_front(0).unwrap();
+
+            assert_eq!(deque.len(), 3);
+            assert_eq!(*deque.front().unwrap(), 0);
+            assert_eq!(*deque.back().unwrap(), 2);
+            assert_eq!(*deque.get(1).unwrap(), 1);
+            assert!(deque.get(3).is_none());
+
+            *deque.get_mut(1).unwrap() = 10;
+            assert_eq!(
+                deque.iter().map(|it| *it).collect::<Vec<_>>(),
+                vec![0, 10, 2]
+            );
+            assert_eq!(
+                deque.iter().rev().map(|it| *it).collect::<Vec<_>>(),
+                vec![2, 10, 0]
+            );
+
+            assert_eq!(deque.pop_front(), Some(0));
+            assert_eq!(deque.pop_back(), Some(2));
+            assert_eq!(deque.pop_back(), Some(10));
+            assert!(deque.is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn wrapped_growth_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut deque = SVecDeque::new_with_capacity(4).unwrap();
+
+            // head in the middle of the buffer, elements wrap around its end
+            deque.push_back(2u64).unwrap();
+            deque.push_back(3).unwrap();
+            deque.push_front(1).unwrap();
+            deque.push_front(0).unwrap();
+            assert_eq!(deque.capacity(), 4);
+
+            deque.push_back(4).unwrap();
+            deque.push_front(100).unwrap();
+            assert_eq!(deque.capacity(), 8);
+
+            assert_eq!(
+                deque.iter().map(|it| *it).collect::<Vec<_>>(),
+                vec![100, 0, 1, 2, 3, 4]
+            );
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn serialization_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut deque = SVecDeque::<u32>::new();
+            deque.push_back(1).unwrap();
+            deque.push_front(0).unwrap();
+
+            let buf = deque.as_new_fixed_size_bytes();
+            let deque1 = SVecDeque::<u32>::from_fixed_size_bytes(buf._deref());
+
+            assert_eq!(deque.ptr, deque1.ptr);
+            assert_eq!(deque.head, deque1.head);
+            assert_eq!(deque.len, deque1.len);
+            assert_eq!(deque.cap, deque1.cap);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[derive(Debug)]
+    enum Action {
+        PushBack,
+        PushFront,
+        PopBack,
+        PopFront,
+        GetMut,
+        Clear,
+        CanisterUpgrade,
+    }
+
+    struct Fuzzer {
+        deque: Option<SVecDeque<SBox<String>>>,
+        example: VecDeque<String>,
+        rng: ThreadRng,
+        log: Vec<Action>,
+    }
+
+    impl Fuzzer {
+        fn new() -> Self {
+            Self {
+                deque: Some(SVecDeque::new()),
+                example: VecDeque::new(),
+                rng: thread_rng(),
+                log: Vec::new(),
+            }
+        }
+
+        fn deque(&mut self) -> &mut SVecDeque<SBox<String>> {
+            self.deque.as_mut().unwrap()
+        }
+
+        fn next(&mut self) {
+            let action = self.rng.gen_range(0..101);
+
+            match action {
+                // PUSH BACK ~30%
+                0..=29 => {
+                    let str = generate_random_string(&mut self.rng);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        if self.deque().push_back(data).is_err() {
+                            return;
+                        }
+
+                        self.example.push_back(str);
+                        self.log.push(Action::PushBack);
+                    }
+                }
+                // PUSH FRONT ~30%
+                30..=59 => {
+                    let str = generate_random_string(&mut self.rng);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        if self.deque().push_front(data).is_err() {
+                            return;
+                        }
+
+                        self.example.push_front(str);
+                        self.log.push(Action::PushFront);
+                    }
+                }
+                // POP BACK ~15%
+                60..=74 => {
+                    assert_eq!(
+                        self.deque().pop_back().map(|it| it.into_inner()),
+                        self.example.pop_back()
+                    );
+                    self.log.push(Action::PopBack);
+                }
+                // POP FRONT ~15%
+                75..=89 => {
+                    assert_eq!(
+                        self.deque().pop_front().map(|it| it.into_inner()),
+                        self.example.pop_front()
+                    );
+                    self.log.push(Action::PopFront);
+                }
+                // GET MUT
+                90..=97 => {
+                    let len = self.deque().len();
+                    if len == 0 {
+                        return self.next();
+                    }
+
+                    let idx = self.rng.gen_range(0..len);
+                    let str = generate_random_string(&mut self.rng);
+
+                    if let Ok(data) = SBox::new(str.clone()) {
+                        *self.deque().get_mut(idx).unwrap() = data;
+                        self.example[idx] = str;
+
+                        self.log.push(Action::GetMut);
+                    }
+                }
+                98 => {
+                    self.deque().clear();
+                    self.example.clear();
+
+                    self.log.push(Action::Clear);
+                }
+                // CANISTER UPGRADE
+                _ => match SBox::new(self.deque.take().unwrap()) {
+                    Ok(data) => {
+                        store_custom_data(1, data);
+
+                        if stable_memory_pre_upgrade().is_ok() {
+                            stable_memory_post_upgrade();
+                        }
+
+                        self.deque = retrieve_custom_data::<SVecDeque<SBox<String>>>(1)
+                            .map(|it| it.into_inner());
+
+                        self.log.push(Action::CanisterUpgrade);
+                    }
+                    Err(e) => {
+                        self.deque = Some(e.into_inner());
+                    }
+                },
+            }
+
+            _debug_validate_allocator();
+            assert_eq!(self.deque().len(), self.example.len());
+
+            let example = self.example.clone();
+            for (idx, it) in self.deque().iter().enumerate() {
+                assert_eq!(**it, example[idx]);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzer_works_fine() {
+        stable::clear();
+        init_allocator(0);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn fuzzer_works_fine_limited_memory() {
+        stable::clear();
+        init_allocator(10);
+
+        {
+            let mut fuzzer = Fuzzer::new();
+
+            for _ in 0..5_000 {
+                fuzzer.next();
+            }
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
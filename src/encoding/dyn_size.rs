@@ -1,3 +1,4 @@
+use crate::utils::error::DecodeError;
 use candid::de::IDLDeserialize;
 use candid::utils::ArgumentDecoder;
 use candid::{CandidType, Deserialize, Result};
@@ -33,6 +34,21 @@ pub trait AsDynSizeBytes {
     /// # Panics
     /// Should panic if data decoding failed.
     fn from_dyn_size_bytes(buf: &[u8]) -> Self;
+
+    /// Decodes self from a slice of bytes, returning [DecodeError] instead of panicking.
+    ///
+    /// Same rules about trailing bytes apply, as for [AsDynSizeBytes::from_dyn_size_bytes].
+    ///
+    /// The default implementation simply calls [AsDynSizeBytes::from_dyn_size_bytes] and therefore
+    /// still panics on invalid data. Implementations provided by this crate (and by its derive macros)
+    /// override it and never panic.
+    #[inline]
+    fn try_from_dyn_size_bytes(buf: &[u8]) -> std::result::Result<Self, DecodeError>
+    where
+        Self: Sized,
+    {
+        Ok(Self::from_dyn_size_bytes(buf))
+    }
 }
 
 #[cfg(not(feature = "custom_dyn_encoding"))]
@@ -55,6 +71,13 @@ impl<T: AsFixedSizeBytes> AsDynSizeBytes for T {
     fn from_dyn_size_bytes(buf: &[u8]) -> Self {
         Self::from_fixed_size_bytes(&buf[0..T::SIZE])
     }
+
+    #[inline]
+    fn try_from_dyn_size_bytes(buf: &[u8]) -> std::result::Result<Self, DecodeError> {
+        DecodeError::check_len(buf, T::SIZE)?;
+
+        Ok(Self::from_fixed_size_bytes(&buf[0..T::SIZE]))
+    }
 }
 
 #[cfg(not(feature = "custom_dyn_encoding"))]
@@ -71,12 +94,17 @@ impl AsDynSizeBytes for Vec<u8> {
 
     #[inline]
     fn from_dyn_size_bytes(buf: &[u8]) -> Self {
+        Self::try_from_dyn_size_bytes(buf).unwrap()
+    }
+
+    fn try_from_dyn_size_bytes(buf: &[u8]) -> std::result::Result<Self, DecodeError> {
+        DecodeError::check_len(buf, usize::SIZE)?;
         let len = usize::from_fixed_size_bytes(&buf[0..usize::SIZE]);
-        let mut v = vec![0u8; len];
 
-        v.copy_from_slice(&buf[usize::SIZE..(usize::SIZE + len)]);
+        let end = usize::SIZE.saturating_add(len);
+        DecodeError::check_len(buf, end)?;
 
-        v
+        Ok(buf[usize::SIZE..end].to_vec())
     }
 }
 
@@ -94,12 +122,13 @@ impl AsDynSizeBytes for String {
 
     #[inline]
     fn from_dyn_size_bytes(buf: &[u8]) -> Self {
-        let len = usize::from_fixed_size_bytes(&buf[0..usize::SIZE]);
-        let mut v = vec![0u8; len];
+        Self::try_from_dyn_size_bytes(buf).unwrap()
+    }
 
-        v.copy_from_slice(&buf[usize::SIZE..(usize::SIZE + len)]);
+    fn try_from_dyn_size_bytes(buf: &[u8]) -> std::result::Result<Self, DecodeError> {
+        let v = Vec::<u8>::try_from_dyn_size_bytes(buf)?;
 
-        String::from_utf8(v).unwrap()
+        String::from_utf8(v).map_err(|_| DecodeError::InvalidUtf8)
     }
 }
 
@@ -120,3 +149,47 @@ where
     let (res,) = candid_decode_args_allow_trailing(bytes)?;
     Ok(res)
 }
+
+#[cfg(all(test, not(feature = "custom_dyn_encoding")))]
+mod tests {
+    use crate::encoding::AsDynSizeBytes;
+    use crate::utils::error::DecodeError;
+
+    #[test]
+    fn try_decoding_works_fine() {
+        let buf = String::from("test").as_dyn_size_bytes();
+        assert_eq!(String::try_from_dyn_size_bytes(&buf).unwrap(), "test");
+
+        assert_eq!(
+            String::try_from_dyn_size_bytes(&buf[0..2]),
+            Err(DecodeError::UnexpectedEnd {
+                expected: 4,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            String::try_from_dyn_size_bytes(&buf[0..6]),
+            Err(DecodeError::UnexpectedEnd {
+                expected: 8,
+                actual: 6
+            })
+        );
+
+        let mut invalid = buf.clone();
+        invalid[4] = 0xff;
+        assert_eq!(
+            String::try_from_dyn_size_bytes(&invalid),
+            Err(DecodeError::InvalidUtf8)
+        );
+
+        let mut huge = buf;
+        huge[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Vec::<u8>::try_from_dyn_size_bytes(&huge).is_err());
+
+        assert_eq!(
+            u64::try_from_dyn_size_bytes(&[1, 0, 0, 0, 0, 0, 0, 0, 1]),
+            Ok(1)
+        );
+        assert!(u64::try_from_dyn_size_bytes(&[1, 0, 0]).is_err());
+    }
+}
@@ -10,11 +10,15 @@
 //! are encoded to [Vec] of [u8].
 //!
 //! [AsFixedSizeBytes] trait encapusaltes these differences providing a simple API.
+//!
+//! `usize` and `isize` are encoded with 4 bytes on every target, just like on `wasm32`, so encoded
+//! data doesn't depend on the pointer width of the build.
 
 use candid::{Int, Nat, Principal};
 use ic_stable_memory_derive::{AsFixedSizeBytes, StableType};
 use num_bigint::{BigInt, BigUint, Sign};
 use ic_ledger_types::Subaccount;
+use std::cmp::Reverse;
 
 /// Allows fast and space-efficient fixed size data encoding.
 ///
@@ -85,11 +89,35 @@ impl_for_number!(i64);
 impl_for_number!(u64);
 impl_for_number!(i128);
 impl_for_number!(u128);
-impl_for_number!(isize);
-impl_for_number!(usize);
 impl_for_number!(f32);
 impl_for_number!(f64);
 
+// pointer-sized numbers are always encoded with 4 bytes, like on wasm32, so the stable memory
+// layout is the same on every target and canister dumps can be inspected by native builds
+macro_rules! impl_for_pointer_sized_number {
+    ($ty:ty, $as:ty) => {
+        impl AsFixedSizeBytes for $ty {
+            const SIZE: usize = <$as>::SIZE;
+            type Buf = [u8; Self::SIZE];
+
+            #[inline]
+            fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+                <$as>::try_from(*self)
+                    .expect(concat!(stringify!($ty), " value does not fit into 4 bytes"))
+                    .as_fixed_size_bytes(buf)
+            }
+
+            #[inline]
+            fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+                <$as>::from_fixed_size_bytes(buf) as $ty
+            }
+        }
+    };
+}
+
+impl_for_pointer_sized_number!(isize, i32);
+impl_for_pointer_sized_number!(usize, u32);
+
 impl AsFixedSizeBytes for char {
     const SIZE: usize = u32::SIZE;
     type Buf = [u8; Self::SIZE];
@@ -159,6 +187,21 @@ impl<T: AsFixedSizeBytes> AsFixedSizeBytes for Option<T> {
     }
 }
 
+impl<T: AsFixedSizeBytes> AsFixedSizeBytes for Reverse<T> {
+    const SIZE: usize = T::SIZE;
+    type Buf = T::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.0.as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        Reverse(T::from_fixed_size_bytes(buf))
+    }
+}
+
 impl<const N: usize> AsFixedSizeBytes for [(); N] {
     const SIZE: usize = 0;
     type Buf = [u8; 0];
@@ -586,4 +629,13 @@ fn subaccount_test() {
   let acc_copy = Subaccount::from_fixed_size_bytes(&buf);
 
   assert_eq!(acc, acc_copy);
-}
\ No newline at end of file
+}
+#[test]
+fn pointer_sized_numbers_have_fixed_width() {
+    assert_eq!(usize::SIZE, 4);
+    assert_eq!(isize::SIZE, 4);
+
+    assert_eq!(usize::from_fixed_size_bytes(&10usize.as_new_fixed_size_bytes()), 10);
+    assert_eq!(isize::from_fixed_size_bytes(&(-10isize).as_new_fixed_size_bytes()), -10);
+    assert_eq!(10usize.as_new_fixed_size_bytes(), 10u32.as_new_fixed_size_bytes());
+}
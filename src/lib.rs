@@ -18,6 +18,7 @@
 //! 5. In addition to these data structures, this crate provides you with a fully featured toolset
 //! to build your own data structure, if you need something more domain-specific.
 use crate::mem::allocator::StableMemoryAllocator;
+use crate::mem::verification::VerificationReport;
 use mem::s_slice::SSlice;
 use std::cell::RefCell;
 
@@ -36,7 +37,8 @@ pub mod utils;
 pub use ic_stable_memory_derive as derive;
 
 use crate::utils::isoprint;
-pub use crate::utils::mem_context::{stable, OutOfMemory, PAGE_SIZE_BYTES};
+pub use crate::utils::error::{DecodeError, OutOfMemory, Rejected, StableMemoryError};
+pub use crate::utils::mem_context::{stable, PAGE_SIZE_BYTES};
 pub use encoding::{AsDynSizeBytes, AsFixedSizeBytes, Buffer};
 pub use primitive::s_box::SBox;
 pub use primitive::StableType;
@@ -165,12 +167,76 @@ pub fn stable_memory_pre_upgrade() -> Result<(), OutOfMemory> {
 /// 1. there is no valid pointer stored at first 8 bytes of stable memory,
 /// 2. there is no valid `SBox` was found at that location,
 /// 3. deserialization step during `SBox`'s "unboxing" failed due to invalid data stored inside this `SBox`,
-/// 4. if there was an already initialized stable memory allocator.
+/// 4. if there was an already initialized stable memory allocator,
+/// 5. stable memory was written by a build with the `checksums` feature set differently - block
+///    headers and `SBox` payloads have a different layout with checksums, so such memory can't be read.
 #[inline]
 pub fn stable_memory_post_upgrade() {
     reinit_allocator();
 }
 
+/// Simulates a canister upgrade in tests
+///
+/// Performs the whole upgrade cycle: runs the provided `pre_upgrade` hook, persists the allocator
+/// with [stable_memory_pre_upgrade], drops the heap state this crate keeps, retrieves the allocator
+/// back with [stable_memory_post_upgrade] and then runs the provided `post_upgrade` hook. The hooks
+/// should only move your state in and out of stable memory (for example, with [store_custom_data]
+/// and [retrieve_custom_data]) - they should not call [stable_memory_pre_upgrade] and
+/// [stable_memory_post_upgrade] themselves. Everything the canister keeps on the heap, should be
+/// taken from `thread_local!` variables by `pre_upgrade`, since a real upgrade wipes it.
+///
+/// Combine it with [stable::save_to_file] and [stable::load_from_file] to test migrations on real
+/// stable memory snapshots.
+///
+/// Utility function which is only available for targets other than `wasm`.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::{
+/// #     retrieve_custom_data, simulate_upgrade, stable_memory_init, store_custom_data, SBox,
+/// # };
+/// # use std::cell::RefCell;
+/// thread_local! {
+///     static STATE: RefCell<Option<SVec<u64>>> = RefCell::default();
+/// }
+///
+/// fn save_state() {
+///     let state = STATE.with(|it| it.borrow_mut().take().unwrap());
+///     store_custom_data(0, SBox::new(state).expect("Out of memory"));
+/// }
+///
+/// fn load_state() {
+///     let state = retrieve_custom_data::<SVec<u64>>(0).unwrap().into_inner();
+///     STATE.with(|it| *it.borrow_mut() = Some(state));
+/// }
+///
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// stable_memory_init();
+///
+/// let mut state = SVec::new();
+/// state.push(10).expect("Out of memory");
+/// STATE.with(|it| *it.borrow_mut() = Some(state));
+///
+/// simulate_upgrade(save_state, load_state);
+///
+/// STATE.with(|it| assert_eq!(*it.borrow().as_ref().unwrap().get(0).unwrap(), 10));
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized allocator, or if there is not enough stable memory to persist
+/// it.
+#[cfg(not(target_family = "wasm"))]
+pub fn simulate_upgrade<Pre: FnOnce(), Post: FnOnce()>(pre_upgrade: Pre, post_upgrade: Post) {
+    pre_upgrade();
+
+    stable_memory_pre_upgrade().expect("Out of memory");
+    mem::allocator::drop_heap_state();
+    stable_memory_post_upgrade();
+
+    post_upgrade();
+}
+
 /// An alias for [stable_memory_init], but allows limiting the maximum number of stable memory pages
 /// that the allocator can grow. [init_allocator(0)] works exactly the same as [stable_memory_init()].
 ///
@@ -522,6 +588,35 @@ pub fn make_sure_can_allocate(size: u64) -> bool {
     })
 }
 
+/// Same as [make_sure_can_allocate()], but returns the [OutOfMemory] error describing the reason of
+/// the failure.
+///
+/// Internally calls [StableMemoryAllocator::reserve](mem::allocator::StableMemoryAllocator::reserve).
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::{reserve, stable_memory_init};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// match reserve(1_000_000) {
+///     Ok(_) => println!("It is possible to allocate a million bytes of stable memory"),
+///     Err(e) => println!("{e}"),
+/// }
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn reserve(size: u64) -> Result<(), OutOfMemory> {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        if let Some(alloc) = &mut *it.borrow_mut() {
+            alloc.reserve(size)
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
 /// Returns the amount of stable memory in bytes which is under the allocator's management.
 ///
 /// Always equals to [stable64_size()](ic_cdk::api::stable::stable64_size) - `8`.
@@ -594,6 +689,46 @@ pub fn get_max_pages() -> u64 {
     })
 }
 
+/// Checks the consistency of the whole stable memory heap, without panicking.
+///
+/// Walks through every memory block, checking that front and rear markers of each block match (so no
+/// two blocks overlap), that there are no adjacent free blocks left unmerged, that every free block is
+/// present in the allocator's free-list and vice versa, and that total free size is tracked correctly.
+/// Found problems are collected into the [VerificationReport].
+///
+/// The verification stops, when roughly `instruction_budget` instructions were spent, and can be resumed
+/// later by calling this function again with the same report. This makes it possible to verify big
+/// heaps from a timer in production. Pass [u64::MAX] to verify the whole heap at once.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::mem::verification::VerificationReport;
+/// # use ic_stable_memory::{stable_memory_init, verify_stable_memory};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut report = VerificationReport::new();
+///
+/// // call it from a timer instead
+/// while !report.is_complete() {
+///     verify_stable_memory(&mut report, 1_000_000_000);
+/// }
+///
+/// assert!(report.is_ok(), "Stable memory is corrupted: {:?}", report.issues);
+/// ```
+///
+/// # Panics
+/// Panics if there is no initialized stable memory allocator.
+#[inline]
+pub fn verify_stable_memory(report: &mut VerificationReport, instruction_budget: u64) {
+    STABLE_MEMORY_ALLOCATOR.with(|it| {
+        if let Some(alloc) = &*it.borrow() {
+            report.step(alloc, instruction_budget);
+        } else {
+            unreachable!("StableMemoryAllocator is not initialized");
+        }
+    })
+}
+
 #[inline]
 pub fn _debug_validate_allocator() {
     STABLE_MEMORY_ALLOCATOR.with(|it: &RefCell<Option<StableMemoryAllocator>>| {
@@ -618,12 +753,15 @@ pub fn _debug_print_allocator() {
 
 #[cfg(test)]
 mod tests {
+    use crate::collections::SVec;
     use crate::{
-        _debug_print_allocator, allocate, deallocate, get_allocated_size, get_free_size,
-        init_allocator, reallocate, retrieve_custom_data, stable_memory_init,
-        stable_memory_post_upgrade, stable_memory_pre_upgrade, store_custom_data, SBox,
+        _debug_print_allocator, _debug_validate_allocator, allocate, deallocate,
+        get_allocated_size, get_free_size, init_allocator, reallocate, retrieve_custom_data,
+        simulate_upgrade, stable, stable_memory_init, stable_memory_post_upgrade,
+        stable_memory_pre_upgrade, store_custom_data, SBox,
     };
     use crate::{deinit_allocator, reinit_allocator, SSlice};
+    use std::cell::RefCell;
 
     #[test]
     fn basic_flow_works_fine() {
@@ -647,6 +785,69 @@ mod tests {
         _debug_print_allocator();
     }
 
+    #[test]
+    fn simulate_upgrade_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        let mut vec = SVec::<SBox<String>>::new();
+        for i in 0..100 {
+            vec.push(SBox::new(i.to_string()).unwrap()).unwrap();
+        }
+
+        let state = RefCell::new(Some(vec));
+
+        simulate_upgrade(
+            || {
+                let vec = state.borrow_mut().take().unwrap();
+                store_custom_data(0, SBox::new(vec).unwrap());
+            },
+            || {
+                *state.borrow_mut() =
+                    retrieve_custom_data::<SVec<SBox<String>>>(0).map(|it| it.into_inner());
+            },
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "ic-stable-memory-simulate-upgrade-works-fine-{}.bin",
+            std::process::id()
+        ));
+
+        // make a snapshot of the upgraded state
+        let vec = state.take().unwrap();
+        store_custom_data(0, SBox::new(vec).unwrap());
+        stable_memory_pre_upgrade().unwrap();
+        stable::save_to_file(&path).unwrap();
+
+        // and restore it from scratch
+        stable::clear();
+        stable::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        stable_memory_post_upgrade();
+        let vec = retrieve_custom_data::<SVec<SBox<String>>>(0)
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(vec.len(), 100);
+        for (i, it) in vec.iter().enumerate() {
+            assert_eq!(**it, i.to_string());
+        }
+
+        drop(vec);
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn simulate_upgrade_without_allocator_should_panic() {
+        stable::clear();
+
+        simulate_upgrade(|| {}, || {});
+    }
+
     #[test]
     #[should_panic]
     fn init_allocator_twice_should_panic() {
@@ -19,12 +19,40 @@ use crate::primitive::StableType;
 use crate::utils::math::ceil_div;
 use crate::{stable, OutOfMemory, PAGE_SIZE_BYTES};
 use candid::{encode_one, CandidType, Deserialize};
+use std::cell::Cell;
 use std::collections::{BTreeMap, HashMap};
 
 pub(crate) const ALLOCATOR_PTR: StablePtr = 0;
 pub(crate) const MIN_PTR: StablePtr = u64::SIZE as u64;
 pub(crate) const EMPTY_PTR: StablePtr = u64::MAX;
 
+// the biggest bit of the persisted allocator pointer tells whether memory was written with the
+// `checksums` feature enabled - block headers and SBox payloads have a different layout in that case
+const CHECKSUMS_FLAG: u64 = 1 << 63;
+
+thread_local! {
+    // incremented each time the free list changes, so resumable scans could tell the heap has changed
+    static EPOCH: Cell<u64> = const { Cell::new(0) };
+}
+
+#[inline]
+pub(crate) fn epoch() -> u64 {
+    EPOCH.with(|it| it.get())
+}
+
+#[inline]
+fn next_epoch() {
+    EPOCH.with(|it| it.set(it.get() + 1));
+}
+
+// A real upgrade wipes the heap, including the free list, resumable scans have seen - they should
+// start over
+#[cfg(not(target_family = "wasm"))]
+#[inline]
+pub(crate) fn drop_heap_state() {
+    next_epoch();
+}
+
 #[doc(hidden)]
 #[derive(Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct StableMemoryAllocator {
@@ -65,11 +93,17 @@ impl StableMemoryAllocator {
         it
     }
 
-    pub fn make_sure_can_allocate(&mut self, mut size: u64) -> bool {
-        size = Self::pad_size(size);
+    #[inline]
+    pub fn make_sure_can_allocate(&mut self, size: u64) -> bool {
+        self.reserve(size).is_ok()
+    }
+
+    pub fn reserve(&mut self, size: u64) -> Result<(), OutOfMemory> {
+        let requested_size = size;
+        let mut size = Self::pad_size(size);
 
         if self.free_blocks.range(size..).next().is_some() {
-            return true;
+            return Ok(());
         }
 
         if self.max_ptr > MIN_PTR {
@@ -80,22 +114,22 @@ impl StableMemoryAllocator {
             }
         }
 
-        match self.grow(size) {
-            Ok(fb) => {
-                self.more_available_size(fb.get_total_size_bytes());
-                self.more_free_size(fb.get_total_size_bytes());
+        let fb = self
+            .grow(size)
+            .map_err(|e| self.out_of_memory(e, requested_size))?;
 
-                self.push_free_block(fb);
+        self.more_available_size(fb.get_total_size_bytes());
+        self.more_free_size(fb.get_total_size_bytes());
 
-                true
-            }
-            Err(_) => false,
-        }
+        self.push_free_block(fb);
+
+        Ok(())
     }
 
     #[allow(clippy::never_loop)]
-    pub fn allocate(&mut self, mut size: u64) -> Result<SSlice, OutOfMemory> {
-        size = Self::pad_size(size);
+    pub fn allocate(&mut self, size: u64) -> Result<SSlice, OutOfMemory> {
+        let requested_size = size;
+        let size = Self::pad_size(size);
 
         // searching for a free block that is equal or bigger in size, than asked
         let free_block = loop {
@@ -106,7 +140,9 @@ impl StableMemoryAllocator {
                     if let Some(last_free_block) =
                         FreeBlock::from_rear_ptr(self.max_ptr - StablePtr::SIZE as u64)
                     {
-                        let fb = self.grow(size - last_free_block.get_size_bytes())?;
+                        let fb = self
+                            .grow(size - last_free_block.get_size_bytes())
+                            .map_err(|e| self.out_of_memory(e, requested_size))?;
 
                         self.more_available_size(fb.get_total_size_bytes());
                         self.more_free_size(fb.get_total_size_bytes());
@@ -117,7 +153,9 @@ impl StableMemoryAllocator {
                     }
                 }
 
-                let fb = self.grow(size)?;
+                let fb = self
+                    .grow(size)
+                    .map_err(|e| self.out_of_memory(e, requested_size))?;
 
                 self.more_available_size(fb.get_total_size_bytes());
                 self.more_free_size(fb.get_total_size_bytes());
@@ -151,8 +189,9 @@ impl StableMemoryAllocator {
         self.push_free_block(free_block);
     }
 
-    pub fn reallocate(&mut self, slice: SSlice, mut new_size: u64) -> Result<SSlice, OutOfMemory> {
-        new_size = Self::pad_size(new_size);
+    pub fn reallocate(&mut self, slice: SSlice, new_size: u64) -> Result<SSlice, OutOfMemory> {
+        let requested_size = new_size;
+        let new_size = Self::pad_size(new_size);
 
         if new_size <= slice.get_size_bytes() {
             return Ok(slice);
@@ -166,9 +205,8 @@ impl StableMemoryAllocator {
         }
 
         // FIXME: can be more accurate by checking, if can merge with back first
-        if !self.make_sure_can_allocate(new_size) {
-            return Err(OutOfMemory);
-        }
+        self.reserve(new_size)
+            .map_err(|e| e.with_requested_size(requested_size))?;
 
         // othewise, get ready for move and copy the data
         let mut b = vec![0u8; slice.get_size_bytes().try_into().unwrap()];
@@ -197,14 +235,31 @@ impl StableMemoryAllocator {
         let buf = self.as_dyn_size_bytes();
 
         unsafe { crate::mem::write_bytes(slice.offset(0), &buf) };
-        unsafe { crate::mem::write_fixed(0, &mut slice.as_ptr()) };
+
+        let mut slice_ptr = if cfg!(feature = "checksums") {
+            slice.as_ptr() | CHECKSUMS_FLAG
+        } else {
+            slice.as_ptr()
+        };
+        unsafe { crate::mem::write_fixed(0, &mut slice_ptr) };
 
         Ok(())
     }
 
     pub fn retrieve() -> Self {
-        let slice_ptr = unsafe { crate::mem::read_fixed_for_reference(0) };
-        let slice = unsafe { SSlice::from_ptr(slice_ptr).unwrap() };
+        let slice_ptr: StablePtr = unsafe { crate::mem::read_fixed_for_reference(0) };
+
+        // checked before reading any header, since a header of a different format can't be decoded
+        let with_checksums = slice_ptr & CHECKSUMS_FLAG == CHECKSUMS_FLAG;
+        if with_checksums != cfg!(feature = "checksums") {
+            panic!(
+                "Stable memory was written with the `checksums` feature {}, but this build has it {}",
+                if with_checksums { "enabled" } else { "disabled" },
+                if with_checksums { "disabled" } else { "enabled" },
+            );
+        }
+
+        let slice = unsafe { SSlice::from_ptr(slice_ptr & !CHECKSUMS_FLAG).unwrap() };
 
         let mut buf = vec![0u8; slice.get_size_bytes() as usize];
         unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
@@ -272,6 +327,21 @@ impl StableMemoryAllocator {
         self.max_pages
     }
 
+    #[inline]
+    pub(crate) fn get_max_ptr(&self) -> StablePtr {
+        self.max_ptr
+    }
+
+    #[inline]
+    pub(crate) fn get_free_blocks(&self) -> &BTreeMap<u64, Vec<FreeBlock>> {
+        &self.free_blocks
+    }
+
+    #[inline]
+    pub(crate) fn get_custom_data_ptr(&self, idx: usize) -> Option<StablePtr> {
+        self.custom_data_pointers.get(&idx).copied()
+    }
+
     fn try_reallocate_in_place(
         &mut self,
         mut free_block: FreeBlock,
@@ -335,6 +405,8 @@ impl StableMemoryAllocator {
     }
 
     fn push_free_block(&mut self, mut free_block: FreeBlock) {
+        next_epoch();
+
         free_block = self.try_merge_with_neighbors(free_block);
 
         free_block.persist();
@@ -355,6 +427,8 @@ impl StableMemoryAllocator {
     fn pop_free_block(&mut self, size: u64) -> Option<FreeBlock> {
         let (&actual_size, blocks) = self.free_blocks.range_mut(size..).next()?;
 
+        next_epoch();
+
         let free_block = unsafe { blocks.pop().unwrap_unchecked() };
 
         if blocks.is_empty() {
@@ -365,6 +439,8 @@ impl StableMemoryAllocator {
     }
 
     fn remove_free_block(&mut self, block: &FreeBlock) {
+        next_epoch();
+
         let blocks = self.free_blocks.get_mut(&block.get_size_bytes()).unwrap();
 
         match blocks.binary_search(block) {
@@ -385,12 +461,13 @@ impl StableMemoryAllocator {
         let available_pages = stable::size_pages();
 
         if self.max_pages != 0 && available_pages + pages_to_grow > self.max_pages {
-            return Err(OutOfMemory);
+            return Err(OutOfMemory::MaxPagesReached {
+                requested_size: size,
+                max_pages: self.max_pages,
+            });
         }
 
-        if stable::grow(pages_to_grow).is_err() {
-            return Err(OutOfMemory);
-        }
+        stable::grow(pages_to_grow).map_err(|e| e.with_requested_size(size))?;
 
         let new_max_ptr = (available_pages + pages_to_grow) * PAGE_SIZE_BYTES;
         let it = FreeBlock::new_total_size(self.max_ptr, new_max_ptr - self.max_ptr);
@@ -400,6 +477,18 @@ impl StableMemoryAllocator {
         Ok(it)
     }
 
+    // if there is enough free memory in total, then the failure is caused by fragmentation
+    fn out_of_memory(&self, e: OutOfMemory, requested_size: u64) -> OutOfMemory {
+        if self.free_size >= FreeBlock::to_total_size(Self::pad_size(requested_size)) {
+            OutOfMemory::NoContiguousBlock {
+                requested_size,
+                free_size: self.free_size,
+            }
+        } else {
+            e.with_requested_size(requested_size)
+        }
+    }
+
     pub fn debug_validate_free_blocks(&self) {
         assert!(
             self.available_size == 0
@@ -457,7 +546,8 @@ impl AsDynSizeBytes for StableMemoryAllocator {
 #[cfg(test)]
 mod tests {
     use crate::encoding::AsDynSizeBytes;
-    use crate::mem::allocator::StableMemoryAllocator;
+    use crate::mem::allocator::{StableMemoryAllocator, CHECKSUMS_FLAG};
+    use crate::mem::StablePtr;
     use crate::primitive::s_box::SBox;
     use crate::utils::mem_context::stable;
     use crate::SSlice;
@@ -524,6 +614,22 @@ mod tests {
         }
     }
 
+    #[test]
+    #[should_panic(expected = "`checksums` feature")]
+    fn mismatched_checksums_feature_is_rejected() {
+        stable::clear();
+
+        let mut sma = StableMemoryAllocator::init(0);
+        sma.store().unwrap();
+
+        // pretend the memory was written by a build with the opposite setting
+        let mut slice_ptr: StablePtr = unsafe { crate::mem::read_fixed_for_reference(0) };
+        slice_ptr ^= CHECKSUMS_FLAG;
+        unsafe { crate::mem::write_fixed(0, &mut slice_ptr) };
+
+        StableMemoryAllocator::retrieve();
+    }
+
     #[derive(Debug)]
     enum Action {
         Alloc(SSlice),
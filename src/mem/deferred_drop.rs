@@ -0,0 +1,495 @@
+//! Instruction-bounded release of big stable collections.
+//!
+//! Stable-dropping or clearing a collection releases all of its elements in one go. For collections
+//! with millions of elements this can easily exceed the instruction limit of a single message and
+//! trap. [IncrementalDrop] allows releasing such a collection step by step, spending no more than the
+//! provided number of instructions per call, and [DeferredDrop] is a queue of such collections, which
+//! can be processed from a timer.
+
+use crate::collections::SVecDeque;
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::s_slice::SSlice;
+use crate::primitive::StableType;
+use crate::{stable, Rejected};
+use std::fmt::{Debug, Formatter};
+
+/// A stable collection, that can be cleared in several steps
+///
+/// Implemented by every collection of this crate, which releases its elements one by one when
+/// stable-dropped, except for [SBloomFilter](crate::collections::SBloomFilter) - it can't stay usable
+/// while its bits are released. Other collections, like
+/// [SCuckooFilter](crate::collections::SCuckooFilter), occupy a single memory block and are released
+/// in constant time.
+pub trait IncrementalDrop: StableType {
+    /// Removes (and stable-drops) elements of this collection, until it is empty or until the
+    /// instruction budget is spent
+    ///
+    /// Returns `true` if the collection is empty. Each element is stable-dropped at once, so a
+    /// single call can overspend the budget by the cost of dropping one element. The collection stays
+    /// fully usable between calls.
+    fn clear_incremental(&mut self, instruction_budget: u64) -> bool;
+
+    /// Returns the number of elements, which [IncrementalDrop::clear_incremental] still has to release
+    ///
+    /// Elements are released one by one, so this number tells how much work is left. What is an
+    /// element depends on the collection: an entry of a map, a word of a bit vector, a chunk of a
+    /// blob etc.
+    fn pending_elements(&self) -> u64;
+}
+
+/// A queue of stable collections waiting to be released
+///
+/// Instead of dropping a huge collection, push it into this queue and call [DeferredDrop::process]
+/// from time to time (for example, from a timer), until [DeferredDrop::pending_drops] returns `0`.
+/// [DeferredDrop::pending_elements] tells how much work is left, which helps to choose the budget.
+///
+/// The queue is a stable collection itself, it implements [StableType] and [AsFixedSizeBytes].
+/// Store it with [store_custom_data](crate::store_custom_data) before an upgrade and retrieve it
+/// afterwards, so pending values don't leak. Values pushed into it must be owned (their stable drop
+/// flag is on). The collection, which is being released, is kept decoded between calls. Dropping the
+/// queue itself stable-drops all pending values at once.
+///
+/// All values of a queue have the same type. Use a queue per type, or wrap collections into an enum,
+/// which implements [IncrementalDrop] by delegating to its variants.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::mem::deferred_drop::DeferredDrop;
+/// # use ic_stable_memory::{get_allocated_size, stable_memory_init, SBox};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut queue = DeferredDrop::new();
+///
+/// for _ in 0..2 {
+///     let mut map = SBTreeMap::new();
+///     for i in 0..1000u64 {
+///         map.insert(i, SBox::new(i.to_string()).unwrap()).unwrap();
+///     }
+///
+///     queue.push(map).expect("Out of memory");
+/// }
+///
+/// assert_eq!(queue.pending_elements(), 2000);
+///
+/// while queue.process(100_000) > 0 {
+///     // in a canister this would happen in a separate message
+/// }
+///
+/// drop(queue);
+/// assert_eq!(get_allocated_size(), 0);
+/// ```
+pub struct DeferredDrop<T: IncrementalDrop + AsFixedSizeBytes> {
+    current: Option<T>,
+    queue: SVecDeque<T>,
+    pending_elements: u64,
+}
+
+impl<T: IncrementalDrop + AsFixedSizeBytes> DeferredDrop<T> {
+    /// Creates an empty queue
+    ///
+    /// Does not allocate any heap or stable memory.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            current: None,
+            queue: SVecDeque::new(),
+            pending_elements: 0,
+        }
+    }
+
+    /// Puts the value at the end of the queue
+    ///
+    /// If the canister is out of stable memory, returns [Rejected] with the value.
+    #[inline]
+    pub fn push(&mut self, value: T) -> Result<(), Rejected<T>> {
+        let elements = value.pending_elements();
+        self.queue.push_back(value)?;
+        self.pending_elements += elements;
+
+        Ok(())
+    }
+
+    /// Releases queued values, until the queue is empty or until the instruction budget is spent
+    ///
+    /// Returns the number of values, which are not yet released.
+    pub fn process(&mut self, instruction_budget: u64) -> usize {
+        let start = stable::instruction_counter();
+
+        loop {
+            if self.current.is_none() {
+                self.current = self.queue.pop_front();
+            }
+
+            let current = match &mut self.current {
+                Some(it) => it,
+                None => break,
+            };
+
+            let spent = stable::instruction_counter().saturating_sub(start);
+            if spent >= instruction_budget {
+                break;
+            }
+
+            let before = current.pending_elements();
+            let done = current.clear_incremental(instruction_budget - spent);
+            let released = before.saturating_sub(current.pending_elements());
+
+            self.pending_elements = self.pending_elements.saturating_sub(released);
+
+            if !done {
+                break;
+            }
+
+            // an empty collection releases the rest of its memory in constant time
+            self.current = None;
+        }
+
+        self.pending_drops()
+    }
+
+    /// Returns the number of values, which are not yet released
+    #[inline]
+    pub fn pending_drops(&self) -> usize {
+        self.queue.len() + self.current.is_some() as usize
+    }
+
+    /// Returns the total number of elements, which queued values still have to release
+    ///
+    /// See [IncrementalDrop::pending_elements].
+    #[inline]
+    pub fn pending_elements(&self) -> u64 {
+        self.pending_elements
+    }
+
+    /// Returns `true` if there are no values waiting to be released
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pending_drops() == 0
+    }
+}
+
+impl<T: IncrementalDrop + AsFixedSizeBytes> Default for DeferredDrop<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: IncrementalDrop + AsFixedSizeBytes> AsFixedSizeBytes for DeferredDrop<T> {
+    const SIZE: usize = Option::<T>::SIZE + SVecDeque::<T>::SIZE + u64::SIZE;
+    type Buf = Vec<u8>;
+
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        let current_size = Option::<T>::SIZE;
+        let queue_end = current_size + SVecDeque::<T>::SIZE;
+
+        self.current
+            .as_fixed_size_bytes(&mut buf[0..current_size]);
+        self.queue
+            .as_fixed_size_bytes(&mut buf[current_size..queue_end]);
+        self.pending_elements
+            .as_fixed_size_bytes(&mut buf[queue_end..Self::SIZE]);
+    }
+
+    fn from_fixed_size_bytes(buf: &[u8]) -> Self {
+        let current_size = Option::<T>::SIZE;
+        let queue_end = current_size + SVecDeque::<T>::SIZE;
+
+        Self {
+            current: Option::<T>::from_fixed_size_bytes(&buf[0..current_size]),
+            queue: SVecDeque::from_fixed_size_bytes(&buf[current_size..queue_end]),
+            pending_elements: u64::from_fixed_size_bytes(&buf[queue_end..Self::SIZE]),
+        }
+    }
+}
+
+impl<T: IncrementalDrop + AsFixedSizeBytes> StableType for DeferredDrop<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        if let Some(it) = &mut self.current {
+            it.stable_drop_flag_on();
+        }
+        self.queue.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        if let Some(it) = &mut self.current {
+            it.stable_drop_flag_off();
+        }
+        self.queue.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        if let Some(it) = &self.current {
+            it.visit_allocations(visitor);
+        }
+        self.queue.visit_allocations(visitor);
+    }
+}
+
+impl<T: IncrementalDrop + AsFixedSizeBytes> Debug for DeferredDrop<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeferredDrop")
+            .field("pending_drops", &self.pending_drops())
+            .field("pending_elements", &self.pending_elements())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{
+        IndexExtractor, SBTreeMap, SBTreeMultiMap, SBTreeSet, SBinaryHeap, SBlob,
+        SCertifiedBTreeMap, SCertifiedBTreeSet, SHashMap, SHashSet, SIndex, SIndexedTable, SLog,
+        SRingLog, SVec, SVecDeque,
+    };
+    use crate::mem::deferred_drop::{DeferredDrop, IncrementalDrop};
+    use crate::utils::certification::Hash;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data, SBox,
+    };
+
+    #[test]
+    fn clear_incremental_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, SBox<String>>::new();
+            let mut hash_map = SHashMap::<u64, SBox<String>>::new();
+
+            for i in 0..1000u64 {
+                map.insert(i, SBox::new(i.to_string()).unwrap()).unwrap();
+                hash_map
+                    .insert(i, SBox::new(i.to_string()).unwrap())
+                    .unwrap();
+            }
+
+            let mut steps = 0;
+            while !map.clear_incremental(10_000) {
+                steps += 1;
+
+                // the map stays valid between steps
+                let len = map.len();
+                assert_eq!(map.iter().count() as u64, len);
+                assert_eq!(**map.get(&0).unwrap(), "0");
+            }
+            assert!(steps > 1);
+            assert!(map.is_empty());
+
+            // and is reusable after
+            map.insert(1, SBox::new(String::from("1")).unwrap())
+                .unwrap();
+            assert_eq!(map.len(), 1);
+
+            let mut steps = 0;
+            while !hash_map.clear_incremental(10_000) {
+                steps += 1;
+
+                let len = hash_map.len();
+                assert_eq!(hash_map.iter().count(), len);
+
+                // inserts in between steps are fine too
+                hash_map
+                    .insert(0, SBox::new(String::from("0")).unwrap())
+                    .unwrap();
+                assert_eq!(**hash_map.get(&0).unwrap(), "0");
+            }
+            assert!(steps > 1);
+            assert!(hash_map.is_empty());
+
+            assert!(!map.clear_incremental(0));
+            assert!(map.clear_incremental(u64::MAX));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    // clears the collection in small steps, checking the progress
+    fn drain<T: IncrementalDrop>(mut it: T, instruction_budget: u64) {
+        let mut prev = it.pending_elements();
+        assert!(prev > 0);
+
+        let mut steps = 0;
+        while !it.clear_incremental(instruction_budget) {
+            assert!(it.pending_elements() <= prev);
+            prev = it.pending_elements();
+            steps += 1;
+        }
+
+        assert!(steps > 1);
+        assert_eq!(it.pending_elements(), 0);
+    }
+
+    struct ByValue;
+
+    impl IndexExtractor<u64, u64> for ByValue {
+        type Key = u64;
+
+        fn index_key(_: &u64, row: &u64) -> Self::Key {
+            *row
+        }
+    }
+
+    fn hash(i: u64) -> Hash {
+        let mut it = Hash::default();
+        it[0..8].copy_from_slice(&i.to_be_bytes());
+
+        it
+    }
+
+    #[test]
+    fn every_collection_clears_incrementally() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::new();
+            let mut deque = SVecDeque::new();
+            let mut log = SLog::new();
+            let mut btree_map = SBTreeMap::new();
+            let mut btree_set = SBTreeSet::new();
+            let mut hash_map = SHashMap::new();
+            let mut hash_set = SHashSet::new();
+            let mut heap = SBinaryHeap::new();
+            let mut ring_log = SRingLog::new(1000).unwrap();
+            let mut blob = SBlob::new();
+            let mut table = SIndexedTable::<u64, u64, (SIndex<u64, u64, ByValue>,)>::new();
+            let mut certified_map = SCertifiedBTreeMap::new();
+            let mut certified_set = SCertifiedBTreeSet::new();
+            let mut multimap = SBTreeMultiMap::new();
+
+            for i in 0..1000u64 {
+                vec.push(SBox::new(i.to_string()).unwrap()).unwrap();
+                deque.push_back(SBox::new(i.to_string()).unwrap()).unwrap();
+                log.push(SBox::new(i.to_string()).unwrap()).unwrap();
+                btree_map
+                    .insert(i, SBox::new(i.to_string()).unwrap())
+                    .unwrap();
+                btree_set.insert(i).unwrap();
+                hash_map
+                    .insert(i, SBox::new(i.to_string()).unwrap())
+                    .unwrap();
+                hash_set.insert(i).unwrap();
+                heap.push(i).unwrap();
+                ring_log.push(SBox::new(i.to_string()).unwrap());
+                table.insert(i, i * 2).unwrap();
+                certified_map.insert(hash(i), ()).unwrap();
+                certified_set.insert(hash(i)).unwrap();
+                multimap.insert(i % 10, i).unwrap();
+            }
+            certified_map.commit();
+            certified_set.commit();
+
+            // a blob has few chunks, so it is drained chunk by chunk
+            for _ in 0..100 {
+                blob.append(&[1u8; 10_000]).unwrap();
+            }
+
+            drain(vec, 10_000);
+            drain(deque, 10_000);
+            drain(log, 10_000);
+            drain(btree_map, 10_000);
+            drain(btree_set, 10_000);
+            drain(hash_map, 10_000);
+            drain(hash_set, 10_000);
+            drain(heap, 10_000);
+            drain(ring_log, 10_000);
+            drain(blob, 1);
+            drain(table, 10_000);
+            drain(certified_map, 10_000);
+            drain(certified_set, 10_000);
+            drain(multimap, 10_000);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn deferred_drop_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut queue = DeferredDrop::new();
+
+            for _ in 0..5 {
+                let mut map = SHashMap::new();
+                for i in 0..1000u64 {
+                    map.insert(i, SBox::new(i.to_string()).unwrap()).unwrap();
+                }
+
+                queue.push(map).unwrap();
+            }
+
+            assert_eq!(queue.pending_drops(), 5);
+            assert_eq!(queue.pending_elements(), 5000);
+
+            let mut prev = queue.pending_elements();
+            let mut steps = 0;
+
+            while queue.process(50_000) > 0 {
+                assert!(queue.pending_elements() < prev);
+                prev = queue.pending_elements();
+                steps += 1;
+            }
+
+            assert!(steps > 5);
+            assert!(queue.is_empty());
+            assert_eq!(queue.pending_elements(), 0);
+
+            let mut vec = SHashMap::new();
+            vec.insert(1, SBox::new(1.to_string()).unwrap()).unwrap();
+            queue.push(vec).unwrap();
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn deferred_drop_survives_upgrades() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut queue = DeferredDrop::new();
+
+            for _ in 0..3 {
+                let mut vec = SVec::new();
+                for i in 0..1000u64 {
+                    vec.push(SBox::new(i.to_string()).unwrap()).unwrap();
+                }
+
+                queue.push(vec).unwrap();
+            }
+
+            while queue.pending_drops() > 0 {
+                queue.process(50_000);
+
+                let pending_drops = queue.pending_drops();
+                let pending_elements = queue.pending_elements();
+
+                store_custom_data(0, SBox::new(queue).unwrap());
+                stable_memory_pre_upgrade().unwrap();
+                stable_memory_post_upgrade();
+                queue = retrieve_custom_data::<DeferredDrop<SVec<SBox<String>>>>(0)
+                    .unwrap()
+                    .into_inner();
+
+                assert_eq!(queue.pending_drops(), pending_drops);
+                assert_eq!(queue.pending_elements(), pending_elements);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -7,7 +7,7 @@
 
 use crate::encoding::{AsFixedSizeBytes, Buffer};
 use crate::mem::allocator::MIN_PTR;
-use crate::mem::s_slice::{SSlice, ALLOCATED, FREE};
+use crate::mem::s_slice::{decode_header, encode_header, SSlice};
 use crate::mem::StablePtr;
 use crate::stable;
 use candid::{CandidType, Deserialize};
@@ -169,15 +169,7 @@ impl FreeBlock {
         let mut meta = [0u8; u64::SIZE];
         stable::read(ptr, &mut meta);
 
-        let encoded_size = u64::from_le_bytes(meta);
-        let mut size = encoded_size;
-
-        let allocated = if encoded_size & ALLOCATED == ALLOCATED {
-            size &= FREE;
-            true
-        } else {
-            false
-        };
+        let (size, allocated) = decode_header(ptr, u64::from_le_bytes(meta));
 
         if allocated {
             None
@@ -187,9 +179,7 @@ impl FreeBlock {
     }
 
     fn write_size(ptr: StablePtr, size: u64) {
-        let encoded_size = size & FREE;
-
-        let meta = encoded_size.to_le_bytes();
+        let meta = encode_header(size, false).to_le_bytes();
 
         stable::write(ptr, &meta);
         stable::write(ptr + (StablePtr::SIZE as u64) + size, &meta);
@@ -0,0 +1,270 @@
+//! Reachability-based stable memory leak detector.
+//!
+//! A memory block leaks, when it stays allocated after nothing owns it anymore. This usually happens
+//! because of wrong stable drop flag handling in a custom [StableType] implementation. Such leaks are
+//! silent - the memory just never gets reused.
+//!
+//! [LeakChecker] marks every memory block reachable from the provided roots (using
+//! [StableType::visit_allocations]) and then walks through the whole stable memory, listing allocated
+//! blocks which were not marked.
+
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
+use crate::mem::allocator::MIN_PTR;
+use crate::mem::free_block::FreeBlock;
+use crate::mem::s_slice::{try_decode_header, SSlice};
+use crate::mem::StablePtr;
+use crate::primitive::s_box::SBox;
+use crate::primitive::StableType;
+use crate::{stable, STABLE_MEMORY_ALLOCATOR};
+use std::collections::HashSet;
+
+/// Finds allocated memory blocks which are not reachable from any root
+///
+/// Roots are your stable data structures: the ones that live in `thread_local!` variables and the ones
+/// stored with [store_custom_data](crate::store_custom_data). Every root should be visited before calling
+/// [LeakChecker::find_leaks], otherwise memory owned by a missing root will be reported as leaked.
+///
+/// Visiting a collection reads every element it stores, so this is an expensive operation, which is
+/// intended for tests and for occasional diagnostics.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::mem::leaks::LeakChecker;
+/// # use ic_stable_memory::{stable_memory_init, store_custom_data, SBox};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut state = SVec::<SBox<String>>::new();
+/// state.push(SBox::new(String::from("hello")).unwrap()).unwrap();
+///
+/// store_custom_data(1, SBox::new(100u64).unwrap());
+///
+/// let mut checker = LeakChecker::new();
+/// checker.visit(&state);
+/// assert!(checker.visit_custom_data::<u64>(1));
+///
+/// assert!(checker.find_leaks().is_empty());
+/// ```
+#[derive(Debug, Default)]
+pub struct LeakChecker {
+    reachable: HashSet<StablePtr>,
+}
+
+impl LeakChecker {
+    /// Creates a checker with no roots
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks every memory block owned by the root as reachable
+    pub fn visit<T: StableType>(&mut self, root: &T) {
+        let reachable = &mut self.reachable;
+
+        root.visit_allocations(&mut |slice| {
+            reachable.insert(slice.as_ptr());
+        });
+    }
+
+    /// Marks every memory block owned by the custom data stored under the `idx` key as reachable
+    ///
+    /// The custom data stays stored. Returns `false`, if there is no custom data under this key.
+    ///
+    /// # Panics
+    /// Panics if there is no initialized stable memory allocator.
+    pub fn visit_custom_data<T: StableType + AsDynSizeBytes>(&mut self, idx: usize) -> bool {
+        let ptr = STABLE_MEMORY_ALLOCATOR.with(|it| {
+            if let Some(alloc) = &*it.borrow() {
+                alloc.get_custom_data_ptr(idx)
+            } else {
+                unreachable!("StableMemoryAllocator is not initialized");
+            }
+        });
+
+        match ptr {
+            Some(ptr) => {
+                // stable drop flag is off, so the data won't be released when the box is dropped
+                let b = unsafe { SBox::<T>::from_ptr(ptr) };
+                self.visit(&b);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns every allocated memory block which is not reachable from visited roots
+    ///
+    /// Stops at the first memory block with invalid markers. Use
+    /// [verify_stable_memory](crate::verify_stable_memory) to find out what is wrong in that case.
+    ///
+    /// # Panics
+    /// Panics if there is no initialized stable memory allocator.
+    pub fn find_leaks(&self) -> Vec<SSlice> {
+        let max_ptr = STABLE_MEMORY_ALLOCATOR.with(|it| {
+            if let Some(alloc) = &*it.borrow() {
+                alloc.get_max_ptr()
+            } else {
+                unreachable!("StableMemoryAllocator is not initialized");
+            }
+        });
+
+        let mut leaks = Vec::new();
+        let mut ptr = MIN_PTR;
+
+        while ptr < max_ptr {
+            let mut buf = [0u8; u64::SIZE];
+            stable::read(ptr, &mut buf);
+
+            let (size, allocated) = match try_decode_header(u64::from_le_bytes(buf)) {
+                Some(it) => it,
+                None => break,
+            };
+
+            if size == 0 {
+                break;
+            }
+
+            if allocated && !self.reachable.contains(&ptr) {
+                leaks.push(SSlice::new(ptr, size, false));
+            }
+
+            ptr += FreeBlock::to_total_size(size);
+        }
+
+        leaks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{
+        SBTreeMap, SBTreeSet, SBinaryHeap, SBlob, SCertifiedBTreeMap, SHashMap, SHashSet, SLog,
+        SRingLog, SVec, SVecDeque,
+    };
+    use crate::mem::leaks::LeakChecker;
+    #[cfg(feature = "compression")]
+    use crate::primitive::compressed::Compressed;
+    use crate::primitive::StableType;
+    use crate::{
+        _debug_validate_allocator, deallocate, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, store_custom_data, SBox,
+    };
+
+    #[test]
+    fn leaks_are_found() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::<SBox<String>>::new();
+            let mut map = SHashMap::<u64, SVec<u64>>::new();
+            let mut btree = SBTreeMap::<u64, SBox<SVec<SBox<String>>>>::new();
+            let mut log = SLog::<SBox<u64>>::new();
+
+            for i in 0..100u64 {
+                vec.push(SBox::new(i.to_string()).unwrap()).unwrap();
+
+                let mut inner = SVec::new();
+                inner.push(i).unwrap();
+                map.insert(i, inner).unwrap();
+
+                let mut inner = SVec::new();
+                inner.push(SBox::new(i.to_string()).unwrap()).unwrap();
+                btree.insert(i, SBox::new(inner).unwrap()).unwrap();
+
+                log.push(SBox::new(i).unwrap()).unwrap();
+            }
+
+            store_custom_data(1, SBox::new(btree).unwrap());
+
+            let mut checker = LeakChecker::new();
+            checker.visit(&vec);
+            checker.visit(&map);
+            checker.visit(&log);
+            assert!(checker.visit_custom_data::<SBTreeMap<u64, SBox<SVec<SBox<String>>>>>(1));
+            assert!(!checker.visit_custom_data::<u64>(2));
+
+            assert!(checker.find_leaks().is_empty());
+
+            // forget to visit a root
+            let mut log_blocks = 0;
+            log.visit_allocations(&mut |_| log_blocks += 1);
+            assert!(log_blocks > 100);
+
+            let mut checker = LeakChecker::new();
+            checker.visit(&vec);
+            checker.visit(&map);
+            checker.visit_custom_data::<SBTreeMap<u64, SBox<SVec<SBox<String>>>>>(1);
+            assert_eq!(checker.find_leaks().len(), log_blocks);
+
+            // leak a value by turning its stable drop flag off
+            let mut leaked = SBox::new(String::from("leaked")).unwrap();
+            unsafe { leaked.stable_drop_flag_off() };
+            let leaked_ptr = leaked.as_ptr();
+            drop(leaked);
+
+            let mut checker = LeakChecker::new();
+            checker.visit(&vec);
+            checker.visit(&map);
+            checker.visit(&log);
+            checker.visit_custom_data::<SBTreeMap<u64, SBox<SVec<SBox<String>>>>>(1);
+
+            let leaks = checker.find_leaks();
+            assert_eq!(leaks.len(), 1);
+            assert_eq!(leaks[0].as_ptr(), leaked_ptr);
+
+            deallocate(leaks[0]);
+            retrieve_custom_data::<SBTreeMap<u64, SBox<SVec<SBox<String>>>>>(1);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn all_collections_are_visited() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut deque = SVecDeque::<SBox<String>>::new();
+            let mut ring = SRingLog::<SBox<String>>::new(10).unwrap();
+            let mut heap = SBinaryHeap::<u64>::new();
+            let mut hash_set = SHashSet::<u64>::new();
+            let mut btree_set = SBTreeSet::<u64>::new();
+            let mut certified = SCertifiedBTreeMap::<u64, u64>::new();
+            let mut blob = SBlob::new();
+            #[cfg(feature = "compression")]
+            let compressed = SBox::new(Compressed::new(SVec::<u64>::new())).unwrap();
+
+            for i in 0..100u64 {
+                deque.push_front(SBox::new(i.to_string()).unwrap()).unwrap();
+                ring.push(SBox::new(i.to_string()).unwrap());
+                heap.push(i).unwrap();
+                hash_set.insert(i).unwrap();
+                btree_set.insert(i).unwrap();
+                certified.insert(i, i).unwrap();
+            }
+            certified.commit();
+
+            std::io::Write::write_all(&mut blob, &[1u8; 10_000]).unwrap();
+
+            let mut checker = LeakChecker::new();
+            checker.visit(&deque);
+            checker.visit(&ring);
+            checker.visit(&heap);
+            checker.visit(&hash_set);
+            checker.visit(&btree_set);
+            checker.visit(&certified);
+            checker.visit(&blob);
+            #[cfg(feature = "compression")]
+            checker.visit(&compressed);
+
+            assert!(checker.find_leaks().is_empty());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
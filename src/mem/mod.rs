@@ -27,8 +27,11 @@ use crate::primitive::StableType;
 use crate::stable;
 
 pub mod allocator;
+pub mod deferred_drop;
 pub mod free_block;
+pub mod leaks;
 pub mod s_slice;
+pub mod verification;
 
 /// A pointer to something is stable memory.
 ///
@@ -41,6 +44,24 @@ pub(crate) fn stable_ptr_buf() -> StablePtrBuf {
     StablePtrBuf::new(<StablePtr as AsFixedSizeBytes>::SIZE)
 }
 
+const MIN_READ_CHUNK_SIZE: usize = 256;
+const MAX_READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// Iterators read elements in chunks. The first chunk is small, so taking a couple of elements stays
+// cheap, and each next chunk is twice as big, until it reaches 64 KB.
+#[inline]
+pub(crate) fn next_read_chunk_len(prev_chunk_len: usize, elem_size: usize) -> usize {
+    let elem_size = elem_size.max(1);
+
+    if prev_chunk_len == 0 {
+        (MIN_READ_CHUNK_SIZE / elem_size).max(1)
+    } else {
+        prev_chunk_len
+            .saturating_mul(2)
+            .min((MAX_READ_CHUNK_SIZE / elem_size).max(1))
+    }
+}
+
 /// Reads raw bytes from stable memory.
 ///
 /// Under the hood simply calls [stable64_read](ic_cdk::api::stable::stable64_read).
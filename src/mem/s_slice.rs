@@ -14,6 +14,89 @@ use crate::utils::mem_context::stable;
 pub(crate) const ALLOCATED: u64 = 2u64.pow(u64::BITS - 1); // first biggest bit set to 1, other set to 0
 pub(crate) const FREE: u64 = ALLOCATED - 1; // first biggest bit set to 0, other set to 1
 
+// with checksums enabled, bits 48..63 of a block header store a checksum of the size and the flag
+#[cfg(feature = "checksums")]
+const CHECKSUM_SHIFT: u32 = 48;
+#[cfg(feature = "checksums")]
+const SIZE_MASK: u64 = (1 << CHECKSUM_SHIFT) - 1;
+#[cfg(feature = "checksums")]
+const CHECKSUM_MASK: u64 = FREE >> CHECKSUM_SHIFT;
+
+/// Encodes the size and the allocated flag of a memory block into a header word
+#[cfg(not(feature = "checksums"))]
+#[inline]
+pub(crate) fn encode_header(size: u64, allocated: bool) -> u64 {
+    if allocated {
+        size | ALLOCATED
+    } else {
+        size & FREE
+    }
+}
+
+/// Decodes the size and the allocated flag of a memory block from a header word
+#[cfg(not(feature = "checksums"))]
+#[inline]
+pub(crate) fn decode_header(_ptr: StablePtr, encoded: u64) -> (u64, bool) {
+    (encoded & FREE, encoded & ALLOCATED == ALLOCATED)
+}
+
+/// Same as [decode_header], but returns [None] if the header is corrupted (never happens without checksums)
+#[cfg(not(feature = "checksums"))]
+#[inline]
+pub(crate) fn try_decode_header(encoded: u64) -> Option<(u64, bool)> {
+    Some(decode_header(0, encoded))
+}
+
+#[cfg(feature = "checksums")]
+#[inline]
+fn header_checksum(size: u64, allocated: bool) -> u64 {
+    let mut buf = [0u8; u64::SIZE + 1];
+    buf[0..u64::SIZE].copy_from_slice(&size.to_le_bytes());
+    buf[u64::SIZE] = allocated as u8;
+
+    crc32fast::hash(&buf) as u64 & CHECKSUM_MASK
+}
+
+/// Encodes the size and the allocated flag of a memory block into a header word, including their checksum
+#[cfg(feature = "checksums")]
+#[inline]
+pub(crate) fn encode_header(size: u64, allocated: bool) -> u64 {
+    assert!(size <= SIZE_MASK, "Memory block is too big");
+
+    let encoded = size | (header_checksum(size, allocated) << CHECKSUM_SHIFT);
+
+    if allocated {
+        encoded | ALLOCATED
+    } else {
+        encoded
+    }
+}
+
+/// Decodes the size and the allocated flag of a memory block from a header word, verifying their checksum
+///
+/// # Panics
+/// Panics if the checksum does not match.
+#[cfg(feature = "checksums")]
+#[inline]
+pub(crate) fn decode_header(ptr: StablePtr, encoded: u64) -> (u64, bool) {
+    try_decode_header(encoded).unwrap_or_else(|| panic!("Corrupted memory block header at {}", ptr))
+}
+
+/// Same as [decode_header], but returns [None] if the checksum does not match
+#[cfg(feature = "checksums")]
+#[inline]
+pub(crate) fn try_decode_header(encoded: u64) -> Option<(u64, bool)> {
+    let size = encoded & SIZE_MASK;
+    let allocated = encoded & ALLOCATED == ALLOCATED;
+    let checksum = (encoded & FREE) >> CHECKSUM_SHIFT;
+
+    if checksum == header_checksum(size, allocated) {
+        Some((size, allocated))
+    } else {
+        None
+    }
+}
+
 /// An allocated block of stable memory.
 ///
 /// Represented by a pointer to the first byte of the memory block and a [u64] size of this block in
@@ -25,6 +108,11 @@ pub(crate) const FREE: u64 = ALLOCATED - 1; // first biggest bit set to 0, other
 /// - bytes `(size + 8)..(size + 16)` - another `size` + `allocated bit flag`
 /// So, a memory block is simply `size` bytes of data wrapped with some metadata from both sides.
 /// [FreeBlock](mem::free_block::FreeBlock) is stored exactly in a same way.
+///
+/// With the `checksums` feature enabled, bits `48..63` of the metadata store a checksum of the size and
+/// the flag, which is verified each time the metadata is read. The size is limited to `48` bits in that case.
+/// Memory written with and without this feature is incompatible, see
+/// [stable_memory_post_upgrade](crate::stable_memory_post_upgrade).
 #[derive(Debug, Copy, Clone)]
 pub struct SSlice {
     ptr: StablePtr,
@@ -147,15 +235,7 @@ impl SSlice {
         let mut meta = StablePtrBuf::new(StablePtr::SIZE);
         stable::read(ptr, &mut meta);
 
-        let encoded_size = u64::from_le_bytes(meta);
-        let mut size = encoded_size;
-
-        let allocated = if encoded_size & ALLOCATED == ALLOCATED {
-            size &= FREE;
-            true
-        } else {
-            false
-        };
+        let (size, allocated) = decode_header(ptr, u64::from_le_bytes(meta));
 
         if allocated {
             Some(size)
@@ -165,9 +245,7 @@ impl SSlice {
     }
 
     fn write_size(ptr: StablePtr, size: u64) {
-        let encoded_size = size | ALLOCATED;
-
-        let meta = encoded_size.to_le_bytes();
+        let meta = encode_header(size, true).to_le_bytes();
 
         stable::write(ptr, &meta);
         stable::write(ptr + (StablePtr::SIZE as u64) + size, &meta);
@@ -215,4 +293,22 @@ mod tests {
         assert_eq!(&b, &b1);
         assert_eq!(&c, &c1);
     }
+
+    #[cfg(feature = "checksums")]
+    #[test]
+    #[should_panic(expected = "Corrupted memory block header")]
+    fn corrupted_headers_are_detected() {
+        stable::clear();
+        stable::grow(1).expect("Unable to grow");
+
+        let m1 = SSlice::new(MIN_PTR, 100, true);
+
+        // flip a bit of the size
+        let mut meta = [0u8; StablePtr::SIZE];
+        stable::read(m1.as_ptr(), &mut meta);
+        meta[0] ^= 1;
+        stable::write(m1.as_ptr(), &meta);
+
+        unsafe { SSlice::from_ptr(m1.as_ptr()) };
+    }
 }
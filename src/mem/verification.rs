@@ -0,0 +1,486 @@
+//! Consistency checker of the whole stable memory heap.
+//!
+//! Unlike [_debug_validate_allocator](crate::_debug_validate_allocator), which only checks the
+//! free-list, this checker walks through every memory block from the beginning of stable memory to
+//! its end, using front and rear size markers of each block. It is resumable and bounded by the
+//! number of executed instructions, so it can be run from a timer in production canisters.
+//!
+//! See [verify_stable_memory](crate::verify_stable_memory).
+
+use crate::encoding::AsFixedSizeBytes;
+use crate::mem::allocator::{epoch, StableMemoryAllocator, MIN_PTR};
+use crate::mem::free_block::FreeBlock;
+use crate::mem::s_slice::try_decode_header;
+use crate::mem::StablePtr;
+use crate::stable;
+use crate::utils::budget::has_budget;
+
+/// A single problem found by [verify_stable_memory](crate::verify_stable_memory)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum VerificationIssue {
+    /// The header of a memory block failed its checksum verification (only with the `checksums` feature enabled)
+    CorruptedHeader {
+        /// Location of the header
+        ptr: StablePtr,
+    },
+    /// The size of a memory block is not a multiple of 8 or is less than the minimum block size
+    InvalidSize {
+        /// Location of the memory block
+        ptr: StablePtr,
+        /// Size of the memory block
+        size: u64,
+    },
+    /// A memory block ends beyond the end of the managed stable memory
+    OutOfBounds {
+        /// Location of the memory block
+        ptr: StablePtr,
+        /// Size of the memory block
+        size: u64,
+        /// The end of the managed stable memory
+        max_ptr: StablePtr,
+    },
+    /// Front and rear markers of a memory block do not match, which means that this block overlaps
+    /// with another one
+    MarkersMismatch {
+        /// Location of the memory block
+        ptr: StablePtr,
+        /// Raw front marker
+        front: u64,
+        /// Raw rear marker
+        rear: u64,
+    },
+    /// Two adjacent free blocks were not merged together
+    UnmergedFreeBlocks {
+        /// Location of the first free block
+        ptr: StablePtr,
+        /// Location of the second free block
+        next_ptr: StablePtr,
+    },
+    /// A free block is not present in the free-list of the allocator, so it will never be reused
+    UnindexedFreeBlock {
+        /// Location of the free block
+        ptr: StablePtr,
+        /// Size of the free block
+        size: u64,
+    },
+    /// A block from the free-list of the allocator is not a free block in stable memory, which means
+    /// that it overlaps with other blocks
+    OverlappingFreeBlock {
+        /// Location of the free block
+        ptr: StablePtr,
+        /// Size of the free block
+        size: u64,
+    },
+    /// Total size of free blocks found in stable memory differs from the one tracked by the allocator
+    FreeSizeMismatch {
+        /// Free size tracked by the allocator
+        expected: u64,
+        /// Total size of free blocks found in stable memory
+        actual: u64,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    // walking through memory blocks one by one
+    Heap {
+        ptr: StablePtr,
+        prev_free_ptr: Option<StablePtr>,
+    },
+    // walking through the free-list of the allocator
+    FreeList {
+        size: u64,
+        idx: usize,
+    },
+    Complete,
+}
+
+/// Progress and result of a stable memory verification
+///
+/// Pass the same report to [verify_stable_memory](crate::verify_stable_memory) repeatedly, until
+/// [VerificationReport::is_complete] returns `true`. If the allocator's free-list changes between
+/// two calls (because some memory was allocated or deallocated), the verification starts over and
+/// [VerificationReport::restarts] gets incremented.
+///
+/// Calling [verify_stable_memory](crate::verify_stable_memory) with an already complete report
+/// starts a new verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationReport {
+    /// Problems found so far
+    pub issues: Vec<VerificationIssue>,
+    /// Number of allocated blocks found so far
+    pub allocated_blocks: u64,
+    /// Total size (including markers) of allocated blocks found so far
+    pub allocated_size: u64,
+    /// Number of free blocks found so far
+    pub free_blocks: u64,
+    /// Total size (including markers) of free blocks found so far
+    pub free_size: u64,
+    /// How many times the verification had to start over, because the heap has changed
+    pub restarts: u32,
+    phase: Phase,
+    epoch: u64,
+    heap_is_broken: bool,
+}
+
+impl VerificationReport {
+    /// Creates a report for a new verification
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            issues: Vec::new(),
+            allocated_blocks: 0,
+            allocated_size: 0,
+            free_blocks: 0,
+            free_size: 0,
+            restarts: 0,
+            phase: Phase::Heap {
+                ptr: MIN_PTR,
+                prev_free_ptr: None,
+            },
+            epoch: epoch(),
+            heap_is_broken: false,
+        }
+    }
+
+    /// Returns `true` if the whole stable memory was verified
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        matches!(self.phase, Phase::Complete)
+    }
+
+    /// Returns `true` if the verification is complete and no issues were found
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.is_complete() && self.issues.is_empty()
+    }
+
+    fn restart(&mut self) {
+        let restarts = self.restarts + 1;
+
+        *self = Self::new();
+        self.restarts = restarts;
+    }
+
+    pub(crate) fn step(&mut self, allocator: &StableMemoryAllocator, instruction_budget: u64) {
+        if self.is_complete() {
+            *self = Self::new();
+        } else if self.epoch != epoch() {
+            self.restart();
+        }
+
+        let start = stable::instruction_counter();
+
+        while !self.is_complete() && has_budget(start, instruction_budget) {
+            match self.phase {
+                Phase::Heap { ptr, prev_free_ptr } => {
+                    self.phase = self.check_block(allocator, ptr, prev_free_ptr)
+                }
+                Phase::FreeList { size, idx } => {
+                    self.phase = self.check_free_list_entry(allocator, size, idx)
+                }
+                Phase::Complete => unreachable!(),
+            }
+        }
+    }
+
+    fn check_block(
+        &mut self,
+        allocator: &StableMemoryAllocator,
+        ptr: StablePtr,
+        prev_free_ptr: Option<StablePtr>,
+    ) -> Phase {
+        let max_ptr = allocator.get_max_ptr();
+
+        if ptr >= max_ptr {
+            return self.heap_done();
+        }
+
+        let front = read_marker(ptr);
+        let (size, allocated) = match try_decode_header(front) {
+            Some(it) => it,
+            None => return self.heap_broken(VerificationIssue::CorruptedHeader { ptr }),
+        };
+
+        if size < (StablePtr::SIZE * 2) as u64 || size % 8 != 0 {
+            return self.heap_broken(VerificationIssue::InvalidSize { ptr, size });
+        }
+
+        let total_size = FreeBlock::to_total_size(size);
+        if !fits(ptr, total_size, max_ptr) {
+            return self.heap_broken(VerificationIssue::OutOfBounds { ptr, size, max_ptr });
+        }
+
+        let rear = read_marker(ptr + StablePtr::SIZE as u64 + size);
+        if rear != front {
+            return self.heap_broken(VerificationIssue::MarkersMismatch { ptr, front, rear });
+        }
+
+        if allocated {
+            self.allocated_blocks += 1;
+            self.allocated_size += total_size;
+
+            return Phase::Heap {
+                ptr: ptr + total_size,
+                prev_free_ptr: None,
+            };
+        }
+
+        self.free_blocks += 1;
+        self.free_size += total_size;
+
+        if let Some(prev_ptr) = prev_free_ptr {
+            self.issues.push(VerificationIssue::UnmergedFreeBlocks {
+                ptr: prev_ptr,
+                next_ptr: ptr,
+            });
+        }
+
+        let is_indexed = match allocator.get_free_blocks().get(&size) {
+            Some(blocks) => blocks.binary_search(&FreeBlock::new(ptr, size)).is_ok(),
+            None => false,
+        };
+
+        if !is_indexed {
+            self.issues
+                .push(VerificationIssue::UnindexedFreeBlock { ptr, size });
+        }
+
+        Phase::Heap {
+            ptr: ptr + total_size,
+            prev_free_ptr: Some(ptr),
+        }
+    }
+
+    fn check_free_list_entry(
+        &mut self,
+        allocator: &StableMemoryAllocator,
+        size: u64,
+        idx: usize,
+    ) -> Phase {
+        let (size, idx) = match allocator.get_free_blocks().range(size..).next() {
+            Some((&actual_size, blocks)) if actual_size == size && idx < blocks.len() => {
+                (size, idx)
+            }
+            Some((&actual_size, _)) if actual_size == size => {
+                match allocator.get_free_blocks().range((size + 1)..).next() {
+                    Some((&next_size, _)) => (next_size, 0),
+                    None => return self.free_list_done(allocator),
+                }
+            }
+            Some((&next_size, _)) => (next_size, 0),
+            None => return self.free_list_done(allocator),
+        };
+
+        let block = allocator.get_free_blocks()[&size][idx];
+        let max_ptr = allocator.get_max_ptr();
+
+        let is_valid = block.as_ptr() >= MIN_PTR
+            && fits(block.as_ptr(), block.get_total_size_bytes(), max_ptr)
+            && {
+                let front = read_marker(block.as_ptr());
+                let rear = read_marker(block.as_rear_ptr());
+
+                front == rear && try_decode_header(front) == Some((size, false))
+            };
+
+        if !is_valid {
+            self.issues.push(VerificationIssue::OverlappingFreeBlock {
+                ptr: block.as_ptr(),
+                size,
+            });
+        }
+
+        Phase::FreeList { size, idx: idx + 1 }
+    }
+
+    fn heap_broken(&mut self, issue: VerificationIssue) -> Phase {
+        self.issues.push(issue);
+        self.heap_is_broken = true;
+
+        self.heap_done()
+    }
+
+    #[inline]
+    fn heap_done(&mut self) -> Phase {
+        Phase::FreeList { size: 0, idx: 0 }
+    }
+
+    fn free_list_done(&mut self, allocator: &StableMemoryAllocator) -> Phase {
+        // totals are meaningless, if the scan stopped in the middle
+        if !self.heap_is_broken && self.free_size != allocator.get_free_size() {
+            self.issues.push(VerificationIssue::FreeSizeMismatch {
+                expected: allocator.get_free_size(),
+                actual: self.free_size,
+            });
+        }
+
+        Phase::Complete
+    }
+}
+
+impl Default for VerificationReport {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[inline]
+fn fits(ptr: StablePtr, total_size: u64, max_ptr: StablePtr) -> bool {
+    matches!(ptr.checked_add(total_size), Some(end) if end <= max_ptr)
+}
+
+#[inline]
+fn read_marker(ptr: StablePtr) -> u64 {
+    let mut buf = [0u8; u64::SIZE];
+    stable::read(ptr, &mut buf);
+
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::AsFixedSizeBytes;
+    use crate::mem::s_slice::{encode_header, SSlice};
+    use crate::mem::verification::{VerificationIssue, VerificationReport};
+    use crate::mem::StablePtr;
+    use crate::{
+        _debug_validate_allocator, allocate, deallocate, get_allocated_size, stable,
+        stable_memory_init, verify_stable_memory,
+    };
+
+    fn verify_fully() -> VerificationReport {
+        let mut report = VerificationReport::new();
+        verify_stable_memory(&mut report, u64::MAX);
+
+        assert!(report.is_complete());
+
+        report
+    }
+
+    #[test]
+    fn healthy_heap_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        unsafe {
+            let report = verify_fully();
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.allocated_blocks, 0);
+            assert_eq!(report.free_blocks, 0);
+
+            let mut slices = Vec::new();
+            for i in 0..1000 {
+                slices.push(allocate(i * 10).unwrap());
+            }
+
+            for slice in slices.iter().step_by(3) {
+                deallocate(*slice);
+            }
+
+            let report = verify_fully();
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.allocated_blocks, 1000 - 334);
+            assert_eq!(report.allocated_size, get_allocated_size());
+            assert_eq!(report.restarts, 0);
+
+            // in chunks
+            let mut report = VerificationReport::new();
+            let mut chunks = 0;
+            while !report.is_complete() {
+                verify_stable_memory(&mut report, 10_000);
+                chunks += 1;
+            }
+
+            assert!(report.is_ok(), "{:?}", report);
+            assert!(chunks > 1);
+
+            // heap changes between chunks
+            let mut report = VerificationReport::new();
+            verify_stable_memory(&mut report, 10_000);
+            assert!(!report.is_complete());
+
+            let slice = allocate(100).unwrap();
+            deallocate(slice);
+
+            while !report.is_complete() {
+                verify_stable_memory(&mut report, 10_000);
+            }
+
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.restarts, 1);
+
+            for (i, slice) in slices.into_iter().enumerate() {
+                if i % 3 != 0 {
+                    deallocate(slice);
+                }
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn broken_heap_is_reported() {
+        stable::clear();
+        stable_memory_init();
+
+        unsafe {
+            let a = allocate(100).unwrap();
+            let b = allocate(100).unwrap();
+            let c = allocate(100).unwrap();
+
+            // pretend "b" is free, but forget to tell the allocator about it
+            let marker = encode_header(b.get_size_bytes(), false).to_le_bytes();
+            stable::write(b.as_ptr(), &marker);
+            stable::write(
+                b.as_ptr() + StablePtr::SIZE as u64 + b.get_size_bytes(),
+                &marker,
+            );
+
+            let report = verify_fully();
+            assert!(!report.is_ok());
+            assert!(report
+                .issues
+                .contains(&VerificationIssue::UnindexedFreeBlock {
+                    ptr: b.as_ptr(),
+                    size: b.get_size_bytes(),
+                }));
+            assert!(report
+                .issues
+                .contains(&VerificationIssue::FreeSizeMismatch {
+                    expected: crate::get_free_size(),
+                    actual: crate::get_free_size() + b.get_total_size_bytes(),
+                }));
+
+            // restore "b" and break the rear marker of "c"
+            SSlice::new(b.as_ptr(), b.get_size_bytes(), true);
+            stable::write(
+                c.as_ptr() + StablePtr::SIZE as u64 + c.get_size_bytes(),
+                &u64::MAX.to_le_bytes(),
+            );
+
+            let report = verify_fully();
+            assert!(matches!(
+                report.issues[0],
+                VerificationIssue::MarkersMismatch { ptr, .. } if ptr == c.as_ptr()
+            ));
+
+            SSlice::new(c.as_ptr(), c.get_size_bytes(), true);
+            assert!(verify_fully().is_ok());
+
+            deallocate(a);
+            deallocate(b);
+
+            let report = verify_fully();
+            assert!(report.is_ok(), "{:?}", report);
+
+            deallocate(c);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
+use crate::mem::s_slice::SSlice;
+use crate::primitive::s_box::SBox;
+use crate::primitive::StableType;
+use crate::utils::error::DecodeError;
+use std::borrow::Borrow;
+use std::ops::{Deref, DerefMut};
+
+const CODEC_RAW: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+
+// payloads smaller than this are not worth compressing
+const MIN_COMPRESSIBLE_SIZE: usize = 64;
+
+// LZ4 can't expand data more than this many times, so a bigger uncompressed size means corruption
+const MAX_LZ4_EXPANSION: usize = 255;
+
+/// [SBox] which stores its value compressed
+///
+/// See [Compressed].
+pub type SCompressedBox<T> = SBox<Compressed<T>>;
+
+/// Wrapper that compresses the [AsDynSizeBytes] encoding of the inner value
+///
+/// Intended to be used inside an [SBox] (see [SCompressedBox]) for large values with a lot of
+/// repetition, like Candid-encoded documents. The encoded value is compressed with LZ4 (using a
+/// pure-Rust implementation, which works fine in `wasm`) and is prefixed with a codec tag. If the
+/// compression does not make the payload smaller, it is stored uncompressed. Decompression happens
+/// transparently, when the value is read from stable memory.
+///
+/// Access to the inner value is provided by dereferencing.
+///
+/// Only available with the `compression` feature.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::primitive::compressed::{Compressed, SCompressedBox};
+/// # use ic_stable_memory::{stable_memory_init, SBox};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let document = "lorem ipsum ".repeat(1000);
+///
+/// let mut b: SCompressedBox<String> = SBox::new(Compressed::new(document.clone()))
+///     .expect("Out of memory");
+///
+/// assert_eq!(b.as_str(), document);
+///
+/// b.with(|it| it.push_str("dolor sit amet")).expect("Out of memory");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Compressed<T>(T);
+
+impl<T> Compressed<T> {
+    /// Wraps the value
+    #[inline]
+    pub fn new(it: T) -> Self {
+        Self(it)
+    }
+
+    /// Returns the inner value
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Compressed<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Compressed<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T> Borrow<T> for Compressed<T> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: StableType> StableType for Compressed<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.0.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.0.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+    }
+}
+
+impl<T: AsDynSizeBytes> AsDynSizeBytes for Compressed<T> {
+    fn as_dyn_size_bytes(&self) -> Vec<u8> {
+        let raw = self.0.as_dyn_size_bytes();
+
+        if raw.len() >= MIN_COMPRESSIBLE_SIZE {
+            let compressed = lz4_flex::compress_prepend_size(&raw);
+
+            if compressed.len() < raw.len() {
+                return encode(CODEC_LZ4, &compressed);
+            }
+        }
+
+        encode(CODEC_RAW, &raw)
+    }
+
+    #[inline]
+    fn from_dyn_size_bytes(buf: &[u8]) -> Self {
+        Self::try_from_dyn_size_bytes(buf).unwrap()
+    }
+
+    fn try_from_dyn_size_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        DecodeError::check_len(buf, 1 + u32::SIZE)?;
+
+        let codec = buf[0];
+        let len = u32::from_fixed_size_bytes(&buf[1..(1 + u32::SIZE)]) as usize;
+
+        DecodeError::check_len(buf, 1 + u32::SIZE + len)?;
+        let payload = &buf[(1 + u32::SIZE)..(1 + u32::SIZE + len)];
+
+        let it = match codec {
+            CODEC_RAW => T::try_from_dyn_size_bytes(payload)?,
+            CODEC_LZ4 => T::try_from_dyn_size_bytes(&decompress(payload)?)?,
+            _ => {
+                return Err(DecodeError::Invalid(format!(
+                    "Unknown compression codec {}",
+                    codec
+                )))
+            }
+        };
+
+        Ok(Self(it))
+    }
+}
+
+// the uncompressed size prefix is checked before allocating, so a corrupted one can't make the
+// canister allocate gigabytes of heap
+fn decompress(payload: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    DecodeError::check_len(payload, u32::SIZE)?;
+
+    let raw_len = u32::from_fixed_size_bytes(&payload[0..u32::SIZE]) as usize;
+    let compressed = &payload[u32::SIZE..];
+
+    if raw_len > compressed.len().saturating_mul(MAX_LZ4_EXPANSION) {
+        return Err(DecodeError::Invalid(format!(
+            "Uncompressed size {} is too big for {} compressed bytes",
+            raw_len,
+            compressed.len()
+        )));
+    }
+
+    let mut raw = vec![0u8; raw_len];
+    let n = lz4_flex::decompress_into(compressed, &mut raw)
+        .map_err(|e| DecodeError::Invalid(e.to_string()))?;
+
+    if n != raw_len {
+        return Err(DecodeError::Invalid(format!(
+            "Decompressed {} bytes, but {} were expected",
+            n, raw_len
+        )));
+    }
+
+    Ok(raw)
+}
+
+fn encode(codec: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; 1 + u32::SIZE + payload.len()];
+
+    buf[0] = codec;
+    (payload.len() as u32).as_fixed_size_bytes(&mut buf[1..(1 + u32::SIZE)]);
+    buf[(1 + u32::SIZE)..].copy_from_slice(payload);
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::AsDynSizeBytes;
+    use crate::primitive::compressed::{Compressed, SCompressedBox, CODEC_LZ4, CODEC_RAW};
+    use crate::primitive::s_box::SBox;
+    use crate::utils::error::DecodeError;
+    use crate::{
+        _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+        store_custom_data,
+    };
+
+    #[test]
+    fn encoding_works_fine() {
+        let small = Compressed::new(String::from("small"));
+        let buf = small.as_dyn_size_bytes();
+        assert_eq!(buf[0], CODEC_RAW);
+        assert_eq!(Compressed::<String>::from_dyn_size_bytes(&buf), small);
+
+        let big = Compressed::new("abc".repeat(10_000));
+        let mut buf = big.as_dyn_size_bytes();
+        assert_eq!(buf[0], CODEC_LZ4);
+        assert!(buf.len() < 1_000);
+
+        // trailing bytes are ignored
+        buf.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(Compressed::<String>::from_dyn_size_bytes(&buf), big);
+
+        let random = Compressed::new(
+            (0..1_000u32)
+                .map(|it| (it * 7919 % 251) as u8)
+                .collect::<Vec<_>>(),
+        );
+        let buf = random.as_dyn_size_bytes();
+        assert_eq!(Compressed::<Vec<u8>>::from_dyn_size_bytes(&buf), random);
+
+        let mut buf = big.as_dyn_size_bytes();
+        assert!(Compressed::<String>::try_from_dyn_size_bytes(&buf[0..10]).is_err());
+
+        // a corrupted uncompressed size is rejected without allocating it
+        let mut corrupted = buf.clone();
+        corrupted[5..9].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Compressed::<String>::try_from_dyn_size_bytes(&corrupted),
+            Err(DecodeError::Invalid(_))
+        ));
+
+        buf[0] = 100;
+        assert_eq!(
+            Compressed::<String>::try_from_dyn_size_bytes(&buf),
+            Err(DecodeError::Invalid(String::from(
+                "Unknown compression codec 100"
+            )))
+        );
+    }
+
+    #[test]
+    fn sbox_works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let document = "lorem ipsum dolor sit amet ".repeat(10_000);
+
+            let mut b: SCompressedBox<String> =
+                SBox::new(Compressed::new(document.clone())).unwrap();
+            assert!(get_allocated_size() < 10_000);
+
+            b.with(|it| it.push('!')).unwrap();
+
+            store_custom_data(1, b);
+            stable_memory_pre_upgrade().unwrap();
+            stable_memory_post_upgrade();
+
+            let b = retrieve_custom_data::<Compressed<String>>(1).unwrap();
+            assert_eq!(b.len(), document.len() + 1);
+            assert!(b.ends_with("amet !"));
+
+            assert_eq!(b.into_inner().into_inner(), document + "!");
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
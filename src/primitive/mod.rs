@@ -1,13 +1,26 @@
 //! Smart-pointers and [StableType] trait
 
+use crate::encoding::{AsFixedSizeBytes, Buffer};
+use crate::mem::s_slice::SSlice;
 use candid::{Int, Nat, Principal};
 use serde_bytes::ByteBuf;
+use std::cmp::Reverse;
 use std::collections::{BTreeSet, HashSet};
 use ic_ledger_types::Subaccount;
 
 /// [SBox] smart-pointer that allows storing dynamically-sized data to stable memory
 pub mod s_box;
 
+/// Compression wrapper for [SBox](s_box::SBox) payloads
+#[cfg(feature = "compression")]
+pub mod compressed;
+
+/// Inline fixed-capacity byte string
+pub mod s_fixed_bytes;
+
+/// Inline fixed-capacity UTF-8 string
+pub mod s_fixed_string;
+
 /// Immutable reference to fixed size data on stable memory
 pub mod s_ref;
 
@@ -82,6 +95,7 @@ pub mod s_ref_mut;
 ///
 /// ```rust
 /// // provide full implementation, if you're building new stable data structure
+/// # use ic_stable_memory::mem::s_slice::SSlice;
 /// # use ic_stable_memory::mem::StablePtr;
 /// # use ic_stable_memory::StableType;
 /// struct ExampleStableDataStructure {
@@ -105,6 +119,10 @@ pub mod s_ref_mut;
 ///     unsafe fn stable_drop(&mut self) {
 ///         // deallocate any stable memory managed by this data structure
 ///     }
+///
+///     fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+///         // report any stable memory managed by this data structure
+///     }
 /// }
 /// ```
 pub trait StableType {
@@ -160,6 +178,27 @@ pub trait StableType {
     /// ```
     #[inline]
     unsafe fn stable_drop(&mut self) {}
+
+    /// Should report every memory block owned by this value (including blocks owned by nested
+    /// values) to the `visitor`
+    ///
+    /// Used by the [leak checker](crate::mem::leaks). The default implementation reports nothing,
+    /// which is correct for data types that do not contain any stable structures. Data types which do
+    /// contain stable structures should call this method on each of them (the derive macro does that).
+    /// Stable data structures should report their own memory blocks and then call this method on
+    /// every element they store.
+    #[inline]
+    fn visit_allocations(&self, _visitor: &mut dyn FnMut(SSlice)) {}
+}
+
+// returns a copy of the value, which doesn't own anything, so it can only be used to look the
+// original value up
+#[inline]
+pub(crate) fn lookup_copy<T: StableType + AsFixedSizeBytes>(it: &T) -> T {
+    let mut copy = T::from_fixed_size_bytes(it.as_new_fixed_size_bytes()._deref());
+    unsafe { copy.stable_drop_flag_off() };
+
+    copy
 }
 
 impl StableType for () {}
@@ -220,6 +259,30 @@ impl<T: StableType> StableType for Option<T> {
             it.stable_drop_flag_off();
         }
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        if let Some(it) = self {
+            it.visit_allocations(visitor);
+        }
+    }
+}
+
+impl<T: StableType> StableType for Reverse<T> {
+    #[inline]
+    unsafe fn stable_drop_flag_on(&mut self) {
+        self.0.stable_drop_flag_on();
+    }
+
+    #[inline]
+    unsafe fn stable_drop_flag_off(&mut self) {
+        self.0.stable_drop_flag_off();
+    }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+    }
 }
 impl<const N: usize> StableType for [ByteBuf; N] {}
 
@@ -233,6 +296,11 @@ impl<A: StableType> StableType for (A,) {
     unsafe fn stable_drop_flag_off(&mut self) {
         self.0.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+    }
 }
 
 impl<A: StableType, B: StableType> StableType for (A, B) {
@@ -247,6 +315,12 @@ impl<A: StableType, B: StableType> StableType for (A, B) {
         self.0.stable_drop_flag_off();
         self.1.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+        self.1.visit_allocations(visitor);
+    }
 }
 
 impl<A: StableType, B: StableType, C: StableType> StableType for (A, B, C) {
@@ -263,6 +337,13 @@ impl<A: StableType, B: StableType, C: StableType> StableType for (A, B, C) {
         self.1.stable_drop_flag_off();
         self.2.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+        self.1.visit_allocations(visitor);
+        self.2.visit_allocations(visitor);
+    }
 }
 
 impl<A: StableType, B: StableType, C: StableType, D: StableType> StableType for (A, B, C, D) {
@@ -281,6 +362,14 @@ impl<A: StableType, B: StableType, C: StableType, D: StableType> StableType for
         self.2.stable_drop_flag_off();
         self.3.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+        self.1.visit_allocations(visitor);
+        self.2.visit_allocations(visitor);
+        self.3.visit_allocations(visitor);
+    }
 }
 
 impl<A: StableType, B: StableType, C: StableType, D: StableType, E: StableType> StableType
@@ -303,6 +392,15 @@ impl<A: StableType, B: StableType, C: StableType, D: StableType, E: StableType>
         self.3.stable_drop_flag_off();
         self.4.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+        self.1.visit_allocations(visitor);
+        self.2.visit_allocations(visitor);
+        self.3.visit_allocations(visitor);
+        self.4.visit_allocations(visitor);
+    }
 }
 
 impl<A: StableType, B: StableType, C: StableType, D: StableType, E: StableType, F: StableType>
@@ -327,6 +425,16 @@ impl<A: StableType, B: StableType, C: StableType, D: StableType, E: StableType,
         self.4.stable_drop_flag_off();
         self.5.stable_drop_flag_off();
     }
+
+    #[inline]
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        self.0.visit_allocations(visitor);
+        self.1.visit_allocations(visitor);
+        self.2.visit_allocations(visitor);
+        self.3.visit_allocations(visitor);
+        self.4.visit_allocations(visitor);
+        self.5.visit_allocations(visitor);
+    }
 }
 
 impl StableType for String {}
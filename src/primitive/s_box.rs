@@ -2,7 +2,8 @@ use crate::encoding::{AsDynSizeBytes, AsFixedSizeBytes};
 use crate::mem::s_slice::SSlice;
 use crate::primitive::StableType;
 use crate::utils::certification::{AsHashTree, AsHashableBytes, HashTree};
-use crate::{allocate, deallocate, reallocate, OutOfMemory};
+use crate::utils::error::DecodeError;
+use crate::{allocate, deallocate, reallocate, OutOfMemory, Rejected};
 use candid::types::{Serializer, Type, TypeId};
 use candid::CandidType;
 use serde::{Deserialize, Deserializer};
@@ -28,6 +29,11 @@ use std::ops::Deref;
 /// You can access the underlying data by dereferencing it, for immutable access. For mutable access
 /// you have to use [SBox::with] method (similar to `thread_local!`'s `with()` method).
 ///
+/// With the `checksums` feature enabled, the serialized value is stored along with its checksum,
+/// which is verified each time the value is read. A mismatch is reported as [DecodeError::ChecksumMismatch]
+/// by [SBox::try_get]. Payloads written with and without this feature are incompatible, see
+/// [stable_memory_post_upgrade](crate::stable_memory_post_upgrade).
+///
 /// # Examples
 /// ```rust
 /// # use ic_stable_memory::{stable_memory_init, SBox};
@@ -55,23 +61,24 @@ pub struct SBox<T: AsDynSizeBytes + StableType> {
 impl<T: AsDynSizeBytes + StableType> SBox<T> {
     /// Stores dynamic sized data on stable memory, immediately serializing and allocating.
     ///
-    /// Returns `Err` and the data, if the canister is `OutOfMemory`.
+    /// Returns [Rejected] with the data, if the canister is [OutOfMemory].
     #[inline]
-    pub fn new(mut it: T) -> Result<Self, T> {
-        let buf = it.as_dyn_size_bytes();
-        if let Ok(slice) = unsafe { allocate(buf.len() as u64) } {
-            unsafe {
-                crate::mem::write_bytes(slice.offset(0), &buf);
-                it.stable_drop_flag_off();
-            }
+    pub fn new(mut it: T) -> Result<Self, Rejected<T>> {
+        let buf = seal_payload(it.as_dyn_size_bytes());
+        match unsafe { allocate(buf.len() as u64) } {
+            Ok(slice) => {
+                unsafe {
+                    crate::mem::write_bytes(slice.offset(0), &buf);
+                    it.stable_drop_flag_off();
+                }
 
-            Ok(Self {
-                slice: Some(slice),
-                inner: UnsafeCell::new(Some(it)),
-                stable_drop_flag: true,
-            })
-        } else {
-            Err(it)
+                Ok(Self {
+                    slice: Some(slice),
+                    inner: UnsafeCell::new(Some(it)),
+                    stable_drop_flag: true,
+                })
+            }
+            Err(e) => Err(Rejected::new(it, e)),
         }
     }
 
@@ -168,7 +175,41 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
         }
     }
 
+    /// Returns an immutable reference to the underlying data, or [DecodeError] if it can't be decoded
+    ///
+    /// Unlike dereferencing, which panics on invalid data, this method allows to handle corrupted
+    /// (or schema-mismatched) values gracefully. Such a [SBox] can still be safely dropped, but the
+    /// stable memory owned by the (undecodable) inner value is leaked in that case.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use ic_stable_memory::{SBox, stable_memory_init};
+    /// # unsafe { ic_stable_memory::mem::clear(); }
+    /// # stable_memory_init();
+    /// let b = SBox::new(String::from("Test string")).expect("Out of memory");
+    ///
+    /// match b.try_get() {
+    ///     Ok(it) => assert_eq!(it, "Test string"),
+    ///     Err(e) => println!("Corrupted value: {e}"),
+    /// }
+    /// ```
+    #[inline]
+    pub fn try_get(&self) -> Result<&T, DecodeError> {
+        unsafe {
+            self.try_lazy_read(false)?;
+
+            Ok((*self.inner.get()).as_ref().unwrap())
+        }
+    }
+
+    #[inline]
     unsafe fn lazy_read(&self, drop_flag: bool) {
+        if let Err(e) = self.try_lazy_read(drop_flag) {
+            panic!("Unable to decode SBox({}): {}", self.as_ptr(), e);
+        }
+    }
+
+    unsafe fn try_lazy_read(&self, drop_flag: bool) -> Result<(), DecodeError> {
         if let Some(it) = (*self.inner.get()).as_mut() {
             if drop_flag {
                 it.stable_drop_flag_on();
@@ -176,14 +217,14 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
                 it.stable_drop_flag_off();
             }
 
-            return;
+            return Ok(());
         }
 
         let slice = self.slice.as_ref().unwrap();
         let mut buf = vec![0u8; slice.get_size_bytes() as usize];
         unsafe { crate::mem::read_bytes(slice.offset(0), &mut buf) };
 
-        let mut inner = T::from_dyn_size_bytes(&buf);
+        let mut inner = T::try_from_dyn_size_bytes(unseal_payload(&buf)?)?;
         if drop_flag {
             inner.stable_drop_flag_on();
         } else {
@@ -191,11 +232,13 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
         }
 
         *self.inner.get() = Some(inner);
+
+        Ok(())
     }
 
     fn repersist(&mut self) -> Result<(), OutOfMemory> {
         let mut slice = self.slice.take().unwrap();
-        let buf = self.inner.get_mut().as_ref().unwrap().as_dyn_size_bytes();
+        let buf = seal_payload(self.inner.get_mut().as_ref().unwrap().as_dyn_size_bytes());
 
         unsafe { self.inner.get_mut().stable_drop_flag_off() };
 
@@ -219,6 +262,50 @@ impl<T: AsDynSizeBytes + StableType> SBox<T> {
     }
 }
 
+// with checksums enabled, the payload is prefixed with its length and its checksum
+#[cfg(feature = "checksums")]
+const PAYLOAD_HEADER_SIZE: usize = u32::SIZE * 2;
+
+#[cfg(not(feature = "checksums"))]
+#[inline]
+fn seal_payload(payload: Vec<u8>) -> Vec<u8> {
+    payload
+}
+
+#[cfg(not(feature = "checksums"))]
+#[inline]
+fn unseal_payload(buf: &[u8]) -> Result<&[u8], DecodeError> {
+    Ok(buf)
+}
+
+#[cfg(feature = "checksums")]
+fn seal_payload(payload: Vec<u8>) -> Vec<u8> {
+    let mut buf = vec![0u8; PAYLOAD_HEADER_SIZE + payload.len()];
+
+    (payload.len() as u32).as_fixed_size_bytes(&mut buf[0..u32::SIZE]);
+    crc32fast::hash(&payload).as_fixed_size_bytes(&mut buf[u32::SIZE..PAYLOAD_HEADER_SIZE]);
+    buf[PAYLOAD_HEADER_SIZE..].copy_from_slice(&payload);
+
+    buf
+}
+
+#[cfg(feature = "checksums")]
+fn unseal_payload(buf: &[u8]) -> Result<&[u8], DecodeError> {
+    DecodeError::check_len(buf, PAYLOAD_HEADER_SIZE)?;
+
+    let len = u32::from_fixed_size_bytes(&buf[0..u32::SIZE]) as usize;
+    let checksum = u32::from_fixed_size_bytes(&buf[u32::SIZE..PAYLOAD_HEADER_SIZE]);
+
+    DecodeError::check_len(buf, PAYLOAD_HEADER_SIZE + len)?;
+    let payload = &buf[PAYLOAD_HEADER_SIZE..(PAYLOAD_HEADER_SIZE + len)];
+
+    if crc32fast::hash(payload) != checksum {
+        return Err(DecodeError::ChecksumMismatch);
+    }
+
+    Ok(payload)
+}
+
 impl<T: AsDynSizeBytes + StableType> AsFixedSizeBytes for SBox<T> {
     const SIZE: usize = u64::SIZE;
     type Buf = [u8; u64::SIZE];
@@ -256,13 +343,26 @@ impl<T: AsDynSizeBytes + StableType> StableType for SBox<T> {
     unsafe fn stable_drop(&mut self) {
         deallocate(self.slice.take().unwrap());
     }
+
+    fn visit_allocations(&self, visitor: &mut dyn FnMut(SSlice)) {
+        if let Some(slice) = self.slice {
+            visitor(slice);
+        }
+
+        // memory owned by an undecodable value can't be visited, it will be reported as leaked
+        if let Ok(it) = self.try_get() {
+            it.visit_allocations(visitor);
+        }
+    }
 }
 
 impl<T: AsDynSizeBytes + StableType> Drop for SBox<T> {
     fn drop(&mut self) {
         unsafe {
             if self.should_stable_drop() {
-                self.lazy_read(true);
+                // an undecodable value can't release the memory it owns, but the box itself is
+                // still released, so corrupted values can be removed from collections
+                let _ = self.try_lazy_read(true);
                 self.stable_drop();
             }
         }
@@ -410,8 +510,11 @@ impl<T: AsDynSizeBytes + StableType> Deref for SBox<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::collections::SVec;
+    use crate::collections::{SBTreeMap, SVec};
+    use crate::encoding::AsFixedSizeBytes;
+    use crate::mem::s_slice::SSlice;
     use crate::primitive::s_box::SBox;
+    use crate::utils::error::DecodeError;
     use crate::{
         _debug_validate_allocator, get_allocated_size, retrieve_custom_data, stable,
         stable_memory_init, store_custom_data,
@@ -486,6 +589,38 @@ mod tests {
         assert_eq!(get_allocated_size(), 0);
     }
 
+    #[test]
+    fn corrupted_values_can_be_quarantined() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SBTreeMap::<u64, SBox<String>>::new();
+            map.insert(1, SBox::new(String::from("good")).unwrap())
+                .unwrap();
+            map.insert(2, SBox::new(String::from("bad")).unwrap())
+                .unwrap();
+
+            // corrupt the string's content, so it is no longer a valid utf-8
+            let ptr = map.get(&2).unwrap().as_ptr();
+            unsafe { crate::mem::write_bytes(SSlice::_offset(ptr, usize::SIZE as u64), &[0xff]) };
+
+            assert_eq!(map.get(&1).unwrap().try_get().unwrap(), "good");
+            #[cfg(not(feature = "checksums"))]
+            let expected = DecodeError::InvalidUtf8;
+            #[cfg(feature = "checksums")]
+            let expected = DecodeError::ChecksumMismatch;
+
+            assert_eq!(map.get(&2).unwrap().try_get().unwrap_err(), expected);
+
+            let bad = map.remove(&2).unwrap();
+            assert!(bad.try_get().is_err());
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
     #[test]
     fn complex_nested_structures_work_fine() {
         stable::clear();
@@ -0,0 +1,250 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::StableType;
+use crate::utils::certification::AsHashableBytes;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// Byte string of up to `N` bytes, stored inline
+///
+/// Unlike `Vec<u8>`, which only implements [AsDynSizeBytes](crate::AsDynSizeBytes) and therefore
+/// needs an [SBox](crate::SBox) (and a separate allocation) to be stored inside a stable collection,
+/// [SFixedBytes] implements [AsFixedSizeBytes] and can be stored directly. It always occupies
+/// `N + 2` bytes: a `u16` length prefix and `N` bytes of data.
+///
+/// [Ord], [Eq] and [Hash] are consistent with `[u8]`, so collections keyed by [SFixedBytes] can be
+/// queried with a plain `&[u8]`.
+///
+/// # Panics
+/// `N` can't be bigger than [u16::MAX]. Constructing such a value will panic.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::primitive::s_fixed_bytes::SFixedBytes;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut map = SBTreeMap::<SFixedBytes<32>, u64>::new();
+///
+/// let key = SFixedBytes::try_from(&[1u8, 2, 3][..]).unwrap();
+/// map.insert(key, 10).expect("Out of memory");
+///
+/// assert_eq!(*map.get(&[1u8, 2, 3][..]).unwrap(), 10);
+/// ```
+#[derive(Clone, Copy)]
+pub struct SFixedBytes<const N: usize> {
+    len: u16,
+    buf: [u8; N],
+}
+
+impl<const N: usize> SFixedBytes<N> {
+    /// Creates an empty [SFixedBytes]
+    #[inline]
+    pub fn new() -> Self {
+        assert!(N <= u16::MAX as usize);
+
+        Self {
+            len: 0,
+            buf: [0u8; N],
+        }
+    }
+
+    /// Returns the maximum number of bytes this type can hold
+    #[inline]
+    pub const fn capacity() -> usize {
+        N
+    }
+
+    /// Returns the number of bytes stored
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns [true] if there are no bytes stored
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the stored bytes as a slice
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[0..(self.len as usize)]
+    }
+}
+
+impl<const N: usize> Default for SFixedBytes<N> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> TryFrom<&'a [u8]> for SFixedBytes<N> {
+    type Error = &'a [u8];
+
+    /// Returns [Err] with the provided slice, if it is longer than `N`
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        if value.len() > N {
+            return Err(value);
+        }
+
+        let mut it = Self::new();
+        it.buf[0..value.len()].copy_from_slice(value);
+        it.len = value.len() as u16;
+
+        Ok(it)
+    }
+}
+
+impl<const N: usize> TryFrom<Vec<u8>> for SFixedBytes<N> {
+    type Error = Vec<u8>;
+
+    /// Returns [Err] with the provided vector, if it is longer than `N`
+    #[inline]
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        match Self::try_from(value.as_slice()) {
+            Ok(it) => Ok(it),
+            Err(_) => Err(value),
+        }
+    }
+}
+
+impl<const N: usize> Deref for SFixedBytes<N> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.as_bytes()
+    }
+}
+
+impl<const N: usize> Borrow<[u8]> for SFixedBytes<N> {
+    #[inline]
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<const N: usize> PartialEq for SFixedBytes<N> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes().eq(other.as_bytes())
+    }
+}
+
+impl<const N: usize> Eq for SFixedBytes<N> {}
+
+impl<const N: usize> PartialOrd for SFixedBytes<N> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize> Ord for SFixedBytes<N> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl<const N: usize> Hash for SFixedBytes<N> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl<const N: usize> Debug for SFixedBytes<N> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.as_bytes().fmt(f)
+    }
+}
+
+impl<const N: usize> AsFixedSizeBytes for SFixedBytes<N> {
+    const SIZE: usize = u16::SIZE + N;
+    type Buf = Vec<u8>;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.len.as_fixed_size_bytes(&mut buf[0..u16::SIZE]);
+        buf[u16::SIZE..Self::SIZE].copy_from_slice(&self.buf);
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let len = u16::from_fixed_size_bytes(&arr[0..u16::SIZE]);
+        assert!(len as usize <= N);
+
+        let mut buf = [0u8; N];
+        buf.copy_from_slice(&arr[u16::SIZE..Self::SIZE]);
+
+        Self { len, buf }
+    }
+}
+
+impl<const N: usize> StableType for SFixedBytes<N> {}
+
+impl<const N: usize> AsHashableBytes for SFixedBytes<N> {
+    #[inline]
+    fn as_hashable_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SHashMap;
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::s_fixed_bytes::SFixedBytes;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+
+    #[test]
+    fn basic_flow_works_fine() {
+        let empty = SFixedBytes::<4>::default();
+        assert!(empty.is_empty());
+        assert_eq!(SFixedBytes::<4>::capacity(), 4);
+
+        let it = SFixedBytes::<4>::try_from(vec![1u8, 2, 3]).unwrap();
+        assert_eq!(it.len(), 3);
+        assert_eq!(&*it, &[1u8, 2, 3]);
+
+        assert!(SFixedBytes::<4>::try_from(vec![1u8, 2, 3, 4, 5]).is_err());
+
+        let buf = it.as_new_fixed_size_bytes();
+        assert_eq!(buf.len(), SFixedBytes::<4>::SIZE);
+        assert_eq!(SFixedBytes::<4>::from_fixed_size_bytes(buf._deref()), it);
+
+        let smaller = SFixedBytes::<4>::try_from(&[1u8, 2][..]).unwrap();
+        assert!(smaller < it);
+        assert!(empty < smaller);
+    }
+
+    #[test]
+    fn hash_map_keys_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut map = SHashMap::<SFixedBytes<8>, u32>::new();
+
+            for i in 0..100u32 {
+                let key = SFixedBytes::try_from(&i.to_le_bytes()[..]).unwrap();
+                map.insert(key, i).unwrap();
+            }
+
+            for i in 0..100u32 {
+                assert_eq!(*map.get(&i.to_le_bytes()[..]).unwrap(), i);
+            }
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::encoding::AsFixedSizeBytes;
+use crate::primitive::s_fixed_bytes::SFixedBytes;
+use crate::primitive::StableType;
+use crate::utils::certification::AsHashableBytes;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// UTF-8 string of up to `N` bytes, stored inline
+///
+/// Unlike [String], which only implements [AsDynSizeBytes](crate::AsDynSizeBytes) and therefore
+/// needs an [SBox](crate::SBox) (and a separate allocation) to be stored inside a stable collection,
+/// [SFixedString] implements [AsFixedSizeBytes] and can be stored directly. It always occupies
+/// `N + 2` bytes: a `u16` length prefix and `N` bytes of data. This is a thin wrapper around
+/// [SFixedBytes], which guarantees the content is a valid UTF-8 string.
+///
+/// [Ord], [Eq] and [Hash] are consistent with [str], so collections keyed by [SFixedString] can be
+/// queried with a plain `&str`.
+///
+/// # Panics
+/// `N` can't be bigger than [u16::MAX]. Constructing such a value will panic.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SBTreeMap;
+/// # use ic_stable_memory::primitive::s_fixed_string::SFixedString;
+/// # use ic_stable_memory::stable_memory_init;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut balances = SBTreeMap::<SFixedString<32>, u64>::new();
+///
+/// let username = SFixedString::try_from("alice").unwrap();
+/// balances.insert(username, 100).expect("Out of memory");
+///
+/// assert_eq!(*balances.get("alice").unwrap(), 100);
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct SFixedString<const N: usize>(SFixedBytes<N>);
+
+impl<const N: usize> SFixedString<N> {
+    /// Creates an empty [SFixedString]
+    #[inline]
+    pub fn new() -> Self {
+        Self(SFixedBytes::new())
+    }
+
+    /// Returns the maximum number of bytes this type can hold
+    #[inline]
+    pub const fn capacity() -> usize {
+        N
+    }
+
+    /// Returns the length of the string in bytes
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns [true] if the string is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the string slice
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        // utf-8 validity is checked on construction and on decoding
+        unsafe { std::str::from_utf8_unchecked(self.0.as_bytes()) }
+    }
+}
+
+impl<'a, const N: usize> TryFrom<&'a str> for SFixedString<N> {
+    type Error = &'a str;
+
+    /// Returns [Err] with the provided string, if it is longer than `N` bytes
+    #[inline]
+    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+        match SFixedBytes::try_from(value.as_bytes()) {
+            Ok(it) => Ok(Self(it)),
+            Err(_) => Err(value),
+        }
+    }
+}
+
+impl<const N: usize> TryFrom<String> for SFixedString<N> {
+    type Error = String;
+
+    /// Returns [Err] with the provided string, if it is longer than `N` bytes
+    #[inline]
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match SFixedBytes::try_from(value.as_bytes()) {
+            Ok(it) => Ok(Self(it)),
+            Err(_) => Err(value),
+        }
+    }
+}
+
+impl<const N: usize> Deref for SFixedString<N> {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> Borrow<str> for SFixedString<N> {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> PartialOrd for SFixedString<N> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const N: usize> Ord for SFixedString<N> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl<const N: usize> Hash for SFixedString<N> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl<const N: usize> Debug for SFixedString<N> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> Display for SFixedString<N> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> AsFixedSizeBytes for SFixedString<N> {
+    const SIZE: usize = SFixedBytes::<N>::SIZE;
+    type Buf = <SFixedBytes<N> as AsFixedSizeBytes>::Buf;
+
+    #[inline]
+    fn as_fixed_size_bytes(&self, buf: &mut [u8]) {
+        self.0.as_fixed_size_bytes(buf)
+    }
+
+    #[inline]
+    fn from_fixed_size_bytes(arr: &[u8]) -> Self {
+        let bytes = SFixedBytes::<N>::from_fixed_size_bytes(arr);
+        std::str::from_utf8(bytes.as_bytes()).expect("Invalid utf-8 string");
+
+        Self(bytes)
+    }
+}
+
+impl<const N: usize> StableType for SFixedString<N> {}
+
+impl<const N: usize> AsHashableBytes for SFixedString<N> {
+    #[inline]
+    fn as_hashable_bytes(&self) -> Vec<u8> {
+        self.0.as_hashable_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::{SBTreeMap, SHashMap};
+    use crate::encoding::{AsFixedSizeBytes, Buffer};
+    use crate::primitive::s_fixed_string::SFixedString;
+    use crate::utils::test::generate_random_string;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+    use rand::thread_rng;
+
+    #[test]
+    fn basic_flow_works_fine() {
+        let it = SFixedString::<8>::try_from("привет").unwrap_err();
+        assert_eq!(it, "привет");
+
+        let it = SFixedString::<12>::try_from("привет").unwrap();
+        assert_eq!(it.len(), 12);
+        assert_eq!(&*it, "привет");
+        assert_eq!(it.to_string(), "привет");
+        assert_eq!(format!("{:?}", it), "\"привет\"");
+
+        let buf = it.as_new_fixed_size_bytes();
+        assert_eq!(SFixedString::<12>::from_fixed_size_bytes(buf._deref()), it);
+
+        let a = SFixedString::<12>::try_from(String::from("a")).unwrap();
+        let b = SFixedString::<12>::try_from("b").unwrap();
+        assert!(a < b);
+        assert!(SFixedString::<12>::new() < a);
+    }
+
+    #[test]
+    fn collection_keys_work_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut rng = thread_rng();
+            let mut btree = SBTreeMap::<SFixedString<32>, usize>::new();
+            let mut hash_map = SHashMap::<SFixedString<32>, usize>::new();
+
+            let mut keys = Vec::new();
+            for i in 0..100 {
+                let mut key = generate_random_string(&mut rng);
+                key.truncate(32);
+
+                btree
+                    .insert(SFixedString::try_from(key.as_str()).unwrap(), i)
+                    .unwrap();
+                hash_map
+                    .insert(SFixedString::try_from(key.as_str()).unwrap(), i)
+                    .unwrap();
+
+                keys.push(key);
+            }
+
+            for (i, key) in keys.iter().enumerate().rev() {
+                if keys[(i + 1)..].contains(key) {
+                    continue;
+                }
+
+                assert_eq!(*btree.get(key.as_str()).unwrap(), i);
+                assert_eq!(*hash_map.get(key.as_str()).unwrap(), i);
+            }
+
+            let mut sorted = keys.clone();
+            sorted.sort();
+            sorted.dedup();
+
+            let iterated = btree.iter().map(|(k, _)| k.to_string()).collect::<Vec<_>>();
+            assert_eq!(iterated, sorted);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
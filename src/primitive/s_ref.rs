@@ -33,6 +33,22 @@ impl<'o, T> SRef<'o, T> {
             _marker: PhantomData::default(),
         }
     }
+
+    // creates a reference to an already decoded value, which should have its stable drop flag off
+    #[inline]
+    pub(crate) unsafe fn new_loaded(ptr: u64, it: T) -> Self {
+        Self {
+            ptr,
+            inner: UnsafeCell::new(Some(it)),
+            _marker: PhantomData,
+        }
+    }
+
+    // creates a reference to a part of the referenced value, located `offset` bytes after its start
+    #[inline]
+    pub(crate) unsafe fn project<U>(&self, offset: usize) -> SRef<'o, U> {
+        SRef::new(self.ptr + offset as u64)
+    }
 }
 
 impl<'o, T: StableType + AsFixedSizeBytes> SRef<'o, T> {
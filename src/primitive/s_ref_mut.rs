@@ -43,6 +43,16 @@ impl<'o, T: StableType + AsFixedSizeBytes> SRefMut<'o, T> {
         }
     }
 
+    // creates a mutable reference to a part of the referenced value, located `offset` bytes after
+    // its start
+    #[inline]
+    pub(crate) unsafe fn project<U: StableType + AsFixedSizeBytes>(
+        self,
+        offset: usize,
+    ) -> SRefMut<'o, U> {
+        SRefMut::new(self.ptr + offset as u64)
+    }
+
     #[inline]
     unsafe fn repersist(&mut self) {
         if let Some(it) = self.inner.get_mut() {
@@ -0,0 +1,153 @@
+//! Helpers for splitting long operations into instruction-bounded chunks
+
+use crate::stable;
+
+#[inline]
+pub(crate) fn has_budget(start: u64, instruction_budget: u64) -> bool {
+    stable::instruction_counter().saturating_sub(start) < instruction_budget
+}
+
+/// An iterator, that stops once the instruction budget is spent
+///
+/// Created by [WithInstructionBudget::with_instruction_budget]. The budget is checked before each
+/// element, so the last element can overspend it by the cost of producing this element. Use
+/// [InstructionBounded::is_budget_exceeded] to find out, why the iteration has stopped, and a cursor of
+/// the inner iterator to resume it in the next call.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::stable_memory_init;
+/// # use ic_stable_memory::utils::budget::WithInstructionBudget;
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// # stable_memory_init();
+/// let mut vec = SVec::new();
+/// for i in 0..10_000u64 {
+///     vec.push(i).expect("Out of memory");
+/// }
+///
+/// let mut cursor = 0;
+/// let mut exported = Vec::new();
+///
+/// loop {
+///     // in a canister each chunk would be exported in a separate message
+///     let mut iter = vec.iter_from_cursor(cursor).with_instruction_budget(100_000);
+///     exported.extend((&mut iter).map(|it| *it));
+///
+///     if !iter.is_budget_exceeded() {
+///         break;
+///     }
+///
+///     cursor = iter.get_ref().cursor();
+/// }
+///
+/// assert_eq!(exported, (0..10_000u64).collect::<Vec<_>>());
+/// ```
+pub struct InstructionBounded<I> {
+    iter: I,
+    start: u64,
+    instruction_budget: u64,
+    exceeded: bool,
+}
+
+impl<I> InstructionBounded<I> {
+    /// Wraps the iterator, starting to count instructions from this moment
+    #[inline]
+    pub fn new(iter: I, instruction_budget: u64) -> Self {
+        Self {
+            iter,
+            start: stable::instruction_counter(),
+            instruction_budget,
+            exceeded: false,
+        }
+    }
+
+    /// Returns `true` if the iteration was stopped because the budget is spent
+    #[inline]
+    pub fn is_budget_exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    /// Returns a reference to the inner iterator
+    #[inline]
+    pub fn get_ref(&self) -> &I {
+        &self.iter
+    }
+
+    /// Returns the inner iterator
+    #[inline]
+    pub fn into_inner(self) -> I {
+        self.iter
+    }
+}
+
+impl<I: Iterator> Iterator for InstructionBounded<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.exceeded {
+            return None;
+        }
+
+        if !has_budget(self.start, self.instruction_budget) {
+            self.exceeded = true;
+
+            return None;
+        }
+
+        self.iter.next()
+    }
+}
+
+/// Adds [WithInstructionBudget::with_instruction_budget] to every iterator
+pub trait WithInstructionBudget: Iterator + Sized {
+    /// Makes the iterator stop, once `instruction_budget` instructions are executed
+    ///
+    /// In a canister instructions are counted by `ic0.performance_counter`. Outside of it only stable
+    /// memory accesses are counted, see [stable::instruction_counter].
+    #[inline]
+    fn with_instruction_budget(self, instruction_budget: u64) -> InstructionBounded<Self> {
+        InstructionBounded::new(self, instruction_budget)
+    }
+}
+
+impl<I: Iterator> WithInstructionBudget for I {}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::utils::budget::WithInstructionBudget;
+    use crate::{_debug_validate_allocator, get_allocated_size, stable, stable_memory_init};
+
+    #[test]
+    fn works_fine() {
+        stable::clear();
+        stable_memory_init();
+
+        {
+            let mut vec = SVec::new();
+            for i in 0..1000u64 {
+                vec.push(i).unwrap();
+            }
+
+            let mut iter = vec.iter().with_instruction_budget(0);
+            assert!(iter.next().is_none());
+            assert!(iter.is_budget_exceeded());
+
+            let mut iter = vec.iter().with_instruction_budget(u64::MAX);
+            assert_eq!(iter.by_ref().count(), 1000);
+            assert!(!iter.is_budget_exceeded());
+
+            // elements are read in small chunks first, so the budget is checked often enough
+            let mut iter = vec.iter().with_instruction_budget(2_000);
+            let count = iter.by_ref().map(|it| *it).collect::<Vec<_>>().len();
+            assert!(count > 0 && count < 1000);
+            assert!(iter.is_budget_exceeded());
+            assert!(iter.next().is_none());
+            assert_eq!(iter.into_inner().cursor(), count);
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+}
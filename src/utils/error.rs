@@ -0,0 +1,392 @@
+//! Error types returned by this crate.
+//!
+//! Every operation that may allocate stable memory can fail with [OutOfMemory], which tells the
+//! reason of the failure and the size of the memory block that was requested. Collections and
+//! [SBox](crate::SBox) also give back the value they were unable to store, wrapping both into a
+//! [Rejected] error. Values that can't be decoded from stable memory are reported with
+//! [DecodeError].
+
+use crate::PAGE_SIZE_BYTES;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Indicates that the canister is out of stable memory at this moment.
+///
+/// Each variant contains the size (in bytes) of the memory block, which the allocator was unable
+/// to provide.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OutOfMemory {
+    /// Stable memory can't grow, because it would exceed the `max_pages` limit, set via
+    /// [init_allocator()](crate::init_allocator)
+    MaxPagesReached {
+        /// Size of the requested memory block in bytes
+        requested_size: u64,
+        /// The `max_pages` limit at the moment of the failure
+        max_pages: u64,
+    },
+    /// `stable64_grow` call failed, which means that the subnet is out of stable memory
+    SubnetExhausted {
+        /// Size of the requested memory block in bytes
+        requested_size: u64,
+    },
+    /// There is enough free stable memory in total, but there is no contiguous free block large
+    /// enough to fit the requested size, and stable memory can't grow anymore
+    NoContiguousBlock {
+        /// Size of the requested memory block in bytes
+        requested_size: u64,
+        /// Total amount of free stable memory in bytes at the moment of the failure
+        free_size: u64,
+    },
+}
+
+impl OutOfMemory {
+    /// Returns the size of the memory block (in bytes) that the allocator failed to provide
+    #[inline]
+    pub fn requested_size(&self) -> u64 {
+        match self {
+            OutOfMemory::MaxPagesReached { requested_size, .. }
+            | OutOfMemory::SubnetExhausted { requested_size }
+            | OutOfMemory::NoContiguousBlock { requested_size, .. } => *requested_size,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn with_requested_size(self, size: u64) -> Self {
+        match self {
+            OutOfMemory::MaxPagesReached { max_pages, .. } => OutOfMemory::MaxPagesReached {
+                requested_size: size,
+                max_pages,
+            },
+            OutOfMemory::SubnetExhausted { .. } => OutOfMemory::SubnetExhausted {
+                requested_size: size,
+            },
+            OutOfMemory::NoContiguousBlock { free_size, .. } => OutOfMemory::NoContiguousBlock {
+                requested_size: size,
+                free_size,
+            },
+        }
+    }
+}
+
+impl Display for OutOfMemory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutOfMemory::MaxPagesReached {
+                requested_size,
+                max_pages,
+            } => write!(
+                f,
+                "Out of stable memory: unable to allocate {} bytes without exceeding the limit of {} pages ({} bytes)",
+                requested_size,
+                max_pages,
+                max_pages * PAGE_SIZE_BYTES
+            ),
+            OutOfMemory::SubnetExhausted { requested_size } => write!(
+                f,
+                "Out of stable memory: unable to allocate {} bytes, the subnet refused to grow stable memory",
+                requested_size
+            ),
+            OutOfMemory::NoContiguousBlock {
+                requested_size,
+                free_size,
+            } => write!(
+                f,
+                "Out of stable memory: unable to find a contiguous block of {} bytes ({} bytes are free in total)",
+                requested_size, free_size
+            ),
+        }
+    }
+}
+
+impl Error for OutOfMemory {}
+
+/// Error returned by collections (and [SBox](crate::SBox)) when they were unable to store a value
+///
+/// Contains the value that was rejected (so it is not lost and can be retried later or returned back to
+/// the caller) and the [OutOfMemory] error, describing the reason.
+///
+/// # Example
+/// ```rust
+/// # use ic_stable_memory::collections::SVec;
+/// # use ic_stable_memory::{init_allocator, OutOfMemory, Rejected};
+/// # unsafe { ic_stable_memory::mem::clear(); }
+/// init_allocator(1);
+///
+/// let mut vec = SVec::<u64>::new();
+///
+/// loop {
+///     match vec.push(10) {
+///         Ok(_) => continue,
+///         Err(Rejected { value, reason }) => {
+///             assert_eq!(value, 10);
+///             assert!(matches!(reason, OutOfMemory::MaxPagesReached { max_pages: 1, .. }));
+///
+///             break;
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected<T> {
+    /// The value that was not stored
+    pub value: T,
+    /// The reason of the failure
+    pub reason: OutOfMemory,
+}
+
+impl<T> Rejected<T> {
+    /// Constructs a new [Rejected] error
+    #[inline]
+    pub fn new(value: T, reason: OutOfMemory) -> Self {
+        Self { value, reason }
+    }
+
+    /// Returns the rejected value, discarding the reason
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// Transforms the rejected value, keeping the reason
+    #[inline]
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Rejected<U> {
+        Rejected {
+            value: f(self.value),
+            reason: self.reason,
+        }
+    }
+}
+
+impl<T> Display for Rejected<T> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.reason, f)
+    }
+}
+
+impl<T: Debug> Error for Rejected<T> {
+    #[inline]
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.reason)
+    }
+}
+
+impl<T> From<Rejected<T>> for OutOfMemory {
+    #[inline]
+    fn from(it: Rejected<T>) -> Self {
+        it.reason
+    }
+}
+
+/// Indicates that a value can't be decoded from its stable memory representation
+///
+/// Returned by [AsDynSizeBytes::try_from_dyn_size_bytes](crate::AsDynSizeBytes::try_from_dyn_size_bytes)
+/// and [SBox::try_get](crate::SBox::try_get).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DecodeError {
+    /// The buffer is too short to contain the encoded value
+    UnexpectedEnd {
+        /// Minimal number of bytes required to decode the value
+        expected: usize,
+        /// Actual number of bytes in the buffer
+        actual: usize,
+    },
+    /// The encoded string is not a valid UTF-8 string
+    InvalidUtf8,
+    /// The checksum of the encoded value does not match (only with the `checksums` feature enabled)
+    ChecksumMismatch,
+    /// Candid deserialization failed
+    Candid(String),
+    /// The encoded value is invalid for some other reason
+    Invalid(String),
+}
+
+impl DecodeError {
+    /// Returns [DecodeError::UnexpectedEnd] if `buf` is shorter than `expected` bytes
+    #[inline]
+    pub fn check_len(buf: &[u8], expected: usize) -> Result<(), DecodeError> {
+        if buf.len() < expected {
+            Err(DecodeError::UnexpectedEnd {
+                expected,
+                actual: buf.len(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd { expected, actual } => write!(
+                f,
+                "Unexpected end of buffer: expected at least {} bytes, found {}",
+                expected, actual
+            ),
+            DecodeError::InvalidUtf8 => f.write_str("Invalid UTF-8 string"),
+            DecodeError::ChecksumMismatch => f.write_str("Checksum mismatch"),
+            DecodeError::Candid(reason) => write!(f, "Candid decoding failed: {}", reason),
+            DecodeError::Invalid(reason) => write!(f, "Invalid value: {}", reason),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Unified error type for all failures of this crate
+///
+/// Useful for canister methods that perform several different stable memory operations and want to
+/// propagate any of their errors with `?`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StableMemoryError {
+    /// Unable to allocate stable memory
+    OutOfMemory(OutOfMemory),
+    /// Unable to decode a value read from stable memory
+    Decoding(DecodeError),
+}
+
+impl Display for StableMemoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StableMemoryError::OutOfMemory(e) => Display::fmt(e, f),
+            StableMemoryError::Decoding(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl Error for StableMemoryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StableMemoryError::OutOfMemory(e) => Some(e),
+            StableMemoryError::Decoding(e) => Some(e),
+        }
+    }
+}
+
+impl From<OutOfMemory> for StableMemoryError {
+    #[inline]
+    fn from(it: OutOfMemory) -> Self {
+        StableMemoryError::OutOfMemory(it)
+    }
+}
+
+impl From<DecodeError> for StableMemoryError {
+    #[inline]
+    fn from(it: DecodeError) -> Self {
+        StableMemoryError::Decoding(it)
+    }
+}
+
+impl<T> From<Rejected<T>> for StableMemoryError {
+    #[inline]
+    fn from(it: Rejected<T>) -> Self {
+        StableMemoryError::OutOfMemory(it.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::SVec;
+    use crate::{
+        _debug_validate_allocator, allocate, deallocate, get_allocated_size, init_allocator,
+        reserve, stable, OutOfMemory, Rejected, StableMemoryError, PAGE_SIZE_BYTES,
+    };
+
+    #[test]
+    fn max_pages_works_fine() {
+        stable::clear();
+        init_allocator(2);
+
+        {
+            let e = reserve(PAGE_SIZE_BYTES * 4).unwrap_err();
+            assert_eq!(
+                e,
+                OutOfMemory::MaxPagesReached {
+                    requested_size: PAGE_SIZE_BYTES * 4,
+                    max_pages: 2
+                }
+            );
+            assert_eq!(e.requested_size(), PAGE_SIZE_BYTES * 4);
+
+            let mut vec = SVec::<u64>::new();
+            let e = loop {
+                if let Err(e) = vec.push(10) {
+                    break e;
+                }
+            };
+
+            assert_eq!(e.value, 10);
+            assert!(matches!(
+                e.reason,
+                OutOfMemory::MaxPagesReached { max_pages: 2, .. }
+            ));
+
+            // the vec is still usable after the failure
+            let len = vec.len();
+            assert_eq!(vec.pop(), Some(10));
+            vec.push(10).unwrap();
+            assert_eq!(vec.len(), len);
+
+            let e: StableMemoryError = e.into();
+            assert!(matches!(e, StableMemoryError::OutOfMemory(_)));
+            assert!(e.to_string().contains("2 pages"));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn no_contiguous_block_works_fine() {
+        stable::clear();
+        init_allocator(1);
+
+        unsafe {
+            let mut slices = Vec::new();
+            while let Ok(slice) = allocate(1000) {
+                slices.push(slice);
+            }
+
+            let mut kept = Vec::new();
+            for (i, slice) in slices.into_iter().enumerate() {
+                if i % 2 == 0 {
+                    deallocate(slice);
+                } else {
+                    kept.push(slice);
+                }
+            }
+
+            let e = allocate(4000).unwrap_err();
+            assert!(matches!(
+                e,
+                OutOfMemory::NoContiguousBlock {
+                    requested_size: 4000,
+                    ..
+                }
+            ));
+
+            for slice in kept {
+                deallocate(slice);
+            }
+
+            let e = allocate(PAGE_SIZE_BYTES * 2).unwrap_err();
+            assert!(matches!(e, OutOfMemory::MaxPagesReached { .. }));
+        }
+
+        _debug_validate_allocator();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn rejected_works_fine() {
+        let reason = OutOfMemory::SubnetExhausted {
+            requested_size: 100,
+        };
+        let e = Rejected::new((1u64, 2u64), reason);
+
+        assert_eq!(e.to_string(), reason.to_string());
+        assert_eq!(e.clone().map(|(k, _)| k).into_inner(), 1);
+        assert_eq!(OutOfMemory::from(e), reason);
+    }
+}
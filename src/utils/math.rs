@@ -1,3 +1,6 @@
+use std::hash::{Hash, Hasher};
+use zwohash::ZwoHasher;
+
 /// Efficient ceiling division of [u64]
 ///
 /// # Important
@@ -26,3 +29,21 @@ pub const fn max_usize(a: usize, b: usize) -> usize {
         b
     }
 }
+
+/// Hashes the value with [zwohash](https://github.com/jix/zwohash), mixing the seed in first
+///
+/// Hashes are deterministic between canister upgrades. Different seeds give independent hashes of
+/// the same value. Unlike plain zwohash, all bits of the result are well mixed (it is finalized
+/// with the `splitmix64` finalizer), so the result can be reduced with `%` or a bit mask.
+#[inline]
+pub fn seeded_hash<T: Hash + ?Sized>(val: &T, seed: u64) -> u64 {
+    let mut hasher = ZwoHasher::default();
+    hasher.write_u64(seed);
+    val.hash(&mut hasher);
+
+    let mut h = hasher.finish();
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    h ^ (h >> 31)
+}
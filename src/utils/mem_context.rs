@@ -11,15 +11,12 @@
 //!
 //! This makes it possible to write full-scale tests which use stable memory as their main memory.
 
+use crate::utils::error::OutOfMemory;
 use std::cmp::min;
 
 /// Each wasm memory page is 64K in size
 pub const PAGE_SIZE_BYTES: u64 = 64 * 1024;
 
-/// Indicates that the canister is out of stable memory at this moment.
-#[derive(Debug, Copy, Clone)]
-pub struct OutOfMemory;
-
 pub(crate) trait MemContext {
     fn size_pages(&self) -> u64;
     fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory>;
@@ -42,7 +39,9 @@ impl MemContext for StableMemContext {
 
     #[inline]
     fn grow(&mut self, new_pages: u64) -> Result<u64, OutOfMemory> {
-        stable64_grow(new_pages).map_err(|_| OutOfMemory)
+        stable64_grow(new_pages).map_err(|_| OutOfMemory::SubnetExhausted {
+            requested_size: new_pages * PAGE_SIZE_BYTES,
+        })
     }
 
     #[inline]
@@ -160,7 +159,8 @@ impl MemContext for TestMemContext {
 
 #[cfg(target_family = "wasm")]
 pub mod stable {
-    use crate::utils::mem_context::{MemContext, OutOfMemory, StableMemContext};
+    use crate::utils::error::OutOfMemory;
+    use crate::utils::mem_context::{MemContext, StableMemContext};
 
     #[inline]
     pub fn size_pages() -> u64 {
@@ -181,15 +181,29 @@ pub mod stable {
     pub fn write(offset: u64, buf: &[u8]) {
         MemContext::write(&mut StableMemContext, offset, buf)
     }
+
+    /// Returns the number of instructions the canister executed during the current message
+    #[inline]
+    pub fn instruction_counter() -> u64 {
+        ic_cdk::api::instruction_counter()
+    }
 }
 
 #[cfg(not(target_family = "wasm"))]
 pub mod stable {
-    use crate::utils::mem_context::{MemContext, OutOfMemory, TestMemContext};
-    use std::cell::RefCell;
+    use crate::utils::error::OutOfMemory;
+    use crate::utils::mem_context::{MemContext, TestMemContext, PAGE_SIZE_BYTES};
+    use std::cell::{Cell, RefCell};
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+
+    // emulated cost of a single stable memory access, in addition to a cost of each byte
+    const ACCESS_INSTRUCTIONS: u64 = 100;
 
     thread_local! {
         static CONTEXT: RefCell<TestMemContext> = RefCell::new(TestMemContext::default());
+        static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
     }
 
     #[inline]
@@ -209,13 +223,86 @@ pub mod stable {
 
     #[inline]
     pub fn read(offset: u64, buf: &mut [u8]) {
+        count_instructions(buf.len());
         CONTEXT.with(|it| it.borrow().read(offset, buf))
     }
 
     #[inline]
     pub fn write(offset: u64, buf: &[u8]) {
+        count_instructions(buf.len());
         CONTEXT.with(|it| it.borrow_mut().write(offset, buf))
     }
+
+    /// Returns the number of instructions executed so far
+    ///
+    /// Outside of a canister only stable memory accesses are counted, so this is a rough emulation
+    /// which is only good for testing instruction-bounded algorithms.
+    #[inline]
+    pub fn instruction_counter() -> u64 {
+        INSTRUCTIONS.with(|it| it.get())
+    }
+
+    /// Writes the whole emulated stable memory into a file
+    ///
+    /// The file contains raw stable memory bytes, page after page, so it can be inspected with
+    /// any hex editor or compared with a stable memory dump of a real canister.
+    pub fn save_to_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+
+        CONTEXT.with(|it| {
+            for page in &it.borrow().pages {
+                file.write_all(page)?;
+            }
+
+            Ok::<(), io::Error>(())
+        })?;
+
+        file.sync_all()
+    }
+
+    /// Replaces the whole emulated stable memory with the contents of a file
+    ///
+    /// The file should contain raw stable memory bytes, for example, one created by [save_to_file]
+    /// or a stable memory dump of a real canister. Its size has to be a multiple of
+    /// [PAGE_SIZE_BYTES], otherwise an [io::ErrorKind::InvalidData] error is returned and the stable
+    /// memory stays untouched.
+    ///
+    /// Stable data structures have the same layout in a native test build and in a `wasm32`
+    /// canister, since pointer-sized numbers (`usize` and `isize`) are always encoded with 4 bytes.
+    ///
+    /// The allocator is not affected by this function. Reinitialize it with
+    /// [stable_memory_post_upgrade](crate::stable_memory_post_upgrade) after loading, just like a
+    /// canister does after an upgrade.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        if size % PAGE_SIZE_BYTES != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Stable memory snapshot size ({size} bytes) is not a multiple of page size"
+                ),
+            ));
+        }
+
+        let mut pages = Vec::with_capacity((size / PAGE_SIZE_BYTES) as usize);
+        for _ in 0..(size / PAGE_SIZE_BYTES) {
+            let mut page = [0u8; PAGE_SIZE_BYTES as usize];
+            file.read_exact(&mut page)?;
+
+            pages.push(page);
+        }
+
+        CONTEXT.with(|it| it.borrow_mut().pages = pages);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn count_instructions(bytes: usize) {
+        INSTRUCTIONS.with(|it| it.set(it.get() + ACCESS_INSTRUCTIONS + bytes as u64))
+    }
 }
 
 #[cfg(test)]
@@ -260,6 +347,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn files_work_fine() {
+        let path = std::env::temp_dir().join(format!(
+            "ic-stable-memory-files-work-fine-{}.bin",
+            std::process::id()
+        ));
+
+        stable::clear();
+        stable::grow(3).unwrap();
+
+        let buf = (0..(PAGE_SIZE_BYTES * 3))
+            .map(|it| (it % 251) as u8)
+            .collect::<Vec<_>>();
+        stable::write(0, &buf);
+
+        stable::save_to_file(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), PAGE_SIZE_BYTES * 3);
+
+        stable::clear();
+        assert_eq!(stable::size_pages(), 0);
+
+        stable::load_from_file(&path).unwrap();
+        assert_eq!(stable::size_pages(), 3);
+
+        let mut buf1 = vec![0u8; buf.len()];
+        stable::read(0, &mut buf1);
+        assert_eq!(buf, buf1);
+
+        // truncated snapshots are rejected
+        std::fs::write(&path, [1u8; 100]).unwrap();
+        assert_eq!(
+            stable::load_from_file(&path).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(stable::size_pages(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(stable::load_from_file(&path).is_err());
+    }
+
     #[test]
     fn big_reads_writes_work_fine() {
         stable::clear();
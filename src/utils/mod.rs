@@ -1,7 +1,9 @@
 //! Various utilities used by this crate
 
+pub mod budget;
 #[doc(hidden)]
 pub mod certification;
+pub mod error;
 #[doc(hidden)]
 pub mod math;
 pub mod mem_context;